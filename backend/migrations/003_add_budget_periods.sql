@@ -0,0 +1,34 @@
+-- Migration: Add periodic budgets to virtual keys
+-- Keys with a budget_period have current_spend reset at budget_reset_at,
+-- and the previous period's total is archived in virtual_key_budget_history
+
+DO $$
+BEGIN
+    IF NOT EXISTS (
+        SELECT 1 FROM information_schema.columns
+        WHERE table_name = 'virtual_keys' AND column_name = 'budget_period'
+    ) THEN
+        ALTER TABLE virtual_keys ADD COLUMN budget_period VARCHAR(50);
+        ALTER TABLE virtual_keys ADD COLUMN budget_reset_at TIMESTAMP WITH TIME ZONE;
+    END IF;
+END $$;
+
+CREATE TABLE IF NOT EXISTS virtual_key_budget_history (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    virtual_key_id UUID NOT NULL REFERENCES virtual_keys(id) ON DELETE CASCADE,
+    period_start TIMESTAMP WITH TIME ZONE NOT NULL,
+    period_end TIMESTAMP WITH TIME ZONE NOT NULL,
+    spend DOUBLE PRECISION NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_virtual_keys_budget_reset_at
+ON virtual_keys(budget_reset_at)
+WHERE budget_period IS NOT NULL;
+
+CREATE INDEX IF NOT EXISTS idx_virtual_key_budget_history_key_id
+ON virtual_key_budget_history(virtual_key_id, period_end DESC);
+
+COMMENT ON COLUMN virtual_keys.budget_period IS 'Budget reset period: daily, weekly, monthly or a duration like 30d';
+COMMENT ON COLUMN virtual_keys.budget_reset_at IS 'When current_spend is next reset to zero';
+COMMENT ON TABLE virtual_key_budget_history IS 'Spend of each completed budget period per virtual key';
@@ -0,0 +1,30 @@
+-- Migration: Add rate limits and budgets above the virtual key level
+-- Users carry limits shared by all of their keys, and global_limits holds a
+-- single row of gateway-wide limits. NULL means unlimited.
+
+DO $$
+BEGIN
+    IF NOT EXISTS (
+        SELECT 1 FROM information_schema.columns
+        WHERE table_name = 'users' AND column_name = 'rate_limit_rpm'
+    ) THEN
+        ALTER TABLE users ADD COLUMN rate_limit_rpm INTEGER;
+        ALTER TABLE users ADD COLUMN rate_limit_tpm INTEGER;
+        ALTER TABLE users ADD COLUMN max_budget DOUBLE PRECISION;
+        ALTER TABLE users ADD COLUMN current_spend DOUBLE PRECISION NOT NULL DEFAULT 0;
+    END IF;
+END $$;
+
+CREATE TABLE IF NOT EXISTS global_limits (
+    id BOOLEAN PRIMARY KEY DEFAULT true CHECK (id),
+    rate_limit_rpm INTEGER,
+    rate_limit_tpm INTEGER,
+    max_budget DOUBLE PRECISION,
+    current_spend DOUBLE PRECISION NOT NULL DEFAULT 0,
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+INSERT INTO global_limits (id) VALUES (true) ON CONFLICT DO NOTHING;
+
+COMMENT ON COLUMN users.max_budget IS 'Spend cap shared by all of the user''s virtual keys';
+COMMENT ON TABLE global_limits IS 'Gateway-wide rate limits and budget (single row)';
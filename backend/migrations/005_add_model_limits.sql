@@ -0,0 +1,18 @@
+-- Migration: Add per-model rate limits and budgets to virtual keys
+-- model_limits maps a model name or pattern (e.g. "claude-3-opus*") to
+-- {"rate_limit_rpm", "rate_limit_tpm", "max_budget"}; model_spend holds the
+-- spend per pattern for the current budget period.
+
+DO $$
+BEGIN
+    IF NOT EXISTS (
+        SELECT 1 FROM information_schema.columns
+        WHERE table_name = 'virtual_keys' AND column_name = 'model_limits'
+    ) THEN
+        ALTER TABLE virtual_keys ADD COLUMN model_limits JSONB NOT NULL DEFAULT '{}'::jsonb;
+        ALTER TABLE virtual_keys ADD COLUMN model_spend JSONB NOT NULL DEFAULT '{}'::jsonb;
+    END IF;
+END $$;
+
+COMMENT ON COLUMN virtual_keys.model_limits IS 'Per-model rate limits and budgets, keyed by model name or pattern';
+COMMENT ON COLUMN virtual_keys.model_spend IS 'Spend per model_limits pattern for the current budget period';
@@ -0,0 +1,15 @@
+-- Migration: Add a parallel request limit to virtual keys
+-- In-flight requests are counted in a Redis semaphore (concurrency:{key_id});
+-- NULL means the key has no parallel request limit.
+
+DO $$
+BEGIN
+    IF NOT EXISTS (
+        SELECT 1 FROM information_schema.columns
+        WHERE table_name = 'virtual_keys' AND column_name = 'max_parallel_requests'
+    ) THEN
+        ALTER TABLE virtual_keys ADD COLUMN max_parallel_requests INTEGER;
+    END IF;
+END $$;
+
+COMMENT ON COLUMN virtual_keys.max_parallel_requests IS 'Maximum number of requests in flight at once for this key';
@@ -0,0 +1,49 @@
+-- Migration: Add teams with members, team-owned keys and per-team usage
+-- Team budgets and rate limits apply on top of each key's own limits, like the
+-- user and global limits. Keys keep their creator in user_id; team_id decides
+-- which team is billed.
+
+CREATE TABLE IF NOT EXISTS teams (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    name VARCHAR(255) UNIQUE NOT NULL,
+    allowed_models TEXT[],
+    max_budget DOUBLE PRECISION,
+    current_spend DOUBLE PRECISION NOT NULL DEFAULT 0,
+    rate_limit_rpm INTEGER,
+    rate_limit_tpm INTEGER,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE TABLE IF NOT EXISTS team_members (
+    team_id UUID NOT NULL REFERENCES teams(id) ON DELETE CASCADE,
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    role VARCHAR(50) NOT NULL DEFAULT 'member'
+        CHECK (role IN ('owner', 'admin', 'member')),
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (team_id, user_id)
+);
+
+DO $$
+BEGIN
+    IF NOT EXISTS (
+        SELECT 1 FROM information_schema.columns
+        WHERE table_name = 'virtual_keys' AND column_name = 'team_id'
+    ) THEN
+        ALTER TABLE virtual_keys ADD COLUMN team_id UUID REFERENCES teams(id) ON DELETE SET NULL;
+    END IF;
+
+    IF NOT EXISTS (
+        SELECT 1 FROM information_schema.columns
+        WHERE table_name = 'usage_records' AND column_name = 'team_id'
+    ) THEN
+        ALTER TABLE usage_records ADD COLUMN team_id UUID REFERENCES teams(id) ON DELETE SET NULL;
+    END IF;
+END $$;
+
+CREATE INDEX IF NOT EXISTS idx_team_members_user_id ON team_members(user_id);
+CREATE INDEX IF NOT EXISTS idx_virtual_keys_team_id ON virtual_keys(team_id);
+CREATE INDEX IF NOT EXISTS idx_usage_records_team_id ON usage_records(team_id, created_at DESC);
+
+COMMENT ON COLUMN virtual_keys.team_id IS 'Team that owns the key and is billed for its usage';
+COMMENT ON COLUMN usage_records.team_id IS 'Team billed for the request, for per-team spend reporting';
@@ -0,0 +1,22 @@
+-- Migration: Virtual key rotation with a grace period
+-- Rotating a key issues a new secret for the same key ID. The replaced secret's hashes
+-- move to the previous_key_* columns and keep authenticating until
+-- previous_key_expires_at; previous_key_last_used_at shows whether callers still use it.
+
+DO $$
+BEGIN
+    IF NOT EXISTS (
+        SELECT 1 FROM information_schema.columns
+        WHERE table_name = 'virtual_keys' AND column_name = 'previous_key_hash'
+    ) THEN
+        ALTER TABLE virtual_keys ADD COLUMN previous_key_hash TEXT;
+        ALTER TABLE virtual_keys ADD COLUMN previous_key_lookup_hash VARCHAR(64);
+        ALTER TABLE virtual_keys ADD COLUMN previous_key_prefix VARCHAR(20);
+        ALTER TABLE virtual_keys ADD COLUMN previous_key_expires_at TIMESTAMP WITH TIME ZONE;
+        ALTER TABLE virtual_keys ADD COLUMN previous_key_last_used_at TIMESTAMP WITH TIME ZONE;
+    END IF;
+END $$;
+
+CREATE INDEX IF NOT EXISTS idx_virtual_keys_previous_key_lookup_hash
+ON virtual_keys(previous_key_lookup_hash)
+WHERE previous_key_lookup_hash IS NOT NULL;
@@ -0,0 +1,17 @@
+-- Migration: Virtual key route, network and origin restrictions
+-- allowed_routes limits a key to matching request paths ("*" wildcards), allowed_ips to
+-- source addresses or CIDR ranges, and allowed_origins to browser Origin/Referer origins.
+-- NULL means unrestricted. read_only keys may only make GET requests (e.g. /stats).
+
+DO $$
+BEGIN
+    IF NOT EXISTS (
+        SELECT 1 FROM information_schema.columns
+        WHERE table_name = 'virtual_keys' AND column_name = 'allowed_routes'
+    ) THEN
+        ALTER TABLE virtual_keys ADD COLUMN allowed_routes TEXT[];
+        ALTER TABLE virtual_keys ADD COLUMN allowed_ips TEXT[];
+        ALTER TABLE virtual_keys ADD COLUMN allowed_origins TEXT[];
+        ALTER TABLE virtual_keys ADD COLUMN read_only BOOLEAN NOT NULL DEFAULT FALSE;
+    END IF;
+END $$;
@@ -0,0 +1,21 @@
+-- Migration: Model permission patterns, access groups and deny lists
+-- allowed_models entries on keys and teams may now be exact names, "*" patterns,
+-- "provider:<id>" or "group:<name>", where groups are defined in model_access_groups.
+-- denied_models takes the same entries and wins over allowed_models.
+
+CREATE TABLE IF NOT EXISTS model_access_groups (
+    name VARCHAR(255) PRIMARY KEY,
+    models TEXT[] NOT NULL DEFAULT '{}',
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+DO $$
+BEGIN
+    IF NOT EXISTS (
+        SELECT 1 FROM information_schema.columns
+        WHERE table_name = 'virtual_keys' AND column_name = 'denied_models'
+    ) THEN
+        ALTER TABLE virtual_keys ADD COLUMN denied_models TEXT[];
+    END IF;
+END $$;
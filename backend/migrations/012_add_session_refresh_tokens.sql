@@ -0,0 +1,22 @@
+-- Migration: Refresh token rotation and session revocation
+-- sessions.token_hash now holds the SHA-256 of the session's current refresh token.
+-- previous_token_hash keeps the token replaced by the last refresh so its reuse can be
+-- detected; revoked_at ends a session (and its access tokens) before expires_at.
+
+DO $$
+BEGIN
+    IF NOT EXISTS (
+        SELECT 1 FROM information_schema.columns
+        WHERE table_name = 'sessions' AND column_name = 'revoked_at'
+    ) THEN
+        ALTER TABLE sessions ADD COLUMN previous_token_hash VARCHAR(255);
+        ALTER TABLE sessions ADD COLUMN revoked_at TIMESTAMP WITH TIME ZONE;
+        ALTER TABLE sessions ADD COLUMN last_refreshed_at TIMESTAMP WITH TIME ZONE;
+        -- Sessions from before refresh tokens have no usable token; their JWTs stop validating
+        DELETE FROM sessions;
+    END IF;
+END $$;
+
+CREATE INDEX IF NOT EXISTS idx_sessions_previous_token_hash
+ON sessions(previous_token_hash)
+WHERE previous_token_hash IS NOT NULL;
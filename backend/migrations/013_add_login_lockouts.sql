@@ -0,0 +1,30 @@
+-- Migration: Login brute-force protection
+-- Consecutive failed password logins are counted per user; reaching the threshold locks
+-- the account until locked_until. Each lockout is recorded in login_lockouts, along with
+-- who unlocked it early.
+
+DO $$
+BEGIN
+    IF NOT EXISTS (
+        SELECT 1 FROM information_schema.columns
+        WHERE table_name = 'users' AND column_name = 'locked_until'
+    ) THEN
+        ALTER TABLE users ADD COLUMN failed_login_attempts INTEGER NOT NULL DEFAULT 0;
+        ALTER TABLE users ADD COLUMN last_failed_login_at TIMESTAMP WITH TIME ZONE;
+        ALTER TABLE users ADD COLUMN locked_until TIMESTAMP WITH TIME ZONE;
+    END IF;
+END $$;
+
+CREATE TABLE IF NOT EXISTS login_lockouts (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    ip_address VARCHAR(45),
+    failed_attempts INTEGER NOT NULL,
+    locked_until TIMESTAMP WITH TIME ZONE NOT NULL,
+    unlocked_at TIMESTAMP WITH TIME ZONE,
+    unlocked_by UUID REFERENCES users(id) ON DELETE SET NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_login_lockouts_user_id
+ON login_lockouts(user_id, created_at DESC);
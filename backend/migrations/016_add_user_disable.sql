@@ -0,0 +1,15 @@
+-- Migration: Disabling users
+-- A disabled user (disabled_at set) can't sign in and their keys are blocked.
+-- blocked_by_user_disable marks the keys that disabling blocked, so enabling the user
+-- again unblocks only those and leaves keys blocked for other reasons alone.
+
+DO $$
+BEGIN
+    IF NOT EXISTS (
+        SELECT 1 FROM information_schema.columns
+        WHERE table_name = 'users' AND column_name = 'disabled_at'
+    ) THEN
+        ALTER TABLE users ADD COLUMN disabled_at TIMESTAMP WITH TIME ZONE;
+        ALTER TABLE virtual_keys ADD COLUMN blocked_by_user_disable BOOLEAN NOT NULL DEFAULT false;
+    END IF;
+END $$;
@@ -0,0 +1,16 @@
+-- Migration: Budget period anchors
+-- budget_anchor_at is the start of a key's first budget period. Every reset is computed
+-- from it, so monthly budgets anchored on the 29th-31st don't drift to the 28th after
+-- February. Existing periodic budgets count their periods from the pending reset.
+
+DO $$
+BEGIN
+    IF NOT EXISTS (
+        SELECT 1 FROM information_schema.columns
+        WHERE table_name = 'virtual_keys' AND column_name = 'budget_anchor_at'
+    ) THEN
+        ALTER TABLE virtual_keys ADD COLUMN budget_anchor_at TIMESTAMP WITH TIME ZONE;
+        UPDATE virtual_keys SET budget_anchor_at = budget_reset_at
+        WHERE budget_period IS NOT NULL;
+    END IF;
+END $$;
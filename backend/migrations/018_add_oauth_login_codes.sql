@@ -0,0 +1,12 @@
+-- Migration: One-time OAuth login codes
+-- The OAuth callback redirects to the frontend with a short-lived code instead of the
+-- session's tokens, so they never end up in browser history, logs or Referer headers.
+-- The frontend exchanges the code over POST; the row is deleted as it is read.
+
+CREATE TABLE IF NOT EXISTS oauth_login_codes (
+    code_hash VARCHAR(64) PRIMARY KEY,
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    provider VARCHAR(100) NOT NULL,
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
@@ -0,0 +1,346 @@
+//! Audit log of administrative and security-relevant actions
+//!
+//! Handlers describe what they did with an [`AuditRecord`] and append it through the
+//! request's [`AuditContext`], which knows who made the request and from where. Objects
+//! are stored as a before/after diff of their JSON form, with secrets redacted.
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts},
+    http::{header, request::Parts, HeaderMap},
+};
+use serde::Serialize;
+use serde_json::{Map, Value};
+use sqlx::{Pool, Postgres};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::{
+    auth::{client_ip, AuthType, AuthUser, HasTrustedProxy},
+    models::{AuditEvent, NewAuditEvent},
+};
+
+/// Stands in for the value of a secret field
+const REDACTED: &str = "[REDACTED]";
+
+/// Longest user agent stored with an event
+const MAX_USER_AGENT_LEN: usize = 512;
+
+/// Fields left out of update diffs because every change touches them
+const IGNORED_DIFF_FIELDS: &[&str] = &["updated_at"];
+
+/// Who performed an audited action
+#[derive(Debug, Clone)]
+pub struct AuditActor {
+    /// `user`, `virtual_key`, `master_key` or `anonymous`
+    pub actor_type: &'static str,
+    pub id: Option<Uuid>,
+    pub email: Option<String>,
+    /// Key the request was made with, for `virtual_key` actors
+    pub key_id: Option<Uuid>,
+}
+
+impl AuditActor {
+    pub fn anonymous() -> Self {
+        Self {
+            actor_type: "anonymous",
+            id: None,
+            email: None,
+            key_id: None,
+        }
+    }
+
+    pub fn user(id: Uuid, email: &str) -> Self {
+        Self {
+            actor_type: "user",
+            id: Some(id),
+            email: Some(email.to_string()),
+            key_id: None,
+        }
+    }
+}
+
+impl From<&AuthUser> for AuditActor {
+    fn from(auth_user: &AuthUser) -> Self {
+        // System keys and the master key have no user
+        let id = Some(auth_user.user_id).filter(|id| !id.is_nil());
+        match auth_user.auth_type {
+            AuthType::MasterKey => Self {
+                actor_type: "master_key",
+                id: None,
+                email: None,
+                key_id: None,
+            },
+            AuthType::JWT { .. } => Self {
+                actor_type: "user",
+                id,
+                email: Some(auth_user.email.clone()),
+                key_id: None,
+            },
+            AuthType::VirtualKey { key_id } => Self {
+                actor_type: "virtual_key",
+                id,
+                email: id.map(|_| auth_user.email.clone()),
+                key_id: Some(key_id),
+            },
+        }
+    }
+}
+
+/// Who made a request and from where, for the audit events it leads to
+///
+/// Extracting it never fails: requests without authentication are `anonymous`.
+#[derive(Debug, Clone)]
+pub struct AuditContext {
+    pub actor: AuditActor,
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+impl AuditContext {
+    pub fn from_request(
+        headers: &HeaderMap,
+        peer: Option<SocketAddr>,
+        trust_forwarded_for: bool,
+        auth_user: Option<&AuthUser>,
+    ) -> Self {
+        let user_agent = headers
+            .get(header::USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .map(|agent| agent.chars().take(MAX_USER_AGENT_LEN).collect());
+
+        Self {
+            actor: auth_user.map_or_else(AuditActor::anonymous, AuditActor::from),
+            ip_address: client_ip(headers, peer, trust_forwarded_for).map(|ip| ip.to_string()),
+            user_agent,
+        }
+    }
+
+    /// The same request attributed to another actor, e.g. the user a login identified
+    pub fn with_actor(&self, actor: AuditActor) -> Self {
+        Self {
+            actor,
+            ..self.clone()
+        }
+    }
+
+    /// Append an event to the audit log
+    ///
+    /// A failed write is logged rather than failing the action, which has already happened.
+    pub async fn record(&self, pool: &Pool<Postgres>, record: AuditRecord) {
+        let event = NewAuditEvent {
+            actor_type: self.actor.actor_type.to_string(),
+            actor_id: self.actor.id,
+            actor_email: self.actor.email.clone(),
+            actor_key_id: self.actor.key_id,
+            action: record.action.to_string(),
+            target_type: record.target_type.map(str::to_string),
+            target_id: record.target_id.clone(),
+            changes: record.changes(),
+            ip_address: self.ip_address.clone(),
+            user_agent: self.user_agent.clone(),
+        };
+
+        if let Err(e) = AuditEvent::insert(pool, &event).await {
+            tracing::error!("Failed to write audit event {}: {}", record.action, e);
+        }
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<Arc<S>> for AuditContext
+where
+    S: HasTrustedProxy + Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<S>,
+    ) -> Result<Self, Self::Rejection> {
+        let peer = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| *addr);
+
+        Ok(Self::from_request(
+            &parts.headers,
+            peer,
+            state.trusts_forwarded_for(),
+            parts.extensions.get::<AuthUser>(),
+        ))
+    }
+}
+
+/// What an audited action did, e.g.
+/// `AuditRecord::new("key.update").target("virtual_key", key.id).updated(&key, &updated)`
+#[derive(Debug, Clone)]
+pub struct AuditRecord {
+    action: &'static str,
+    target_type: Option<&'static str>,
+    target_id: Option<String>,
+    before: Option<Value>,
+    after: Option<Value>,
+    details: Option<Value>,
+}
+
+impl AuditRecord {
+    pub fn new(action: &'static str) -> Self {
+        Self {
+            action,
+            target_type: None,
+            target_id: None,
+            before: None,
+            after: None,
+            details: None,
+        }
+    }
+
+    pub fn target(mut self, target_type: &'static str, target_id: impl ToString) -> Self {
+        self.target_type = Some(target_type);
+        self.target_id = Some(target_id.to_string());
+        self
+    }
+
+    pub fn created(mut self, after: &impl Serialize) -> Self {
+        self.after = serde_json::to_value(after).ok();
+        self
+    }
+
+    /// Only the fields that changed are stored
+    pub fn updated(mut self, before: &impl Serialize, after: &impl Serialize) -> Self {
+        self.before = serde_json::to_value(before).ok();
+        self.after = serde_json::to_value(after).ok();
+        self
+    }
+
+    pub fn deleted(mut self, before: &impl Serialize) -> Self {
+        self.before = serde_json::to_value(before).ok();
+        self
+    }
+
+    /// Anything else worth knowing about the action
+    pub fn details(mut self, details: Value) -> Self {
+        self.details = Some(details);
+        self
+    }
+
+    /// `{"before": .., "after": .., "details": ..}` with secrets redacted
+    fn changes(&self) -> Option<Value> {
+        let (before, after) = match (&self.before, &self.after) {
+            (Some(before), Some(after)) => {
+                let (before, after) = diff(before, after);
+                (Some(before), Some(after))
+            }
+            (before, after) => (before.clone(), after.clone()),
+        };
+
+        let mut changes = Map::new();
+        for (name, value) in [
+            ("before", before),
+            ("after", after),
+            ("details", self.details.clone()),
+        ] {
+            if let Some(mut value) = value {
+                redact(&mut value);
+                changes.insert(name.to_string(), value);
+            }
+        }
+        (!changes.is_empty()).then_some(Value::Object(changes))
+    }
+}
+
+/// The fields of two objects that differ (missing fields count as null); values that
+/// aren't both objects are returned whole
+fn diff(before: &Value, after: &Value) -> (Value, Value) {
+    let (Value::Object(before), Value::Object(after)) = (before, after) else {
+        return (before.clone(), after.clone());
+    };
+
+    let (mut old, mut new) = (Map::new(), Map::new());
+    for field in before.keys().chain(after.keys()) {
+        if IGNORED_DIFF_FIELDS.contains(&field.as_str()) || old.contains_key(field) {
+            continue;
+        }
+        let old_value = before.get(field).unwrap_or(&Value::Null);
+        let new_value = after.get(field).unwrap_or(&Value::Null);
+        if old_value != new_value {
+            old.insert(field.clone(), old_value.clone());
+            new.insert(field.clone(), new_value.clone());
+        }
+    }
+    (Value::Object(old), Value::Object(new))
+}
+
+/// Replace the values of secret-looking fields, at any depth
+fn redact(value: &mut Value) {
+    match value {
+        Value::Object(fields) => {
+            for (name, value) in fields.iter_mut() {
+                if is_secret_field(name) && !value.is_null() {
+                    *value = Value::String(REDACTED.to_string());
+                } else {
+                    redact(value);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
+
+/// Fields named like `password`, `api_key`, `refresh_token`, `key_hash` or `totp_code`
+fn is_secret_field(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    let last_word = name.rsplit(['_', '-']).next().unwrap_or(&name);
+    matches!(
+        last_word,
+        "password" | "secret" | "token" | "key" | "hash" | "encrypted" | "code" | "codes"
+    ) || name == "authorization"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_update_diff_keeps_changed_fields_and_redacts_secrets() {
+        let record = AuditRecord::new("key.update").updated(
+            &json!({"name": "a", "blocked": false, "key_hash": "h1", "updated_at": "t1"}),
+            &json!({"name": "a", "blocked": true, "key_hash": "h2", "updated_at": "t2",
+                    "max_tokens": 10}),
+        );
+
+        assert_eq!(
+            record.changes().unwrap(),
+            json!({
+                "before": {"blocked": false, "key_hash": "[REDACTED]", "max_tokens": null},
+                "after": {"blocked": true, "key_hash": "[REDACTED]", "max_tokens": 10},
+            })
+        );
+    }
+
+    #[test]
+    fn test_redact_nested_secrets() {
+        let mut value = json!({
+            "provider_id": "openai",
+            "api_key": "sk-live",
+            "accounts": [{"refresh_token": "r", "access_token_encrypted": null}],
+            "key_prefix": "sk-abc",
+        });
+        redact(&mut value);
+
+        assert_eq!(
+            value,
+            json!({
+                "provider_id": "openai",
+                "api_key": "[REDACTED]",
+                "accounts": [{"refresh_token": "[REDACTED]", "access_token_encrypted": null}],
+                "key_prefix": "sk-abc",
+            })
+        );
+        assert!(AuditRecord::new("user.logout").changes().is_none());
+    }
+}
@@ -1,57 +1,52 @@
 use chrono::{Duration, Utc};
-use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
 use crate::error::{ApiError, ApiResult};
 
+use super::JwtKeys;
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Claims {
     pub sub: String,      // User ID
     pub email: String,    // User email
     pub role: String,     // User role
+    pub sid: String,      // Session ID, checked against `sessions` on every request
+    #[serde(default)]
+    pub totp_pending: bool, // Must set up TOTP before using anything but enrollment
     pub exp: i64,         // Expiration time
     pub iat: i64,         // Issued at
 }
 
-/// Generate a JWT token for a user
+/// Generate a short-lived access token for a user's session
 pub fn generate_token(
     user_id: Uuid,
     email: String,
     role: String,
-    secret: &str,
-    expiry_hours: i64,
+    session_id: Uuid,
+    totp_pending: bool,
+    keys: &JwtKeys,
+    ttl_seconds: i64,
 ) -> ApiResult<String> {
     let now = Utc::now();
-    let expiration = now + Duration::hours(expiry_hours);
+    let expiration = now + Duration::seconds(ttl_seconds);
 
     let claims = Claims {
         sub: user_id.to_string(),
         email,
         role,
+        sid: session_id.to_string(),
+        totp_pending,
         exp: expiration.timestamp(),
         iat: now.timestamp(),
     };
 
-    encode(
-        &Header::default(),
-        &claims,
-        &EncodingKey::from_secret(secret.as_bytes()),
-    )
-    .map_err(|e| ApiError::InternalError(format!("Failed to generate token: {}", e)))
+    keys.sign(&claims)
 }
 
 /// Validate and decode a JWT token
-pub fn validate_token(token: &str, secret: &str) -> ApiResult<Claims> {
-    let validation = Validation::default();
-
-    decode::<Claims>(
-        token,
-        &DecodingKey::from_secret(secret.as_bytes()),
-        &validation,
-    )
-    .map(|data| data.claims)
-    .map_err(|e| ApiError::AuthenticationFailed)
+pub fn validate_token(token: &str, keys: &JwtKeys) -> ApiResult<Claims> {
+    keys.verify(token)
 }
 
 /// Extract token from Authorization header
@@ -63,14 +58,11 @@ pub fn extract_bearer_token(auth_header: &str) -> ApiResult<&str> {
     Ok(&auth_header[7..]) // Skip "Bearer "
 }
 
-/// Hash a token for storage (for session tracking and invalidation)
+/// Hash a refresh token for storage (SHA-256; the tokens are random, so no salt is needed)
 pub fn hash_token(token: &str) -> String {
-    use std::collections::hash_map::DefaultHasher;
-    use std::hash::{Hash, Hasher};
+    use sha2::{Digest, Sha256};
 
-    let mut hasher = DefaultHasher::new();
-    token.hash(&mut hasher);
-    format!("{:x}", hasher.finish())
+    hex::encode(Sha256::digest(token.as_bytes()))
 }
 
 #[cfg(test)]
@@ -80,17 +72,20 @@ mod tests {
 
     #[test]
     fn test_generate_and_validate_token() {
-        let secret = "test_secret_key";
+        let keys = JwtKeys::new("test_secret_key", None, &[]).unwrap();
         let user_id = Uuid::new_v4();
         let email = "test@example.com".to_string();
         let role = "user".to_string();
+        let session_id = Uuid::new_v4();
 
-        let token = generate_token(user_id, email.clone(), role.clone(), secret, 24).unwrap();
-        let claims = validate_token(&token, secret).unwrap();
+        let token =
+            generate_token(user_id, email.clone(), role.clone(), session_id, false, &keys, 900).unwrap();
+        let claims = validate_token(&token, &keys).unwrap();
 
         assert_eq!(claims.sub, user_id.to_string());
         assert_eq!(claims.email, email);
         assert_eq!(claims.role, role);
+        assert_eq!(claims.sid, session_id.to_string());
     }
 
     #[test]
@@ -105,8 +100,8 @@ mod tests {
 
     #[test]
     fn test_invalid_token() {
-        let secret = "test_secret_key";
+        let keys = JwtKeys::new("test_secret_key", None, &[]).unwrap();
         let invalid_token = "invalid.token.here";
-        assert!(validate_token(invalid_token, secret).is_err());
+        assert!(validate_token(invalid_token, &keys).is_err());
     }
 }
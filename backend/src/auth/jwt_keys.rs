@@ -0,0 +1,337 @@
+//! Keys access tokens are signed and verified with
+//!
+//! Without a signing key, tokens are HS256-signed with `JWT_SECRET` as before. With one
+//! (an RSA or Ed25519 private key in PEM), tokens are RS256/EdDSA-signed, carry the key's
+//! `kid`, and other services can verify them with the public keys published at
+//! `/.well-known/jwks.json`. Retired public keys stay configured for verification so
+//! tokens signed before a rotation keep working until they expire.
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::{
+    decode, decode_header, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation,
+};
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use rsa::{
+    pkcs1::{DecodeRsaPrivateKey, DecodeRsaPublicKey},
+    pkcs8::{
+        DecodePrivateKey, DecodePublicKey, Document, ObjectIdentifier, SecretDocument,
+        SubjectPublicKeyInfoRef,
+    },
+    traits::PublicKeyParts,
+    RsaPrivateKey, RsaPublicKey,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use thiserror::Error;
+
+use crate::error::{ApiError, ApiResult};
+
+const ED25519_OID: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.101.112");
+
+#[derive(Debug, Error)]
+pub enum JwtKeyError {
+    #[error("Invalid JWT key: {0}")]
+    InvalidKey(String),
+}
+
+/// A public key in JWK form (RFC 7517)
+#[derive(Debug, Clone, Serialize)]
+pub struct Jwk {
+    pub kty: &'static str,
+    pub kid: String,
+    #[serde(rename = "use")]
+    pub key_use: &'static str,
+    pub alg: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub crv: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub n: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub e: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub x: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct JwkSet {
+    pub keys: Vec<Jwk>,
+}
+
+struct VerificationKey {
+    algorithm: Algorithm,
+    decoding: DecodingKey,
+    jwk: Jwk,
+}
+
+impl VerificationKey {
+    fn rsa(key: &RsaPublicKey) -> Result<Self, JwtKeyError> {
+        let n = URL_SAFE_NO_PAD.encode(key.n().to_bytes_be());
+        let e = URL_SAFE_NO_PAD.encode(key.e().to_bytes_be());
+        let decoding = DecodingKey::from_rsa_components(&n, &e).map_err(invalid)?;
+        let kid = thumbprint(&format!(r#"{{"e":"{}","kty":"RSA","n":"{}"}}"#, e, n));
+
+        Ok(Self {
+            algorithm: Algorithm::RS256,
+            decoding,
+            jwk: Jwk {
+                kty: "RSA",
+                kid,
+                key_use: "sig",
+                alg: "RS256",
+                crv: None,
+                n: Some(n),
+                e: Some(e),
+                x: None,
+            },
+        })
+    }
+
+    fn ed25519(public_key: &[u8]) -> Result<Self, JwtKeyError> {
+        let x = URL_SAFE_NO_PAD.encode(public_key);
+        let decoding = DecodingKey::from_ed_components(&x).map_err(invalid)?;
+        let kid = thumbprint(&format!(r#"{{"crv":"Ed25519","kty":"OKP","x":"{}"}}"#, x));
+
+        Ok(Self {
+            algorithm: Algorithm::EdDSA,
+            decoding,
+            jwk: Jwk {
+                kty: "OKP",
+                kid,
+                key_use: "sig",
+                alg: "EdDSA",
+                crv: Some("Ed25519"),
+                n: None,
+                e: None,
+                x: Some(x),
+            },
+        })
+    }
+}
+
+/// The signing key for new tokens and every key tokens may be verified with
+pub struct JwtKeys {
+    header: Header,
+    signing: EncodingKey,
+    /// Verifies tokens without a `kid`; only set when signing with the shared secret
+    secret: Option<DecodingKey>,
+    verification: HashMap<String, VerificationKey>,
+}
+
+impl JwtKeys {
+    /// Build from configuration: an optional private key file to sign with, and
+    /// comma-separated public key files still accepted for verification
+    pub fn from_settings(
+        secret: &str,
+        signing_key_file: Option<&str>,
+        verification_key_files: Option<&str>,
+    ) -> Result<Self, JwtKeyError> {
+        let signing_pem = signing_key_file.map(read_key_file).transpose()?;
+        let verification_pems = verification_key_files
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|path| !path.is_empty())
+            .map(read_key_file)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Self::new(secret, signing_pem.as_deref(), &verification_pems)
+    }
+
+    pub fn new(
+        secret: &str,
+        signing_pem: Option<&str>,
+        verification_pems: &[String],
+    ) -> Result<Self, JwtKeyError> {
+        let mut verification = HashMap::new();
+        for pem in verification_pems {
+            let key = parse_public_key(pem)?;
+            verification.insert(key.jwk.kid.clone(), key);
+        }
+
+        let (header, signing, secret) = match signing_pem {
+            Some(pem) => {
+                let (signing, key) = parse_private_key(pem)?;
+                let mut header = Header::new(key.algorithm);
+                header.kid = Some(key.jwk.kid.clone());
+                verification.insert(key.jwk.kid.clone(), key);
+                (header, signing, None)
+            }
+            None => (
+                Header::new(Algorithm::HS256),
+                EncodingKey::from_secret(secret.as_bytes()),
+                Some(DecodingKey::from_secret(secret.as_bytes())),
+            ),
+        };
+
+        Ok(Self {
+            header,
+            signing,
+            secret,
+            verification,
+        })
+    }
+
+    /// Algorithm new tokens are signed with
+    pub fn algorithm(&self) -> Algorithm {
+        self.header.alg
+    }
+
+    /// `kid` of the key new tokens are signed with, unless signing with the shared secret
+    pub fn signing_key_id(&self) -> Option<&str> {
+        self.header.kid.as_deref()
+    }
+
+    pub fn sign<T: Serialize>(&self, claims: &T) -> ApiResult<String> {
+        encode(&self.header, claims, &self.signing)
+            .map_err(|e| ApiError::InternalError(format!("Failed to generate token: {}", e)))
+    }
+
+    /// Verify a token against the key its `kid` names, or the shared secret if it has none
+    ///
+    /// Each key only accepts its own algorithm, so a token can't switch algorithms to
+    /// have a public key used as an HMAC secret.
+    pub fn verify<T: DeserializeOwned>(&self, token: &str) -> ApiResult<T> {
+        let header = decode_header(token).map_err(|_| ApiError::AuthenticationFailed)?;
+        let (key, algorithm) = match header.kid {
+            Some(kid) => {
+                let key = self
+                    .verification
+                    .get(&kid)
+                    .ok_or(ApiError::AuthenticationFailed)?;
+                (&key.decoding, key.algorithm)
+            }
+            None => (
+                self.secret.as_ref().ok_or(ApiError::AuthenticationFailed)?,
+                Algorithm::HS256,
+            ),
+        };
+
+        decode::<T>(token, key, &Validation::new(algorithm))
+            .map(|data| data.claims)
+            .map_err(|_| ApiError::AuthenticationFailed)
+    }
+
+    /// Public keys for `/.well-known/jwks.json`, the signing key first
+    pub fn jwks(&self) -> JwkSet {
+        let signing_kid = self.signing_key_id();
+        let mut keys: Vec<&VerificationKey> = self.verification.values().collect();
+        keys.sort_by_key(|key| (Some(key.jwk.kid.as_str()) != signing_kid, &key.jwk.kid));
+
+        JwkSet {
+            keys: keys.into_iter().map(|key| key.jwk.clone()).collect(),
+        }
+    }
+}
+
+fn read_key_file(path: &str) -> Result<String, JwtKeyError> {
+    std::fs::read_to_string(path)
+        .map_err(|e| JwtKeyError::InvalidKey(format!("cannot read key file {}: {}", path, e)))
+}
+
+/// Parse an RSA (PKCS#8 or PKCS#1) or Ed25519 (PKCS#8) private key
+fn parse_private_key(pem: &str) -> Result<(EncodingKey, VerificationKey), JwtKeyError> {
+    if let Ok(key) =
+        RsaPrivateKey::from_pkcs8_pem(pem).or_else(|_| RsaPrivateKey::from_pkcs1_pem(pem))
+    {
+        let signing = EncodingKey::from_rsa_pem(pem.as_bytes()).map_err(invalid)?;
+        return Ok((signing, VerificationKey::rsa(&key.to_public_key())?));
+    }
+
+    let (_, der) = SecretDocument::from_pem(pem).map_err(invalid)?;
+    let pair = Ed25519KeyPair::from_pkcs8_maybe_unchecked(der.as_bytes()).map_err(|_| {
+        JwtKeyError::InvalidKey("expected an RSA or Ed25519 private key".to_string())
+    })?;
+    let verification = VerificationKey::ed25519(pair.public_key().as_ref())?;
+    Ok((EncodingKey::from_ed_der(der.as_bytes()), verification))
+}
+
+/// Parse an RSA (SPKI or PKCS#1) or Ed25519 (SPKI) public key
+fn parse_public_key(pem: &str) -> Result<VerificationKey, JwtKeyError> {
+    if let Ok(key) =
+        RsaPublicKey::from_public_key_pem(pem).or_else(|_| RsaPublicKey::from_pkcs1_pem(pem))
+    {
+        return VerificationKey::rsa(&key);
+    }
+
+    let (_, der) = Document::from_pem(pem).map_err(invalid)?;
+    let spki = SubjectPublicKeyInfoRef::try_from(der.as_bytes()).map_err(invalid)?;
+    if spki.algorithm.oid != ED25519_OID {
+        return Err(JwtKeyError::InvalidKey(
+            "expected an RSA or Ed25519 public key".to_string(),
+        ));
+    }
+    VerificationKey::ed25519(spki.subject_public_key.raw_bytes())
+}
+
+/// JWK thumbprint (RFC 7638) of a key's required members, used as its `kid`
+fn thumbprint(canonical_jwk: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(canonical_jwk.as_bytes()))
+}
+
+fn invalid(e: impl std::fmt::Display) -> JwtKeyError {
+    JwtKeyError::InvalidKey(e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::rand::SystemRandom;
+    use rsa::pkcs8::LineEnding;
+    use serde::Deserialize;
+
+    #[derive(Debug, Serialize, Deserialize, PartialEq)]
+    struct TestClaims {
+        sub: String,
+        exp: i64,
+    }
+
+    /// A fresh Ed25519 key as (private PKCS#8 PEM, public SPKI PEM)
+    fn ed25519_key() -> (String, String) {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let private = SecretDocument::try_from(pkcs8.as_ref())
+            .unwrap()
+            .to_pem("PRIVATE KEY", LineEnding::LF)
+            .unwrap()
+            .to_string();
+
+        let spki_prefix = [
+            0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00,
+        ];
+        let public = Document::try_from([&spki_prefix[..], pair.public_key().as_ref()].concat())
+            .unwrap()
+            .to_pem("PUBLIC KEY", LineEnding::LF)
+            .unwrap();
+        (private, public)
+    }
+
+    #[test]
+    fn test_rotation_and_jwks() {
+        let claims = TestClaims {
+            sub: "user".to_string(),
+            exp: chrono::Utc::now().timestamp() + 60,
+        };
+        let (old_private, old_public) = ed25519_key();
+        let (new_private, _) = ed25519_key();
+
+        let shared = JwtKeys::new("secret", None, &[]).unwrap();
+        let old = JwtKeys::new("secret", Some(&old_private), &[]).unwrap();
+        let rotated = JwtKeys::new("secret", Some(&new_private), &[old_public]).unwrap();
+        assert_eq!(rotated.algorithm(), Algorithm::EdDSA);
+
+        // Tokens signed before the rotation still verify, HS256 tokens no longer do
+        let old_token = old.sign(&claims).unwrap();
+        assert_eq!(rotated.verify::<TestClaims>(&old_token).unwrap(), claims);
+        let new_token = rotated.sign(&claims).unwrap();
+        assert_eq!(rotated.verify::<TestClaims>(&new_token).unwrap(), claims);
+        assert!(old.verify::<TestClaims>(&new_token).is_err());
+        let shared_token = shared.sign(&claims).unwrap();
+        assert!(rotated.verify::<TestClaims>(&shared_token).is_err());
+        assert!(shared.jwks().keys.is_empty());
+
+        let jwks = rotated.jwks();
+        assert_eq!(jwks.keys.len(), 2);
+        assert_eq!(Some(jwks.keys[0].kid.as_str()), rotated.signing_key_id());
+        assert_eq!(Some(jwks.keys[1].kid.as_str()), old.signing_key_id());
+    }
+}
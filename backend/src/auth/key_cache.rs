@@ -0,0 +1,185 @@
+use dashmap::DashMap;
+use futures::StreamExt;
+use redis::AsyncCommands;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+
+use crate::{auth::is_legacy_key_hash, local_store::RedisConnection, models::VirtualKey};
+
+use super::Principal;
+
+/// Redis channel replicas use to tell each other a key changed
+pub const KEY_INVALIDATION_CHANNEL: &str = "inferxgate:key-invalidations";
+
+/// Invalidation message dropping every cached key
+const INVALIDATE_ALL: &str = "*";
+
+/// How long an authenticated key is served from cache
+const KEY_CACHE_TTL: Duration = Duration::from_secs(300);
+
+/// Wait before resubscribing after the invalidation channel drops
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(1);
+
+/// Cache key for a verified key, by the HMAC hash of the secret used
+pub fn key_cache_key(key_hash: &str) -> String {
+    format!("auth:key:{}", key_hash)
+}
+
+/// Resolved [`Principal`]s for authenticated keys, in-process and in Redis
+///
+/// Each replica keeps its own in-process copy in front of Redis. Changing a key
+/// (update, block, delete, rotation, crossing or resetting its budget) must call
+/// [`KeyCache::invalidate`], which drops the Redis entries and publishes the key's hashes
+/// so every replica drops its in-process copy too. Changes to what a principal bundles
+/// from elsewhere (users, teams, scope limits) call [`KeyCache::invalidate_all`].
+#[derive(Clone)]
+pub struct KeyCache {
+    redis: RedisConnection,
+    local: Arc<DashMap<String, (Principal, Instant)>>,
+}
+
+impl KeyCache {
+    pub fn new(redis: RedisConnection) -> Self {
+        Self {
+            redis,
+            local: Arc::new(DashMap::new()),
+        }
+    }
+
+    pub async fn get(&self, cache_key: &str) -> Option<Principal> {
+        if let Some(entry) = self.local.get(cache_key) {
+            let (principal, expires_at) = entry.value();
+            if *expires_at > Instant::now() {
+                return Some(principal.clone());
+            }
+        }
+        self.local.remove_if(cache_key, |_, (_, expires_at)| {
+            *expires_at <= Instant::now()
+        });
+
+        let mut conn = self.redis.get()?;
+        let cached: Option<String> = conn.get(cache_key).await.ok()?;
+        let principal: Principal = serde_json::from_str(&cached?).ok()?;
+        self.local.insert(
+            cache_key.to_string(),
+            (principal.clone(), Instant::now() + KEY_CACHE_TTL),
+        );
+        Some(principal)
+    }
+
+    pub async fn set(&self, cache_key: &str, principal: &Principal) {
+        self.local.insert(
+            cache_key.to_string(),
+            (principal.clone(), Instant::now() + KEY_CACHE_TTL),
+        );
+
+        if let Some(mut conn) = self.redis.get() {
+            if let Ok(serialized) = serde_json::to_string(principal) {
+                let _: Result<(), _> = conn
+                    .set_ex(cache_key, serialized, KEY_CACHE_TTL.as_secs())
+                    .await;
+            }
+        }
+    }
+
+    /// Drop every cached copy of a key, on this replica, in Redis and on other replicas
+    pub async fn invalidate(&self, key: &VirtualKey) {
+        // Legacy bcrypt-hashed secrets can't have been cached: they're upgraded on first use
+        let key_hashes: Vec<&str> = [Some(&key.key_hash), key.previous_key_hash.as_ref()]
+            .into_iter()
+            .flatten()
+            .map(String::as_str)
+            .filter(|hash| !is_legacy_key_hash(hash))
+            .collect();
+
+        for key_hash in &key_hashes {
+            self.local.remove(&key_cache_key(key_hash));
+        }
+
+        let Some(mut conn) = self.redis.get() else {
+            return;
+        };
+        let cache_keys: Vec<String> = key_hashes.iter().map(|hash| key_cache_key(hash)).collect();
+        if cache_keys.is_empty() {
+            return;
+        }
+        if let Err(e) = conn.del::<_, ()>(&cache_keys).await {
+            warn!("Failed to drop cached key {} from Redis: {}", key.id, e);
+        }
+        for key_hash in key_hashes {
+            publish(&mut conn, key_hash).await;
+        }
+    }
+
+    /// Drop every cached key everywhere, for changes that affect many keys at once
+    pub async fn invalidate_all(&self) {
+        self.local.clear();
+
+        let Some(mut conn) = self.redis.get() else {
+            return;
+        };
+        let cache_keys: Vec<String> = match conn.scan_match(key_cache_key("*")).await {
+            Ok(iter) => iter.collect().await,
+            Err(e) => {
+                warn!("Failed to list cached keys in Redis: {}", e);
+                Vec::new()
+            }
+        };
+        if !cache_keys.is_empty() {
+            if let Err(e) = conn.del::<_, ()>(&cache_keys).await {
+                warn!("Failed to drop cached keys from Redis: {}", e);
+            }
+        }
+        publish(&mut conn, INVALIDATE_ALL).await;
+    }
+
+    /// Listen for invalidations published by other replicas
+    ///
+    /// Messages sent while the subscription is down are lost, so the in-process cache is
+    /// cleared every time it (re)subscribes.
+    pub fn spawn_invalidation_listener(&self, client: redis::Client) {
+        let cache = self.clone();
+        tokio::spawn(async move {
+            loop {
+                match client.get_async_pubsub().await {
+                    Ok(mut pubsub) => {
+                        if let Err(e) = pubsub.subscribe(KEY_INVALIDATION_CHANNEL).await {
+                            warn!("Failed to subscribe to key invalidations: {}", e);
+                        } else {
+                            info!("Listening for key invalidations from other replicas");
+                            cache.local.clear();
+                            let mut messages = pubsub.into_on_message();
+                            while let Some(message) = messages.next().await {
+                                match message.get_payload::<String>() {
+                                    Ok(message) if message == INVALIDATE_ALL => {
+                                        debug!("All keys invalidated by another replica");
+                                        cache.local.clear();
+                                    }
+                                    Ok(key_hash) => {
+                                        debug!("Key invalidated by another replica");
+                                        cache.local.remove(&key_cache_key(&key_hash));
+                                    }
+                                    Err(_) => {}
+                                }
+                            }
+                            warn!("Key invalidation subscription closed, resubscribing");
+                        }
+                    }
+                    Err(e) => warn!("Failed to connect for key invalidations: {}", e),
+                }
+                cache.local.clear();
+                tokio::time::sleep(RESUBSCRIBE_DELAY).await;
+            }
+        });
+    }
+}
+
+async fn publish(conn: &mut redis::aio::ConnectionManager, message: &str) {
+    if let Err(e) = conn
+        .publish::<_, _, ()>(KEY_INVALIDATION_CHANNEL, message)
+        .await
+    {
+        warn!("Failed to broadcast key invalidation: {}", e);
+    }
+}
@@ -11,24 +11,37 @@ pub fn generate_virtual_key() -> String {
     format!("sk-{}", key)
 }
 
-/// Hash a virtual key for storage
-/// Uses bcrypt cost of 10 for balance between security and performance
-/// Cost 10 provides ~100ms verification time (vs 9+ seconds with higher costs)
-pub fn hash_virtual_key(key: &str) -> ApiResult<String> {
-    use bcrypt::hash;
-    const BCRYPT_COST: u32 = 10;
-    hash(key, BCRYPT_COST)
-        .map_err(|e| ApiError::InternalError(format!("Failed to hash key: {}", e)))
+/// Hash a virtual key for storage and lookup: HMAC-SHA256 under the server-side pepper
+///
+/// Keys are 256-bit random tokens, so unlike passwords they don't need a slow hash; the
+/// pepper means a leaked `virtual_keys` table can't be used to check guessed keys.
+pub fn hash_virtual_key(key: &str, pepper: &str) -> String {
+    use hmac::{Hmac, Mac};
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(pepper.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(key.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
 }
 
-/// Verify a virtual key against a hash
+/// Compare a stored key hash with a computed one in constant time
+pub fn key_hash_matches(stored: &str, computed: &str) -> bool {
+    use subtle::ConstantTimeEq;
+    stored.as_bytes().ct_eq(computed.as_bytes()).into()
+}
+
+/// Whether a stored key hash is a bcrypt hash from before keys were HMAC-hashed
+pub fn is_legacy_key_hash(hash: &str) -> bool {
+    hash.starts_with("$2")
+}
+
+/// Verify a virtual key against a legacy bcrypt hash
 pub fn verify_virtual_key(key: &str, hash: &str) -> ApiResult<bool> {
     use bcrypt::verify;
     verify(key, hash).map_err(|e| ApiError::InternalError(format!("Failed to verify key: {}", e)))
 }
 
 /// Create a SHA256 lookup hash for fast key authentication
-/// This is used for O(1) database lookups, not for security (bcrypt is still used for that)
+/// Only legacy bcrypt-hashed keys are looked up this way, until their first use upgrades them
 pub fn create_lookup_hash(key: &str) -> String {
     let mut hasher = Sha256::new();
     hasher.update(key.as_bytes());
@@ -74,8 +87,27 @@ mod tests {
     #[test]
     fn test_hash_and_verify_key() {
         let key = generate_virtual_key();
-        let hash = hash_virtual_key(&key).unwrap();
+        let hash = hash_virtual_key(&key, "pepper");
+
+        assert_eq!(hash.len(), 64);
+        assert!(!is_legacy_key_hash(&hash));
+        assert!(key_hash_matches(&hash, &hash_virtual_key(&key, "pepper")));
+        assert!(!key_hash_matches(
+            &hash,
+            &hash_virtual_key(&key, "other-pepper")
+        ));
+        assert!(!key_hash_matches(
+            &hash,
+            &hash_virtual_key("sk-wrong-key", "pepper")
+        ));
+    }
+
+    #[test]
+    fn test_verify_legacy_key() {
+        let key = generate_virtual_key();
+        let hash = bcrypt::hash(&key, 4).unwrap();
 
+        assert!(is_legacy_key_hash(&hash));
         assert!(verify_virtual_key(&key, &hash).unwrap());
         assert!(!verify_virtual_key("sk-wrong-key", &hash).unwrap());
     }
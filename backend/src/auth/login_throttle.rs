@@ -0,0 +1,121 @@
+use std::net::IpAddr;
+use std::time::Duration;
+
+use crate::{
+    config::AppConfig,
+    error::{ApiError, ApiResult},
+    rate_limiter::{LimitScope, RateLimit, RateLimitLevel, RateLimiter},
+};
+
+/// Longest delay before answering a failed login
+const MAX_FAILURE_DELAY: Duration = Duration::from_secs(10);
+
+/// Brute-force protection for password logins and registrations
+///
+/// Attempts are rate limited per client address and, for logins, per account (by email,
+/// whether or not the account exists). Failed logins to an existing account are answered
+/// after a delay that doubles with each consecutive failure, and enough of them lock the
+/// account for a while (see [`crate::models::LoginLockout`]).
+#[derive(Debug, Clone)]
+pub struct LoginThrottle {
+    per_ip: RateLimit,
+    per_account: RateLimit,
+    pub lockout_threshold: i32,
+    pub lockout_seconds: i64,
+    failure_delay: Duration,
+}
+
+impl LoginThrottle {
+    pub fn from_config(config: &AppConfig) -> Self {
+        let per_minute = |limit: i32| RateLimit {
+            requests_per_minute: Some(limit).filter(|limit| *limit > 0),
+            tokens_per_minute: None,
+        };
+
+        Self {
+            per_ip: per_minute(config.login_rate_limit_per_ip),
+            per_account: per_minute(config.login_rate_limit_per_account),
+            lockout_threshold: config.login_lockout_threshold,
+            lockout_seconds: config.login_lockout_seconds,
+            failure_delay: Duration::from_millis(config.login_failure_delay_ms),
+        }
+    }
+
+    /// Charge an attempt to the client's address and, for logins, the account
+    /// Fails with `TooManyAttempts` when either is over its limit.
+    pub async fn check(
+        &self,
+        limiter: &RateLimiter,
+        ip: Option<IpAddr>,
+        email: Option<&str>,
+    ) -> ApiResult<()> {
+        let mut levels = Vec::new();
+        if let Some(ip) = ip {
+            levels.push(RateLimitLevel::new(
+                LimitScope::Ip,
+                ip.to_canonical().to_string(),
+                self.per_ip.clone(),
+            ));
+        }
+        if let Some(email) = email {
+            levels.push(RateLimitLevel::new(
+                LimitScope::Account,
+                account_id(email),
+                self.per_account.clone(),
+            ));
+        }
+
+        let status = limiter.check_and_increment(&levels, 0).await?;
+        if status.limited {
+            return Err(ApiError::TooManyAttempts(
+                status.retry_after.unwrap_or(1).max(1) as u64,
+            ));
+        }
+        Ok(())
+    }
+
+    /// How long to wait before answering the `attempts`-th consecutive failed login
+    pub fn failure_delay(&self, attempts: i32) -> Duration {
+        let doublings = attempts.saturating_sub(1).clamp(0, 16) as u32;
+        self.failure_delay
+            .saturating_mul(1 << doublings)
+            .min(MAX_FAILURE_DELAY)
+    }
+
+    /// Clear the per-account rate limit, e.g. when an admin unlocks the account
+    pub async fn reset_account(&self, limiter: &RateLimiter, email: &str) -> ApiResult<()> {
+        limiter.reset(LimitScope::Account, &account_id(email)).await
+    }
+}
+
+/// Rate limit ID of the account an email signs in to
+fn account_id(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_failure_delay_doubles_up_to_cap() {
+        let throttle = LoginThrottle {
+            per_ip: RateLimit {
+                requests_per_minute: Some(20),
+                tokens_per_minute: None,
+            },
+            per_account: RateLimit {
+                requests_per_minute: Some(10),
+                tokens_per_minute: None,
+            },
+            lockout_threshold: 5,
+            lockout_seconds: 900,
+            failure_delay: Duration::from_millis(500),
+        };
+
+        assert_eq!(throttle.failure_delay(0), Duration::from_millis(500));
+        assert_eq!(throttle.failure_delay(1), Duration::from_millis(500));
+        assert_eq!(throttle.failure_delay(3), Duration::from_secs(2));
+        assert_eq!(throttle.failure_delay(100), MAX_FAILURE_DELAY);
+    }
+}
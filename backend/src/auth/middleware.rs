@@ -1,17 +1,29 @@
 use axum::{
     async_trait,
-    extract::{FromRequestParts, Request, State},
-    http::{request::Parts, StatusCode},
+    extract::{ConnectInfo, FromRequestParts, Request, State},
+    http::{header, request::Parts, HeaderMap, HeaderValue, Method, StatusCode},
     middleware::Next,
-    response::Response,
+    response::{IntoResponse, Response},
 };
+use futures::StreamExt;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
+use subtle::ConstantTimeEq;
 
 use crate::{
-    auth::{extract_bearer_token, validate_token},
+    audit::{AuditContext, AuditRecord},
+    auth::{
+        create_lookup_hash, extract_bearer_token, hash_virtual_key, is_legacy_key_hash,
+        key_cache_key, key_hash_matches, validate_token, verify_virtual_key, Claims, KeyCache,
+        Principal,
+    },
+    concurrency::{ConcurrencyLimiter, ConcurrencyPermit},
+    error::ApiError,
     metrics::MetricsCollector,
-    models::{User, VirtualKey},
-    rate_limiter::{RateLimit, RateLimiter},
+    models::{KeyRestrictions, KeySecret, ScopedLimits, User, VirtualKey},
+    rate_limiter::{LimitScope, RateLimit, RateLimitLevel, RateLimitStatus, RateLimiter},
+    token_counter,
+    usage::UsageRecorder,
 };
 
 /// Authenticated user information extracted from JWT or API key
@@ -25,7 +37,7 @@ pub struct AuthUser {
 
 #[derive(Debug, Clone)]
 pub enum AuthType {
-    JWT,
+    JWT { session_id: uuid::Uuid },
     VirtualKey { key_id: uuid::Uuid },
     MasterKey,
 }
@@ -103,7 +115,7 @@ pub async fn require_jwt<S>(
     next: Next,
 ) -> Result<Response, (StatusCode, String)>
 where
-    S: HasJwtSecret + HasDatabase,
+    S: HasJwtKeys + HasDatabase,
 {
     let auth_header = request
         .headers()
@@ -123,15 +135,14 @@ where
         )
     })?;
 
-    let jwt_secret = state.get_jwt_secret();
-    let claims = validate_token(token, jwt_secret).map_err(|_| {
+    let jwt_keys = state.get_jwt_keys();
+    let claims = validate_token(token, jwt_keys).map_err(|_| {
         (
             StatusCode::UNAUTHORIZED,
             "Invalid or expired token".to_string(),
         )
     })?;
 
-    // Verify user still exists in database
     let pool = state.get_database_pool().ok_or_else(|| {
         (
             StatusCode::INTERNAL_SERVER_ERROR,
@@ -139,44 +150,50 @@ where
         )
     })?;
 
-    let user_id = uuid::Uuid::parse_str(&claims.sub).map_err(|_| {
-        (
-            StatusCode::UNAUTHORIZED,
-            "Invalid user ID in token".to_string(),
-        )
-    })?;
-
-    let user = User::find_by_id(pool, user_id)
-        .await
-        .map_err(|_| {
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Failed to verify user".to_string(),
-            )
-        })?
-        .ok_or_else(|| (StatusCode::UNAUTHORIZED, "User not found".to_string()))?;
+    let auth_user = session_user(pool, &claims).await?;
+    require_totp_enrollment(&claims, request.uri().path())?;
+    request.extensions_mut().insert(auth_user);
 
-    let auth_user = AuthUser {
-        user_id: user.id,
-        email: user.email,
-        role: user.role,
-        auth_type: AuthType::JWT,
-    };
+    Ok(next.run(request).await)
+}
 
-    request.extensions_mut().insert(auth_user);
+/// Middleware for management routes, layered inside `require_auth`: virtual keys only
+/// authenticate API calls, so a key can't raise its own limits, unblock itself or mint
+/// more keys for its owner
+pub async fn deny_virtual_keys(
+    request: Request,
+    next: Next,
+) -> Result<Response, (StatusCode, String)> {
+    if let Some(AuthUser {
+        auth_type: AuthType::VirtualKey { .. },
+        ..
+    }) = request.extensions().get::<AuthUser>()
+    {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "Virtual keys can't be used for management endpoints; sign in or use the master key"
+                .to_string(),
+        ));
+    }
 
     Ok(next.run(request).await)
 }
 
-/// Middleware to require authentication (JWT or virtual key)
-/// Used for API endpoints - accepts both JWT and API keys
+/// Middleware to require authentication (master key, JWT or virtual key)
+/// Used for API endpoints - accepts the master key, JWTs and API keys
 pub async fn require_auth<S>(
     State(state): State<Arc<S>>,
     mut request: Request,
     next: Next,
 ) -> Result<Response, (StatusCode, String)>
 where
-    S: HasJwtSecret + HasDatabase + HasRedis,
+    S: HasMasterKey
+        + HasJwtKeys
+        + HasKeyPepper
+        + HasDatabase
+        + HasKeyCache
+        + HasUsageRecorder
+        + HasTrustedProxy,
 {
     let auth_header = request
         .headers()
@@ -203,160 +220,81 @@ where
         )
     })?;
 
-    // Try JWT first
-    let jwt_secret = state.get_jwt_secret();
-    if let Ok(claims) = validate_token(token, jwt_secret) {
-        let user_id = uuid::Uuid::parse_str(&claims.sub).map_err(|_| {
-            (
-                StatusCode::UNAUTHORIZED,
-                "Invalid user ID in token".to_string(),
-            )
-        })?;
-
-        let user = User::find_by_id(pool, user_id)
-            .await
-            .map_err(|_| {
-                (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    "Failed to verify user".to_string(),
-                )
-            })?
-            .ok_or_else(|| (StatusCode::UNAUTHORIZED, "User not found".to_string()))?;
-
+    // The master key acts as an admin without a user
+    let master_key = state.get_master_key();
+    if !master_key.is_empty() && bool::from(token.as_bytes().ct_eq(master_key.as_bytes())) {
         let auth_user = AuthUser {
-            user_id: user.id,
-            email: user.email,
-            role: user.role,
-            auth_type: AuthType::JWT,
+            user_id: uuid::Uuid::nil(),
+            email: "admin".to_string(),
+            role: "admin".to_string(),
+            auth_type: AuthType::MasterKey,
         };
 
         request.extensions_mut().insert(auth_user);
         return Ok(next.run(request).await);
     }
 
+    // Try JWT next
+    let jwt_keys = state.get_jwt_keys();
+    if let Ok(claims) = validate_token(token, jwt_keys) {
+        let auth_user = session_user(pool, &claims).await?;
+        require_totp_enrollment(&claims, request.uri().path())?;
+        request.extensions_mut().insert(auth_user);
+        return Ok(next.run(request).await);
+    }
+
     // Try virtual key if JWT fails
     if token.starts_with("sk-") {
-        // Create lookup hash for fast O(1) database query
-        let lookup_hash = crate::auth::keys::create_lookup_hash(token);
-
-        // CRITICAL FIX: Check verified token cache first to skip expensive bcrypt
-        // This cache stores tokens that have already passed bcrypt verification
-        let verified_token_key = format!("auth:verified:{}", lookup_hash);
-        if let Some(redis_conn) = state.get_redis_connection() {
-            if let Ok(Some(cached_key)) = get_cached_key(redis_conn, &verified_token_key).await {
-                // Token already verified! Skip bcrypt entirely ✅
-                tracing::debug!("Using verified token cache (skipping bcrypt)");
-
-                // Validate key is still valid
-                if cached_key.is_valid() {
-                    let (user_id, email, role) = if let Some(user_id) = cached_key.user_id {
-                        let user = User::find_by_id(pool, user_id)
-                            .await
-                            .map_err(|_| {
-                                (
-                                    StatusCode::INTERNAL_SERVER_ERROR,
-                                    "Failed to verify user".to_string(),
-                                )
-                            })?
-                            .ok_or_else(|| {
-                                (StatusCode::UNAUTHORIZED, "User not found".to_string())
-                            })?;
-                        (user.id, user.email, user.role)
-                    } else {
-                        (
-                            uuid::Uuid::nil(),
-                            "anonymous".to_string(),
-                            "user".to_string(),
-                        )
-                    };
-
-                    let auth_user = AuthUser {
-                        user_id,
-                        email,
-                        role,
-                        auth_type: AuthType::VirtualKey {
-                            key_id: cached_key.id,
-                        },
-                    };
-
-                    request.extensions_mut().insert(auth_user);
-                    return Ok(next.run(request).await);
-                }
-            }
-        }
-
-        // Try to get from Redis cache first (5 minute TTL)
-        let redis_key = format!("auth:key:{}", lookup_hash);
-        let virtual_key = if let Some(redis_conn) = state.get_redis_connection() {
-            // Try cache first
-            match get_cached_key(redis_conn, &redis_key).await {
-                Ok(Some(cached_key)) => Some(cached_key),
-                Ok(None) => {
-                    // Cache miss, fetch from database
-                    let key = VirtualKey::find_by_lookup_hash(pool, &lookup_hash)
+        // HMAC under the server-side pepper: cheap enough for every request, and the
+        // cache and database are both keyed by it
+        let key_hash = hash_virtual_key(token, state.get_key_pepper());
+        let key_cache = state.get_key_cache();
+        let cache_key = key_cache_key(&key_hash);
+
+        // Keys are only cached after their secret has been verified below, together with
+        // everything later middleware and handlers need to know about them
+        let cached = key_cache.get(&cache_key).await.and_then(|principal| {
+            let secret = principal.key.secret_for(token)?;
+            Some((principal, secret))
+        });
+        let (principal, secret) = match cached {
+            Some(cached) => cached,
+            None => {
+                let virtual_key =
+                    VirtualKey::find_by_secret(pool, &key_hash, &create_lookup_hash(token))
                         .await
                         .map_err(|_| {
                             (
                                 StatusCode::INTERNAL_SERVER_ERROR,
                                 "Failed to verify key".to_string(),
                             )
-                        })?;
+                        })?
+                        .ok_or_else(|| (StatusCode::UNAUTHORIZED, "Invalid API key".to_string()))?;
 
-                    // Cache for 5 minutes if found
-                    if let Some(ref k) = key {
-                        let _ = cache_key(redis_conn, &redis_key, k, 300).await;
-                    }
+                let secret = verify_key_secret(pool, &virtual_key, token, &key_hash)
+                    .await
+                    .ok_or_else(|| (StatusCode::UNAUTHORIZED, "Invalid API key".to_string()))?;
 
-                    key
-                }
-                Err(_) => {
-                    // Redis error, fall back to database
-                    VirtualKey::find_by_lookup_hash(pool, &lookup_hash)
+                let principal =
+                    Principal::resolve(pool, virtual_key)
                         .await
-                        .map_err(|_| {
-                            (
+                        .map_err(|e| match e {
+                            ApiError::AuthenticationFailed => {
+                                (StatusCode::UNAUTHORIZED, "User not found".to_string())
+                            }
+                            _ => (
                                 StatusCode::INTERNAL_SERVER_ERROR,
-                                "Failed to verify key".to_string(),
-                            )
-                        })?
-                }
+                                "Failed to verify user".to_string(),
+                            ),
+                        })?;
+
+                key_cache.set(&cache_key, &principal).await;
+                (principal, secret)
             }
-        } else {
-            // No Redis, direct database lookup
-            VirtualKey::find_by_lookup_hash(pool, &lookup_hash)
-                .await
-                .map_err(|_| {
-                    (
-                        StatusCode::INTERNAL_SERVER_ERROR,
-                        "Failed to verify key".to_string(),
-                    )
-                })?
         };
 
-        let virtual_key = virtual_key.ok_or_else(|| {
-            // Key not found, but verify with bcrypt anyway to prevent timing attacks
-            let _ = crate::auth::keys::verify_virtual_key(
-                token,
-                "$2b$12$dummy.hash.for.timing.attack.prevention.only",
-            );
-            (StatusCode::UNAUTHORIZED, "Invalid API key".to_string())
-        })?;
-
-        // Verify with bcrypt (single verification, not N verifications!)
-        if !crate::auth::keys::verify_virtual_key(token, &virtual_key.key_hash).unwrap_or(false) {
-            return Err((StatusCode::UNAUTHORIZED, "Invalid API key".to_string()));
-        }
-
-        // CRITICAL FIX: Cache the verified token to skip bcrypt on future requests
-        // This dramatically speeds up authenticated requests (9s → <10ms)
-        if let Some(redis_conn) = state.get_redis_connection() {
-            let verified_token_key = format!("auth:verified:{}", lookup_hash);
-            // Cache for 5 minutes - shorter than key cache for security
-            let _ = cache_key(redis_conn, &verified_token_key, &virtual_key, 300).await;
-            tracing::debug!("Cached verified token for future requests");
-        }
-
         // Validate key
+        let virtual_key = &principal.key;
         if !virtual_key.is_valid() {
             let reason = if virtual_key.blocked {
                 "API key is blocked"
@@ -370,42 +308,44 @@ where
             return Err((StatusCode::UNAUTHORIZED, reason.to_string()));
         }
 
-        // Get user info if key has a user
-        let (user_id, email, role) = if let Some(user_id) = virtual_key.user_id {
-            let user = User::find_by_id(pool, user_id)
-                .await
-                .map_err(|_| {
-                    (
-                        StatusCode::INTERNAL_SERVER_ERROR,
-                        "Failed to verify user".to_string(),
-                    )
-                })?
-                .ok_or_else(|| (StatusCode::UNAUTHORIZED, "User not found".to_string()))?;
-            (user.id, user.email, user.role)
-        } else {
-            // System key without user
-            (
-                uuid::Uuid::nil(),
-                "system".to_string(),
-                "system".to_string(),
+        if let Some(reason) = key_restriction_violation(
+            &virtual_key.restrictions,
+            &request,
+            state.trusts_forwarded_for(),
+        ) {
+            let peer = request
+                .extensions()
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|ConnectInfo(addr)| *addr);
+            AuditContext::from_request(
+                request.headers(),
+                peer,
+                state.trusts_forwarded_for(),
+                Some(&principal.auth_user()),
             )
-        };
-
-        let auth_user = AuthUser {
-            user_id,
-            email,
-            role,
-            auth_type: AuthType::VirtualKey {
-                key_id: virtual_key.id,
-            },
-        };
+            .record(
+                pool,
+                AuditRecord::new("key.access_denied")
+                    .target("virtual_key", virtual_key.id)
+                    .details(serde_json::json!({
+                        "reason": reason,
+                        "method": request.method().as_str(),
+                        "path": request.uri().path(),
+                    })),
+            )
+            .await;
+            return Err((StatusCode::FORBIDDEN, reason));
+        }
 
-        request.extensions_mut().insert(auth_user);
+        // Written in the background with the next usage flush
+        state
+            .get_usage_recorder()
+            .record_use(virtual_key.id, secret);
 
-        // Update last used
-        let _ = VirtualKey::update_last_used(pool, virtual_key.id).await;
+        request.extensions_mut().insert(principal.auth_user());
+        request.extensions_mut().insert(principal);
 
-        return Ok(next.run(request).await);
+        return Ok(with_key_secret_header(next.run(request).await, secret));
     }
 
     Err((
@@ -414,14 +354,181 @@ where
     ))
 }
 
+/// The user behind an access token, as long as the session it was issued for is still
+/// active; revoking the session (logout, admin revocation) or deleting the user ends it
+async fn session_user(
+    pool: &sqlx::Pool<sqlx::Postgres>,
+    claims: &Claims,
+) -> Result<AuthUser, (StatusCode, String)> {
+    let invalid = || (StatusCode::UNAUTHORIZED, "Invalid token".to_string());
+    let user_id = uuid::Uuid::parse_str(&claims.sub).map_err(|_| invalid())?;
+    let session_id = uuid::Uuid::parse_str(&claims.sid).map_err(|_| invalid())?;
+
+    let user = User::find_by_session(pool, session_id)
+        .await
+        .map_err(|_| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to verify session".to_string(),
+            )
+        })?
+        .filter(|user| user.id == user_id)
+        .ok_or_else(|| {
+            (
+                StatusCode::UNAUTHORIZED,
+                "Session has expired or been revoked".to_string(),
+            )
+        })?;
+
+    Ok(AuthUser {
+        user_id: user.id,
+        email: user.email,
+        role: user.role,
+        auth_type: AuthType::JWT { session_id },
+    })
+}
+
+/// Routes a session may use while its user still has to set up mandatory TOTP
+const TOTP_ENROLLMENT_ROUTES: &[&str] = &[
+    "/auth/me",
+    "/auth/logout",
+    "/auth/logout-all",
+    "/auth/totp",
+    "/auth/totp/setup",
+    "/auth/totp/confirm",
+];
+
+/// Refuse everything but enrollment to sessions whose role requires TOTP the user hasn't
+/// set up yet
+fn require_totp_enrollment(claims: &Claims, path: &str) -> Result<(), (StatusCode, String)> {
+    if claims.totp_pending && !TOTP_ENROLLMENT_ROUTES.contains(&path) {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "Two-factor authentication must be set up first (POST /auth/totp/setup)".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Check the token against the stored hash of the secret it claims to be (current, or
+/// previous during its grace period)
+///
+/// Keys created before HMAC hashing still have a bcrypt hash; it's verified once and then
+/// replaced with the HMAC hash, so bcrypt only ever runs on a legacy key's first use.
+async fn verify_key_secret(
+    pool: &sqlx::Pool<sqlx::Postgres>,
+    key: &VirtualKey,
+    token: &str,
+    key_hash: &str,
+) -> Option<KeySecret> {
+    let secret = key.secret_for(token)?;
+    let stored = key.hash_for(secret)?;
+
+    if !is_legacy_key_hash(stored) {
+        return key_hash_matches(stored, key_hash).then_some(secret);
+    }
+
+    if !verify_virtual_key(token, stored).unwrap_or(false) {
+        return None;
+    }
+    match VirtualKey::upgrade_key_hash(pool, key.id, secret, key_hash).await {
+        Ok(()) => tracing::info!("Upgraded key {} from bcrypt to HMAC hashing", key.id),
+        Err(e) => tracing::warn!("Failed to upgrade hash of key {}: {}", key.id, e),
+    }
+    Some(secret)
+}
+
+/// Why a key's route, network or origin restrictions forbid a request, if they do
+fn key_restriction_violation(
+    restrictions: &KeyRestrictions,
+    request: &Request,
+    trust_forwarded_for: bool,
+) -> Option<String> {
+    // Every inference endpoint is a POST, so read-only keys can't reach them; listing
+    // models is a GET
+    if restrictions.read_only && !matches!(*request.method(), Method::GET | Method::HEAD) {
+        return Some("API key is read-only".to_string());
+    }
+
+    let path = request.uri().path();
+    if !restrictions.allows_route(path) {
+        return Some(format!("API key is not allowed to call {}", path));
+    }
+
+    if restrictions.allowed_ips.is_some() {
+        let peer = request
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| *addr);
+        match client_ip(request.headers(), peer, trust_forwarded_for) {
+            Some(ip) if restrictions.allows_ip(ip) => {}
+            Some(ip) => return Some(format!("API key is not allowed from {}", ip)),
+            None => return Some("API key requires a known client address".to_string()),
+        }
+    }
+
+    if restrictions.allowed_origins.is_some() {
+        match request_origin(request.headers()) {
+            Some(origin) if restrictions.allows_origin(&origin) => {}
+            Some(origin) => return Some(format!("API key is not allowed from origin {}", origin)),
+            None => return Some("API key requires an Origin or Referer header".to_string()),
+        }
+    }
+
+    None
+}
+
+/// The client's address: the last `X-Forwarded-For` hop when behind a trusted proxy,
+/// otherwise the peer of the connection
+///
+/// Only the last hop is used because it's the one the proxy added; earlier entries are
+/// whatever the client sent.
+pub fn client_ip(
+    headers: &HeaderMap,
+    peer: Option<SocketAddr>,
+    trust_forwarded_for: bool,
+) -> Option<IpAddr> {
+    if trust_forwarded_for {
+        let forwarded = headers
+            .get("x-forwarded-for")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.rsplit(',').next())
+            .and_then(|hop| hop.trim().parse().ok());
+        if forwarded.is_some() {
+            return forwarded;
+        }
+    }
+    peer.map(|addr| addr.ip())
+}
+
+/// The origin a browser request came from: its `Origin` header, else its `Referer`'s origin
+fn request_origin(headers: &HeaderMap) -> Option<String> {
+    let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+
+    if let Some(origin) = header(header::ORIGIN).filter(|origin| *origin != "null") {
+        return Some(origin.trim_end_matches('/').to_string());
+    }
+    let origin = url::Url::parse(header(header::REFERER)?).ok()?.origin();
+    origin.is_tuple().then(|| origin.ascii_serialization())
+}
+
+/// Tell the caller which secret of a rotated key it used, so clients still sending the
+/// previous one can be spotted before the grace period ends
+fn with_key_secret_header(mut response: Response, secret: KeySecret) -> Response {
+    response
+        .headers_mut()
+        .insert("X-Key-Secret", HeaderValue::from_static(secret.as_str()));
+    response
+}
+
 /// Trait for state that has a master key
 pub trait HasMasterKey {
     fn get_master_key(&self) -> &str;
 }
 
-/// Trait for state that has a JWT secret
-pub trait HasJwtSecret {
-    fn get_jwt_secret(&self) -> &str;
+/// Trait for state that has the keys access tokens are verified with
+pub trait HasJwtKeys {
+    fn get_jwt_keys(&self) -> &super::JwtKeys;
 }
 
 /// Trait for state that has database access
@@ -429,52 +536,57 @@ pub trait HasDatabase {
     fn get_database_pool(&self) -> Option<&sqlx::Pool<sqlx::Postgres>>;
 }
 
-/// Trait for state that has Redis access
-pub trait HasRedis {
-    fn get_redis_connection(&self) -> Option<&redis::aio::ConnectionManager>;
+/// Trait for state that has the secret virtual keys are hashed with
+pub trait HasKeyPepper {
+    fn get_key_pepper(&self) -> &str;
 }
 
-/// Cache a virtual key in Redis
-async fn cache_key(
-    redis_conn: &redis::aio::ConnectionManager,
-    redis_key: &str,
-    key: &VirtualKey,
-    ttl_seconds: i64,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    use redis::AsyncCommands;
-
-    let mut conn = redis_conn.clone();
-    let serialized = serde_json::to_string(key)?;
-    conn.set_ex::<_, _, ()>(redis_key, serialized, ttl_seconds as u64)
-        .await?;
-    Ok(())
+/// Trait for state that caches authenticated virtual keys
+pub trait HasKeyCache {
+    fn get_key_cache(&self) -> &KeyCache;
 }
 
-/// Get a cached virtual key from Redis
-async fn get_cached_key(
-    redis_conn: &redis::aio::ConnectionManager,
-    redis_key: &str,
-) -> Result<Option<VirtualKey>, Box<dyn std::error::Error + Send + Sync>> {
-    use redis::AsyncCommands;
+/// Trait for state that knows whether it runs behind a proxy setting `X-Forwarded-For`
+pub trait HasTrustedProxy {
+    fn trusts_forwarded_for(&self) -> bool;
+}
 
-    let mut conn = redis_conn.clone();
-    let cached: Option<String> = conn.get(redis_key).await?;
+/// Trait for state that knows which provider serves each routed model
+pub trait HasModelProviders {
+    fn get_model_provider(&self, model: &str) -> Option<String>;
+}
 
-    match cached {
-        Some(data) => {
-            let key: VirtualKey = serde_json::from_str(&data)?;
-            Ok(Some(key))
-        }
-        None => Ok(None),
-    }
+/// Trait for state that batches key usage and spend writes
+pub trait HasUsageRecorder {
+    fn get_usage_recorder(&self) -> &UsageRecorder;
 }
 
 /// Virtual key information for rate limiting
 #[derive(Debug, Clone)]
 pub struct VirtualKeyInfo {
     pub key_id: uuid::Uuid,
-    pub rate_limit_rpm: Option<i32>,
-    pub rate_limit_tpm: Option<i32>,
+    pub user_id: Option<uuid::Uuid>,
+    /// Team that owns the key; usage and spend are attributed to it
+    pub team_id: Option<uuid::Uuid>,
+    /// Every level with rate limits that applies to this request (key, model, team, user, global)
+    pub rate_limit_levels: Vec<RateLimitLevel>,
+    /// The key's per-model limit pattern matching the requested model, if any
+    pub model_pattern: Option<String>,
+    /// Tokens reserved against the TPM limit before the call (prompt estimate + max_tokens)
+    pub reserved_tokens: i32,
+    /// Estimated prompt tokens, used when a stream doesn't report usage
+    pub estimated_prompt_tokens: i32,
+    /// Rate limit status after the pre-flight check
+    pub rate_limit_status: Option<RateLimitStatus>,
+}
+
+impl VirtualKeyInfo {
+    /// Whether any level limits tokens, so token usage has to be reserved and reconciled
+    pub fn has_token_limit(&self) -> bool {
+        self.rate_limit_levels
+            .iter()
+            .any(|level| level.limit.tokens_per_minute.is_some())
+    }
 }
 
 /// Implement FromRequestParts to allow VirtualKeyInfo to be used as an extractor
@@ -504,15 +616,30 @@ pub trait HasRateLimiter {
     fn get_rate_limiter(&self) -> Option<&RateLimiter>;
 }
 
+/// Trait for state that limits parallel requests per key
+pub trait HasConcurrencyLimiter {
+    fn get_concurrency_limiter(&self) -> Option<&ConcurrencyLimiter>;
+}
+
+/// Largest request body buffered for token estimation
+const MAX_ESTIMATE_BODY_BYTES: usize = 16 * 1024 * 1024;
+
 /// Middleware to enforce rate limits for virtual keys
 /// This should be applied after require_auth middleware
+///
+/// For keys with a TPM limit, the request body is parsed to estimate its prompt tokens,
+/// and the estimate plus `max_tokens` is reserved before the call. The handler reconciles
+/// the reservation against the real usage once the call finishes.
+///
+/// Keys with `max_parallel_requests` hold a slot until the response body has been fully
+/// sent or dropped, so streams count for their whole duration and disconnects free the slot.
 pub async fn enforce_rate_limit<S>(
     State(state): State<Arc<S>>,
     mut request: Request,
     next: Next,
 ) -> Result<Response, (StatusCode, String)>
 where
-    S: HasDatabase + HasRateLimiter,
+    S: HasRateLimiter + HasConcurrencyLimiter + HasModelProviders,
 {
     // Get auth user from extensions (added by require_auth)
     let auth_user = request
@@ -526,111 +653,282 @@ where
         })?
         .clone();
 
+    let mut permit: Option<ConcurrencyPermit> = None;
+
     // Only enforce rate limits for virtual keys
     if let AuthType::VirtualKey { key_id } = auth_user.auth_type {
-        let pool = state.get_database_pool().ok_or_else(|| {
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Database not available".to_string(),
-            )
-        })?;
-
-        // Fetch the virtual key to get rate limit settings
-        let virtual_key = VirtualKey::find_by_id(pool, key_id)
-            .await
-            .map_err(|_| {
+        // Resolved by require_auth, with the key's team and the limits above it
+        let principal = request
+            .extensions()
+            .get::<Principal>()
+            .cloned()
+            .ok_or_else(|| {
                 (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    "Failed to fetch key info".to_string(),
+                    StatusCode::UNAUTHORIZED,
+                    "Missing authentication".to_string(),
                 )
-            })?
-            .ok_or_else(|| (StatusCode::UNAUTHORIZED, "Key not found".to_string()))?;
+            })?;
+        let virtual_key = &principal.key;
+        let scoped_limits = &principal.scoped_limits;
+
+        // The key's own budget is checked in require_auth
+        if let Some(scoped) = scoped_limits.iter().find(|s| s.limits.is_over_budget()) {
+            let mut response = (
+                StatusCode::FORBIDDEN,
+                format!("Budget exceeded ({} limit)", scoped.scope),
+            )
+                .into_response();
+            response.headers_mut().insert(
+                "X-RateLimit-Scope",
+                HeaderValue::from_static(scoped.scope.as_str()),
+            );
+            return Ok(response);
+        }
 
-        // Store key info in extensions for handler use
-        let key_info = VirtualKeyInfo {
+        let mut key_info = VirtualKeyInfo {
             key_id: virtual_key.id,
-            rate_limit_rpm: virtual_key.rate_limit_rpm,
-            rate_limit_tpm: virtual_key.rate_limit_tpm,
-        };
-        request.extensions_mut().insert(key_info);
-
-        // Check rate limits if configured
-        if virtual_key.rate_limit_rpm.is_some() || virtual_key.rate_limit_tpm.is_some() {
-            if let Some(rate_limiter) = state.get_rate_limiter() {
-                let rate_limit = RateLimit {
+            user_id: virtual_key.user_id,
+            team_id: virtual_key.team_id,
+            rate_limit_levels: vec![RateLimitLevel::new(
+                LimitScope::Key,
+                key_id.to_string(),
+                RateLimit {
                     requests_per_minute: virtual_key.rate_limit_rpm,
                     tokens_per_minute: virtual_key.rate_limit_tpm,
-                };
+                },
+            )],
+            model_pattern: None,
+            reserved_tokens: 0,
+            estimated_prompt_tokens: 0,
+            rate_limit_status: None,
+        };
+        key_info
+            .rate_limit_levels
+            .extend(scoped_limits.iter().map(ScopedLimits::rate_limit_level));
+
+        let rate_limiter = state.get_rate_limiter();
+
+        // The body is needed to find the model for model restrictions and per-model limits,
+        // and to estimate the tokens a request can consume so TPM is enforced up front
+        if principal.restricts_models()
+            || !virtual_key.model_limits.is_empty()
+            || (rate_limiter.is_some() && key_info.has_token_limit())
+        {
+            let (parts, body) = request.into_parts();
+            let bytes = axum::body::to_bytes(body, MAX_ESTIMATE_BODY_BYTES)
+                .await
+                .map_err(|_| {
+                    (
+                        StatusCode::PAYLOAD_TOO_LARGE,
+                        "Request body too large".to_string(),
+                    )
+                })?;
+
+            // Malformed bodies are rejected by the handler; limit and reserve nothing for them
+            if let Ok(chat_request) = serde_json::from_slice::<crate::ChatCompletionRequest>(&bytes)
+            {
+                let provider = state.get_model_provider(&chat_request.model);
+                if let Some(reason) =
+                    principal.model_denial(&chat_request.model, provider.as_deref())
+                {
+                    return Err((StatusCode::FORBIDDEN, reason));
+                }
 
-                // For pre-flight check, we only check request count (tokens will be checked after processing)
+                if let Some((pattern, model_limit)) =
+                    virtual_key.model_limit_for(&chat_request.model)
+                {
+                    if virtual_key.is_model_over_budget(pattern) {
+                        let mut response = (
+                            StatusCode::FORBIDDEN,
+                            format!("Budget exceeded ({} limit)", LimitScope::Model),
+                        )
+                            .into_response();
+                        response.headers_mut().insert(
+                            "X-RateLimit-Scope",
+                            HeaderValue::from_static(LimitScope::Model.as_str()),
+                        );
+                        return Ok(response);
+                    }
+
+                    key_info.rate_limit_levels.push(RateLimitLevel::new(
+                        LimitScope::Model,
+                        format!("{}:{}", key_id, pattern),
+                        model_limit.rate_limit(),
+                    ));
+                    key_info.model_pattern = Some(pattern.clone());
+                }
+
+                if let Some(rate_limiter) = rate_limiter.filter(|_| key_info.has_token_limit()) {
+                    key_info.estimated_prompt_tokens =
+                        token_counter::estimate_prompt_tokens(&chat_request);
+                    key_info.reserved_tokens = token_counter::estimate_request_tokens(
+                        &chat_request,
+                        rate_limiter.default_max_tokens(),
+                    );
+                }
+            }
+
+            request = Request::from_parts(parts, axum::body::Body::from(bytes));
+        }
+
+        key_info
+            .rate_limit_levels
+            .retain(|level| !level.limit.is_unlimited());
+
+        // A reservation above the smallest TPM could never fit, however long it queued. The
+        // actual usage is charged when the response completes.
+        if let Some(capacity) = key_info
+            .rate_limit_levels
+            .iter()
+            .filter_map(|level| level.limit.tokens_per_minute)
+            .filter(|tpm| *tpm > 0)
+            .min()
+        {
+            key_info.reserved_tokens = key_info.reserved_tokens.min(capacity);
+        }
+
+        // Take a parallel request slot before reserving rate limit capacity, so a rejected
+        // request doesn't use up the key's RPM/TPM
+        if let (Some(max_parallel), Some(concurrency_limiter)) = (
+            virtual_key.max_parallel_requests,
+            state.get_concurrency_limiter(),
+        ) {
+            permit = concurrency_limiter
+                .acquire(&key_id.to_string(), max_parallel)
+                .await
+                .map_err(|e| match e {
+                    ApiError::ServiceUnavailable => (
+                        StatusCode::SERVICE_UNAVAILABLE,
+                        "Parallel request limiting is temporarily unavailable".to_string(),
+                    ),
+                    e => (
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        format!("Parallel request check failed: {}", e),
+                    ),
+                })?;
+
+            if permit.is_none() {
+                MetricsCollector::record_rate_limit_exceeded(&key_id.to_string(), "parallel");
+                let mut response = (
+                    StatusCode::TOO_MANY_REQUESTS,
+                    format!("Too many parallel requests ({} limit)", LimitScope::Key),
+                )
+                    .into_response();
+                response.headers_mut().insert(
+                    "X-RateLimit-Scope",
+                    HeaderValue::from_static(LimitScope::Key.as_str()),
+                );
+                return Ok(response);
+            }
+        }
+
+        // Check rate limits at every level that has them, in one round-trip
+        if !key_info.rate_limit_levels.is_empty() {
+            if let Some(rate_limiter) = rate_limiter {
                 let status = rate_limiter
-                    .check_and_increment(&key_id.to_string(), &rate_limit, 1)
+                    .check_and_reserve(&key_info.rate_limit_levels, key_info.reserved_tokens)
                     .await
-                    .map_err(|e| {
-                        (
+                    .map_err(|e| match e {
+                        // Fail-closed mode while Redis is unreachable
+                        ApiError::ServiceUnavailable => (
+                            StatusCode::SERVICE_UNAVAILABLE,
+                            "Rate limiting is temporarily unavailable".to_string(),
+                        ),
+                        e => (
                             StatusCode::INTERNAL_SERVER_ERROR,
                             format!("Rate limit check failed: {}", e),
-                        )
+                        ),
                     })?;
 
+                let key_id_str = key_id.to_string();
                 if status.limited {
                     // Record rate limit exceeded metrics
-                    let key_id_str = key_id.to_string();
                     if status.requests_remaining == Some(0) {
                         MetricsCollector::record_rate_limit_exceeded(&key_id_str, "requests");
                     }
-                    if status.tokens_remaining == Some(0) {
+                    if status
+                        .tokens_remaining
+                        .is_some_and(|remaining| remaining < key_info.reserved_tokens)
+                    {
                         MetricsCollector::record_rate_limit_exceeded(&key_id_str, "tokens");
                     }
 
-                    use axum::http::header::{HeaderMap, HeaderValue};
-                    let mut headers = HeaderMap::new();
-
-                    if let Some(reset_at) = status.reset_at {
-                        headers.insert(
-                            "X-RateLimit-Reset",
-                            HeaderValue::from_str(&reset_at.to_string()).unwrap(),
-                        );
-                    }
-
-                    if let Some(retry_after) = status.retry_after {
-                        headers.insert(
-                            "Retry-After",
-                            HeaderValue::from_str(&retry_after.to_string()).unwrap(),
-                        );
-                    }
-
-                    if let Some(remaining) = status.requests_remaining {
-                        headers.insert(
-                            "X-RateLimit-Remaining-Requests",
-                            HeaderValue::from_str(&remaining.to_string()).unwrap(),
-                        );
-                    }
-
-                    if let Some(remaining) = status.tokens_remaining {
-                        headers.insert(
-                            "X-RateLimit-Remaining-Tokens",
-                            HeaderValue::from_str(&remaining.to_string()).unwrap(),
-                        );
-                    }
-
-                    return Err((
+                    let scope = status.blocked_by.unwrap_or(LimitScope::Key);
+                    let mut response = (
                         StatusCode::TOO_MANY_REQUESTS,
-                        "Rate limit exceeded".to_string(),
-                    ));
+                        format!("Rate limit exceeded ({} limit)", scope),
+                    )
+                        .into_response();
+                    apply_rate_limit_headers(response.headers_mut(), &status);
+                    return Ok(response);
                 }
 
                 // Update rate limit remaining metrics
-                let key_id_str = key_id.to_string();
                 if let Some(remaining) = status.requests_remaining {
                     MetricsCollector::set_rate_limit_remaining(&key_id_str, "requests", remaining);
                 }
                 if let Some(remaining) = status.tokens_remaining {
                     MetricsCollector::set_rate_limit_remaining(&key_id_str, "tokens", remaining);
                 }
+
+                key_info.rate_limit_status = Some(status);
             }
         }
+
+        // Store key info in extensions for handler use
+        request.extensions_mut().insert(key_info);
     }
 
-    Ok(next.run(request).await)
+    let response = next.run(request).await;
+    let Some(permit) = permit else {
+        return Ok(response);
+    };
+
+    // The body stream owns the permit: it is released when the stream ends or is dropped
+    let (parts, body) = response.into_parts();
+    let body = body.into_data_stream().map(move |chunk| {
+        let _ = &permit;
+        chunk
+    });
+    Ok(Response::from_parts(
+        parts,
+        axum::body::Body::from_stream(body),
+    ))
+}
+
+/// Add `X-RateLimit-*` and `Retry-After` headers describing a rate limit status
+pub fn apply_rate_limit_headers(headers: &mut HeaderMap, status: &RateLimitStatus) {
+    let values = [
+        (
+            "X-RateLimit-Limit-Requests",
+            status.requests_limit.map(i64::from),
+        ),
+        (
+            "X-RateLimit-Limit-Tokens",
+            status.tokens_limit.map(i64::from),
+        ),
+        (
+            "X-RateLimit-Remaining-Requests",
+            status.requests_remaining.map(i64::from),
+        ),
+        (
+            "X-RateLimit-Remaining-Tokens",
+            status.tokens_remaining.map(i64::from),
+        ),
+        ("X-RateLimit-Reset", status.reset_at),
+        ("Retry-After", status.retry_after.filter(|_| status.limited)),
+    ];
+
+    if let Some(scope) = status.blocked_by.filter(|_| status.limited) {
+        headers.insert(
+            "X-RateLimit-Scope",
+            HeaderValue::from_static(scope.as_str()),
+        );
+    }
+
+    for (name, value) in values {
+        if let Some(value) = value {
+            headers.insert(name, HeaderValue::from(value));
+        }
+    }
 }
@@ -1,11 +1,25 @@
 pub mod jwt;
+pub mod jwt_keys;
+pub mod key_cache;
 pub mod keys;
+pub mod login_throttle;
 pub mod middleware;
 pub mod oauth;
 pub mod password;
+pub mod principal;
+pub mod rbac;
+pub mod session;
+pub mod totp;
 
 pub use jwt::*;
+pub use jwt_keys::*;
+pub use key_cache::*;
 pub use keys::*;
+pub use login_throttle::*;
 pub use middleware::*;
 pub use oauth::*;
 pub use password::*;
+pub use principal::*;
+pub use rbac::*;
+pub use session::*;
+pub use totp::*;
@@ -26,6 +26,7 @@ struct TokenRequest {
     client_secret: String,
     code: String,
     redirect_uri: String,
+    code_verifier: String,
 }
 
 #[derive(Debug, Deserialize)]
@@ -91,12 +92,18 @@ impl OAuthProvider for GitHubOAuthProvider {
         url.to_string()
     }
 
-    async fn exchange_code(&self, code: &str, redirect_uri: &str) -> ApiResult<OAuthTokens> {
+    async fn exchange_code(
+        &self,
+        code: &str,
+        redirect_uri: &str,
+        code_verifier: &str,
+    ) -> ApiResult<OAuthTokens> {
         let request_body = TokenRequest {
             client_id: self.client_id.clone(),
             client_secret: self.client_secret.clone(),
             code: code.to_string(),
             redirect_uri: redirect_uri.to_string(),
+            code_verifier: code_verifier.to_string(),
         };
 
         let response = self
@@ -124,6 +131,7 @@ impl OAuthProvider for GitHubOAuthProvider {
             access_token: token_response.access_token,
             refresh_token: None, // GitHub doesn't provide refresh tokens
             expires_in: None,    // GitHub tokens don't expire
+            id_token: None,
         })
     }
 
@@ -194,6 +202,7 @@ impl OAuthProvider for GitHubOAuthProvider {
             email,
             username: Some(github_user.login),
             avatar_url: github_user.avatar_url,
+            groups: None,
         })
     }
 }
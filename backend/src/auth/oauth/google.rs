@@ -0,0 +1,20 @@
+use super::OidcConfig;
+
+const GOOGLE_ISSUER_URL: &str = "https://accounts.google.com";
+const GOOGLE_SCOPES: &str = "openid email profile";
+
+impl OidcConfig {
+    /// Google sign-in is plain OpenID Connect against Google's issuer
+    /// Google ID tokens carry no group claim, so group mappings don't apply to it.
+    pub fn google(client_id: String, client_secret: String) -> Self {
+        Self {
+            name: "google".to_string(),
+            issuer_url: GOOGLE_ISSUER_URL.to_string(),
+            client_id,
+            client_secret,
+            scopes: GOOGLE_SCOPES.to_string(),
+            email_claim: "email".to_string(),
+            groups_claim: None,
+        }
+    }
+}
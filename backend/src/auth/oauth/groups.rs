@@ -0,0 +1,201 @@
+use sqlx::{Pool, Postgres};
+use uuid::Uuid;
+
+use crate::{
+    auth::Role,
+    error::{ApiError, ApiResult},
+    models::{Team, TeamRole, User},
+};
+
+/// Maps identity-provider groups to gateway roles and team memberships
+///
+/// Configured as comma-separated `group=value` pairs:
+/// - `OAUTH_GROUP_ROLES="platform-admins=admin,sre=key-manager"`
+/// - `OAUTH_GROUP_TEAMS="ml-eng=<team uuid>,ml-leads=<team uuid>:admin"`
+#[derive(Debug, Clone, Default)]
+pub struct GroupMappings {
+    roles: Vec<(String, Role)>,
+    teams: Vec<(String, Uuid, TeamRole)>,
+}
+
+impl GroupMappings {
+    pub fn parse(roles: Option<&str>, teams: Option<&str>) -> ApiResult<Self> {
+        let roles = pairs(roles)?
+            .into_iter()
+            .map(|(group, role)| Ok((group, Role::parse(&role)?)))
+            .collect::<ApiResult<_>>()?;
+
+        let teams = pairs(teams)?
+            .into_iter()
+            .map(|(group, target)| {
+                let (team_id, role) = match target.split_once(':') {
+                    Some((team_id, role)) => (team_id, TeamRole::parse(role)?),
+                    None => (target.as_str(), TeamRole::Member),
+                };
+                let team_id = Uuid::parse_str(team_id).map_err(|_| {
+                    ApiError::BadRequest(format!(
+                        "Invalid team ID '{}' for group '{}'",
+                        team_id, group
+                    ))
+                })?;
+                Ok((group, team_id, role))
+            })
+            .collect::<ApiResult<_>>()?;
+
+        Ok(Self { roles, teams })
+    }
+
+    /// Most privileged role granted by any of the groups, or `user` if none match
+    /// `None` when no role mappings are configured, so roles stay managed in the gateway.
+    pub fn role_for(&self, groups: &[String]) -> Option<Role> {
+        if self.roles.is_empty() {
+            return None;
+        }
+
+        let role = self
+            .roles
+            .iter()
+            .filter(|(group, _)| groups.contains(group))
+            .map(|(_, role)| *role)
+            .max_by_key(privilege)
+            .unwrap_or(Role::User);
+        Some(role)
+    }
+
+    /// Teams the groups grant membership of, with the highest role per team
+    pub fn teams_for(&self, groups: &[String]) -> Vec<(Uuid, TeamRole)> {
+        let mut teams: Vec<(Uuid, TeamRole)> = Vec::new();
+        for (_, team_id, role) in self
+            .teams
+            .iter()
+            .filter(|(group, ..)| groups.contains(group))
+        {
+            match teams.iter_mut().find(|(id, _)| id == team_id) {
+                Some((_, existing)) => *existing = (*existing).max(*role),
+                None => teams.push((*team_id, *role)),
+            }
+        }
+        teams
+    }
+
+    /// Sync a user's role and team memberships with their provider groups
+    ///
+    /// With role mappings configured the provider is the source of truth for the role,
+    /// except that the last admin is never demoted. Team mappings only add members or
+    /// raise their role; removing someone from a team stays a manual action.
+    pub async fn apply(
+        &self,
+        pool: &Pool<Postgres>,
+        user: &User,
+        groups: &[String],
+    ) -> ApiResult<()> {
+        if let Some(role) = self.role_for(groups) {
+            let current = Role::from_stored(&user.role);
+            let demotes_last_admin = current == Role::Admin
+                && role != Role::Admin
+                && User::count_by_role(pool, Role::Admin.as_str()).await? <= 1;
+
+            if role != current && !demotes_last_admin {
+                User::update_role(pool, user.id, role.as_str().to_string()).await?;
+                tracing::info!(
+                    "Group mapping changed the role of {} from {} to {}",
+                    user.email,
+                    user.role,
+                    role
+                );
+            }
+        }
+
+        for (team_id, role) in self.teams_for(groups) {
+            if Team::find_by_id(pool, team_id).await?.is_none() {
+                tracing::warn!("Group mapping refers to unknown team {}", team_id);
+                continue;
+            }
+            let current = Team::member_role(pool, team_id, user.id).await?;
+            if current.is_none_or(|current| current < role) {
+                Team::set_member(pool, team_id, user.id, role).await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn privilege(role: &Role) -> u8 {
+    match role {
+        Role::User => 0,
+        Role::Viewer => 1,
+        Role::KeyManager => 2,
+        Role::Admin => 3,
+    }
+}
+
+fn pairs(value: Option<&str>) -> ApiResult<Vec<(String, String)>> {
+    value
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            pair.split_once('=')
+                .map(|(group, value)| (group.trim().to_string(), value.trim().to_string()))
+                .ok_or_else(|| {
+                    ApiError::BadRequest(format!(
+                        "Invalid group mapping '{}': expected group=value",
+                        pair
+                    ))
+                })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn groups(names: &[&str]) -> Vec<String> {
+        names.iter().map(|g| g.to_string()).collect()
+    }
+
+    #[test]
+    fn test_role_mapping() {
+        let mappings =
+            GroupMappings::parse(Some("admins=admin, sre=key-manager,auditors=viewer"), None)
+                .unwrap();
+
+        assert_eq!(
+            mappings.role_for(&groups(&["sre", "admins"])),
+            Some(Role::Admin)
+        );
+        assert_eq!(
+            mappings.role_for(&groups(&["auditors"])),
+            Some(Role::Viewer)
+        );
+        assert_eq!(mappings.role_for(&groups(&["other"])), Some(Role::User));
+        assert_eq!(
+            GroupMappings::default().role_for(&groups(&["admins"])),
+            None
+        );
+
+        assert!(GroupMappings::parse(Some("admins=root"), None).is_err());
+        assert!(GroupMappings::parse(Some("admins"), None).is_err());
+    }
+
+    #[test]
+    fn test_team_mapping() {
+        let team = Uuid::new_v4();
+        let mappings =
+            GroupMappings::parse(None, Some(&format!("eng={team},leads={team}:admin"))).unwrap();
+
+        assert_eq!(
+            mappings.teams_for(&groups(&["eng"])),
+            vec![(team, TeamRole::Member)]
+        );
+        assert_eq!(
+            mappings.teams_for(&groups(&["eng", "leads"])),
+            vec![(team, TeamRole::Admin)]
+        );
+        assert!(mappings.teams_for(&groups(&["other"])).is_empty());
+        assert!(GroupMappings::parse(None, Some("eng=not-a-uuid")).is_err());
+    }
+}
@@ -1,5 +1,14 @@
 pub mod github;
+pub mod google;
+pub mod groups;
+pub mod oidc;
 pub mod provider;
+pub mod registry;
+pub mod state;
 
 pub use github::*;
+pub use groups::*;
+pub use oidc::*;
 pub use provider::*;
+pub use registry::*;
+pub use state::*;
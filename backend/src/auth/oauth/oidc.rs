@@ -0,0 +1,463 @@
+use async_trait::async_trait;
+use jsonwebtoken::{decode, decode_header, jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use reqwest::Client;
+use serde::Deserialize;
+use serde_json::Value;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use url::Url;
+
+use crate::error::{ApiError, ApiResult};
+
+use super::{OAuthProvider, OAuthTokens, OAuthUserInfo};
+
+/// Minimum time between JWKS refetches triggered by an unknown `kid`
+const JWKS_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Signature algorithms accepted on ID tokens (never HMAC or `none`)
+const ID_TOKEN_ALGORITHMS: &[Algorithm] = &[
+    Algorithm::RS256,
+    Algorithm::RS384,
+    Algorithm::RS512,
+    Algorithm::PS256,
+    Algorithm::PS384,
+    Algorithm::PS512,
+    Algorithm::ES256,
+    Algorithm::ES384,
+    Algorithm::EdDSA,
+];
+
+/// Settings for an OpenID Connect provider
+#[derive(Debug, Clone)]
+pub struct OidcConfig {
+    /// Name used in routes and stored on linked accounts (e.g. "google", "okta")
+    pub name: String,
+    pub issuer_url: String,
+    pub client_id: String,
+    pub client_secret: String,
+    pub scopes: String,
+    /// Claim holding the user's email address
+    pub email_claim: String,
+    /// Claim holding the user's groups, if the provider sends them
+    pub groups_claim: Option<String>,
+}
+
+/// Relevant fields of `/.well-known/openid-configuration`
+#[derive(Debug, Clone, Deserialize)]
+pub struct OidcDiscovery {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub userinfo_endpoint: Option<String>,
+    pub jwks_uri: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    refresh_token: Option<String>,
+    expires_in: Option<i64>,
+    id_token: Option<String>,
+}
+
+struct CachedJwks {
+    keys: JwkSet,
+    fetched_at: Instant,
+}
+
+/// Generic OpenID Connect provider: endpoints come from the discovery document and
+/// identity comes from the ID token, verified against the provider's JWKS
+pub struct OidcProvider {
+    config: OidcConfig,
+    discovery: OidcDiscovery,
+    jwks: RwLock<CachedJwks>,
+    http_client: Arc<Client>,
+}
+
+impl OidcProvider {
+    /// Fetch the discovery document and signing keys for `config.issuer_url`
+    pub async fn discover(config: OidcConfig) -> ApiResult<Self> {
+        let client = Client::builder()
+            .pool_max_idle_per_host(5)
+            .pool_idle_timeout(Duration::from_secs(60))
+            .timeout(Duration::from_secs(30))
+            .connect_timeout(Duration::from_secs(10))
+            .tcp_keepalive(Duration::from_secs(60))
+            .tcp_nodelay(true)
+            .build()
+            .expect("Failed to create HTTP client for OidcProvider");
+
+        let discovery_url = format!(
+            "{}/.well-known/openid-configuration",
+            config.issuer_url.trim_end_matches('/')
+        );
+        let discovery: OidcDiscovery = fetch_json(&client, &discovery_url).await?;
+
+        // The discovery document must describe the issuer we were configured with,
+        // otherwise ID tokens from it would never validate
+        if discovery.issuer.trim_end_matches('/') != config.issuer_url.trim_end_matches('/') {
+            return Err(ApiError::ExternalApiError(format!(
+                "OIDC discovery for {} returned issuer {}",
+                config.issuer_url, discovery.issuer
+            )));
+        }
+
+        let keys: JwkSet = fetch_json(&client, &discovery.jwks_uri).await?;
+
+        Ok(Self {
+            config,
+            discovery,
+            jwks: RwLock::new(CachedJwks {
+                keys,
+                fetched_at: Instant::now(),
+            }),
+            http_client: Arc::new(client),
+        })
+    }
+
+    /// Find the key for `kid`, refetching the JWKS once if the provider rotated keys
+    async fn decoding_key(&self, kid: Option<&str>) -> ApiResult<DecodingKey> {
+        if let Some(key) = self.cached_key(kid)? {
+            return Ok(key);
+        }
+
+        let stale = self.jwks.read().unwrap().fetched_at.elapsed() >= JWKS_REFRESH_INTERVAL;
+        if stale {
+            let keys: JwkSet = fetch_json(&self.http_client, &self.discovery.jwks_uri).await?;
+            *self.jwks.write().unwrap() = CachedJwks {
+                keys,
+                fetched_at: Instant::now(),
+            };
+            if let Some(key) = self.cached_key(kid)? {
+                return Ok(key);
+            }
+        }
+
+        Err(ApiError::ExternalApiError(format!(
+            "No signing key found for ID token from {}",
+            self.config.name
+        )))
+    }
+
+    fn cached_key(&self, kid: Option<&str>) -> ApiResult<Option<DecodingKey>> {
+        let jwks = self.jwks.read().unwrap();
+        let jwk = match kid {
+            Some(kid) => jwks.keys.find(kid),
+            // Without a kid the token can only be checked against a single-key set
+            None if jwks.keys.keys.len() == 1 => jwks.keys.keys.first(),
+            None => None,
+        };
+
+        jwk.map(DecodingKey::from_jwk)
+            .transpose()
+            .map_err(|e| ApiError::ExternalApiError(format!("Invalid JWKS key: {}", e)))
+    }
+
+    /// Verify an ID token's signature, issuer, audience, expiry and nonce and return its claims
+    pub async fn validate_id_token(&self, id_token: &str, nonce: &str) -> ApiResult<Value> {
+        let header = decode_header(id_token).map_err(|_| ApiError::AuthenticationFailed)?;
+        if !ID_TOKEN_ALGORITHMS.contains(&header.alg) {
+            return Err(ApiError::AuthenticationFailed);
+        }
+
+        let key = self.decoding_key(header.kid.as_deref()).await?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&self.discovery.issuer]);
+        validation.set_audience(&[&self.config.client_id]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+
+        let claims = decode::<Value>(id_token, &key, &validation)
+            .map(|data| data.claims)
+            .map_err(|e| {
+                tracing::warn!("Rejected ID token from {}: {}", self.config.name, e);
+                ApiError::AuthenticationFailed
+            })?;
+
+        // A token minted for another login (or replayed) carries a different nonce
+        if claims.get("nonce").and_then(Value::as_str) != Some(nonce) {
+            tracing::warn!(
+                "Rejected ID token from {}: nonce mismatch",
+                self.config.name
+            );
+            return Err(ApiError::AuthenticationFailed);
+        }
+
+        Ok(claims)
+    }
+
+    /// Map ID token or userinfo claims to the gateway's user info
+    pub fn user_info_from_claims(&self, claims: &Value) -> ApiResult<OAuthUserInfo> {
+        let provider_user_id = claims
+            .get("sub")
+            .and_then(Value::as_str)
+            .ok_or_else(|| ApiError::ExternalApiError("OIDC claims missing 'sub'".to_string()))?
+            .to_string();
+
+        let email = claims
+            .get(&self.config.email_claim)
+            .and_then(Value::as_str)
+            .ok_or_else(|| {
+                ApiError::ExternalApiError(format!(
+                    "No '{}' claim in {} account",
+                    self.config.email_claim, self.config.name
+                ))
+            })?
+            .to_string();
+
+        if claims.get("email_verified").and_then(Value::as_bool) == Some(false) {
+            return Err(ApiError::ExternalApiError(format!(
+                "Email for {} account is not verified",
+                self.config.name
+            )));
+        }
+
+        let username = ["preferred_username", "name"]
+            .iter()
+            .find_map(|claim| claims.get(*claim).and_then(Value::as_str))
+            .map(str::to_string);
+
+        let avatar_url = claims
+            .get("picture")
+            .and_then(Value::as_str)
+            .map(str::to_string);
+
+        // A configured but absent claim means the user is in no groups
+        let groups = self
+            .config
+            .groups_claim
+            .as_deref()
+            .map(|claim| claims.get(claim).map(claim_groups).unwrap_or_default());
+
+        Ok(OAuthUserInfo {
+            provider_user_id,
+            email,
+            username,
+            avatar_url,
+            groups,
+        })
+    }
+}
+
+/// Groups are usually a JSON array, but some providers send a single string
+fn claim_groups(value: &Value) -> Vec<String> {
+    match value {
+        Value::Array(items) => items
+            .iter()
+            .filter_map(Value::as_str)
+            .map(str::to_string)
+            .collect(),
+        Value::String(group) => vec![group.clone()],
+        _ => Vec::new(),
+    }
+}
+
+async fn fetch_json<T: serde::de::DeserializeOwned>(client: &Client, url: &str) -> ApiResult<T> {
+    let response = client
+        .get(url)
+        .header("Accept", "application/json")
+        .send()
+        .await
+        .map_err(|e| ApiError::ExternalApiError(format!("Failed to fetch {}: {}", url, e)))?;
+
+    if !response.status().is_success() {
+        return Err(ApiError::ExternalApiError(format!(
+            "Fetching {} failed with status {}",
+            url,
+            response.status()
+        )));
+    }
+
+    response
+        .json()
+        .await
+        .map_err(|e| ApiError::ExternalApiError(format!("Failed to parse {}: {}", url, e)))
+}
+
+#[async_trait]
+impl OAuthProvider for OidcProvider {
+    fn name(&self) -> &str {
+        &self.config.name
+    }
+
+    fn authorize_url(&self, state: &str, redirect_uri: &str) -> String {
+        let mut url = Url::parse(&self.discovery.authorization_endpoint).unwrap();
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &self.config.client_id)
+            .append_pair("redirect_uri", redirect_uri)
+            .append_pair("scope", &self.config.scopes)
+            .append_pair("state", state);
+
+        url.to_string()
+    }
+
+    async fn exchange_code(
+        &self,
+        code: &str,
+        redirect_uri: &str,
+        code_verifier: &str,
+    ) -> ApiResult<OAuthTokens> {
+        let response = self
+            .http_client
+            .post(&self.discovery.token_endpoint)
+            .header("Accept", "application/json")
+            .form(&[
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("redirect_uri", redirect_uri),
+                ("code_verifier", code_verifier),
+                ("client_id", &self.config.client_id),
+                ("client_secret", &self.config.client_secret),
+            ])
+            .send()
+            .await
+            .map_err(|e| ApiError::ExternalApiError(format!("Failed to exchange code: {}", e)))?;
+
+        if !response.status().is_success() {
+            let error_text = response.text().await.unwrap_or_default();
+            return Err(ApiError::ExternalApiError(format!(
+                "{} token exchange failed: {}",
+                self.config.name, error_text
+            )));
+        }
+
+        let token_response: TokenResponse = response.json().await.map_err(|e| {
+            ApiError::ExternalApiError(format!("Failed to parse token response: {}", e))
+        })?;
+
+        Ok(OAuthTokens {
+            access_token: token_response.access_token,
+            refresh_token: token_response.refresh_token,
+            expires_in: token_response.expires_in,
+            id_token: token_response.id_token,
+        })
+    }
+
+    async fn get_user_info(&self, access_token: &str) -> ApiResult<OAuthUserInfo> {
+        let userinfo_endpoint = self.discovery.userinfo_endpoint.as_ref().ok_or_else(|| {
+            ApiError::ExternalApiError(format!(
+                "{} returned no ID token and has no userinfo endpoint",
+                self.config.name
+            ))
+        })?;
+
+        let response = self
+            .http_client
+            .get(userinfo_endpoint)
+            .header("Authorization", format!("Bearer {}", access_token))
+            .send()
+            .await
+            .map_err(|e| ApiError::ExternalApiError(format!("Failed to get user info: {}", e)))?;
+
+        if !response.status().is_success() {
+            let error_text = response.text().await.unwrap_or_default();
+            return Err(ApiError::ExternalApiError(format!(
+                "{} user info failed: {}",
+                self.config.name, error_text
+            )));
+        }
+
+        let claims: Value = response
+            .json()
+            .await
+            .map_err(|e| ApiError::ExternalApiError(format!("Failed to parse user info: {}", e)))?;
+
+        self.user_info_from_claims(&claims)
+    }
+
+    /// Prefer the verified ID token; fall back to the userinfo endpoint without one
+    async fn user_info(&self, tokens: &OAuthTokens, nonce: &str) -> ApiResult<OAuthUserInfo> {
+        match &tokens.id_token {
+            Some(id_token) => {
+                let claims = self.validate_id_token(id_token, nonce).await?;
+                self.user_info_from_claims(&claims)
+            }
+            None => self.get_user_info(&tokens.access_token).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn provider(groups_claim: Option<&str>) -> OidcProvider {
+        OidcProvider {
+            config: OidcConfig {
+                name: "oidc".to_string(),
+                issuer_url: "https://idp.example.com".to_string(),
+                client_id: "client".to_string(),
+                client_secret: "secret".to_string(),
+                scopes: "openid email profile groups".to_string(),
+                email_claim: "email".to_string(),
+                groups_claim: groups_claim.map(str::to_string),
+            },
+            discovery: OidcDiscovery {
+                issuer: "https://idp.example.com".to_string(),
+                authorization_endpoint: "https://idp.example.com/authorize".to_string(),
+                token_endpoint: "https://idp.example.com/token".to_string(),
+                userinfo_endpoint: None,
+                jwks_uri: "https://idp.example.com/jwks".to_string(),
+            },
+            jwks: RwLock::new(CachedJwks {
+                keys: JwkSet { keys: Vec::new() },
+                fetched_at: Instant::now(),
+            }),
+            http_client: Arc::new(Client::new()),
+        }
+    }
+
+    #[test]
+    fn test_claims_mapping() {
+        let provider = provider(Some("groups"));
+        let info = provider
+            .user_info_from_claims(&json!({
+                "sub": "abc",
+                "email": "dev@example.com",
+                "email_verified": true,
+                "preferred_username": "dev",
+                "groups": ["eng", "admins"],
+            }))
+            .unwrap();
+
+        assert_eq!(info.provider_user_id, "abc");
+        assert_eq!(info.email, "dev@example.com");
+        assert_eq!(info.username.as_deref(), Some("dev"));
+        assert_eq!(info.groups.unwrap(), vec!["eng", "admins"]);
+
+        let unverified = json!({"sub": "abc", "email": "dev@example.com", "email_verified": false});
+        assert!(provider.user_info_from_claims(&unverified).is_err());
+        assert!(provider
+            .user_info_from_claims(&json!({"sub": "abc"}))
+            .is_err());
+    }
+
+    #[test]
+    fn test_authorize_url() {
+        let url = provider(None).authorize_url("xyz", "http://localhost:3000/callback");
+
+        assert!(url.starts_with("https://idp.example.com/authorize?"));
+        assert!(url.contains("response_type=code"));
+        assert!(url.contains("client_id=client"));
+        assert!(url.contains("state=xyz"));
+    }
+
+    #[tokio::test]
+    async fn test_rejects_hmac_id_token() {
+        use jsonwebtoken::{encode, EncodingKey, Header};
+
+        let token = encode(
+            &Header::default(),
+            &json!({"sub": "abc", "iss": "https://idp.example.com", "aud": "client", "exp": 4102444800i64}),
+            &EncodingKey::from_secret(b"secret"),
+        )
+        .unwrap();
+
+        assert!(provider(None)
+            .validate_id_token(&token, "nonce")
+            .await
+            .is_err());
+    }
+}
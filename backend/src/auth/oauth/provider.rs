@@ -1,5 +1,6 @@
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
+use url::Url;
 
 use crate::error::ApiResult;
 
@@ -10,6 +11,10 @@ pub struct OAuthUserInfo {
     pub email: String,
     pub username: Option<String>,
     pub avatar_url: Option<String>,
+    /// Groups from the provider's group claim; `None` when the provider sends none,
+    /// so group mappings leave the user alone
+    #[serde(default)]
+    pub groups: Option<Vec<String>>,
 }
 
 /// OAuth tokens returned after authorization
@@ -18,6 +23,8 @@ pub struct OAuthTokens {
     pub access_token: String,
     pub refresh_token: Option<String>,
     pub expires_in: Option<i64>,
+    /// OpenID Connect ID token, when the provider issues one
+    pub id_token: Option<String>,
 }
 
 /// Trait for OAuth provider implementations
@@ -30,13 +37,39 @@ pub trait OAuthProvider: Send + Sync {
     /// Generate the authorization URL to redirect users to
     fn authorize_url(&self, state: &str, redirect_uri: &str) -> String;
 
-    /// Exchange authorization code for access tokens
+    /// Authorization URL with a PKCE S256 challenge and an OpenID Connect nonce
+    fn authorize_url_with_pkce(
+        &self,
+        state: &str,
+        redirect_uri: &str,
+        code_challenge: &str,
+        nonce: &str,
+    ) -> String {
+        let mut url = Url::parse(&self.authorize_url(state, redirect_uri))
+            .expect("authorize_url returns a valid URL");
+        url.query_pairs_mut()
+            .append_pair("code_challenge", code_challenge)
+            .append_pair("code_challenge_method", "S256")
+            .append_pair("nonce", nonce);
+
+        url.to_string()
+    }
+
+    /// Exchange authorization code for access tokens, proving possession of the PKCE verifier
     async fn exchange_code(
         &self,
         code: &str,
         redirect_uri: &str,
+        code_verifier: &str,
     ) -> ApiResult<OAuthTokens>;
 
     /// Get user information from the provider using access token
     async fn get_user_info(&self, access_token: &str) -> ApiResult<OAuthUserInfo>;
+
+    /// Resolve the signed-in user from the exchanged tokens
+    /// OpenID Connect providers override this to use the verified ID token, which must
+    /// carry the login's `nonce`.
+    async fn user_info(&self, tokens: &OAuthTokens, _nonce: &str) -> ApiResult<OAuthUserInfo> {
+        self.get_user_info(&tokens.access_token).await
+    }
 }
@@ -0,0 +1,100 @@
+use dashmap::DashMap;
+use std::sync::Arc;
+
+use crate::{
+    config::AppConfig,
+    error::{ApiError, ApiResult},
+};
+
+use super::{GitHubOAuthProvider, GroupMappings, OAuthProvider, OidcConfig, OidcProvider};
+
+/// The OAuth providers configured for this gateway, looked up by name
+///
+/// OpenID Connect providers are discovered on first use and kept for the life of the
+/// process, so their discovery document and JWKS are fetched once rather than per login.
+pub struct OAuthRegistry {
+    github: Option<GitHubOAuthProvider>,
+    oidc: Vec<OidcConfig>,
+    discovered: DashMap<String, Arc<OidcProvider>>,
+    pub group_mappings: GroupMappings,
+}
+
+impl OAuthRegistry {
+    pub fn from_config(config: &AppConfig) -> ApiResult<Self> {
+        let github = match (&config.github_client_id, &config.github_client_secret) {
+            (Some(id), Some(secret)) => Some(GitHubOAuthProvider::new(id.clone(), secret.clone())),
+            _ => None,
+        };
+
+        let mut oidc = Vec::new();
+        if let (Some(id), Some(secret)) = (&config.google_client_id, &config.google_client_secret) {
+            oidc.push(OidcConfig::google(id.clone(), secret.clone()));
+        }
+        if let (Some(issuer_url), Some(id), Some(secret)) = (
+            &config.oidc_issuer_url,
+            &config.oidc_client_id,
+            &config.oidc_client_secret,
+        ) {
+            let name = config.oidc_provider_name.trim().to_lowercase();
+            if name == "github" || oidc.iter().any(|c: &OidcConfig| c.name == name) {
+                return Err(ApiError::BadRequest(format!(
+                    "OIDC_PROVIDER_NAME '{}' clashes with a built-in provider",
+                    name
+                )));
+            }
+            oidc.push(OidcConfig {
+                name,
+                issuer_url: issuer_url.clone(),
+                client_id: id.clone(),
+                client_secret: secret.clone(),
+                scopes: config.oidc_scopes.clone(),
+                email_claim: config.oidc_email_claim.clone(),
+                groups_claim: config.oidc_groups_claim.clone(),
+            });
+        }
+
+        let group_mappings = GroupMappings::parse(
+            config.oauth_group_roles.as_deref(),
+            config.oauth_group_teams.as_deref(),
+        )?;
+
+        Ok(Self {
+            github,
+            oidc,
+            discovered: DashMap::new(),
+            group_mappings,
+        })
+    }
+
+    /// Names of the configured providers
+    pub fn names(&self) -> Vec<&str> {
+        self.github
+            .iter()
+            .map(|_| "github")
+            .chain(self.oidc.iter().map(|c| c.name.as_str()))
+            .collect()
+    }
+
+    /// Get a provider by name, running OIDC discovery the first time it's used
+    pub async fn get(&self, name: &str) -> ApiResult<Arc<dyn OAuthProvider>> {
+        if name == "github" {
+            if let Some(github) = &self.github {
+                return Ok(Arc::new(github.clone()));
+            }
+        }
+
+        let config = self.oidc.iter().find(|c| c.name == name).ok_or_else(|| {
+            ApiError::BadRequest(format!("OAuth provider '{}' not configured", name))
+        })?;
+
+        if let Some(provider) = self.discovered.get(name) {
+            return Ok(provider.clone());
+        }
+
+        // Failed discovery isn't cached, so a provider outage at startup heals itself
+        let provider = Arc::new(OidcProvider::discover(config.clone()).await?);
+        self.discovered.insert(name.to_string(), provider.clone());
+
+        Ok(provider)
+    }
+}
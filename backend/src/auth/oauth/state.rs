@@ -0,0 +1,159 @@
+use axum::http::{header, HeaderMap};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+/// Cookie binding a pending OAuth login to the browser that started it
+pub const OAUTH_STATE_COOKIE: &str = "inferxgate_oauth_state";
+
+/// How long a user has to finish signing in at the provider
+pub const OAUTH_STATE_TTL_SECONDS: i64 = 600;
+
+/// How long the frontend has to exchange the login code of a finished OAuth login,
+/// including asking for a TOTP code
+pub const OAUTH_LOGIN_CODE_TTL_SECONDS: i64 = 300;
+
+/// Per-login secrets: `state` guards against CSRF, the PKCE verifier binds the
+/// authorization code to this login and the nonce binds the OIDC ID token to it
+pub struct OAuthFlow {
+    pub state: String,
+    pub code_verifier: String,
+    pub nonce: String,
+}
+
+impl OAuthFlow {
+    pub fn new() -> Self {
+        Self {
+            state: random_token(),
+            code_verifier: random_token(),
+            nonce: random_token(),
+        }
+    }
+
+    /// PKCE S256 challenge for the verifier
+    pub fn code_challenge(&self) -> String {
+        URL_SAFE_NO_PAD.encode(Sha256::digest(self.code_verifier.as_bytes()))
+    }
+}
+
+/// One-time code the frontend exchanges for a session after an OAuth login, so the
+/// session's tokens never appear in a URL
+pub fn generate_login_code() -> String {
+    random_token()
+}
+
+/// 256 random bits, base64url-encoded (43 characters, valid as a PKCE verifier)
+fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// States are stored hashed, since they travel in URLs and logs
+pub fn hash_state(state: &str) -> String {
+    hex::encode(Sha256::digest(state.as_bytes()))
+}
+
+fn signature(state: &str, secret: &str) -> Vec<u8> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(state.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Cookie value carrying the state and its HMAC
+pub fn sign_state(state: &str, secret: &str) -> String {
+    format!(
+        "{}.{}",
+        state,
+        URL_SAFE_NO_PAD.encode(signature(state, secret))
+    )
+}
+
+/// Check a signed cookie against the state returned by the provider
+pub fn verify_state_cookie(cookie: &str, state: &str, secret: &str) -> bool {
+    let Some((cookie_state, cookie_signature)) = cookie.rsplit_once('.') else {
+        return false;
+    };
+    let Ok(cookie_signature) = URL_SAFE_NO_PAD.decode(cookie_signature) else {
+        return false;
+    };
+
+    let signature_ok = cookie_signature.ct_eq(&signature(cookie_state, secret));
+    let state_ok = cookie_state.as_bytes().ct_eq(state.as_bytes());
+    (signature_ok & state_ok).into()
+}
+
+/// `Set-Cookie` value for the state cookie; an empty value with max-age 0 clears it
+/// SameSite=Lax so the cookie survives the top-level redirect back from the provider.
+pub fn state_cookie(value: &str, max_age_seconds: i64, secure: bool) -> String {
+    format!(
+        "{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax{}",
+        OAUTH_STATE_COOKIE,
+        value,
+        max_age_seconds,
+        if secure { "; Secure" } else { "" }
+    )
+}
+
+/// Read a cookie from the request headers
+pub fn cookie_value<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pkce_challenge() {
+        // BASE64URL(SHA256(verifier)), without padding
+        let flow = OAuthFlow {
+            state: String::new(),
+            code_verifier: "dBjftJeZ4CVP-mJ92K1qMp9JxE4cgM1aBAAkLumSgM4".to_string(),
+            nonce: String::new(),
+        };
+        assert_eq!(
+            flow.code_challenge(),
+            "Va-EOAtxPRlPwoo_iWCP2vTZkPObZ0kKXT0MySbGuiw"
+        );
+    }
+
+    #[test]
+    fn test_state_cookie_signature() {
+        let flow = OAuthFlow::new();
+        let cookie = sign_state(&flow.state, "secret");
+
+        assert!(verify_state_cookie(&cookie, &flow.state, "secret"));
+        assert!(!verify_state_cookie(&cookie, &flow.state, "other-secret"));
+        assert!(!verify_state_cookie(&cookie, "another-state", "secret"));
+        assert!(!verify_state_cookie(
+            &sign_state("another-state", "secret"),
+            &flow.state,
+            "secret"
+        ));
+        assert!(!verify_state_cookie("garbage", &flow.state, "secret"));
+    }
+
+    #[test]
+    fn test_cookie_value() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::COOKIE,
+            "theme=dark; inferxgate_oauth_state=abc.def"
+                .parse()
+                .unwrap(),
+        );
+        assert_eq!(cookie_value(&headers, OAUTH_STATE_COOKIE), Some("abc.def"));
+        assert_eq!(cookie_value(&headers, "missing"), None);
+    }
+}
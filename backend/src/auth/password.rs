@@ -1,27 +1,274 @@
-use bcrypt::{hash, verify, DEFAULT_COST};
+use argon2::{
+    password_hash::{self, PasswordHash, PasswordHasher as _, PasswordVerifier, SaltString},
+    Algorithm, Argon2, Params, Version,
+};
+use rand::rngs::OsRng;
+use reqwest::Client;
+use std::{collections::HashSet, time::Duration};
 
-use crate::error::{ApiError, ApiResult};
+use crate::{
+    config::AppConfig,
+    error::{ApiError, ApiResult},
+};
 
-/// Hash a password using bcrypt
-pub fn hash_password(password: &str) -> ApiResult<String> {
-    hash(password, DEFAULT_COST).map_err(|e| ApiError::InternalError(format!("Failed to hash password: {}", e)))
+/// Longest password accepted; Argon2 has no limit of its own
+pub const MAX_PASSWORD_LENGTH: usize = 1024;
+
+/// Have I Been Pwned's Pwned Passwords range API, queried by the first 5 hex of a SHA-1
+const PWNED_PASSWORDS_RANGE_URL: &str = "https://api.pwnedpasswords.com/range/";
+
+/// Common passwords from public breach corpora, one per line
+const BREACHED_PASSWORDS: &str = include_str!("breached_passwords.txt");
+
+lazy_static::lazy_static! {
+    static ref BREACHED_PASSWORD_SET: HashSet<&'static str> = BREACHED_PASSWORDS
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .collect();
+}
+
+/// Hashes passwords with Argon2id using the configured cost parameters
+#[derive(Debug, Clone)]
+pub struct PasswordHasher {
+    params: Params,
+}
+
+impl PasswordHasher {
+    pub fn from_config(config: &AppConfig) -> Result<Self, argon2::Error> {
+        Ok(Self {
+            params: Params::new(
+                config.password_hash_memory_kib,
+                config.password_hash_iterations,
+                config.password_hash_parallelism,
+                None,
+            )?,
+        })
+    }
+
+    fn argon2(&self) -> Argon2<'static> {
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, self.params.clone())
+    }
+
+    /// Hash a password into a PHC string (`$argon2id$v=19$m=...`)
+    pub fn hash(&self, password: &str) -> ApiResult<String> {
+        let salt = SaltString::generate(&mut OsRng);
+        self.argon2()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|e| ApiError::InternalError(format!("Failed to hash password: {}", e)))
+    }
+
+    /// Whether a stored hash should be replaced: it's bcrypt, or Argon2 with other parameters
+    pub fn needs_rehash(&self, hash: &str) -> bool {
+        let Ok(parsed) = PasswordHash::new(hash) else {
+            return true;
+        };
+        let current = parsed.algorithm == Algorithm::Argon2id.ident()
+            && parsed.version == Some(Version::V0x13.into())
+            && Params::try_from(&parsed).is_ok_and(|params| {
+                params.m_cost() == self.params.m_cost()
+                    && params.t_cost() == self.params.t_cost()
+                    && params.p_cost() == self.params.p_cost()
+            });
+        !current
+    }
 }
 
-/// Verify a password against a hash
+/// Verify a password against an Argon2 hash, or a bcrypt hash from before Argon2id
 pub fn verify_password(password: &str, hash: &str) -> ApiResult<bool> {
-    verify(password, hash).map_err(|e| ApiError::InternalError(format!("Failed to verify password: {}", e)))
+    if !hash.starts_with("$argon2") {
+        return bcrypt::verify(password, hash)
+            .map_err(|e| ApiError::InternalError(format!("Failed to verify password: {}", e)));
+    }
+
+    let parsed = PasswordHash::new(hash)
+        .map_err(|e| ApiError::InternalError(format!("Invalid password hash: {}", e)))?;
+    // The algorithm and its parameters come from the hash
+    match Argon2::default().verify_password(password.as_bytes(), &parsed) {
+        Ok(()) => Ok(true),
+        Err(password_hash::Error::Password) => Ok(false),
+        Err(e) => Err(ApiError::InternalError(format!(
+            "Failed to verify password: {}",
+            e
+        ))),
+    }
+}
+
+/// Rules new passwords must follow
+///
+/// The bundled breached list only holds a few hundred of the most common passwords, enough
+/// to catch the obvious ones offline. The real breach check is the optional Pwned Passwords
+/// lookup (`PASSWORD_CHECK_HIBP`): only the first 5 hex characters of the password's SHA-1
+/// leave the server (k-anonymity), and the password is allowed if the API can't be reached.
+#[derive(Debug, Clone)]
+pub struct PasswordPolicy {
+    pub min_length: usize,
+    /// Refuse passwords on the bundled list of common breached passwords
+    pub check_breached: bool,
+    /// Refuse passwords Have I Been Pwned has seen in a breach
+    pub check_hibp: bool,
+    http: Client,
+}
+
+impl PasswordPolicy {
+    pub fn from_config(config: &AppConfig) -> Self {
+        Self {
+            min_length: config.password_min_length,
+            check_breached: config.password_check_breached,
+            check_hibp: config.password_check_hibp,
+            http: Client::builder()
+                .timeout(Duration::from_secs(5))
+                .build()
+                .expect("Failed to create HTTP client"),
+        }
+    }
+
+    /// Fails with `BadRequest` saying why the password isn't allowed for this account
+    pub fn check(&self, password: &str, email: &str) -> ApiResult<()> {
+        let length = password.chars().count();
+        if length < self.min_length {
+            return Err(ApiError::BadRequest(format!(
+                "Password must be at least {} characters long",
+                self.min_length
+            )));
+        }
+        if length > MAX_PASSWORD_LENGTH {
+            return Err(ApiError::BadRequest(format!(
+                "Password must be at most {} characters long",
+                MAX_PASSWORD_LENGTH
+            )));
+        }
+
+        let lowercase = password.to_lowercase();
+        let email = email.to_lowercase();
+        let local_part = email.split('@').next().unwrap_or(&email);
+        if lowercase == email || lowercase == local_part {
+            return Err(ApiError::BadRequest(
+                "Password must not be your email address".to_string(),
+            ));
+        }
+        if self.check_breached && BREACHED_PASSWORD_SET.contains(lowercase.as_str()) {
+            return Err(ApiError::BadRequest(
+                "This password is known from data breaches; choose another".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Fails with `BadRequest` if Have I Been Pwned knows the password, when enabled
+    pub async fn check_pwned(&self, password: &str) -> ApiResult<()> {
+        if !self.check_hibp {
+            return Ok(());
+        }
+
+        let digest = ring::digest::digest(
+            &ring::digest::SHA1_FOR_LEGACY_USE_ONLY,
+            password.as_bytes(),
+        );
+        let hash = hex::encode_upper(digest.as_ref());
+        let (prefix, suffix) = hash.split_at(5);
+
+        // Padding hides the real number of matches from anyone watching the response size
+        let response = self
+            .http
+            .get(format!("{}{}", PWNED_PASSWORDS_RANGE_URL, prefix))
+            .header("Add-Padding", "true")
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        let body = match response {
+            Ok(response) => response.text().await,
+            Err(e) => Err(e),
+        };
+        let body = match body {
+            Ok(body) => body,
+            Err(e) => {
+                tracing::warn!("Pwned Passwords lookup failed, allowing the password: {}", e);
+                return Ok(());
+            }
+        };
+
+        if pwned_count(&body, suffix) > 0 {
+            return Err(ApiError::BadRequest(
+                "This password is known from data breaches; choose another".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Breach count for a SHA-1 suffix in a Pwned Passwords range response (`SUFFIX:COUNT` lines)
+fn pwned_count(body: &str, suffix: &str) -> u64 {
+    body.lines()
+        .filter_map(|line| line.trim().split_once(':'))
+        .find(|(candidate, _)| candidate.eq_ignore_ascii_case(suffix))
+        .and_then(|(_, count)| count.trim().parse().ok())
+        .unwrap_or(0)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn hasher() -> PasswordHasher {
+        // Cheap parameters keep the tests fast
+        PasswordHasher {
+            params: Params::new(1024, 1, 1, None).unwrap(),
+        }
+    }
+
     #[test]
     fn test_hash_and_verify() {
         let password = "test_password_123";
-        let hash = hash_password(password).unwrap();
+        let hash = hasher().hash(password).unwrap();
 
+        assert!(hash.starts_with("$argon2id$"));
         assert!(verify_password(password, &hash).unwrap());
         assert!(!verify_password("wrong_password", &hash).unwrap());
+        assert!(!hasher().needs_rehash(&hash));
+    }
+
+    #[test]
+    fn test_bcrypt_hashes_verify_and_need_rehash() {
+        let hash = bcrypt::hash("test_password_123", 4).unwrap();
+
+        assert!(verify_password("test_password_123", &hash).unwrap());
+        assert!(!verify_password("wrong_password", &hash).unwrap());
+        assert!(hasher().needs_rehash(&hash));
+
+        let stronger = PasswordHasher {
+            params: Params::new(2048, 1, 1, None).unwrap(),
+        };
+        assert!(stronger.needs_rehash(&hasher().hash("test_password_123").unwrap()));
+    }
+
+    #[test]
+    fn test_policy() {
+        let policy = PasswordPolicy {
+            min_length: 8,
+            check_breached: true,
+            check_hibp: false,
+            http: Client::new(),
+        };
+
+        assert!(policy
+            .check("correct horse battery", "ada@example.com")
+            .is_ok());
+        assert!(policy.check("short", "ada@example.com").is_err());
+        assert!(policy.check("Password123", "ada@example.com").is_err());
+        assert!(policy.check("ada@example.com", "Ada@Example.com").is_err());
+    }
+
+    #[test]
+    fn test_pwned_count() {
+        let body = "0018A45C4D1DEF81644B54AB7F969B88D65:1\r\n\
+                    00D4F6E8FA6EECAD2A3AA415EEC418D38EC:2\r\n\
+                    011053FD0102E94D6AE2F8B83D76FAF94F6:0\r\n";
+        assert_eq!(pwned_count(body, "00D4F6E8FA6EECAD2A3AA415EEC418D38EC"), 2);
+        assert_eq!(pwned_count(body, "00d4f6e8fa6eecad2a3aa415eec418d38ec"), 2);
+        // Padding entries have a count of 0
+        assert_eq!(pwned_count(body, "011053FD0102E94D6AE2F8B83D76FAF94F6"), 0);
+        assert_eq!(pwned_count(body, "FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF"), 0);
     }
 }
@@ -0,0 +1,128 @@
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+use crate::{
+    error::{ApiError, ApiResult},
+    models::{
+        referenced_groups, ModelAccessGroup, ScopeLimits, ScopedLimits, Team, User, VirtualKey,
+    },
+};
+
+use super::{AuthType, AuthUser};
+
+/// Everything a key-authenticated request needs: the key, its user and team, and the
+/// limits that apply above it
+///
+/// Resolved once by `require_auth`, cached in [`super::KeyCache`] and handed to later
+/// middleware and handlers through request extensions, so a request doesn't look any of
+/// it up again.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Principal {
+    pub key: VirtualKey,
+    pub user: Option<PrincipalUser>,
+    pub team: Option<Team>,
+    /// Team, user and global limits, in that order
+    pub scoped_limits: Vec<ScopedLimits>,
+    /// Models of the access groups the key's and team's model entries refer to
+    #[serde(default)]
+    pub model_groups: HashMap<String, Vec<String>>,
+}
+
+/// The parts of the key's owner needed to authorize a request
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PrincipalUser {
+    pub id: Uuid,
+    pub email: String,
+    pub role: String,
+}
+
+impl Principal {
+    /// Look up the key's user, team and scope limits concurrently
+    /// Fails with `AuthenticationFailed` if the key's user no longer exists or is disabled.
+    pub async fn resolve(pool: &Pool<Postgres>, key: VirtualKey) -> ApiResult<Self> {
+        let user = async {
+            match key.user_id {
+                Some(user_id) => User::find_by_id(pool, user_id)
+                    .await?
+                    .filter(|user| user.disabled_at.is_none())
+                    .map(|user| PrincipalUser {
+                        id: user.id,
+                        email: user.email,
+                        role: user.role,
+                    })
+                    .map(Some)
+                    .ok_or(ApiError::AuthenticationFailed),
+                None => Ok(None),
+            }
+        };
+        let team = async {
+            match key.team_id {
+                Some(team_id) => Team::find_by_id(pool, team_id).await,
+                None => Ok(None),
+            }
+        };
+        let scoped_limits = ScopeLimits::applicable(pool, key.user_id, key.team_id);
+
+        let (user, team, scoped_limits) = tokio::try_join!(user, team, scoped_limits)?;
+
+        let group_names = referenced_groups(
+            [&key.allowed_models, &key.denied_models]
+                .into_iter()
+                .chain(team.as_ref().map(|team| &team.allowed_models))
+                .flatten()
+                .flatten(),
+        );
+        let model_groups = ModelAccessGroup::models_by_name(pool, &group_names).await?;
+
+        Ok(Self {
+            key,
+            user,
+            team,
+            scoped_limits,
+            model_groups,
+        })
+    }
+
+    /// Why the key (or its team) may not use a model served by `provider`, if it may not
+    pub fn model_denial(&self, model: &str, provider: Option<&str>) -> Option<String> {
+        if !self
+            .key
+            .can_access_model(model, provider, &self.model_groups)
+        {
+            return Some(format!("Model '{}' is not allowed for this key", model));
+        }
+        self.team
+            .as_ref()
+            .filter(|team| !team.can_access_model(model, provider, &self.model_groups))
+            .map(|team| format!("Model '{}' is not allowed for team '{}'", model, team.name))
+    }
+
+    /// Whether the key's or its team's model entries restrict which models it may use
+    pub fn restricts_models(&self) -> bool {
+        self.key.allowed_models.is_some()
+            || self.key.denied_models.is_some()
+            || self
+                .team
+                .as_ref()
+                .is_some_and(|team| team.allowed_models.is_some())
+    }
+
+    /// The identity handlers authorize against; keys without a user act as the system
+    pub fn auth_user(&self) -> AuthUser {
+        let (user_id, email, role) = match &self.user {
+            Some(user) => (user.id, user.email.clone(), user.role.clone()),
+            None => (Uuid::nil(), "system".to_string(), "system".to_string()),
+        };
+
+        AuthUser {
+            user_id,
+            email,
+            role,
+            auth_type: AuthType::VirtualKey {
+                key_id: self.key.id,
+            },
+        }
+    }
+}
@@ -0,0 +1,469 @@
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{request::Parts, StatusCode},
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use std::fmt;
+use std::marker::PhantomData;
+
+use crate::{
+    auth::{AuthType, AuthUser},
+    error::{ApiError, ApiResult},
+    models::{KeyUpdate, Team, VirtualKey},
+};
+
+/// Gateway-wide role of a user
+///
+/// - `admin`: everything, including providers, limits, teams, user roles and the audit log
+/// - `key-manager`: view and manage every virtual key, view gateway stats
+/// - `viewer`: read-only access to every key and to gateway stats
+/// - `user`: only their own keys and usage (plus their teams')
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Role {
+    Admin,
+    KeyManager,
+    Viewer,
+    User,
+}
+
+/// Something a role may be allowed to do beyond its own resources
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    /// Read any virtual key, not just owned ones
+    ViewAllKeys,
+    /// Update, delete and move any virtual key
+    ManageAllKeys,
+    /// Gateway-wide usage stats rather than the caller's own
+    ViewStats,
+    /// Configure and delete provider API keys, and define model access groups
+    ManageProviders,
+    /// Set user and global rate limits and budgets
+    ManageLimits,
+    /// See and manage every team, and set team budgets
+    ManageTeams,
+    /// Change user roles
+    ManageUsers,
+    /// Read and export the audit log
+    ViewAuditLog,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Admin => "admin",
+            Role::KeyManager => "key-manager",
+            Role::Viewer => "viewer",
+            Role::User => "user",
+        }
+    }
+
+    pub fn parse(role: &str) -> ApiResult<Self> {
+        match role {
+            "admin" => Ok(Role::Admin),
+            "key-manager" => Ok(Role::KeyManager),
+            "viewer" => Ok(Role::Viewer),
+            "user" => Ok(Role::User),
+            _ => Err(ApiError::BadRequest(format!(
+                "Invalid role '{}': expected admin, key-manager, viewer or user",
+                role
+            ))),
+        }
+    }
+
+    /// Role of a stored `users.role` value; anything unrecognised gets the least privilege
+    pub fn from_stored(role: &str) -> Self {
+        Self::parse(role).unwrap_or(Role::User)
+    }
+
+    pub fn has(&self, permission: Permission) -> bool {
+        match self {
+            Role::Admin => true,
+            Role::KeyManager => matches!(
+                permission,
+                Permission::ViewAllKeys | Permission::ManageAllKeys | Permission::ViewStats
+            ),
+            Role::Viewer => matches!(permission, Permission::ViewAllKeys | Permission::ViewStats),
+            Role::User => false,
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl AuthUser {
+    /// Effective role: the master key acts as an admin
+    pub fn role(&self) -> Role {
+        match self.auth_type {
+            AuthType::MasterKey => Role::Admin,
+            _ => Role::from_stored(&self.role),
+        }
+    }
+
+    pub fn has(&self, permission: Permission) -> bool {
+        self.role().has(permission)
+    }
+
+    pub fn require(&self, permission: Permission) -> ApiResult<()> {
+        if self.has(permission) {
+            Ok(())
+        } else {
+            Err(ApiError::Forbidden)
+        }
+    }
+}
+
+/// A permission checked by the `Authorized` extractor
+pub trait RequiredPermission {
+    const PERMISSION: Permission;
+}
+
+/// Permission markers for `Authorized`, e.g. `Authorized<require::ManageProviders>`
+pub mod require {
+    use super::{Permission, RequiredPermission};
+
+    macro_rules! permission_markers {
+        ($($name:ident),* $(,)?) => {
+            $(
+                // Only ever used as a type parameter
+                #[allow(dead_code)]
+                pub struct $name;
+
+                impl RequiredPermission for $name {
+                    const PERMISSION: Permission = Permission::$name;
+                }
+            )*
+        };
+    }
+
+    permission_markers!(
+        ViewAllKeys,
+        ManageAllKeys,
+        ViewStats,
+        ManageProviders,
+        ManageLimits,
+        ManageTeams,
+        ManageUsers,
+        ViewAuditLog,
+    );
+}
+
+/// Extractor for handlers that need a permission: the request is rejected with 403
+/// before the handler runs if the caller's role lacks it
+pub struct Authorized<P: RequiredPermission> {
+    pub user: AuthUser,
+    _permission: PhantomData<P>,
+}
+
+#[async_trait]
+impl<S, P> FromRequestParts<S> for Authorized<P>
+where
+    S: Send + Sync,
+    P: RequiredPermission,
+{
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let user = AuthUser::from_request_parts(parts, state).await?;
+
+        if !user.has(P::PERMISSION) {
+            return Err((
+                StatusCode::FORBIDDEN,
+                format!(
+                    "Role '{}' is missing the {:?} permission",
+                    user.role(),
+                    P::PERMISSION
+                ),
+            ));
+        }
+
+        Ok(Self {
+            user,
+            _permission: PhantomData,
+        })
+    }
+}
+
+/// What a caller wants to do with a virtual key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyAccess {
+    View,
+    Manage,
+}
+
+/// Check the caller may view or manage a key: its owner, a member (view) or owner/admin
+/// (manage) of the team that owns it, or a role with access to every key
+pub async fn authorize_key(
+    pool: &Pool<Postgres>,
+    auth_user: &AuthUser,
+    key: &VirtualKey,
+    access: KeyAccess,
+) -> ApiResult<()> {
+    let permission = match access {
+        KeyAccess::View => Permission::ViewAllKeys,
+        KeyAccess::Manage => Permission::ManageAllKeys,
+    };
+    if auth_user.has(permission) || key.user_id == Some(auth_user.user_id) {
+        return Ok(());
+    }
+
+    if let Some(team_id) = key.team_id {
+        let team_role = Team::member_role(pool, team_id, auth_user.user_id).await?;
+        let allowed = match access {
+            KeyAccess::View => team_role.is_some(),
+            KeyAccess::Manage => team_role.is_some_and(|role| role.can_manage()),
+        };
+        if allowed {
+            return Ok(());
+        }
+    }
+
+    Err(ApiError::Forbidden)
+}
+
+/// Whether the caller may loosen a key's limits (see [`loosens_key_limits`]): roles that
+/// manage every key, and owners/admins of the team that owns it, whose spend the team
+/// budget already caps
+///
+/// Owners of personal keys may only tighten them, so limits an admin set stay in place;
+/// the user and global limits are the ceiling for keys they create.
+pub async fn can_loosen_key_limits(
+    pool: &Pool<Postgres>,
+    auth_user: &AuthUser,
+    key: &VirtualKey,
+) -> ApiResult<bool> {
+    if auth_user.has(Permission::ManageAllKeys) {
+        return Ok(true);
+    }
+    Ok(match key.team_id {
+        Some(team_id) => Team::member_role(pool, team_id, auth_user.user_id)
+            .await?
+            .is_some_and(|role| role.can_manage()),
+        None => false,
+    })
+}
+
+/// Whether an update would widen what a key may do: raise its budget or rate limits,
+/// unblock it, restart its budget period, replace its per-model limits, allow more models,
+/// deny fewer, lift a route/IP/origin restriction or make it writable again
+pub fn loosens_key_limits(key: &VirtualKey, update: &KeyUpdate) -> bool {
+    fn raises<T: PartialOrd>(current: Option<T>, new: Option<T>) -> bool {
+        matches!((current, new), (Some(current), Some(new)) if new > current)
+    }
+
+    // An allow list loosens when it gains an entry; for restrictions an empty list lifts it
+    fn widens(current: &Option<Vec<String>>, new: &Option<Vec<String>>, empty_lifts: bool) -> bool {
+        match (current, new) {
+            (Some(current), Some(new)) => {
+                (empty_lifts && new.is_empty()) || new.iter().any(|entry| !current.contains(entry))
+            }
+            _ => false,
+        }
+    }
+
+    // A deny list loosens when it loses an entry
+    let denies_less = match (&key.denied_models, &update.denied_models) {
+        (Some(current), Some(new)) => current.iter().any(|entry| !new.contains(entry)),
+        _ => false,
+    };
+
+    let restrictions = &key.restrictions;
+    let new_restrictions = &update.restrictions;
+
+    raises(key.max_budget, update.max_budget)
+        || raises(key.rate_limit_rpm, update.rate_limit_rpm)
+        || raises(key.rate_limit_tpm, update.rate_limit_tpm)
+        || raises(key.max_parallel_requests, update.max_parallel_requests)
+        || (key.blocked && update.blocked == Some(false))
+        || (key.max_budget.is_some()
+            && update
+                .budget_period
+                .as_deref()
+                .is_some_and(|period| !period.is_empty()))
+        || (!key.model_limits.is_empty() && update.model_limits.is_some())
+        || widens(&key.allowed_models, &update.allowed_models, false)
+        || denies_less
+        || widens(
+            &restrictions.allowed_routes,
+            &new_restrictions.allowed_routes,
+            true,
+        )
+        || widens(
+            &restrictions.allowed_ips,
+            &new_restrictions.allowed_ips,
+            true,
+        )
+        || widens(
+            &restrictions.allowed_origins,
+            &new_restrictions.allowed_origins,
+            true,
+        )
+        || (restrictions.read_only && new_restrictions.read_only == Some(false))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::UpdateKeyRestrictions;
+
+    fn key(fields: serde_json::Value) -> VirtualKey {
+        let mut value = serde_json::json!({
+            "id": uuid::Uuid::nil(),
+            "key_hash": "hash",
+            "key_prefix": "sk-test",
+            "current_spend": 0.0,
+            "blocked": false,
+            "created_at": "2025-01-01T00:00:00Z",
+        });
+        value
+            .as_object_mut()
+            .unwrap()
+            .extend(fields.as_object().unwrap().clone());
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn strings(values: &[&str]) -> Option<Vec<String>> {
+        Some(values.iter().map(|value| value.to_string()).collect())
+    }
+
+    #[test]
+    fn test_loosening_limits() {
+        let limited = key(serde_json::json!({ "max_budget": 10.0, "rate_limit_rpm": 60 }));
+        assert!(loosens_key_limits(
+            &limited,
+            &KeyUpdate {
+                max_budget: Some(20.0),
+                ..Default::default()
+            }
+        ));
+        assert!(!loosens_key_limits(
+            &limited,
+            &KeyUpdate {
+                max_budget: Some(5.0),
+                rate_limit_rpm: Some(30),
+                ..Default::default()
+            }
+        ));
+        assert!(loosens_key_limits(
+            &limited,
+            &KeyUpdate {
+                budget_period: Some("daily".to_string()),
+                ..Default::default()
+            }
+        ));
+    }
+
+    #[test]
+    fn test_widening_allowed_models() {
+        let restricted = key(serde_json::json!({ "allowed_models": ["gpt-4o"] }));
+        let allow = |models: &[&str]| KeyUpdate {
+            allowed_models: strings(models),
+            ..Default::default()
+        };
+        assert!(loosens_key_limits(
+            &restricted,
+            &allow(&["gpt-4o", "gpt-4*"])
+        ));
+        assert!(!loosens_key_limits(&restricted, &allow(&["gpt-4o"])));
+        assert!(!loosens_key_limits(&restricted, &allow(&[])));
+        // Restricting an unrestricted key tightens it
+        assert!(!loosens_key_limits(
+            &key(serde_json::json!({})),
+            &allow(&["gpt-4o"])
+        ));
+    }
+
+    #[test]
+    fn test_shrinking_denied_models() {
+        let denying = key(serde_json::json!({ "denied_models": ["gpt-4o", "o1*"] }));
+        let deny = |models: &[&str]| KeyUpdate {
+            denied_models: strings(models),
+            ..Default::default()
+        };
+        assert!(loosens_key_limits(&denying, &deny(&[])));
+        assert!(loosens_key_limits(&denying, &deny(&["gpt-4o"])));
+        assert!(!loosens_key_limits(
+            &denying,
+            &deny(&["gpt-4o", "o1*", "o3"])
+        ));
+    }
+
+    #[test]
+    fn test_lifting_key_restrictions() {
+        let restricted = key(serde_json::json!({
+            "allowed_routes": ["/v1/chat/completions"],
+            "allowed_ips": ["10.0.0.0/8"],
+            "allowed_origins": ["https://example.com"],
+            "read_only": true,
+        }));
+        let restrict = |restrictions: UpdateKeyRestrictions| KeyUpdate {
+            restrictions,
+            ..Default::default()
+        };
+
+        for restrictions in [
+            UpdateKeyRestrictions {
+                allowed_routes: strings(&[]),
+                ..Default::default()
+            },
+            UpdateKeyRestrictions {
+                allowed_routes: strings(&["/v1/*"]),
+                ..Default::default()
+            },
+            UpdateKeyRestrictions {
+                allowed_ips: strings(&[]),
+                ..Default::default()
+            },
+            UpdateKeyRestrictions {
+                allowed_origins: strings(&[]),
+                ..Default::default()
+            },
+            UpdateKeyRestrictions {
+                read_only: Some(false),
+                ..Default::default()
+            },
+        ] {
+            assert!(loosens_key_limits(&restricted, &restrict(restrictions)));
+        }
+
+        assert!(!loosens_key_limits(
+            &restricted,
+            &restrict(UpdateKeyRestrictions {
+                allowed_ips: strings(&["10.0.0.0/8"]),
+                read_only: Some(true),
+                ..Default::default()
+            })
+        ));
+    }
+
+    #[test]
+    fn test_role_permissions() {
+        assert!(Role::Admin.has(Permission::ManageProviders));
+        assert!(Role::KeyManager.has(Permission::ManageAllKeys));
+        assert!(!Role::KeyManager.has(Permission::ManageProviders));
+        assert!(Role::Viewer.has(Permission::ViewAllKeys));
+        assert!(!Role::Viewer.has(Permission::ManageAllKeys));
+        assert!(!Role::User.has(Permission::ViewStats));
+    }
+
+    #[test]
+    fn test_role_parsing() {
+        assert_eq!(Role::parse("key-manager").unwrap(), Role::KeyManager);
+        assert!(Role::parse("superuser").is_err());
+        assert_eq!(Role::from_stored("system"), Role::User);
+
+        let master = AuthUser {
+            user_id: uuid::Uuid::nil(),
+            email: "admin".to_string(),
+            role: "user".to_string(),
+            auth_type: AuthType::MasterKey,
+        };
+        assert_eq!(master.role(), Role::Admin);
+    }
+}
@@ -0,0 +1,30 @@
+use base64::{engine::general_purpose, Engine as _};
+use rand::Rng;
+use sqlx::{Pool, Postgres};
+use tracing::{error, info};
+
+use crate::models::Session;
+
+/// Generate a refresh token: 256 random bits, stored only as its `hash_token` hash
+pub fn generate_refresh_token() -> String {
+    let random_bytes: Vec<u8> = (0..32).map(|_| rand::thread_rng().gen()).collect();
+    format!(
+        "rt-{}",
+        general_purpose::URL_SAFE_NO_PAD.encode(&random_bytes)
+    )
+}
+
+/// Spawn the background job that deletes expired and revoked sessions
+pub fn spawn_session_cleanup_job(pool: Pool<Postgres>, interval_seconds: u64) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(interval_seconds));
+        loop {
+            interval.tick().await;
+            match Session::cleanup_expired(&pool).await {
+                Ok(0) => {}
+                Ok(count) => info!("🧹 Deleted {} expired or revoked sessions", count),
+                Err(e) => error!("Session cleanup job failed: {}", e),
+            }
+        }
+    });
+}
@@ -4,6 +4,9 @@
 /// Since we don't store plaintext keys (only bcrypt hashes), we cannot
 /// backfill lookup_hash for existing keys. Users must regenerate their keys.
 ///
+/// Keys hashed with HMAC have no lookup_hash and don't need one; other bcrypt keys are
+/// upgraded to HMAC on their first use.
+///
 /// Usage:
 ///   cargo run --bin backfill_key_hashes
 use sqlx::postgres::PgPoolOptions;
@@ -24,10 +27,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("✅ Connected to database");
 
     // Count keys without lookup_hash
-    let count: (i64,) =
-        sqlx::query_as("SELECT COUNT(*) FROM virtual_keys WHERE key_lookup_hash IS NULL")
-            .fetch_one(&pool)
-            .await?;
+    let count: (i64,) = sqlx::query_as(
+        "SELECT COUNT(*) FROM virtual_keys WHERE key_lookup_hash IS NULL AND key_hash LIKE '$2%'",
+    )
+    .fetch_one(&pool)
+    .await?;
 
     let keys_needing_regeneration = count.0;
 
@@ -56,7 +60,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             key_prefix,
             user_id::text
         FROM virtual_keys
-        WHERE key_lookup_hash IS NULL
+        WHERE key_lookup_hash IS NULL AND key_hash LIKE '$2%'
         ORDER BY created_at DESC
         "#,
     )
@@ -74,10 +78,9 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     }
 
     println!("\n🔄 Action Required:");
-    println!("   These keys need to be regenerated to benefit from the new");
-    println!("   O(1) lookup performance. The old keys will continue to work");
-    println!("   but will use the slower fallback authentication method.");
-    println!("\n   To regenerate:");
+    println!("   These keys can no longer be authenticated and need a new secret.");
+    println!("\n   To keep their limits and spend: POST /auth/key/rotate {{\"key_id\": ...}}");
+    println!("\n   Or regenerate:");
     println!("   1. Create new keys via the API: POST /auth/key/generate");
     println!("   2. Update your applications with the new keys");
     println!("   3. Delete the old keys via: DELETE /auth/key/{{key_id}}");
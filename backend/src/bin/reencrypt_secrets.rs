@@ -0,0 +1,160 @@
+/// Re-encrypt secrets stored in the database under the current master key
+///
+/// Covers provider API keys, OAuth tokens and TOTP secrets. Rows written in plaintext before
+/// ENCRYPTION_KEY was configured are encrypted too.
+///
+/// To rotate the master key: set ENCRYPTION_KEY to the new key and move the old one
+/// to ENCRYPTION_PREVIOUS_KEYS, run this tool, then remove the old key.
+///
+/// Usage:
+///   cargo run --bin reencrypt_secrets [-- --dry-run]
+#[path = "../crypto.rs"]
+mod crypto;
+
+use crypto::Encryptor;
+use sqlx::postgres::PgPoolOptions;
+use std::env;
+
+/// id, provider, provider_user_id, access token, refresh token
+type OAuthTokenRow = (uuid::Uuid, String, String, Option<String>, Option<String>);
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    dotenv::dotenv().ok();
+
+    let dry_run = env::args().any(|arg| arg == "--dry-run");
+    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+
+    let encryptor = Encryptor::from_settings(
+        env::var("ENCRYPTION_KEY").ok().as_deref(),
+        env::var("ENCRYPTION_KEY_FILE").ok().as_deref(),
+        env::var("ENCRYPTION_PREVIOUS_KEYS").ok().as_deref(),
+    )?;
+    let Some(key_id) = encryptor.current_key_id() else {
+        eprintln!("❌ ENCRYPTION_KEY or ENCRYPTION_KEY_FILE must be set");
+        std::process::exit(1);
+    };
+    println!("🔑 Current encryption key: {}", key_id);
+
+    println!("🔗 Connecting to database...");
+    let pool = PgPoolOptions::new()
+        .max_connections(5)
+        .connect(&database_url)
+        .await?;
+    println!("✅ Connected to database");
+
+    // Everything is rewritten in one transaction: either all rows move to the new
+    // key or none do
+    let mut tx = pool.begin().await?;
+
+    // Contexts must match provider_key_context in database.rs
+    let provider_keys: Vec<(String, String)> = sqlx::query_as(
+        "SELECT provider_id, api_key_encrypted FROM provider_keys ORDER BY provider_id FOR UPDATE",
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    let mut provider_keys_updated = 0;
+    for (provider_id, stored) in provider_keys {
+        if !encryptor.needs_reencryption(&stored) {
+            continue;
+        }
+        let context = format!("provider_keys:{}", provider_id);
+        let api_key = encryptor
+            .decrypt(&stored, &context)
+            .map_err(|e| format!("provider key {}: {}", provider_id, e))?;
+
+        sqlx::query("UPDATE provider_keys SET api_key_encrypted = $1 WHERE provider_id = $2")
+            .bind(encryptor.encrypt(&api_key, &context)?)
+            .bind(&provider_id)
+            .execute(&mut *tx)
+            .await?;
+        provider_keys_updated += 1;
+    }
+
+    // Contexts must match OAuthAccount::token_context in models/user.rs
+    let accounts: Vec<OAuthTokenRow> = sqlx::query_as(
+        r#"
+            SELECT id, provider, provider_user_id, access_token_encrypted, refresh_token_encrypted
+            FROM oauth_accounts
+            FOR UPDATE
+            "#,
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    let mut oauth_accounts_updated = 0;
+    for (id, provider, provider_user_id, access_token, refresh_token) in accounts {
+        let stale = [&access_token, &refresh_token]
+            .into_iter()
+            .flatten()
+            .any(|stored| encryptor.needs_reencryption(stored));
+        if !stale {
+            continue;
+        }
+
+        let reencrypt = |stored: Option<String>, kind: &str| -> Result<Option<String>, String> {
+            let context = format!("oauth_accounts:{}:{}:{}", provider, provider_user_id, kind);
+            stored
+                .map(|stored| {
+                    let token = encryptor.decrypt(&stored, &context)?;
+                    encryptor.encrypt(&token, &context)
+                })
+                .transpose()
+                .map_err(|e| format!("OAuth account {}: {}", id, e))
+        };
+
+        sqlx::query(
+            r#"
+            UPDATE oauth_accounts
+            SET access_token_encrypted = $1, refresh_token_encrypted = $2
+            WHERE id = $3
+            "#,
+        )
+        .bind(reencrypt(access_token, "access_token")?)
+        .bind(reencrypt(refresh_token, "refresh_token")?)
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+        oauth_accounts_updated += 1;
+    }
+
+    // Contexts must match UserTotp::secret_context in models/totp.rs
+    let totp_secrets: Vec<(uuid::Uuid, String)> =
+        sqlx::query_as("SELECT user_id, secret_encrypted FROM user_totp FOR UPDATE")
+            .fetch_all(&mut *tx)
+            .await?;
+
+    let mut totp_secrets_updated = 0;
+    for (user_id, stored) in totp_secrets {
+        if !encryptor.needs_reencryption(&stored) {
+            continue;
+        }
+        let context = format!("user_totp:{}", user_id);
+        let secret = encryptor
+            .decrypt(&stored, &context)
+            .map_err(|e| format!("TOTP secret of user {}: {}", user_id, e))?;
+
+        sqlx::query("UPDATE user_totp SET secret_encrypted = $1 WHERE user_id = $2")
+            .bind(encryptor.encrypt(&secret, &context)?)
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+        totp_secrets_updated += 1;
+    }
+
+    println!("\n📋 Provider keys re-encrypted: {}", provider_keys_updated);
+    println!("📋 OAuth accounts re-encrypted: {}", oauth_accounts_updated);
+    println!("📋 TOTP secrets re-encrypted: {}", totp_secrets_updated);
+
+    if dry_run {
+        tx.rollback().await?;
+        println!("\n💡 Dry run: no changes were saved");
+    } else {
+        tx.commit().await?;
+        println!("\n✅ All secrets are encrypted with key {}", key_id);
+        println!("   Previous keys can now be removed from ENCRYPTION_PREVIOUS_KEYS");
+    }
+
+    Ok(())
+}
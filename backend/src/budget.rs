@@ -0,0 +1,282 @@
+use chrono::{DateTime, Duration, Months, Utc};
+use sqlx::{Pool, Postgres};
+use std::fmt;
+use tracing::{debug, error, info, warn};
+
+use crate::auth::KeyCache;
+use crate::error::{ApiError, ApiResult};
+use crate::models::VirtualKey;
+
+/// Budget period for a virtual key
+/// Stored as "daily", "weekly", "monthly" or a custom duration such as "30d" or "12h"
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BudgetPeriod {
+    Daily,
+    Weekly,
+    Monthly,
+    Custom(Duration),
+}
+
+impl BudgetPeriod {
+    /// Parse a budget period from its stored representation
+    pub fn parse(value: &str) -> ApiResult<Self> {
+        let value = value.trim().to_lowercase();
+        match value.as_str() {
+            "daily" => return Ok(Self::Daily),
+            "weekly" => return Ok(Self::Weekly),
+            "monthly" => return Ok(Self::Monthly),
+            _ => {}
+        }
+
+        let invalid = || {
+            ApiError::BadRequest(format!(
+                "Invalid budget period '{}': use daily, weekly, monthly or a duration like 30d, 12h, 90m",
+                value
+            ))
+        };
+
+        if value.len() < 2 {
+            return Err(invalid());
+        }
+        let (amount, unit) = value.split_at(value.len() - 1);
+        let amount: i64 = amount.parse().map_err(|_| invalid())?;
+        if amount <= 0 {
+            return Err(invalid());
+        }
+
+        let duration = match unit {
+            "s" => Duration::try_seconds(amount),
+            "m" => Duration::try_minutes(amount),
+            "h" => Duration::try_hours(amount),
+            "d" => Duration::try_days(amount),
+            _ => None,
+        }
+        .ok_or_else(invalid)?;
+
+        // Anything shorter than a minute would just churn the reset job
+        if duration < Duration::minutes(1) {
+            return Err(invalid());
+        }
+
+        Ok(Self::Custom(duration))
+    }
+
+    /// Parse the budget period of a key update, where "none" removes the period
+    pub fn parse_update(value: &str) -> ApiResult<Option<Self>> {
+        if value.trim().eq_ignore_ascii_case("none") {
+            return Ok(None);
+        }
+        Self::parse(value).map(Some)
+    }
+
+    /// Length of a period; months are approximated as 30 days
+    fn length(&self) -> Duration {
+        match self {
+            Self::Daily => Duration::days(1),
+            Self::Weekly => Duration::weeks(1),
+            Self::Monthly => Duration::days(30),
+            Self::Custom(duration) => *duration,
+        }
+    }
+
+    /// The `n`th period boundary counted from `anchor`, the start of the first period
+    ///
+    /// Months are added to the anchor rather than chained from the previous reset, so a
+    /// budget anchored on the 31st resets on the last day of shorter months and returns to
+    /// the 31st afterwards.
+    fn boundary(&self, anchor: DateTime<Utc>, n: i32) -> DateTime<Utc> {
+        match self {
+            Self::Monthly => {
+                let months = Months::new(n.unsigned_abs());
+                if n >= 0 {
+                    anchor.checked_add_months(months)
+                } else {
+                    anchor.checked_sub_months(months)
+                }
+                .unwrap_or(anchor + self.length() * n)
+            }
+            _ => anchor + self.length() * n,
+        }
+    }
+
+    /// Index of the period containing `at`
+    fn period_index(&self, anchor: DateTime<Utc>, at: DateTime<Utc>) -> i32 {
+        let elapsed = (at - anchor).num_seconds();
+        let mut n = elapsed.div_euclid(self.length().num_seconds()) as i32;
+        while self.boundary(anchor, n) > at {
+            n -= 1;
+        }
+        while self.boundary(anchor, n + 1) <= at {
+            n += 1;
+        }
+        n
+    }
+
+    /// End of the period containing `at`, for a budget whose first period started at `anchor`
+    pub fn next_reset(&self, anchor: DateTime<Utc>, at: DateTime<Utc>) -> DateTime<Utc> {
+        self.boundary(anchor, self.period_index(anchor, at) + 1)
+    }
+
+    /// Start of the period that ends at `reset_at`
+    pub fn period_start(&self, anchor: DateTime<Utc>, reset_at: DateTime<Utc>) -> DateTime<Utc> {
+        self.boundary(anchor, self.period_index(anchor, reset_at) - 1)
+    }
+}
+
+impl fmt::Display for BudgetPeriod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Daily => write!(f, "daily"),
+            Self::Weekly => write!(f, "weekly"),
+            Self::Monthly => write!(f, "monthly"),
+            Self::Custom(duration) => {
+                let seconds = duration.num_seconds();
+                if seconds % 86_400 == 0 {
+                    write!(f, "{}d", seconds / 86_400)
+                } else if seconds % 3_600 == 0 {
+                    write!(f, "{}h", seconds / 3_600)
+                } else if seconds % 60 == 0 {
+                    write!(f, "{}m", seconds / 60)
+                } else {
+                    write!(f, "{}s", seconds)
+                }
+            }
+        }
+    }
+}
+
+/// Reset spend for every key whose budget period has ended
+/// The previous period's total is archived in virtual_key_budget_history
+pub async fn reset_due_budgets(pool: &Pool<Postgres>, key_cache: &KeyCache) -> ApiResult<usize> {
+    let now = Utc::now();
+    let due_keys = VirtualKey::find_due_for_budget_reset(pool).await?;
+    let mut reset_count = 0;
+
+    for key in due_keys {
+        let (Some(period), Some(reset_at)) = (&key.budget_period, key.budget_reset_at) else {
+            continue;
+        };
+
+        let period = match BudgetPeriod::parse(period) {
+            Ok(period) => period,
+            Err(e) => {
+                warn!("Skipping budget reset for key {}: {}", key.id, e);
+                continue;
+            }
+        };
+
+        // Keys from before anchors were stored count their periods from the pending reset.
+        // Periods that elapsed while the job wasn't running are skipped.
+        let anchor = key.budget_anchor_at.unwrap_or(reset_at);
+        let next_reset_at = period.next_reset(anchor, now);
+
+        // Another replica may have reset this key already; the update is a no-op then
+        if VirtualKey::reset_budget_period(
+            pool,
+            key.id,
+            period.period_start(anchor, reset_at),
+            reset_at,
+            next_reset_at,
+        )
+        .await?
+        {
+            debug!(
+                "Reset budget for key {} (spent ${:.4}), next reset at {}",
+                key.id, key.current_spend, next_reset_at
+            );
+            // Cached copies still carry the old spend and may be rejected as over budget
+            key_cache.invalidate(&key).await;
+            reset_count += 1;
+        }
+    }
+
+    Ok(reset_count)
+}
+
+/// Spawn the background job that resets periodic budgets
+pub fn spawn_budget_reset_job(pool: Pool<Postgres>, key_cache: KeyCache, interval_seconds: u64) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(interval_seconds));
+        loop {
+            interval.tick().await;
+            match reset_due_budgets(&pool, &key_cache).await {
+                Ok(0) => {}
+                Ok(count) => info!("🔄 Reset budgets for {} virtual keys", count),
+                Err(e) => error!("Budget reset job failed: {}", e),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_parse_budget_period() {
+        assert_eq!(BudgetPeriod::parse("daily").unwrap(), BudgetPeriod::Daily);
+        assert_eq!(BudgetPeriod::parse("Weekly").unwrap(), BudgetPeriod::Weekly);
+        assert_eq!(
+            BudgetPeriod::parse("monthly").unwrap(),
+            BudgetPeriod::Monthly
+        );
+        assert_eq!(
+            BudgetPeriod::parse("30d").unwrap(),
+            BudgetPeriod::Custom(Duration::days(30))
+        );
+        assert_eq!(
+            BudgetPeriod::parse("12h").unwrap(),
+            BudgetPeriod::Custom(Duration::hours(12))
+        );
+
+        assert!(BudgetPeriod::parse("yearly").is_err());
+        assert!(BudgetPeriod::parse("0d").is_err());
+        assert!(BudgetPeriod::parse("30s").is_err());
+        assert!(BudgetPeriod::parse("d").is_err());
+
+        assert_eq!(BudgetPeriod::parse_update("None").unwrap(), None);
+        assert_eq!(
+            BudgetPeriod::parse_update("weekly").unwrap(),
+            Some(BudgetPeriod::Weekly)
+        );
+    }
+
+    #[test]
+    fn test_budget_period_round_trip() {
+        for value in ["daily", "weekly", "monthly", "30d", "12h", "90m"] {
+            assert_eq!(BudgetPeriod::parse(value).unwrap().to_string(), value);
+        }
+    }
+
+    #[test]
+    fn test_monthly_reset_keeps_anchor_day() {
+        let day = |month, day| Utc.with_ymd_and_hms(2025, month, day, 0, 0, 0).unwrap();
+        let monthly = BudgetPeriod::Monthly;
+        let jan_31 = day(1, 31);
+
+        // Clamped to the end of February, then back to the 31st
+        assert_eq!(monthly.next_reset(jan_31, jan_31), day(2, 28));
+        assert_eq!(monthly.next_reset(jan_31, day(2, 28)), day(3, 31));
+        assert_eq!(monthly.next_reset(jan_31, day(4, 15)), day(4, 30));
+        assert_eq!(monthly.next_reset(jan_31, day(4, 30)), day(5, 31));
+
+        assert_eq!(monthly.period_start(jan_31, day(2, 28)), jan_31);
+        assert_eq!(monthly.period_start(jan_31, day(3, 31)), day(2, 28));
+    }
+
+    #[test]
+    fn test_next_reset_skips_elapsed_periods() {
+        let anchor = Utc.with_ymd_and_hms(2025, 1, 1, 6, 0, 0).unwrap();
+        let now = Utc.with_ymd_and_hms(2025, 1, 4, 12, 0, 0).unwrap();
+        assert_eq!(
+            BudgetPeriod::Daily.next_reset(anchor, now),
+            Utc.with_ymd_and_hms(2025, 1, 5, 6, 0, 0).unwrap()
+        );
+        assert_eq!(
+            BudgetPeriod::Custom(Duration::hours(12))
+                .period_start(anchor, Utc.with_ymd_and_hms(2025, 1, 4, 18, 0, 0).unwrap()),
+            Utc.with_ymd_and_hms(2025, 1, 4, 6, 0, 0).unwrap()
+        );
+    }
+}
@@ -1,57 +1,48 @@
-use redis::{AsyncCommands, Client};
+use redis::AsyncCommands;
 use serde::{Deserialize, Serialize};
-use tracing::{debug, error, info, warn};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, error, info};
 
 use crate::error::ApiResult;
+use crate::local_store::{LruCache, RedisConnection, RedisHealth};
 
 #[derive(Clone)]
 pub struct CacheManager {
     // ConnectionManager is already designed for concurrent use via cloning
     // No need for Arc<Mutex<>> wrapper - this was causing serialized access!
-    client: Option<redis::aio::ConnectionManager>,
+    client: RedisConnection,
+    // Used when Redis isn't configured or is unreachable
+    local: Arc<LruCache>,
+    health: RedisHealth,
     ttl_seconds: u64,
     enabled: bool,
 }
 
 impl CacheManager {
-    pub async fn new(redis_url: Option<String>, ttl_seconds: u64, enabled: bool) -> Self {
+    pub fn new(
+        client: RedisConnection,
+        ttl_seconds: u64,
+        enabled: bool,
+        local_max_entries: usize,
+    ) -> Self {
         if !enabled {
             debug!("Caching disabled");
-            return Self {
-                client: None,
-                ttl_seconds,
-                enabled: false,
-            };
-        }
-
-        let client = if let Some(url) = redis_url {
-            match Client::open(url.as_str()) {
-                Ok(client) => {
-                    match client.get_connection_manager().await {
-                        Ok(conn) => {
-                            info!("✅ Redis connection established for caching (lock-free concurrency)");
-                            Some(conn)
-                        }
-                        Err(e) => {
-                            error!("Failed to connect to Redis: {}", e);
-                            None
-                        }
-                    }
-                }
-                Err(e) => {
-                    error!("Failed to create Redis client: {}", e);
-                    None
-                }
-            }
+        } else if client.is_configured() {
+            info!("✅ Redis caching enabled (lock-free concurrency)");
         } else {
-            warn!("Redis URL not provided, caching disabled");
-            None
-        };
+            info!(
+                "Redis not available, caching in-process (up to {} entries)",
+                local_max_entries
+            );
+        }
 
         Self {
-            enabled: client.is_some(),
             client,
+            local: Arc::new(LruCache::new(local_max_entries)),
+            health: RedisHealth::new("caching"),
             ttl_seconds,
+            enabled,
         }
     }
 
@@ -59,6 +50,18 @@ impl CacheManager {
         self.enabled
     }
 
+    /// Whether entries are currently kept in-process rather than in Redis
+    pub fn is_local(&self) -> bool {
+        !self.client.is_connected() || self.health.is_degraded()
+    }
+
+    /// Redis connection to use for the next operation, if it's healthy
+    fn redis(&self) -> Option<redis::aio::ConnectionManager> {
+        // Clone the connection manager for concurrent access (cheap operation)
+        // ConnectionManager handles connection pooling internally
+        self.client.get().filter(|_| self.health.should_try())
+    }
+
     pub async fn get<T>(&self, key: &str) -> ApiResult<Option<T>>
     where
         T: for<'de> Deserialize<'de>,
@@ -67,33 +70,32 @@ impl CacheManager {
             return Ok(None);
         }
 
-        let client = match &self.client {
-            Some(c) => c,
-            None => return Ok(None),
-        };
-
-        // Clone the connection manager for concurrent access (cheap operation)
-        // ConnectionManager handles connection pooling internally
-        let mut conn = client.clone();
-        match conn.get::<_, String>(key).await {
-            Ok(value) => {
-                debug!("Cache hit for key: {}", key);
-                match serde_json::from_str(&value) {
-                    Ok(data) => Ok(Some(data)),
-                    Err(e) => {
-                        error!("Failed to deserialize cached value: {}", e);
-                        Ok(None)
-                    }
+        let value = match self.redis() {
+            Some(mut conn) => match conn.get::<_, Option<String>>(key).await {
+                Ok(value) => {
+                    self.health.record_success();
+                    value
                 }
-            }
-            Err(e) => {
-                if e.kind() == redis::ErrorKind::TypeError {
-                    debug!("Cache miss for key: {}", key);
-                    Ok(None)
-                } else {
+                Err(e) => {
                     error!("Redis error: {}", e);
-                    Ok(None)
+                    self.health.record_failure(&e);
+                    self.local.get(key)
                 }
+            },
+            None => self.local.get(key),
+        };
+
+        let Some(value) = value else {
+            debug!("Cache miss for key: {}", key);
+            return Ok(None);
+        };
+
+        debug!("Cache hit for key: {}", key);
+        match serde_json::from_str(&value) {
+            Ok(data) => Ok(Some(data)),
+            Err(e) => {
+                error!("Failed to deserialize cached value: {}", e);
+                Ok(None)
             }
         }
     }
@@ -106,11 +108,6 @@ impl CacheManager {
             return Ok(());
         }
 
-        let client = match &self.client {
-            Some(c) => c,
-            None => return Ok(()),
-        };
-
         let serialized = match serde_json::to_string(value) {
             Ok(s) => s,
             Err(e) => {
@@ -119,24 +116,33 @@ impl CacheManager {
             }
         };
 
-        // Clone the connection manager for concurrent access
-        let mut conn = client.clone();
-        match conn
-            .set_ex::<_, _, ()>(key, serialized, self.ttl_seconds)
-            .await
-        {
-            Ok(_) => {
-                debug!(
-                    "Cached value for key: {} with TTL: {}s",
-                    key, self.ttl_seconds
-                );
-                Ok(())
-            }
-            Err(e) => {
-                error!("Failed to cache value: {}", e);
-                Ok(())
+        if let Some(mut conn) = self.redis() {
+            match conn
+                .set_ex::<_, _, ()>(key, &serialized, self.ttl_seconds)
+                .await
+            {
+                Ok(_) => {
+                    self.health.record_success();
+                    debug!(
+                        "Cached value for key: {} with TTL: {}s",
+                        key, self.ttl_seconds
+                    );
+                    return Ok(());
+                }
+                Err(e) => {
+                    error!("Failed to cache value: {}", e);
+                    self.health.record_failure(&e);
+                }
             }
         }
+
+        self.local
+            .set(key, serialized, Duration::from_secs(self.ttl_seconds));
+        debug!(
+            "Cached value in-process for key: {} with TTL: {}s",
+            key, self.ttl_seconds
+        );
+        Ok(())
     }
 
     pub async fn delete(&self, key: &str) -> ApiResult<()> {
@@ -144,23 +150,23 @@ impl CacheManager {
             return Ok(());
         }
 
-        let client = match &self.client {
-            Some(c) => c,
-            None => return Ok(()),
-        };
+        // Entries may have been written locally during an outage
+        self.local.delete(key);
 
-        // Clone the connection manager for concurrent access
-        let mut conn = client.clone();
-        match conn.del::<_, ()>(key).await {
-            Ok(_) => {
-                debug!("Deleted cache key: {}", key);
-                Ok(())
-            }
-            Err(e) => {
-                error!("Failed to delete cache key: {}", e);
-                Ok(())
+        if let Some(mut conn) = self.redis() {
+            match conn.del::<_, ()>(key).await {
+                Ok(_) => {
+                    self.health.record_success();
+                    debug!("Deleted cache key: {}", key);
+                }
+                Err(e) => {
+                    error!("Failed to delete cache key: {}", e);
+                    self.health.record_failure(&e);
+                }
             }
         }
+
+        Ok(())
     }
 
     pub fn generate_cache_key(&self, model: &str, messages: &str) -> String {
@@ -0,0 +1,218 @@
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, warn};
+
+use crate::error::{ApiError, ApiResult};
+use crate::local_store::{LocalSemaphore, RedisConnection, RedisHealth};
+use crate::metrics::MetricsCollector;
+
+/// How long a permit is held without a heartbeat before other replicas consider it abandoned
+const LEASE_MS: i64 = 60_000;
+
+/// How often a held permit renews its lease
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(20);
+
+/// Distributed semaphore over a sorted set of permit IDs scored by lease expiry.
+/// Expired leases (from crashed replicas) are dropped before counting.
+///
+/// KEYS[1]: semaphore key
+/// ARGV[1]: mode - "acquire", "refresh", "release" or "count"
+/// ARGV[2]: permit ID
+/// ARGV[3]: limit (acquire only)
+/// ARGV[4]: lease in ms
+///
+/// Returns {acquired (1/0), in_flight}
+const SEMAPHORE_SCRIPT: &str = r#"
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+local mode = ARGV[1]
+local lease = tonumber(ARGV[4])
+
+redis.call('ZREMRANGEBYSCORE', KEYS[1], '-inf', now)
+
+if mode == 'release' then
+    redis.call('ZREM', KEYS[1], ARGV[2])
+    return { 0, redis.call('ZCARD', KEYS[1]) }
+end
+
+if mode == 'refresh' then
+    redis.call('ZADD', KEYS[1], 'XX', now + lease, ARGV[2])
+    redis.call('PEXPIRE', KEYS[1], lease)
+    return { 1, redis.call('ZCARD', KEYS[1]) }
+end
+
+local count = redis.call('ZCARD', KEYS[1])
+if mode == 'count' then
+    return { 0, count }
+end
+
+if count >= tonumber(ARGV[3]) then
+    return { 0, count }
+end
+redis.call('ZADD', KEYS[1], now + lease, ARGV[2])
+redis.call('PEXPIRE', KEYS[1], lease)
+return { 1, count + 1 }
+"#;
+
+/// Limits how many requests a virtual key can have in flight at once
+///
+/// Permits live in Redis so the limit holds across replicas, with the same in-process
+/// fallback and fail-open/fail-closed behaviour as the rate limiter.
+#[derive(Clone)]
+pub struct ConcurrencyLimiter {
+    redis_client: RedisConnection,
+    script: redis::Script,
+    local: Arc<LocalSemaphore>,
+    health: RedisHealth,
+    fail_open: bool,
+}
+
+/// A held slot; released when dropped, i.e. when the response body finishes or is abandoned
+pub struct ConcurrencyPermit {
+    limiter: ConcurrencyLimiter,
+    key_id: String,
+    permit_id: String,
+    in_redis: bool,
+    heartbeat: Option<tokio::task::JoinHandle<()>>,
+}
+
+impl ConcurrencyLimiter {
+    pub fn new(redis_client: RedisConnection, fail_open: bool) -> Self {
+        Self {
+            redis_client,
+            script: redis::Script::new(SEMAPHORE_SCRIPT),
+            local: Arc::new(LocalSemaphore::default()),
+            health: RedisHealth::new("parallel request limits"),
+            fail_open,
+        }
+    }
+
+    /// Take a slot for `key_id` if fewer than `limit` requests are in flight
+    /// Returns None when the key is at its limit.
+    pub async fn acquire(&self, key_id: &str, limit: i32) -> ApiResult<Option<ConcurrencyPermit>> {
+        let permit_id = uuid::Uuid::new_v4().to_string();
+
+        let (acquired, in_flight, in_redis) = match self
+            .run_script(key_id, "acquire", &permit_id, limit)
+            .await?
+        {
+            Some((acquired, in_flight)) => (acquired, in_flight, true),
+            None => match self.local.try_acquire(key_id, limit as i64) {
+                Some(in_flight) => (true, in_flight, false),
+                None => (false, self.local.count(key_id), false),
+            },
+        };
+
+        MetricsCollector::set_key_in_flight_requests(key_id, in_flight);
+        if !acquired {
+            debug!(
+                "Parallel request limit reached for key {}: {} in flight",
+                key_id, in_flight
+            );
+            return Ok(None);
+        }
+
+        let heartbeat = in_redis.then(|| {
+            let limiter = self.clone();
+            let key_id = key_id.to_string();
+            let permit_id = permit_id.clone();
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(HEARTBEAT_INTERVAL);
+                interval.tick().await;
+                loop {
+                    interval.tick().await;
+                    let _ = limiter.run_script(&key_id, "refresh", &permit_id, 0).await;
+                }
+            })
+        });
+
+        Ok(Some(ConcurrencyPermit {
+            limiter: self.clone(),
+            key_id: key_id.to_string(),
+            permit_id,
+            in_redis,
+            heartbeat,
+        }))
+    }
+
+    /// Number of requests currently in flight for a key
+    pub async fn in_flight(&self, key_id: &str) -> ApiResult<i64> {
+        Ok(match self.run_script(key_id, "count", "", 0).await? {
+            Some((_, in_flight)) => in_flight,
+            None => self.local.count(key_id),
+        })
+    }
+
+    /// Run the semaphore script; None means Redis isn't available and local state applies
+    async fn run_script(
+        &self,
+        key_id: &str,
+        mode: &str,
+        permit_id: &str,
+        limit: i32,
+    ) -> ApiResult<Option<(bool, i64)>> {
+        if !self.redis_client.is_configured() {
+            return Ok(None);
+        }
+
+        if let Some(mut conn) = self.redis_client.get().filter(|_| self.health.should_try()) {
+            let reply: redis::RedisResult<(i64, i64)> = self
+                .script
+                .key(Self::semaphore_key(key_id))
+                .arg(mode)
+                .arg(permit_id)
+                .arg(limit)
+                .arg(LEASE_MS)
+                .invoke_async(&mut conn)
+                .await;
+
+            match reply {
+                Ok((acquired, in_flight)) => {
+                    self.health.record_success();
+                    return Ok(Some((acquired == 1, in_flight)));
+                }
+                Err(e) => self.health.record_failure(&e),
+            }
+        }
+
+        if !self.fail_open {
+            return Err(ApiError::ServiceUnavailable);
+        }
+
+        Ok(None)
+    }
+
+    fn semaphore_key(key_id: &str) -> String {
+        format!("concurrency:{}", key_id)
+    }
+}
+
+impl Drop for ConcurrencyPermit {
+    fn drop(&mut self) {
+        if let Some(heartbeat) = self.heartbeat.take() {
+            heartbeat.abort();
+        }
+
+        if !self.in_redis {
+            let in_flight = self.limiter.local.release(&self.key_id);
+            MetricsCollector::set_key_in_flight_requests(&self.key_id, in_flight);
+            return;
+        }
+
+        let limiter = self.limiter.clone();
+        let key_id = std::mem::take(&mut self.key_id);
+        let permit_id = std::mem::take(&mut self.permit_id);
+        tokio::spawn(async move {
+            match limiter.run_script(&key_id, "release", &permit_id, 0).await {
+                Ok(Some((_, in_flight))) => {
+                    MetricsCollector::set_key_in_flight_requests(&key_id, in_flight)
+                }
+                // The lease expires on its own if Redis went away in the meantime
+                _ => warn!(
+                    "Failed to release parallel request slot for key {}, it will expire",
+                    key_id
+                ),
+            }
+        });
+    }
+}
@@ -1,6 +1,24 @@
 use serde::{Deserialize, Serialize};
 use std::env;
 
+/// JWT secret used when `JWT_SECRET` isn't set, refused in production
+const DEFAULT_JWT_SECRET: &str = "default-jwt-secret-change-me-in-production";
+
+/// Placeholder secrets from `.env.example`, refused in production like the default
+const EXAMPLE_JWT_SECRETS: &[&str] = &[
+    DEFAULT_JWT_SECRET,
+    "your-jwt-secret-change-me-in-production",
+];
+
+/// Virtual key pepper used when `KEY_HASH_PEPPER` isn't set, refused in production
+const DEFAULT_KEY_HASH_PEPPER: &str = "default-key-hash-pepper-change-me-in-production";
+
+/// Placeholder peppers, refused in production like the default
+const EXAMPLE_KEY_HASH_PEPPERS: &[&str] = &[
+    DEFAULT_KEY_HASH_PEPPER,
+    "your-key-hash-pepper-change-me-in-production",
+];
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct AppConfig {
     pub host: String,
@@ -15,26 +33,85 @@ pub struct AppConfig {
     pub aws_region: Option<String>,
     pub cohere_api_key: Option<String>,
     pub log_level: String,
+    /// `ENVIRONMENT=production` refuses to start with insecure defaults
+    pub production: bool,
     pub redis_url: Option<String>,
     pub database_url: Option<String>,
     pub enable_caching: bool,
     pub cache_ttl_seconds: u64,
+    pub local_cache_max_entries: usize,
+    pub redis_fail_open: bool,
+    pub budget_reset_interval_seconds: u64,
+    pub usage_flush_interval_ms: u64,
+    pub rate_limit_default_max_tokens: i32,
+    pub rate_limit_max_queue_ms: u64,
 
     // Authentication configuration
     pub master_key: Option<String>,
     pub jwt_secret: String,
-    pub jwt_expiry_hours: i64,
+    /// RSA or Ed25519 private key (PEM) to sign access tokens with instead of `jwt_secret`
+    pub jwt_signing_key_file: Option<String>,
+    /// Comma-separated public keys (PEM) still accepted after rotating the signing key
+    pub jwt_verification_key_files: Option<String>,
+    /// Server-side secret virtual keys are HMAC-hashed with; changing it invalidates every key
+    pub key_hash_pepper: String,
+    /// Lifetime of access tokens (JWTs); sessions are checked on every request regardless
+    pub access_token_ttl_seconds: i64,
+    /// Lifetime of a login, after which its refresh token stops working
+    pub session_ttl_hours: i64,
+    pub session_cleanup_interval_seconds: u64,
+    /// How long a virtual key's previous secret keeps working after a rotation
+    pub key_rotation_grace_seconds: i64,
     pub require_auth: bool,
+    /// Login and registration attempts allowed per client address per minute (0 = unlimited)
+    pub login_rate_limit_per_ip: i32,
+    /// Login attempts allowed per account per minute (0 = unlimited)
+    pub login_rate_limit_per_account: i32,
+    /// Consecutive failed logins that lock an account (0 = never lock)
+    pub login_lockout_threshold: i32,
+    pub login_lockout_seconds: i64,
+    /// Delay before answering a failed login, doubled for each consecutive failure
+    pub login_failure_delay_ms: u64,
+    /// Roles whose password users must set up TOTP before using the dashboard
+    pub totp_required_roles: Vec<String>,
+    /// Issuer name authenticator apps show next to the account
+    pub totp_issuer: String,
+    /// Argon2id memory cost in KiB for new password hashes
+    pub password_hash_memory_kib: u32,
+    /// Argon2id passes over memory
+    pub password_hash_iterations: u32,
+    /// Argon2id lanes
+    pub password_hash_parallelism: u32,
+    /// Shortest password accepted at registration
+    pub password_min_length: usize,
+    /// Refuse passwords on the bundled breached-password list at registration
+    pub password_check_breached: bool,
+    /// Also look passwords up in Have I Been Pwned (k-anonymity range API) at registration
+    pub password_check_hibp: bool,
+    /// Take client addresses from `X-Forwarded-For`; only safe behind a proxy that sets it
+    pub trust_forwarded_for: bool,
 
     // OAuth configuration
     pub github_client_id: Option<String>,
     pub github_client_secret: Option<String>,
     pub google_client_id: Option<String>,
     pub google_client_secret: Option<String>,
+    pub oidc_provider_name: String,
+    pub oidc_issuer_url: Option<String>,
+    pub oidc_client_id: Option<String>,
+    pub oidc_client_secret: Option<String>,
+    pub oidc_scopes: String,
+    pub oidc_email_claim: String,
+    pub oidc_groups_claim: Option<String>,
+    pub oauth_group_roles: Option<String>,
+    pub oauth_group_teams: Option<String>,
     pub oauth_redirect_url: String,
     pub frontend_url: String,
 
     // Security configuration
+    pub encryption_key: Option<String>,
+    pub encryption_key_file: Option<String>,
+    pub encryption_previous_keys: Option<String>,
     pub allowed_email_domains: Option<Vec<String>>,
     pub proxy_admin_id: Option<String>,
 }
@@ -43,11 +120,40 @@ impl AppConfig {
     pub fn load() -> Result<Self, Box<dyn std::error::Error>> {
         dotenv::dotenv().ok();
 
+        let production = env::var("ENVIRONMENT")
+            .map(|environment| environment.trim().eq_ignore_ascii_case("production"))
+            .unwrap_or(false);
+
         // Generate a default JWT secret if not provided (for development only)
         let jwt_secret = env::var("JWT_SECRET").unwrap_or_else(|_| {
             eprintln!("WARNING: JWT_SECRET not set, using default (INSECURE for production!)");
-            "default-jwt-secret-change-me-in-production".to_string()
+            DEFAULT_JWT_SECRET.to_string()
         });
+        if production && EXAMPLE_JWT_SECRETS.contains(&jwt_secret.as_str()) {
+            return Err("JWT_SECRET must be set to a strong random secret in production".into());
+        }
+
+        // Kept apart from JWT_SECRET so rotating the signing secret doesn't invalidate keys
+        let key_hash_pepper = match env::var("KEY_HASH_PEPPER") {
+            Ok(pepper) if !pepper.trim().is_empty() => pepper,
+            _ if production => {
+                return Err("KEY_HASH_PEPPER must be set in production".into());
+            }
+            _ => {
+                tracing::warn!(
+                    "KEY_HASH_PEPPER not set, using a default (INSECURE for production!); \
+                     virtual keys hashed under any other pepper (including JWT_SECRET, the old \
+                     fallback) will fail to verify until rotated with POST /auth/key/rotate; \
+                     bcrypt keys are unaffected and upgraded on first use"
+                );
+                DEFAULT_KEY_HASH_PEPPER.to_string()
+            }
+        };
+        if production && EXAMPLE_KEY_HASH_PEPPERS.contains(&key_hash_pepper.as_str()) {
+            return Err(
+                "KEY_HASH_PEPPER must be set to a strong random secret in production".into(),
+            );
+        }
 
         // Parse allowed email domains
         let allowed_email_domains = env::var("ALLOWED_EMAIL_DOMAINS").ok().map(|domains| {
@@ -73,6 +179,7 @@ impl AppConfig {
             aws_region: env::var("AWS_REGION").ok(),
             cohere_api_key: env::var("COHERE_API_KEY").ok(),
             log_level: env::var("LOG_LEVEL").unwrap_or_else(|_| "info".to_string()),
+            production,
             redis_url: env::var("REDIS_URL").ok(),
             database_url: env::var("DATABASE_URL").ok(),
             enable_caching: env::var("ENABLE_CACHING")
@@ -83,30 +190,152 @@ impl AppConfig {
                 .unwrap_or_else(|_| "3600".to_string())
                 .parse()
                 .unwrap_or(3600),
+            local_cache_max_entries: env::var("LOCAL_CACHE_MAX_ENTRIES")
+                .unwrap_or_else(|_| "10000".to_string())
+                .parse()
+                .unwrap_or(10000),
+            // "open" falls back to in-process state while Redis is down, "closed" rejects
+            redis_fail_open: env::var("REDIS_FAILURE_MODE")
+                .map(|mode| !mode.trim().eq_ignore_ascii_case("closed"))
+                .unwrap_or(true),
+            // Job intervals are at least 1, tokio panics on a zero interval
+            budget_reset_interval_seconds: env::var("BUDGET_RESET_INTERVAL_SECONDS")
+                .unwrap_or_else(|_| "60".to_string())
+                .parse()
+                .unwrap_or(60)
+                .max(1),
+            usage_flush_interval_ms: env::var("USAGE_FLUSH_INTERVAL_MS")
+                .unwrap_or_else(|_| "1000".to_string())
+                .parse()
+                .unwrap_or(1000)
+                .max(1),
+            rate_limit_default_max_tokens: env::var("RATE_LIMIT_DEFAULT_MAX_TOKENS")
+                .unwrap_or_else(|_| "1024".to_string())
+                .parse()
+                .unwrap_or(1024),
+            rate_limit_max_queue_ms: env::var("RATE_LIMIT_MAX_QUEUE_MS")
+                .unwrap_or_else(|_| "0".to_string())
+                .parse()
+                .unwrap_or(0),
 
             // Authentication configuration
             master_key: env::var("INFERXGATE_MASTER_KEY").ok(),
             jwt_secret,
-            jwt_expiry_hours: env::var("JWT_EXPIRY_HOURS")
+            jwt_signing_key_file: env::var("JWT_SIGNING_KEY_FILE").ok(),
+            jwt_verification_key_files: env::var("JWT_VERIFICATION_KEY_FILES").ok(),
+            key_hash_pepper,
+            access_token_ttl_seconds: env::var("ACCESS_TOKEN_TTL_SECONDS")
+                .unwrap_or_else(|_| "900".to_string()) // 15 minutes default
+                .parse()
+                .unwrap_or(900),
+            // JWT_EXPIRY_HOURS is the name from before refresh tokens
+            session_ttl_hours: env::var("SESSION_TTL_HOURS")
+                .or_else(|_| env::var("JWT_EXPIRY_HOURS"))
                 .unwrap_or_else(|_| "168".to_string()) // 7 days default
                 .parse()
                 .unwrap_or(168),
+            session_cleanup_interval_seconds: env::var("SESSION_CLEANUP_INTERVAL_SECONDS")
+                .unwrap_or_else(|_| "3600".to_string())
+                .parse()
+                .unwrap_or(3600)
+                .max(1),
+            key_rotation_grace_seconds: env::var("KEY_ROTATION_GRACE_SECONDS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(86400), // 1 day default
             require_auth: env::var("REQUIRE_AUTH")
                 .unwrap_or_else(|_| "false".to_string())
                 .parse()
                 .unwrap_or(false),
+            login_rate_limit_per_ip: env::var("LOGIN_RATE_LIMIT_PER_IP")
+                .unwrap_or_else(|_| "20".to_string())
+                .parse()
+                .unwrap_or(20),
+            login_rate_limit_per_account: env::var("LOGIN_RATE_LIMIT_PER_ACCOUNT")
+                .unwrap_or_else(|_| "10".to_string())
+                .parse()
+                .unwrap_or(10),
+            login_lockout_threshold: env::var("LOGIN_LOCKOUT_THRESHOLD")
+                .unwrap_or_else(|_| "5".to_string())
+                .parse()
+                .unwrap_or(5),
+            login_lockout_seconds: env::var("LOGIN_LOCKOUT_SECONDS")
+                .unwrap_or_else(|_| "900".to_string()) // 15 minutes default
+                .parse()
+                .unwrap_or(900),
+            login_failure_delay_ms: env::var("LOGIN_FAILURE_DELAY_MS")
+                .unwrap_or_else(|_| "500".to_string())
+                .parse()
+                .unwrap_or(500),
+            totp_required_roles: env::var("TOTP_REQUIRED_ROLES")
+                .map(|roles| {
+                    roles
+                        .split(',')
+                        .map(|role| role.trim().to_lowercase())
+                        .filter(|role| !role.is_empty())
+                        .collect()
+                })
+                .unwrap_or_default(),
+            totp_issuer: env::var("TOTP_ISSUER").unwrap_or_else(|_| "InferXgate".to_string()),
+            // OWASP's minimum recommendation for Argon2id
+            password_hash_memory_kib: env::var("PASSWORD_HASH_MEMORY_KIB")
+                .unwrap_or_else(|_| "19456".to_string())
+                .parse()
+                .unwrap_or(19456),
+            password_hash_iterations: env::var("PASSWORD_HASH_ITERATIONS")
+                .unwrap_or_else(|_| "2".to_string())
+                .parse()
+                .unwrap_or(2),
+            password_hash_parallelism: env::var("PASSWORD_HASH_PARALLELISM")
+                .unwrap_or_else(|_| "1".to_string())
+                .parse()
+                .unwrap_or(1),
+            password_min_length: env::var("PASSWORD_MIN_LENGTH")
+                .unwrap_or_else(|_| "8".to_string())
+                .parse()
+                .unwrap_or(8),
+            password_check_breached: env::var("PASSWORD_CHECK_BREACHED")
+                .unwrap_or_else(|_| "true".to_string())
+                .parse()
+                .unwrap_or(true),
+            password_check_hibp: env::var("PASSWORD_CHECK_HIBP")
+                .unwrap_or_else(|_| "false".to_string())
+                .parse()
+                .unwrap_or(false),
+            trust_forwarded_for: env::var("TRUST_X_FORWARDED_FOR")
+                .unwrap_or_else(|_| "false".to_string())
+                .parse()
+                .unwrap_or(false),
 
             // OAuth configuration
             github_client_id: env::var("GITHUB_CLIENT_ID").ok(),
             github_client_secret: env::var("GITHUB_CLIENT_SECRET").ok(),
             google_client_id: env::var("GOOGLE_CLIENT_ID").ok(),
             google_client_secret: env::var("GOOGLE_CLIENT_SECRET").ok(),
+            // Generic OpenID Connect provider (Okta, Azure AD, Keycloak, ...)
+            oidc_provider_name: env::var("OIDC_PROVIDER_NAME")
+                .unwrap_or_else(|_| "oidc".to_string()),
+            oidc_issuer_url: env::var("OIDC_ISSUER_URL").ok(),
+            oidc_client_id: env::var("OIDC_CLIENT_ID").ok(),
+            oidc_client_secret: env::var("OIDC_CLIENT_SECRET").ok(),
+            oidc_scopes: env::var("OIDC_SCOPES")
+                .unwrap_or_else(|_| "openid email profile".to_string()),
+            oidc_email_claim: env::var("OIDC_EMAIL_CLAIM").unwrap_or_else(|_| "email".to_string()),
+            oidc_groups_claim: env::var("OIDC_GROUPS_CLAIM").ok(),
+            // group=role and group=team_id[:team_role] pairs, comma-separated
+            oauth_group_roles: env::var("OAUTH_GROUP_ROLES").ok(),
+            oauth_group_teams: env::var("OAUTH_GROUP_TEAMS").ok(),
             oauth_redirect_url: env::var("OAUTH_REDIRECT_URL")
                 .unwrap_or_else(|_| "http://localhost:3000/auth/oauth/callback".to_string()),
             frontend_url: env::var("FRONTEND_URL")
                 .unwrap_or_else(|_| "http://localhost:5173".to_string()),
 
             // Security configuration
+            // Master key for secrets at rest: base64 of 32 bytes, inline or in a file
+            encryption_key: env::var("ENCRYPTION_KEY").ok(),
+            encryption_key_file: env::var("ENCRYPTION_KEY_FILE").ok(),
+            // Retired master keys, kept for decryption until rows are re-encrypted
+            encryption_previous_keys: env::var("ENCRYPTION_PREVIOUS_KEYS").ok(),
             allowed_email_domains,
             proxy_admin_id: env::var("PROXY_ADMIN_ID").ok(),
         })
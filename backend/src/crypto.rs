@@ -0,0 +1,288 @@
+//! Envelope encryption for secrets stored in the database (provider API keys, OAuth tokens)
+//!
+//! Each value is encrypted with its own random data key (AES-256-GCM), and the data key
+//! is wrapped with the master key. Stored values look like
+//! `enc:v1:{key_id}:{wrapped_data_key}:{ciphertext}`, so every row records which master
+//! key protects it and old keys can stay configured for decryption during a rotation.
+//!
+//! This module only depends on external crates so the `reencrypt_secrets` binary can
+//! include it directly.
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    Aes256Gcm, Nonce,
+};
+use base64::{
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+    Engine,
+};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+const PREFIX: &str = "enc:v1:";
+const NONCE_LEN: usize = 12;
+
+#[derive(Debug, Error)]
+pub enum CryptoError {
+    #[error("Invalid encryption key: {0}")]
+    InvalidKey(String),
+
+    #[error("No encryption key configured with ID '{0}'")]
+    UnknownKey(String),
+
+    #[error("Malformed encrypted value")]
+    Malformed,
+
+    #[error("Decryption failed (wrong key or tampered value)")]
+    DecryptionFailed,
+
+    #[error("Encryption failed")]
+    EncryptionFailed,
+}
+
+struct MasterKey {
+    id: String,
+    cipher: Aes256Gcm,
+}
+
+impl MasterKey {
+    fn new(bytes: &[u8]) -> Result<Self, CryptoError> {
+        if bytes.len() != 32 {
+            return Err(CryptoError::InvalidKey(format!(
+                "expected 32 bytes, got {}",
+                bytes.len()
+            )));
+        }
+        let cipher = Aes256Gcm::new_from_slice(bytes)
+            .map_err(|_| CryptoError::InvalidKey("invalid key length".to_string()))?;
+        Ok(Self {
+            id: key_id(bytes),
+            cipher,
+        })
+    }
+
+    fn parse(encoded: &str) -> Result<Self, CryptoError> {
+        let bytes = STANDARD
+            .decode(encoded.trim())
+            .map_err(|_| CryptoError::InvalidKey("not valid base64".to_string()))?;
+        Self::new(&bytes)
+    }
+}
+
+/// Short, non-secret identifier for a master key
+fn key_id(key: &[u8]) -> String {
+    hex::encode(&Sha256::digest(key)[..8])
+}
+
+/// Encrypts new values under the current master key and decrypts values written under
+/// the current or any previous key
+///
+/// Without a current key values are stored as-is, which is how rows written before
+/// encryption was configured are read back too.
+#[derive(Default)]
+pub struct Encryptor {
+    current: Option<MasterKey>,
+    previous: Vec<MasterKey>,
+}
+
+impl Encryptor {
+    /// Build from configuration: the current key inline or from a file (base64, 32 bytes)
+    /// and comma-separated previous keys still needed for decryption
+    pub fn from_settings(
+        key: Option<&str>,
+        key_file: Option<&str>,
+        previous_keys: Option<&str>,
+    ) -> Result<Self, CryptoError> {
+        let current = match (key, key_file) {
+            (Some(key), _) => Some(MasterKey::parse(key)?),
+            (None, Some(path)) => {
+                let contents = std::fs::read_to_string(path).map_err(|e| {
+                    CryptoError::InvalidKey(format!("cannot read key file {}: {}", path, e))
+                })?;
+                Some(MasterKey::parse(&contents)?)
+            }
+            (None, None) => None,
+        };
+
+        let previous = previous_keys
+            .unwrap_or_default()
+            .split(',')
+            .filter(|key| !key.trim().is_empty())
+            .map(MasterKey::parse)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self { current, previous })
+    }
+
+    /// ID of the key new values are encrypted with
+    pub fn current_key_id(&self) -> Option<&str> {
+        self.current.as_ref().map(|key| key.id.as_str())
+    }
+
+    /// Encrypt a value; `context` (e.g. `provider_keys:openai`) is authenticated with it
+    /// so a ciphertext can't be copied into another row
+    pub fn encrypt(&self, plaintext: &str, context: &str) -> Result<String, CryptoError> {
+        let Some(master) = &self.current else {
+            return Ok(plaintext.to_string());
+        };
+
+        let data_key = Aes256Gcm::generate_key(OsRng);
+        let data_cipher = Aes256Gcm::new(&data_key);
+        let nonce = Aes256Gcm::generate_nonce(OsRng);
+        let ciphertext = data_cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext.as_bytes(),
+                    aad: context.as_bytes(),
+                },
+            )
+            .map_err(|_| CryptoError::EncryptionFailed)?;
+
+        let wrap_nonce = Aes256Gcm::generate_nonce(OsRng);
+        let wrapped_key = master
+            .cipher
+            .encrypt(
+                &wrap_nonce,
+                Payload {
+                    msg: &data_key[..],
+                    aad: master.id.as_bytes(),
+                },
+            )
+            .map_err(|_| CryptoError::EncryptionFailed)?;
+
+        Ok(format!(
+            "{}{}:{}:{}",
+            PREFIX,
+            master.id,
+            URL_SAFE_NO_PAD.encode([&wrap_nonce[..], &wrapped_key].concat()),
+            URL_SAFE_NO_PAD.encode([&nonce[..], &ciphertext].concat()),
+        ))
+    }
+
+    /// Decrypt a stored value; values without the `enc:v1:` prefix are legacy plaintext
+    pub fn decrypt(&self, stored: &str, context: &str) -> Result<String, CryptoError> {
+        let Some(rest) = stored.strip_prefix(PREFIX) else {
+            return Ok(stored.to_string());
+        };
+
+        let mut parts = rest.splitn(3, ':');
+        let (Some(id), Some(wrapped), Some(payload)) = (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(CryptoError::Malformed);
+        };
+
+        let master = self
+            .current
+            .iter()
+            .chain(&self.previous)
+            .find(|key| key.id == id)
+            .ok_or_else(|| CryptoError::UnknownKey(id.to_string()))?;
+
+        let (wrap_nonce, wrapped_key) = split_nonce(wrapped)?;
+        let data_key = master
+            .cipher
+            .decrypt(
+                &wrap_nonce,
+                Payload {
+                    msg: &wrapped_key,
+                    aad: master.id.as_bytes(),
+                },
+            )
+            .map_err(|_| CryptoError::DecryptionFailed)?;
+        let data_cipher =
+            Aes256Gcm::new_from_slice(&data_key).map_err(|_| CryptoError::Malformed)?;
+
+        let (nonce, ciphertext) = split_nonce(payload)?;
+        let plaintext = data_cipher
+            .decrypt(
+                &nonce,
+                Payload {
+                    msg: &ciphertext,
+                    aad: context.as_bytes(),
+                },
+            )
+            .map_err(|_| CryptoError::DecryptionFailed)?;
+
+        String::from_utf8(plaintext).map_err(|_| CryptoError::Malformed)
+    }
+
+    /// Whether a stored value is plaintext or under a key other than the current one
+    pub fn needs_reencryption(&self, stored: &str) -> bool {
+        match self.current_key_id() {
+            Some(id) => !stored.starts_with(&format!("{}{}:", PREFIX, id)),
+            None => false,
+        }
+    }
+}
+
+type GcmNonce = Nonce<<Aes256Gcm as AeadCore>::NonceSize>;
+
+/// Split a base64url `nonce || ciphertext` blob
+fn split_nonce(encoded: &str) -> Result<(GcmNonce, Vec<u8>), CryptoError> {
+    let bytes = URL_SAFE_NO_PAD
+        .decode(encoded)
+        .map_err(|_| CryptoError::Malformed)?;
+    if bytes.len() <= NONCE_LEN {
+        return Err(CryptoError::Malformed);
+    }
+    let (nonce, ciphertext) = bytes.split_at(NONCE_LEN);
+    let nonce: &GcmNonce = nonce.into();
+    Ok((*nonce, ciphertext.to_vec()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encryptor(key: u8, previous: &[u8]) -> Encryptor {
+        let encode = |byte: u8| STANDARD.encode([byte; 32]);
+        let previous = previous
+            .iter()
+            .map(|b| encode(*b))
+            .collect::<Vec<_>>()
+            .join(",");
+        Encryptor::from_settings(Some(&encode(key)), None, Some(&previous)).unwrap()
+    }
+
+    #[test]
+    fn test_round_trip_and_context() {
+        let enc = encryptor(1, &[]);
+        let stored = enc.encrypt("sk-secret", "provider_keys:openai").unwrap();
+
+        assert!(stored.starts_with(&format!("enc:v1:{}:", enc.current_key_id().unwrap())));
+        assert!(!stored.contains("sk-secret"));
+        assert_eq!(
+            enc.decrypt(&stored, "provider_keys:openai").unwrap(),
+            "sk-secret"
+        );
+        assert!(enc.decrypt(&stored, "provider_keys:anthropic").is_err());
+    }
+
+    #[test]
+    fn test_rotation() {
+        let old = encryptor(1, &[]);
+        let stored = old.encrypt("sk-secret", "ctx").unwrap();
+
+        let rotated = encryptor(2, &[1]);
+        assert!(rotated.needs_reencryption(&stored));
+        assert_eq!(rotated.decrypt(&stored, "ctx").unwrap(), "sk-secret");
+
+        let reencrypted = rotated.encrypt("sk-secret", "ctx").unwrap();
+        assert!(!rotated.needs_reencryption(&reencrypted));
+        assert!(matches!(
+            encryptor(3, &[]).decrypt(&stored, "ctx"),
+            Err(CryptoError::UnknownKey(_))
+        ));
+    }
+
+    #[test]
+    fn test_plaintext_passthrough() {
+        let disabled = Encryptor::default();
+        assert_eq!(disabled.encrypt("sk-plain", "ctx").unwrap(), "sk-plain");
+
+        let enc = encryptor(1, &[]);
+        assert_eq!(enc.decrypt("sk-plain", "ctx").unwrap(), "sk-plain");
+        assert!(enc.needs_reencryption("sk-plain"));
+        assert!(Encryptor::from_settings(Some("c2hvcnQ="), None, None).is_err());
+    }
+}
@@ -1,15 +1,20 @@
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use sqlx::{postgres::PgPoolOptions, Pool, Postgres};
+use std::sync::Arc;
 use tracing::{debug, error, info};
 use uuid::Uuid;
 
-use crate::error::{ApiError, ApiResult};
+use crate::{
+    crypto::Encryptor,
+    error::{ApiError, ApiResult},
+};
 
 #[derive(Clone)]
 pub struct DatabaseManager {
     pool: Option<Pool<Postgres>>,
     enabled: bool,
+    encryptor: Arc<Encryptor>,
 }
 
 #[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
@@ -23,11 +28,29 @@ pub struct UsageRecord {
     pub cost_usd: f64,
     pub latency_ms: i64,
     pub user_id: Option<String>,
+    pub team_id: Option<Uuid>,
     pub cached: bool,
     pub error: Option<String>,
     pub created_at: DateTime<Utc>,
 }
 
+/// A request's usage, stored with [`DatabaseManager::record_usage`]
+#[derive(Debug)]
+pub struct NewUsageRecord<'a> {
+    pub model: &'a str,
+    pub provider: &'a str,
+    pub prompt_tokens: i32,
+    pub completion_tokens: i32,
+    pub total_tokens: i32,
+    pub cost_usd: f64,
+    pub latency_ms: i64,
+    pub user_id: Option<String>,
+    pub virtual_key_id: Option<Uuid>,
+    pub team_id: Option<Uuid>,
+    pub cached: bool,
+    pub error: Option<String>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct UsageStats {
     pub total_requests: i64,
@@ -37,6 +60,7 @@ pub struct UsageStats {
     pub cache_hit_rate: f64,
     pub requests_by_model: Vec<ModelStats>,
     pub requests_by_provider: Vec<ProviderStats>,
+    pub requests_by_team: Vec<TeamStats>,
 }
 
 #[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
@@ -55,8 +79,17 @@ pub struct ProviderStats {
     pub total_cost: f64,
 }
 
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
+pub struct TeamStats {
+    pub team_id: Uuid,
+    pub team_name: String,
+    pub count: i64,
+    pub total_tokens: i64,
+    pub total_cost: f64,
+}
+
 impl DatabaseManager {
-    pub async fn new(database_url: Option<String>) -> Self {
+    pub async fn new(database_url: Option<String>, encryptor: Arc<Encryptor>) -> Self {
         let pool = if let Some(url) = database_url {
             match PgPoolOptions::new().max_connections(10).connect(&url).await {
                 Ok(pool) => {
@@ -68,6 +101,7 @@ impl DatabaseManager {
                         return Self {
                             pool: None,
                             enabled: false,
+                            encryptor,
                         };
                     }
 
@@ -86,6 +120,7 @@ impl DatabaseManager {
         Self {
             enabled: pool.is_some(),
             pool,
+            encryptor,
         }
     }
 
@@ -97,6 +132,11 @@ impl DatabaseManager {
         self.pool.as_ref()
     }
 
+    /// Encryption for secrets stored in the database
+    pub fn encryptor(&self) -> &Encryptor {
+        &self.encryptor
+    }
+
     async fn run_migrations(pool: &Pool<Postgres>) -> ApiResult<()> {
         info!("Running database migrations...");
 
@@ -182,6 +222,264 @@ impl DatabaseManager {
         .await
         .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
 
+        // Add budget period columns if they don't exist (for periodic budget resets)
+        sqlx::query(
+            r#"
+            DO $$
+            BEGIN
+                IF NOT EXISTS (
+                    SELECT 1 FROM information_schema.columns
+                    WHERE table_name = 'virtual_keys' AND column_name = 'budget_period'
+                ) THEN
+                    ALTER TABLE virtual_keys ADD COLUMN budget_period VARCHAR(50);
+                    ALTER TABLE virtual_keys ADD COLUMN budget_reset_at TIMESTAMP WITH TIME ZONE;
+                END IF;
+            END $$;
+            "#,
+        )
+        .execute(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        // Add per-model limit columns if they don't exist (limits and spend per model pattern)
+        sqlx::query(
+            r#"
+            DO $$
+            BEGIN
+                IF NOT EXISTS (
+                    SELECT 1 FROM information_schema.columns
+                    WHERE table_name = 'virtual_keys' AND column_name = 'model_limits'
+                ) THEN
+                    ALTER TABLE virtual_keys ADD COLUMN model_limits JSONB NOT NULL DEFAULT '{}'::jsonb;
+                    ALTER TABLE virtual_keys ADD COLUMN model_spend JSONB NOT NULL DEFAULT '{}'::jsonb;
+                END IF;
+            END $$;
+            "#,
+        )
+        .execute(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        // Add max_parallel_requests column if it doesn't exist (in-flight request cap per key)
+        sqlx::query(
+            r#"
+            DO $$
+            BEGIN
+                IF NOT EXISTS (
+                    SELECT 1 FROM information_schema.columns
+                    WHERE table_name = 'virtual_keys' AND column_name = 'max_parallel_requests'
+                ) THEN
+                    ALTER TABLE virtual_keys ADD COLUMN max_parallel_requests INTEGER;
+                END IF;
+            END $$;
+            "#,
+        )
+        .execute(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        // Add previous-secret columns if they don't exist (key rotation with a grace period)
+        sqlx::query(
+            r#"
+            DO $$
+            BEGIN
+                IF NOT EXISTS (
+                    SELECT 1 FROM information_schema.columns
+                    WHERE table_name = 'virtual_keys' AND column_name = 'previous_key_hash'
+                ) THEN
+                    ALTER TABLE virtual_keys ADD COLUMN previous_key_hash TEXT;
+                    ALTER TABLE virtual_keys ADD COLUMN previous_key_lookup_hash VARCHAR(64);
+                    ALTER TABLE virtual_keys ADD COLUMN previous_key_prefix VARCHAR(20);
+                    ALTER TABLE virtual_keys ADD COLUMN previous_key_expires_at TIMESTAMP WITH TIME ZONE;
+                    ALTER TABLE virtual_keys ADD COLUMN previous_key_last_used_at TIMESTAMP WITH TIME ZONE;
+                END IF;
+            END $$;
+            "#,
+        )
+        .execute(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        // Add route, network and origin restriction columns if they don't exist
+        sqlx::query(
+            r#"
+            DO $$
+            BEGIN
+                IF NOT EXISTS (
+                    SELECT 1 FROM information_schema.columns
+                    WHERE table_name = 'virtual_keys' AND column_name = 'allowed_routes'
+                ) THEN
+                    ALTER TABLE virtual_keys ADD COLUMN allowed_routes TEXT[];
+                    ALTER TABLE virtual_keys ADD COLUMN allowed_ips TEXT[];
+                    ALTER TABLE virtual_keys ADD COLUMN allowed_origins TEXT[];
+                    ALTER TABLE virtual_keys ADD COLUMN read_only BOOLEAN NOT NULL DEFAULT FALSE;
+                END IF;
+            END $$;
+            "#,
+        )
+        .execute(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        sqlx::query(
+            r#"
+            CREATE INDEX IF NOT EXISTS idx_virtual_keys_previous_key_lookup_hash
+            ON virtual_keys(previous_key_lookup_hash)
+            WHERE previous_key_lookup_hash IS NOT NULL
+            "#,
+        )
+        .execute(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        // Add the model deny list column if it doesn't exist
+        sqlx::query(
+            r#"
+            DO $$
+            BEGIN
+                IF NOT EXISTS (
+                    SELECT 1 FROM information_schema.columns
+                    WHERE table_name = 'virtual_keys' AND column_name = 'denied_models'
+                ) THEN
+                    ALTER TABLE virtual_keys ADD COLUMN denied_models TEXT[];
+                END IF;
+            END $$;
+            "#,
+        )
+        .execute(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        // Create model_access_groups table (named model sets for "group:<name>" entries)
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS model_access_groups (
+                name VARCHAR(255) PRIMARY KEY,
+                models TEXT[] NOT NULL DEFAULT '{}',
+                created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+                updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+            )
+            "#,
+        )
+        .execute(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        // Create virtual_key_budget_history table (spend archived at each budget reset)
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS virtual_key_budget_history (
+                id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+                virtual_key_id UUID NOT NULL REFERENCES virtual_keys(id) ON DELETE CASCADE,
+                period_start TIMESTAMP WITH TIME ZONE NOT NULL,
+                period_end TIMESTAMP WITH TIME ZONE NOT NULL,
+                spend DOUBLE PRECISION NOT NULL,
+                created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+            )
+            "#,
+        )
+        .execute(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        // Add rate limit and budget columns to users (limits shared by all of a user's keys)
+        sqlx::query(
+            r#"
+            DO $$
+            BEGIN
+                IF NOT EXISTS (
+                    SELECT 1 FROM information_schema.columns
+                    WHERE table_name = 'users' AND column_name = 'rate_limit_rpm'
+                ) THEN
+                    ALTER TABLE users ADD COLUMN rate_limit_rpm INTEGER;
+                    ALTER TABLE users ADD COLUMN rate_limit_tpm INTEGER;
+                    ALTER TABLE users ADD COLUMN max_budget DOUBLE PRECISION;
+                    ALTER TABLE users ADD COLUMN current_spend DOUBLE PRECISION NOT NULL DEFAULT 0;
+                END IF;
+            END $$;
+            "#,
+        )
+        .execute(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        // Create global_limits table (single row of gateway-wide limits)
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS global_limits (
+                id BOOLEAN PRIMARY KEY DEFAULT true CHECK (id),
+                rate_limit_rpm INTEGER,
+                rate_limit_tpm INTEGER,
+                max_budget DOUBLE PRECISION,
+                current_spend DOUBLE PRECISION NOT NULL DEFAULT 0,
+                updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+            )
+            "#,
+        )
+        .execute(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        sqlx::query("INSERT INTO global_limits (id) VALUES (true) ON CONFLICT DO NOTHING")
+            .execute(pool)
+            .await
+            .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        // Create teams table (groups of users sharing keys, model access and a budget)
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS teams (
+                id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+                name VARCHAR(255) UNIQUE NOT NULL,
+                allowed_models TEXT[],
+                max_budget DOUBLE PRECISION,
+                current_spend DOUBLE PRECISION NOT NULL DEFAULT 0,
+                rate_limit_rpm INTEGER,
+                rate_limit_tpm INTEGER,
+                created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+                updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+            )
+            "#,
+        )
+        .execute(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        // Create team_members table
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS team_members (
+                team_id UUID NOT NULL REFERENCES teams(id) ON DELETE CASCADE,
+                user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+                role VARCHAR(50) NOT NULL DEFAULT 'member'
+                    CHECK (role IN ('owner', 'admin', 'member')),
+                created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+                PRIMARY KEY (team_id, user_id)
+            )
+            "#,
+        )
+        .execute(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        // Add team_id column to virtual_keys if it doesn't exist (team-owned keys)
+        sqlx::query(
+            r#"
+            DO $$
+            BEGIN
+                IF NOT EXISTS (
+                    SELECT 1 FROM information_schema.columns
+                    WHERE table_name = 'virtual_keys' AND column_name = 'team_id'
+                ) THEN
+                    ALTER TABLE virtual_keys ADD COLUMN team_id UUID REFERENCES teams(id) ON DELETE SET NULL;
+                END IF;
+            END $$;
+            "#,
+        )
+        .execute(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
         // Create sessions table
         sqlx::query(
             r#"
@@ -198,6 +496,109 @@ impl DatabaseManager {
         .await
         .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
 
+        // Add refresh token rotation and revocation columns if they don't exist
+        sqlx::query(
+            r#"
+            DO $$
+            BEGIN
+                IF NOT EXISTS (
+                    SELECT 1 FROM information_schema.columns
+                    WHERE table_name = 'sessions' AND column_name = 'revoked_at'
+                ) THEN
+                    ALTER TABLE sessions ADD COLUMN previous_token_hash VARCHAR(255);
+                    ALTER TABLE sessions ADD COLUMN revoked_at TIMESTAMP WITH TIME ZONE;
+                    ALTER TABLE sessions ADD COLUMN last_refreshed_at TIMESTAMP WITH TIME ZONE;
+                    DELETE FROM sessions;
+                END IF;
+            END $$;
+            "#,
+        )
+        .execute(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        // Add failed login tracking columns to users if they don't exist
+        sqlx::query(
+            r#"
+            DO $$
+            BEGIN
+                IF NOT EXISTS (
+                    SELECT 1 FROM information_schema.columns
+                    WHERE table_name = 'users' AND column_name = 'locked_until'
+                ) THEN
+                    ALTER TABLE users ADD COLUMN failed_login_attempts INTEGER NOT NULL DEFAULT 0;
+                    ALTER TABLE users ADD COLUMN last_failed_login_at TIMESTAMP WITH TIME ZONE;
+                    ALTER TABLE users ADD COLUMN locked_until TIMESTAMP WITH TIME ZONE;
+                END IF;
+            END $$;
+            "#,
+        )
+        .execute(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        // Create login_lockouts table (audit record of every account lockout)
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS login_lockouts (
+                id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+                user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+                ip_address VARCHAR(45),
+                failed_attempts INTEGER NOT NULL,
+                locked_until TIMESTAMP WITH TIME ZONE NOT NULL,
+                unlocked_at TIMESTAMP WITH TIME ZONE,
+                unlocked_by UUID REFERENCES users(id) ON DELETE SET NULL,
+                created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+            )
+            "#,
+        )
+        .execute(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        sqlx::query(
+            r#"
+            CREATE INDEX IF NOT EXISTS idx_login_lockouts_user_id
+            ON login_lockouts(user_id, created_at DESC)
+            "#,
+        )
+        .execute(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        // Create user_totp table (encrypted TOTP secrets, pending until a code is confirmed)
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS user_totp (
+                user_id UUID PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+                secret_encrypted TEXT NOT NULL,
+                enabled_at TIMESTAMP WITH TIME ZONE,
+                last_used_step BIGINT,
+                created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+            )
+            "#,
+        )
+        .execute(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        // Create totp_recovery_codes table (hashed single-use recovery codes)
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS totp_recovery_codes (
+                id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+                user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+                code_hash VARCHAR(64) NOT NULL,
+                used_at TIMESTAMP WITH TIME ZONE,
+                created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+                UNIQUE (user_id, code_hash)
+            )
+            "#,
+        )
+        .execute(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
         // Create usage_records table
         sqlx::query(
             r#"
@@ -287,6 +688,28 @@ impl DatabaseManager {
         .await
         .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
 
+        // Create index for the budget reset job
+        sqlx::query(
+            r#"
+            CREATE INDEX IF NOT EXISTS idx_virtual_keys_budget_reset_at
+            ON virtual_keys(budget_reset_at)
+            WHERE budget_period IS NOT NULL
+            "#,
+        )
+        .execute(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        sqlx::query(
+            r#"
+            CREATE INDEX IF NOT EXISTS idx_virtual_key_budget_history_key_id
+            ON virtual_key_budget_history(virtual_key_id, period_end DESC)
+            "#,
+        )
+        .execute(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
         // Create indexes for sessions
         sqlx::query(
             r#"
@@ -308,6 +731,17 @@ impl DatabaseManager {
         .await
         .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
 
+        sqlx::query(
+            r#"
+            CREATE INDEX IF NOT EXISTS idx_sessions_previous_token_hash
+            ON sessions(previous_token_hash)
+            WHERE previous_token_hash IS NOT NULL
+            "#,
+        )
+        .execute(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
         sqlx::query(
             r#"
             CREATE INDEX IF NOT EXISTS idx_sessions_expires_at
@@ -359,6 +793,55 @@ impl DatabaseManager {
         .await
         .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
 
+        // Add team_id column to usage_records if it doesn't exist (spend reporting per team)
+        sqlx::query(
+            r#"
+            DO $$
+            BEGIN
+                IF NOT EXISTS (
+                    SELECT 1 FROM information_schema.columns
+                    WHERE table_name = 'usage_records' AND column_name = 'team_id'
+                ) THEN
+                    ALTER TABLE usage_records ADD COLUMN team_id UUID REFERENCES teams(id) ON DELETE SET NULL;
+                END IF;
+            END $$;
+            "#,
+        )
+        .execute(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        sqlx::query(
+            r#"
+            CREATE INDEX IF NOT EXISTS idx_usage_records_team_id
+            ON usage_records(team_id, created_at DESC)
+            "#,
+        )
+        .execute(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        // Create indexes for teams
+        sqlx::query(
+            r#"
+            CREATE INDEX IF NOT EXISTS idx_team_members_user_id
+            ON team_members(user_id)
+            "#,
+        )
+        .execute(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        sqlx::query(
+            r#"
+            CREATE INDEX IF NOT EXISTS idx_virtual_keys_team_id
+            ON virtual_keys(team_id)
+            "#,
+        )
+        .execute(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
         // Create provider_keys table for storing provider API keys
         sqlx::query(
             r#"
@@ -375,23 +858,193 @@ impl DatabaseManager {
         .await
         .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
 
+        // Pending OAuth logins: state (hashed), PKCE verifier and nonce, consumed once
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS oauth_states (
+                state_hash VARCHAR(64) PRIMARY KEY,
+                provider VARCHAR(100) NOT NULL,
+                code_verifier VARCHAR(128) NOT NULL,
+                nonce VARCHAR(128) NOT NULL,
+                expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+                created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+            )
+            "#,
+        )
+        .execute(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        sqlx::query(
+            r#"
+            CREATE INDEX IF NOT EXISTS idx_oauth_states_expires_at
+            ON oauth_states(expires_at)
+            "#,
+        )
+        .execute(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        // Finished OAuth logins: one-time code (hashed) the frontend exchanges for a session
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS oauth_login_codes (
+                code_hash VARCHAR(64) PRIMARY KEY,
+                user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+                provider VARCHAR(100) NOT NULL,
+                expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+                created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+            )
+            "#,
+        )
+        .execute(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        // Create audit_events table (append-only; actors aren't foreign keys so events outlive them)
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS audit_events (
+                id BIGSERIAL PRIMARY KEY,
+                actor_type VARCHAR(20) NOT NULL,
+                actor_id UUID,
+                actor_email VARCHAR(255),
+                actor_key_id UUID,
+                action VARCHAR(100) NOT NULL,
+                target_type VARCHAR(50),
+                target_id VARCHAR(255),
+                changes JSONB,
+                ip_address VARCHAR(45),
+                user_agent TEXT,
+                created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+            )
+            "#,
+        )
+        .execute(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        sqlx::query(
+            r#"
+            CREATE INDEX IF NOT EXISTS idx_audit_events_actor_id
+            ON audit_events(actor_id, id)
+            "#,
+        )
+        .execute(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        sqlx::query(
+            r#"
+            CREATE INDEX IF NOT EXISTS idx_audit_events_target
+            ON audit_events(target_type, target_id, id)
+            "#,
+        )
+        .execute(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        sqlx::query(
+            r#"
+            CREATE INDEX IF NOT EXISTS idx_audit_events_action
+            ON audit_events(action, id)
+            "#,
+        )
+        .execute(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        sqlx::query(
+            r#"
+            CREATE INDEX IF NOT EXISTS idx_audit_events_created_at
+            ON audit_events(created_at)
+            "#,
+        )
+        .execute(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        // Refuse updates, deletes and truncation of audit events
+        sqlx::query(
+            r#"
+            CREATE OR REPLACE FUNCTION audit_events_append_only() RETURNS trigger AS $$
+            BEGIN
+                RAISE EXCEPTION 'audit_events is append-only';
+            END;
+            $$ LANGUAGE plpgsql
+            "#,
+        )
+        .execute(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        sqlx::query(
+            r#"
+            CREATE OR REPLACE TRIGGER audit_events_no_update
+            BEFORE UPDATE OR DELETE ON audit_events
+            FOR EACH ROW EXECUTE FUNCTION audit_events_append_only()
+            "#,
+        )
+        .execute(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        sqlx::query(
+            r#"
+            CREATE OR REPLACE TRIGGER audit_events_no_truncate
+            BEFORE TRUNCATE ON audit_events
+            FOR EACH STATEMENT EXECUTE FUNCTION audit_events_append_only()
+            "#,
+        )
+        .execute(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        // Add user disabling columns if they don't exist
+        sqlx::query(
+            r#"
+            DO $$
+            BEGIN
+                IF NOT EXISTS (
+                    SELECT 1 FROM information_schema.columns
+                    WHERE table_name = 'users' AND column_name = 'disabled_at'
+                ) THEN
+                    ALTER TABLE users ADD COLUMN disabled_at TIMESTAMP WITH TIME ZONE;
+                    ALTER TABLE virtual_keys ADD COLUMN blocked_by_user_disable BOOLEAN NOT NULL DEFAULT false;
+                END IF;
+            END $$;
+            "#,
+        )
+        .execute(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        // Add the budget anchor column if it doesn't exist; existing periodic budgets count
+        // their periods from the pending reset
+        sqlx::query(
+            r#"
+            DO $$
+            BEGIN
+                IF NOT EXISTS (
+                    SELECT 1 FROM information_schema.columns
+                    WHERE table_name = 'virtual_keys' AND column_name = 'budget_anchor_at'
+                ) THEN
+                    ALTER TABLE virtual_keys ADD COLUMN budget_anchor_at TIMESTAMP WITH TIME ZONE;
+                    UPDATE virtual_keys SET budget_anchor_at = budget_reset_at
+                    WHERE budget_period IS NOT NULL;
+                END IF;
+            END $$;
+            "#,
+        )
+        .execute(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
         info!("Database migrations completed successfully");
         Ok(())
     }
 
-    pub async fn record_usage(
-        &self,
-        model: &str,
-        provider: &str,
-        prompt_tokens: i32,
-        completion_tokens: i32,
-        total_tokens: i32,
-        cost_usd: f64,
-        latency_ms: i64,
-        user_id: Option<String>,
-        cached: bool,
-        error: Option<String>,
-    ) -> ApiResult<Uuid> {
+    pub async fn record_usage(&self, record: NewUsageRecord<'_>) -> ApiResult<Uuid> {
         if !self.enabled {
             return Ok(Uuid::new_v4());
         }
@@ -407,33 +1060,40 @@ impl DatabaseManager {
             r#"
             INSERT INTO usage_records
             (id, model, provider, prompt_tokens, completion_tokens, total_tokens,
-             cost_usd, latency_ms, user_id, cached, error)
-            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
+             cost_usd, latency_ms, user_id, virtual_key_id, team_id, cached, error)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13)
             "#,
         )
         .bind(id)
-        .bind(model)
-        .bind(provider)
-        .bind(prompt_tokens)
-        .bind(completion_tokens)
-        .bind(total_tokens)
-        .bind(cost_usd)
-        .bind(latency_ms)
-        .bind(user_id)
-        .bind(cached)
-        .bind(error)
+        .bind(record.model)
+        .bind(record.provider)
+        .bind(record.prompt_tokens)
+        .bind(record.completion_tokens)
+        .bind(record.total_tokens)
+        .bind(record.cost_usd)
+        .bind(record.latency_ms)
+        .bind(record.user_id)
+        .bind(record.virtual_key_id)
+        .bind(record.team_id)
+        .bind(record.cached)
+        .bind(record.error)
         .execute(pool)
         .await
         .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
 
         debug!(
             "Recorded usage for model: {}, tokens: {}",
-            model, total_tokens
+            record.model, record.total_tokens
         );
         Ok(id)
     }
 
-    pub async fn get_usage_stats(&self, days: i32) -> ApiResult<UsageStats> {
+    /// Usage over the last `days` days; `owner_id` restricts it to the keys a user owns
+    pub async fn get_usage_stats(
+        &self,
+        days: i32,
+        owner_id: Option<Uuid>,
+    ) -> ApiResult<UsageStats> {
         if !self.enabled {
             return Err(ApiError::DatabaseError(
                 "Database not available".to_string(),
@@ -455,9 +1115,11 @@ impl DatabaseManager {
                 CAST(COALESCE(AVG(latency_ms), 0) AS DOUBLE PRECISION) as average_latency_ms
             FROM usage_records
             WHERE created_at >= NOW() - INTERVAL '1 day' * $1
+              AND ($2::uuid IS NULL OR virtual_key_id IN (SELECT id FROM virtual_keys WHERE user_id = $2))
             "#,
         )
         .bind(days)
+        .bind(owner_id)
         .fetch_one(pool)
         .await
         .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
@@ -470,9 +1132,11 @@ impl DatabaseManager {
                 COUNT(*) as total_requests
             FROM usage_records
             WHERE created_at >= NOW() - INTERVAL '1 day' * $1
+              AND ($2::uuid IS NULL OR virtual_key_id IN (SELECT id FROM virtual_keys WHERE user_id = $2))
             "#,
         )
         .bind(days)
+        .bind(owner_id)
         .fetch_one(pool)
         .await
         .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
@@ -493,11 +1157,13 @@ impl DatabaseManager {
                 CAST(COALESCE(SUM(cost_usd), 0) AS DOUBLE PRECISION) as total_cost
             FROM usage_records
             WHERE created_at >= NOW() - INTERVAL '1 day' * $1
+              AND ($2::uuid IS NULL OR virtual_key_id IN (SELECT id FROM virtual_keys WHERE user_id = $2))
             GROUP BY model
             ORDER BY count DESC
             "#,
         )
         .bind(days)
+        .bind(owner_id)
         .fetch_all(pool)
         .await
         .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
@@ -512,11 +1178,36 @@ impl DatabaseManager {
                 CAST(COALESCE(SUM(cost_usd), 0) AS DOUBLE PRECISION) as total_cost
             FROM usage_records
             WHERE created_at >= NOW() - INTERVAL '1 day' * $1
+              AND ($2::uuid IS NULL OR virtual_key_id IN (SELECT id FROM virtual_keys WHERE user_id = $2))
             GROUP BY provider
             ORDER BY count DESC
             "#,
         )
         .bind(days)
+        .bind(owner_id)
+        .fetch_all(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        // Get stats by team (usage of keys without a team is left out)
+        let team_stats: Vec<TeamStats> = sqlx::query_as(
+            r#"
+            SELECT
+                t.id as team_id,
+                t.name as team_name,
+                COUNT(*) as count,
+                COALESCE(SUM(u.total_tokens), 0) as total_tokens,
+                CAST(COALESCE(SUM(u.cost_usd), 0) AS DOUBLE PRECISION) as total_cost
+            FROM usage_records u
+            JOIN teams t ON t.id = u.team_id
+            WHERE u.created_at >= NOW() - INTERVAL '1 day' * $1
+              AND ($2::uuid IS NULL OR u.virtual_key_id IN (SELECT id FROM virtual_keys WHERE user_id = $2))
+            GROUP BY t.id, t.name
+            ORDER BY total_cost DESC
+            "#,
+        )
+        .bind(days)
+        .bind(owner_id)
         .fetch_all(pool)
         .await
         .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
@@ -529,10 +1220,16 @@ impl DatabaseManager {
             cache_hit_rate,
             requests_by_model: model_stats,
             requests_by_provider: provider_stats,
+            requests_by_team: team_stats,
         })
     }
 
-    pub async fn get_recent_usage(&self, limit: i64) -> ApiResult<Vec<UsageRecord>> {
+    /// Most recent requests; `owner_id` restricts them to the keys a user owns
+    pub async fn get_recent_usage(
+        &self,
+        limit: i64,
+        owner_id: Option<Uuid>,
+    ) -> ApiResult<Vec<UsageRecord>> {
         if !self.enabled {
             return Err(ApiError::DatabaseError(
                 "Database not available".to_string(),
@@ -547,13 +1244,15 @@ impl DatabaseManager {
         let records: Vec<UsageRecord> = sqlx::query_as(
             r#"
             SELECT id, model, provider, prompt_tokens, completion_tokens,
-                   total_tokens, cost_usd, latency_ms, user_id, cached, error, created_at
+                   total_tokens, cost_usd, latency_ms, user_id, team_id, cached, error, created_at
             FROM usage_records
+            WHERE $2::uuid IS NULL OR virtual_key_id IN (SELECT id FROM virtual_keys WHERE user_id = $2)
             ORDER BY created_at DESC
             LIMIT $1
             "#,
         )
         .bind(limit)
+        .bind(owner_id)
         .fetch_all(pool)
         .await
         .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
@@ -574,8 +1273,10 @@ impl DatabaseManager {
             .as_ref()
             .ok_or_else(|| ApiError::DatabaseError("Database pool not available".to_string()))?;
 
-        // For now, we'll store the key as-is. In production, you should encrypt it.
-        // TODO: Implement proper encryption using a secret key from environment
+        let api_key_encrypted = self
+            .encryptor
+            .encrypt(api_key, &provider_key_context(provider_id))?;
+
         sqlx::query(
             r#"
             INSERT INTO provider_keys (provider_id, api_key_encrypted, updated_at)
@@ -585,7 +1286,7 @@ impl DatabaseManager {
             "#,
         )
         .bind(provider_id)
-        .bind(api_key)
+        .bind(&api_key_encrypted)
         .execute(pool)
         .await
         .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
@@ -616,7 +1317,13 @@ impl DatabaseManager {
         .await
         .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
 
-        Ok(result.map(|r| r.0))
+        result
+            .map(|(stored,)| {
+                self.encryptor
+                    .decrypt(&stored, &provider_key_context(provider_id))
+                    .map_err(ApiError::from)
+            })
+            .transpose()
     }
 
     /// Delete a provider API key
@@ -668,6 +1375,38 @@ impl DatabaseManager {
         .await
         .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
 
-        Ok(results)
+        let stale = results
+            .iter()
+            .filter(|(_, stored)| self.encryptor.needs_reencryption(stored))
+            .count();
+        if stale > 0 {
+            tracing::warn!(
+                "{} provider keys are not encrypted with the current key; run reencrypt_secrets",
+                stale
+            );
+        }
+
+        // A key that can't be decrypted is skipped so the gateway still starts,
+        // falling back to the environment for that provider
+        Ok(results
+            .into_iter()
+            .filter_map(|(provider_id, stored)| {
+                match self
+                    .encryptor
+                    .decrypt(&stored, &provider_key_context(&provider_id))
+                {
+                    Ok(api_key) => Some((provider_id, api_key)),
+                    Err(e) => {
+                        error!("Failed to decrypt provider key for {}: {}", provider_id, e);
+                        None
+                    }
+                }
+            })
+            .collect())
     }
 }
+
+/// Authenticated context binding a provider key ciphertext to its row
+fn provider_key_context(provider_id: &str) -> String {
+    format!("provider_keys:{}", provider_id)
+}
@@ -1,5 +1,5 @@
 use axum::{
-    http::StatusCode,
+    http::{header, HeaderValue, StatusCode},
     response::{IntoResponse, Response},
     Json,
 };
@@ -25,9 +25,17 @@ pub enum ApiError {
     #[error("Authentication failed")]
     AuthenticationFailed,
 
+    /// Password was right, but the account needs a TOTP or recovery code too
+    #[error("Two-factor authentication code required")]
+    TotpRequired,
+
     #[error("Forbidden")]
     Forbidden,
 
+    /// Credentials were right, but an admin has disabled the account
+    #[error("Account is disabled")]
+    AccountDisabled,
+
     #[error("Not found: {0}")]
     NotFound(String),
 
@@ -43,6 +51,10 @@ pub enum ApiError {
     #[error("Rate limit exceeded")]
     RateLimitExceeded,
 
+    /// Too many login or registration attempts; retry after this many seconds
+    #[error("Too many attempts")]
+    TooManyAttempts(u64),
+
     #[error("Internal server error")]
     InternalServerError,
 
@@ -78,7 +90,17 @@ impl IntoResponse for ApiError {
                 "Authentication failed".to_string(),
                 "AuthenticationFailed",
             ),
+            ApiError::TotpRequired => (
+                StatusCode::UNAUTHORIZED,
+                "Two-factor authentication code required".to_string(),
+                "TotpRequired",
+            ),
             ApiError::Forbidden => (StatusCode::FORBIDDEN, "Forbidden".to_string(), "Forbidden"),
+            ApiError::AccountDisabled => (
+                StatusCode::FORBIDDEN,
+                "Account is disabled".to_string(),
+                "AccountDisabled",
+            ),
             ApiError::NotFound(msg) => (StatusCode::NOT_FOUND, msg.clone(), "NotFound"),
             ApiError::BadRequest(msg) => (StatusCode::BAD_REQUEST, msg.clone(), "BadRequest"),
             ApiError::ExternalApiError(msg) => {
@@ -94,6 +116,11 @@ impl IntoResponse for ApiError {
                 "Rate limit exceeded".to_string(),
                 "RateLimitExceeded",
             ),
+            ApiError::TooManyAttempts(retry_after) => (
+                StatusCode::TOO_MANY_REQUESTS,
+                format!("Too many attempts, try again in {} seconds", retry_after),
+                "TooManyAttempts",
+            ),
             ApiError::InternalServerError => (
                 StatusCode::INTERNAL_SERVER_ERROR,
                 "Internal server error".to_string(),
@@ -132,6 +159,18 @@ impl IntoResponse for ApiError {
             }
         }));
 
-        (status, body).into_response()
+        let mut response = (status, body).into_response();
+        if let ApiError::TooManyAttempts(retry_after) = &self {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(*retry_after));
+        }
+        response
+    }
+}
+
+impl From<crate::crypto::CryptoError> for ApiError {
+    fn from(e: crate::crypto::CryptoError) -> Self {
+        ApiError::InternalError(e.to_string())
     }
 }
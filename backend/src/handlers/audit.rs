@@ -0,0 +1,147 @@
+use axum::{
+    body::Body,
+    extract::{Query, State},
+    http::header,
+    response::{IntoResponse, Response},
+    Json,
+};
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+use crate::{
+    audit::{AuditContext, AuditRecord},
+    auth::{require, Authorized},
+    error::{ApiError, ApiResult},
+    models::{AuditEvent, AuditEventFilter},
+    AppState,
+};
+
+// ============================================================================
+// Audit Log
+// ============================================================================
+
+const DEFAULT_AUDIT_PAGE_SIZE: i64 = 100;
+const MAX_AUDIT_PAGE_SIZE: i64 = 1000;
+
+/// Events read from the database at a time while exporting
+const AUDIT_EXPORT_BATCH_SIZE: i64 = 1000;
+
+#[derive(Debug, Deserialize)]
+pub struct AuditPageQuery {
+    /// `next_before` of the previous page
+    pub before: Option<i64>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AuditEventsResponse {
+    pub events: Vec<AuditEvent>,
+    /// Pass as `before` to get the next (older) page; absent on the last page
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_before: Option<i64>,
+}
+
+/// List audit events, newest first (requires the ViewAuditLog permission)
+///
+/// Filters: `actor_id`, `action` (exact, or a prefix like `key.`), `target_type`,
+/// `target_id`, `since` and `until` (RFC 3339).
+pub async fn list_audit_events(
+    State(state): State<Arc<AppState>>,
+    _auth: Authorized<require::ViewAuditLog>,
+    Query(filter): Query<AuditEventFilter>,
+    Query(page): Query<AuditPageQuery>,
+) -> ApiResult<Json<AuditEventsResponse>> {
+    let pool = state
+        .database
+        .get_pool()
+        .ok_or_else(|| ApiError::DatabaseError("Database not available".to_string()))?;
+
+    let limit = page
+        .limit
+        .unwrap_or(DEFAULT_AUDIT_PAGE_SIZE)
+        .clamp(1, MAX_AUDIT_PAGE_SIZE);
+    // One extra event tells whether there's another page
+    let mut events = AuditEvent::list(pool, &filter, page.before, limit + 1).await?;
+    let next_before = if events.len() as i64 > limit {
+        events.truncate(limit as usize);
+        events.last().map(|event| event.id)
+    } else {
+        None
+    };
+
+    Ok(Json(AuditEventsResponse {
+        events,
+        next_before,
+    }))
+}
+
+/// Export the audit events matching the same filters as JSON Lines, oldest first
+/// (requires the ViewAuditLog permission)
+pub async fn export_audit_events(
+    State(state): State<Arc<AppState>>,
+    _auth: Authorized<require::ViewAuditLog>,
+    audit: AuditContext,
+    Query(filter): Query<AuditEventFilter>,
+) -> ApiResult<Response> {
+    let pool = state
+        .database
+        .get_pool()
+        .ok_or_else(|| ApiError::DatabaseError("Database not available".to_string()))?
+        .clone();
+
+    audit
+        .record(
+            &pool,
+            AuditRecord::new("audit.export").details(serde_json::json!({
+                "actor_id": filter.actor_id,
+                "action": filter.action,
+                "target_type": filter.target_type,
+                "target_id": filter.target_id,
+                "since": filter.since,
+                "until": filter.until,
+            })),
+        )
+        .await;
+
+    // Streamed in batches so large exports don't have to fit in memory
+    let lines = async_stream::stream! {
+        let mut after_id = 0;
+        loop {
+            let events =
+                match AuditEvent::list_after(&pool, &filter, after_id, AUDIT_EXPORT_BATCH_SIZE)
+                    .await
+                {
+                    Ok(events) => events,
+                    Err(e) => {
+                        tracing::error!("Audit export failed after event {}: {}", after_id, e);
+                        yield Err(std::io::Error::other(e.to_string()));
+                        break;
+                    }
+                };
+
+            let done = (events.len() as i64) < AUDIT_EXPORT_BATCH_SIZE;
+            for event in events {
+                after_id = event.id;
+                let mut line = serde_json::to_vec(&event).unwrap_or_default();
+                line.push(b'\n');
+                yield Ok(Bytes::from(line));
+            }
+            if done {
+                break;
+            }
+        }
+    };
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/x-ndjson"),
+            (
+                header::CONTENT_DISPOSITION,
+                "attachment; filename=\"audit-events.jsonl\"",
+            ),
+        ],
+        Body::from_stream(lines),
+    )
+        .into_response())
+}
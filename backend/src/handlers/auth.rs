@@ -1,24 +1,34 @@
 use axum::{
-    extract::{Query, State},
-    http::StatusCode,
+    extract::{ConnectInfo, Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
     response::{IntoResponse, Redirect},
     Json,
 };
-use base64::Engine;
 use chrono::Utc;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
 use uuid::Uuid;
 
 use crate::{
+    audit::{AuditActor, AuditContext, AuditRecord},
     auth::{
-        create_lookup_hash, generate_token, generate_virtual_key, get_key_prefix, hash_password,
-        hash_token, hash_virtual_key, validate_master_key_format, verify_password, AuthUser,
-        GitHubOAuthProvider, OAuthProvider,
+        authorize_key, can_loosen_key_limits, client_ip, cookie_value, generate_login_code,
+        generate_refresh_token, generate_token, generate_virtual_key, get_key_prefix, hash_state,
+        hash_token, hash_virtual_key, loosens_key_limits, sign_state, state_cookie,
+        validate_master_key_format, verify_password, verify_state_cookie, AuthType, AuthUser,
+        KeyAccess, OAuthFlow, Permission, MAX_PASSWORD_LENGTH, OAUTH_LOGIN_CODE_TTL_SECONDS,
+        OAUTH_STATE_COOKIE, OAUTH_STATE_TTL_SECONDS,
     },
+    budget::BudgetPeriod,
     error::{ApiError, ApiResult},
+    handlers::{totp_enrollment_pending, verify_second_factor},
     models::{
-        CreateVirtualKeyRequest, OAuthAccount, Session, User, VirtualKey, VirtualKeyResponse,
+        validate_key_restrictions, validate_max_parallel_requests, validate_model_entries,
+        validate_model_limits, CreateVirtualKeyRequest, KeyUpdate, LoginLockout, ModelLimit,
+        NewVirtualKey, OAuthAccount, OAuthLoginCode, OAuthState, Session, Team,
+        UpdateKeyRestrictions, User, UserTotp, VirtualKey, VirtualKeyResponse,
     },
     AppState,
 };
@@ -36,10 +46,20 @@ pub struct RegisterRequest {
 
 #[derive(Debug, Serialize)]
 pub struct AuthResponse {
-    pub token: String,
+    #[serde(flatten)]
+    pub tokens: SessionTokens,
     pub user: UserResponse,
 }
 
+/// Tokens for a session: a short-lived access token and the refresh token that renews it
+#[derive(Debug, Serialize)]
+pub struct SessionTokens {
+    pub token: String,
+    pub refresh_token: String,
+    /// Seconds until `token` expires
+    pub expires_in: i64,
+}
+
 #[derive(Debug, Serialize)]
 pub struct UserResponse {
     pub id: Uuid,
@@ -48,9 +68,68 @@ pub struct UserResponse {
     pub role: String,
 }
 
+/// Start a session for a user and issue its first tokens
+async fn start_session(
+    state: &AppState,
+    pool: &sqlx::Pool<sqlx::Postgres>,
+    user: &User,
+) -> ApiResult<SessionTokens> {
+    let refresh_token = generate_refresh_token();
+    let expires_at = Utc::now() + chrono::Duration::hours(state.config.session_ttl_hours);
+    let session = Session::create(pool, user.id, hash_token(&refresh_token), expires_at).await?;
+
+    session_tokens(state, pool, user, session.id, refresh_token).await
+}
+
+async fn session_tokens(
+    state: &AppState,
+    pool: &sqlx::Pool<sqlx::Postgres>,
+    user: &User,
+    session_id: Uuid,
+    refresh_token: String,
+) -> ApiResult<SessionTokens> {
+    let token = generate_token(
+        user.id,
+        user.email.clone(),
+        user.role.clone(),
+        session_id,
+        totp_enrollment_pending(state, pool, user).await?,
+        &state.jwt_keys,
+        state.config.access_token_ttl_seconds,
+    )?;
+
+    Ok(SessionTokens {
+        token,
+        refresh_token,
+        expires_in: state.config.access_token_ttl_seconds,
+    })
+}
+
+/// Refuse emails outside `allowed_email_domains`, when it's configured
+pub(crate) fn check_email_domain(state: &AppState, email: &str) -> ApiResult<()> {
+    let Some(allowed_domains) = &state.config.allowed_email_domains else {
+        return Ok(());
+    };
+    let email_domain = email
+        .split('@')
+        .nth(1)
+        .ok_or_else(|| ApiError::BadRequest("Invalid email format".to_string()))?;
+
+    if !allowed_domains.iter().any(|d| d == email_domain) {
+        return Err(ApiError::BadRequest(format!(
+            "Email domain '{}' is not allowed",
+            email_domain
+        )));
+    }
+    Ok(())
+}
+
 /// Register a new user with email and password
 pub async fn register(
     State(state): State<Arc<AppState>>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    audit: AuditContext,
     Json(request): Json<RegisterRequest>,
 ) -> ApiResult<Json<AuthResponse>> {
     let pool = state
@@ -58,24 +137,19 @@ pub async fn register(
         .get_pool()
         .ok_or_else(|| ApiError::DatabaseError("Database not available".to_string()))?;
 
-    // Validate email domain if configured
-    if let Some(allowed_domains) = &state.config.allowed_email_domains {
-        let email_domain = request
-            .email
-            .split('@')
-            .nth(1)
-            .ok_or_else(|| ApiError::BadRequest("Invalid email format".to_string()))?;
-
-        if !allowed_domains.iter().any(|d| d == email_domain) {
-            return Err(ApiError::BadRequest(format!(
-                "Email domain '{}' is not allowed",
-                email_domain
-            )));
-        }
-    }
+    let ip = request_ip(&state, connect_info, &headers);
+    state
+        .login_throttle
+        .check(&state.rate_limiter, ip, None)
+        .await?;
+
+    check_email_domain(&state, &request.email)?;
 
-    // Hash password
-    let password_hash = hash_password(&request.password)?;
+    state
+        .password_policy
+        .check(&request.password, &request.email)?;
+    state.password_policy.check_pwned(&request.password).await?;
+    let password_hash = state.password_hasher.hash(&request.password)?;
 
     // Create user
     let user = User::create(
@@ -86,23 +160,20 @@ pub async fn register(
         "user".to_string(),
     )
     .await?;
+    audit
+        .with_actor(AuditActor::user(user.id, &user.email))
+        .record(
+            pool,
+            AuditRecord::new("user.register")
+                .target("user", user.id)
+                .created(&user),
+        )
+        .await;
 
-    // Generate JWT token
-    let token = generate_token(
-        user.id,
-        user.email.clone(),
-        user.role.clone(),
-        &state.config.jwt_secret,
-        state.config.jwt_expiry_hours,
-    )?;
-
-    // Create session
-    let token_hash = hash_token(&token);
-    let expires_at = Utc::now() + chrono::Duration::hours(state.config.jwt_expiry_hours);
-    Session::create(pool, user.id, token_hash, expires_at).await?;
+    let tokens = start_session(&state, pool, &user).await?;
 
     Ok(Json(AuthResponse {
-        token,
+        tokens,
         user: UserResponse {
             id: user.id,
             email: user.email,
@@ -116,11 +187,16 @@ pub async fn register(
 pub struct LoginRequest {
     pub email: String,
     pub password: String,
+    /// Current TOTP code, or a recovery code, for users with two-factor authentication
+    pub totp_code: Option<String>,
 }
 
 /// Login with email and password
 pub async fn login(
     State(state): State<Arc<AppState>>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    audit: AuditContext,
     Json(request): Json<LoginRequest>,
 ) -> ApiResult<Json<AuthResponse>> {
     let pool = state
@@ -128,37 +204,95 @@ pub async fn login(
         .get_pool()
         .ok_or_else(|| ApiError::DatabaseError("Database not available".to_string()))?;
 
-    // Find user by email
-    let user = User::find_by_email(pool, &request.email)
-        .await?
-        .ok_or_else(|| ApiError::AuthenticationFailed)?;
+    let ip = request_ip(&state, connect_info, &headers);
+    state
+        .login_throttle
+        .check(&state.rate_limiter, ip, Some(&request.email))
+        .await?;
 
-    // Verify password
-    let password_hash = user
-        .password_hash
-        .as_ref()
-        .ok_or_else(|| ApiError::AuthenticationFailed)?;
+    // No stored password is this long; don't spend a hash on it
+    if request.password.chars().count() > MAX_PASSWORD_LENGTH {
+        return Err(ApiError::AuthenticationFailed);
+    }
 
-    if !verify_password(&request.password, password_hash)? {
+    // Find user by email
+    let Some(user) = User::find_by_email(pool, &request.email).await? else {
+        audit
+            .record(
+                pool,
+                AuditRecord::new("user.login_failed").details(serde_json::json!({
+                    "email": request.email,
+                    "reason": "unknown_email",
+                })),
+            )
+            .await;
+        tokio::time::sleep(state.login_throttle.failure_delay(1)).await;
         return Err(ApiError::AuthenticationFailed);
+    };
+    let audit = audit.with_actor(AuditActor::user(user.id, &user.email));
+
+    if let Some(locked_until) = LoginLockout::locked_until(pool, user.id).await? {
+        audit
+            .record(
+                pool,
+                AuditRecord::new("user.login_failed")
+                    .target("user", user.id)
+                    .details(serde_json::json!({ "reason": "locked" })),
+            )
+            .await;
+        return Err(ApiError::TooManyAttempts(seconds_until(locked_until)));
     }
 
-    // Generate JWT token
-    let token = generate_token(
-        user.id,
-        user.email.clone(),
-        user.role.clone(),
-        &state.config.jwt_secret,
-        state.config.jwt_expiry_hours,
-    )?;
+    // Verify password
+    let verified = match &user.password_hash {
+        Some(password_hash) => verify_password(&request.password, password_hash)?,
+        None => false,
+    };
+    if !verified {
+        return Err(reject_login(&state, pool, &user, ip, &audit, "invalid_password").await?);
+    }
+
+    // Second factor, once the user has enrolled; wrong codes count as failed logins
+    let totp = UserTotp::find_enabled(pool, user.id).await?;
+    if let Some(totp) = &totp {
+        let Some(code) = &request.totp_code else {
+            return Err(ApiError::TotpRequired);
+        };
+        if !verify_second_factor(&state, pool, totp, code).await? {
+            return Err(reject_login(&state, pool, &user, ip, &audit, "invalid_totp_code").await?);
+        }
+    }
+    LoginLockout::clear_failures(pool, user.id).await?;
+    if let Some(password_hash) = &user.password_hash {
+        rehash_password(&state, pool, user.id, &request.password, password_hash).await;
+    }
+    if user.disabled_at.is_some() {
+        audit
+            .record(
+                pool,
+                AuditRecord::new("user.login_failed")
+                    .target("user", user.id)
+                    .details(serde_json::json!({ "reason": "disabled" })),
+            )
+            .await;
+        return Err(ApiError::AccountDisabled);
+    }
+    audit
+        .record(
+            pool,
+            AuditRecord::new("user.login")
+                .target("user", user.id)
+                .details(serde_json::json!({
+                    "method": "password",
+                    "two_factor": totp.is_some(),
+                })),
+        )
+        .await;
 
-    // Create session
-    let token_hash = hash_token(&token);
-    let expires_at = Utc::now() + chrono::Duration::hours(state.config.jwt_expiry_hours);
-    Session::create(pool, user.id, token_hash, expires_at).await?;
+    let tokens = start_session(&state, pool, &user).await?;
 
     Ok(Json(AuthResponse {
-        token,
+        tokens,
         user: UserResponse {
             id: user.id,
             email: user.email,
@@ -168,22 +302,208 @@ pub async fn login(
     }))
 }
 
-/// Logout (invalidate session)
+/// Replace a bcrypt hash, or an Argon2 hash with outdated parameters, now that the
+/// password is known; failing only delays the upgrade to the next login
+async fn rehash_password(
+    state: &AppState,
+    pool: &sqlx::Pool<sqlx::Postgres>,
+    user_id: Uuid,
+    password: &str,
+    password_hash: &str,
+) {
+    if !state.password_hasher.needs_rehash(password_hash) {
+        return;
+    }
+    let result = match state.password_hasher.hash(password) {
+        Ok(new_hash) => User::update_password(pool, user_id, new_hash).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        tracing::warn!("Failed to rehash password of user {}: {}", user_id, e);
+    }
+}
+
+/// The client address login attempts are throttled by
+fn request_ip(
+    state: &AppState,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    headers: &HeaderMap,
+) -> Option<IpAddr> {
+    let peer = connect_info.map(|ConnectInfo(addr)| addr);
+    client_ip(headers, peer, state.config.trust_forwarded_for)
+}
+
+/// Count a failed login and pick the error to answer with: a lockout if this failure
+/// locked the account, otherwise `AuthenticationFailed` after the progressive delay
+async fn reject_login(
+    state: &AppState,
+    pool: &sqlx::Pool<sqlx::Postgres>,
+    user: &User,
+    ip: Option<IpAddr>,
+    audit: &AuditContext,
+    reason: &str,
+) -> ApiResult<ApiError> {
+    let throttle = &state.login_throttle;
+    let failure = LoginLockout::record_failure(
+        pool,
+        user.id,
+        ip.map(|ip| ip.to_string()),
+        throttle.lockout_threshold,
+        throttle.lockout_seconds,
+    )
+    .await?;
+    audit
+        .record(
+            pool,
+            AuditRecord::new("user.login_failed")
+                .target("user", user.id)
+                .details(serde_json::json!({
+                    "reason": reason,
+                    "consecutive_failures": failure.attempts,
+                })),
+        )
+        .await;
+
+    if let Some(lockout) = failure.lockout {
+        audit
+            .record(
+                pool,
+                AuditRecord::new("user.lock")
+                    .target("user", user.id)
+                    .created(&lockout),
+            )
+            .await;
+        tracing::warn!(
+            "🔒 Locked {} until {} after {} failed logins (last from {})",
+            user.email,
+            lockout.locked_until,
+            lockout.failed_attempts,
+            lockout.ip_address.as_deref().unwrap_or("unknown address")
+        );
+        return Ok(ApiError::TooManyAttempts(seconds_until(
+            lockout.locked_until,
+        )));
+    }
+
+    tokio::time::sleep(throttle.failure_delay(failure.attempts)).await;
+    Ok(ApiError::AuthenticationFailed)
+}
+
+/// Whole seconds until `time`, at least 1, for `Retry-After`
+fn seconds_until(time: chrono::DateTime<Utc>) -> u64 {
+    (time - Utc::now()).num_seconds().max(1) as u64
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RefreshRequest {
+    pub refresh_token: String,
+}
+
+/// Exchange a refresh token for a new access token and refresh token
+///
+/// Each refresh token works once. Presenting one that has already been exchanged means
+/// it was copied, so the whole session is revoked; clients must not refresh concurrently.
+pub async fn refresh_session(
+    State(state): State<Arc<AppState>>,
+    audit: AuditContext,
+    Json(request): Json<RefreshRequest>,
+) -> ApiResult<Json<SessionTokens>> {
+    let pool = state
+        .database
+        .get_pool()
+        .ok_or_else(|| ApiError::DatabaseError("Database not available".to_string()))?;
+
+    let token_hash = hash_token(&request.refresh_token);
+    let refresh_token = generate_refresh_token();
+    let Some(session) = Session::rotate(pool, &token_hash, &hash_token(&refresh_token)).await?
+    else {
+        if Session::revoke_reused(pool, &token_hash).await? {
+            tracing::warn!("Refresh token was reused; revoked its session");
+            audit
+                .record(pool, AuditRecord::new("session.refresh_token_reused"))
+                .await;
+        }
+        return Err(ApiError::AuthenticationFailed);
+    };
+
+    let user = User::find_by_id(pool, session.user_id)
+        .await?
+        .filter(|user| user.disabled_at.is_none())
+        .ok_or(ApiError::AuthenticationFailed)?;
+
+    Ok(Json(
+        session_tokens(&state, pool, &user, session.id, refresh_token).await?,
+    ))
+}
+
+/// Logout (revoke the current session)
 pub async fn logout(
     State(state): State<Arc<AppState>>,
     auth_user: AuthUser,
+    audit: AuditContext,
 ) -> ApiResult<StatusCode> {
     let pool = state
         .database
         .get_pool()
         .ok_or_else(|| ApiError::DatabaseError("Database not available".to_string()))?;
 
-    // Delete all sessions for the user
-    Session::delete_by_user(pool, auth_user.user_id).await?;
+    if let AuthType::JWT { session_id } = auth_user.auth_type {
+        Session::revoke(pool, session_id).await?;
+        audit
+            .record(
+                pool,
+                AuditRecord::new("user.logout").target("session", session_id),
+            )
+            .await;
+    }
 
     Ok(StatusCode::OK)
 }
 
+#[derive(Debug, Serialize)]
+pub struct RevokeSessionsResponse {
+    pub revoked_sessions: u64,
+}
+
+/// Log out everywhere (revoke every session of the current user)
+pub async fn logout_all(
+    State(state): State<Arc<AppState>>,
+    auth_user: AuthUser,
+    audit: AuditContext,
+) -> ApiResult<Json<RevokeSessionsResponse>> {
+    let pool = state
+        .database
+        .get_pool()
+        .ok_or_else(|| ApiError::DatabaseError("Database not available".to_string()))?;
+
+    let revoked_sessions = Session::revoke_by_user(pool, auth_user.user_id).await?;
+    audit
+        .record(
+            pool,
+            AuditRecord::new("user.logout_all")
+                .target("user", auth_user.user_id)
+                .details(serde_json::json!({ "revoked_sessions": revoked_sessions })),
+        )
+        .await;
+
+    Ok(Json(RevokeSessionsResponse { revoked_sessions }))
+}
+
+/// List the current user's active sessions
+pub async fn list_sessions(
+    State(state): State<Arc<AppState>>,
+    auth_user: AuthUser,
+) -> ApiResult<Json<Vec<Session>>> {
+    let pool = state
+        .database
+        .get_pool()
+        .ok_or_else(|| ApiError::DatabaseError("Database not available".to_string()))?;
+
+    Ok(Json(
+        Session::find_active_by_user(pool, auth_user.user_id).await?,
+    ))
+}
+
 /// Get current user info
 pub async fn get_current_user(
     State(state): State<Arc<AppState>>,
@@ -207,7 +527,7 @@ pub async fn get_current_user(
 }
 
 // ============================================================================
-// OAuth (GitHub)
+// OAuth (GitHub, Google, generic OpenID Connect)
 // ============================================================================
 
 #[derive(Debug, Deserialize)]
@@ -216,44 +536,90 @@ pub struct OAuthCallbackQuery {
     pub state: String,
 }
 
-/// Initiate GitHub OAuth flow
-pub async fn github_oauth_start(
+#[derive(Debug, Deserialize)]
+pub struct OAuthExchangeRequest {
+    /// Login code the OAuth callback redirected to the frontend with
+    pub code: String,
+    /// Current TOTP code, or a recovery code, for users with two-factor authentication
+    pub totp_code: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct OAuthProvidersResponse {
+    pub providers: Vec<String>,
+}
+
+/// List the configured OAuth providers, for rendering sign-in buttons
+pub async fn list_oauth_providers(
     State(state): State<Arc<AppState>>,
-) -> ApiResult<Json<OAuthStartResponse>> {
-    let github_client_id = state
-        .config
-        .github_client_id
-        .as_ref()
-        .ok_or_else(|| ApiError::BadRequest("GitHub OAuth not configured".to_string()))?;
+) -> Json<OAuthProvidersResponse> {
+    Json(OAuthProvidersResponse {
+        providers: state.oauth.names().into_iter().map(String::from).collect(),
+    })
+}
 
-    let github_client_secret = state
-        .config
-        .github_client_secret
-        .as_ref()
-        .ok_or_else(|| ApiError::BadRequest("GitHub OAuth not configured".to_string()))?;
+/// Public keys access tokens can be verified with (`/.well-known/jwks.json`)
+/// Empty while tokens are signed with the shared `JWT_SECRET`.
+pub async fn jwks(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    (
+        [(header::CACHE_CONTROL, "public, max-age=300")],
+        Json(state.jwt_keys.jwks()),
+    )
+}
 
-    let provider = GitHubOAuthProvider::new(github_client_id.clone(), github_client_secret.clone());
+/// Initiate the OAuth flow for a provider (`/auth/oauth/{provider}`)
+/// Browsers navigate here directly: the pending login is stored server-side, bound to
+/// the browser with a signed cookie, and the response redirects to the provider.
+pub async fn oauth_start(
+    State(state): State<Arc<AppState>>,
+    Path(provider_name): Path<String>,
+) -> ApiResult<impl IntoResponse> {
+    let pool = state
+        .database
+        .get_pool()
+        .ok_or_else(|| ApiError::DatabaseError("Database not available".to_string()))?;
 
-    // Generate random state for CSRF protection
-    let state_token = generate_virtual_key(); // Reuse key generation for random string
+    let provider = state.oauth.get(&provider_name).await?;
 
-    let auth_url = provider.authorize_url(&state_token, &state.config.oauth_redirect_url);
+    let flow = OAuthFlow::new();
+    OAuthState::create(
+        pool,
+        &OAuthState {
+            state_hash: hash_state(&flow.state),
+            provider: provider.name().to_string(),
+            code_verifier: flow.code_verifier.clone(),
+            nonce: flow.nonce.clone(),
+            expires_at: Utc::now() + chrono::Duration::seconds(OAUTH_STATE_TTL_SECONDS),
+        },
+    )
+    .await?;
 
-    Ok(Json(OAuthStartResponse {
-        auth_url,
-        state: state_token,
-    }))
+    let auth_url = provider.authorize_url_with_pkce(
+        &flow.state,
+        &state.config.oauth_redirect_url,
+        &flow.code_challenge(),
+        &flow.nonce,
+    );
+
+    let cookie = state_cookie(
+        &sign_state(&flow.state, &state.config.jwt_secret),
+        OAUTH_STATE_TTL_SECONDS,
+        secure_cookies(&state),
+    );
+
+    Ok(([(header::SET_COOKIE, cookie)], Redirect::to(&auth_url)))
 }
 
-#[derive(Debug, Serialize)]
-pub struct OAuthStartResponse {
-    pub auth_url: String,
-    pub state: String,
+/// Only mark cookies Secure when the callback is served over HTTPS
+fn secure_cookies(state: &AppState) -> bool {
+    state.config.oauth_redirect_url.starts_with("https://")
 }
 
 /// Handle OAuth callback (all providers)
 pub async fn oauth_callback(
     State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    audit: AuditContext,
     Query(query): Query<OAuthCallbackQuery>,
 ) -> ApiResult<impl IntoResponse> {
     let pool = state
@@ -261,44 +627,46 @@ pub async fn oauth_callback(
         .get_pool()
         .ok_or_else(|| ApiError::DatabaseError("Database not available".to_string()))?;
 
-    // For now, assume GitHub (can be extended with provider parameter)
-    let github_client_id = state
-        .config
-        .github_client_id
-        .as_ref()
-        .ok_or_else(|| ApiError::BadRequest("GitHub OAuth not configured".to_string()))?;
-
-    let github_client_secret = state
-        .config
-        .github_client_secret
-        .as_ref()
-        .ok_or_else(|| ApiError::BadRequest("GitHub OAuth not configured".to_string()))?;
+    // The state must come back to the browser that started the login...
+    let bound_to_browser = cookie_value(&headers, OAUTH_STATE_COOKIE)
+        .is_some_and(|cookie| verify_state_cookie(cookie, &query.state, &state.config.jwt_secret));
+    if !bound_to_browser {
+        return Err(ApiError::BadRequest(
+            "OAuth state does not match this browser".to_string(),
+        ));
+    }
 
-    let provider = GitHubOAuthProvider::new(github_client_id.clone(), github_client_secret.clone());
+    // ...and match a pending login that hasn't been used or expired
+    let pending = OAuthState::consume(pool, &hash_state(&query.state))
+        .await?
+        .ok_or_else(|| ApiError::BadRequest("Unknown or expired OAuth state".to_string()))?;
+    let provider = state.oauth.get(&pending.provider).await?;
 
     // Exchange code for tokens
     let tokens = provider
-        .exchange_code(&query.code, &state.config.oauth_redirect_url)
+        .exchange_code(
+            &query.code,
+            &state.config.oauth_redirect_url,
+            &pending.code_verifier,
+        )
         .await?;
 
-    // Get user info from provider
-    let oauth_user_info = provider.get_user_info(&tokens.access_token).await?;
-
-    // Validate email domain if configured
-    if let Some(allowed_domains) = &state.config.allowed_email_domains {
-        let email_domain = oauth_user_info
-            .email
-            .split('@')
-            .nth(1)
-            .ok_or_else(|| ApiError::BadRequest("Invalid email format".to_string()))?;
-
-        if !allowed_domains.iter().any(|d| d == email_domain) {
-            return Err(ApiError::BadRequest(format!(
-                "Email domain '{}' is not allowed",
-                email_domain
-            )));
-        }
-    }
+    // Get user info from provider (verified ID token claims for OpenID Connect)
+    let oauth_user_info = provider.user_info(&tokens, &pending.nonce).await?;
+
+    check_email_domain(&state, &oauth_user_info.email)?;
+
+    // Provider tokens are stored encrypted, bound to the account they belong to
+    let encryptor = state.database.encryptor();
+    let token_context = |kind: &str| {
+        OAuthAccount::token_context(provider.name(), &oauth_user_info.provider_user_id, kind)
+    };
+    let access_token = encryptor.encrypt(&tokens.access_token, &token_context("access_token"))?;
+    let refresh_token = tokens
+        .refresh_token
+        .as_deref()
+        .map(|token| encryptor.encrypt(token, &token_context("refresh_token")))
+        .transpose()?;
 
     // Check if OAuth account exists
     let oauth_account =
@@ -313,8 +681,8 @@ pub async fn oauth_callback(
             provider.name().to_string(),
             oauth_user_info.provider_user_id.clone(),
             oauth_user_info.username.clone(),
-            Some(tokens.access_token.clone()),
-            tokens.refresh_token.clone(),
+            Some(access_token),
+            refresh_token,
             None,
         )
         .await?;
@@ -339,48 +707,160 @@ pub async fn oauth_callback(
             provider.name().to_string(),
             oauth_user_info.provider_user_id,
             oauth_user_info.username,
-            Some(tokens.access_token),
-            tokens.refresh_token,
+            Some(access_token),
+            refresh_token,
             None,
         )
         .await?;
+        audit
+            .with_actor(AuditActor::user(user.id, &user.email))
+            .record(
+                pool,
+                AuditRecord::new("user.register")
+                    .target("user", user.id)
+                    .created(&user)
+                    .details(serde_json::json!({ "oauth_provider": provider.name() })),
+            )
+            .await;
 
         user
     };
 
-    // Generate JWT token
-    let token = generate_token(
-        user.id,
-        user.email.clone(),
-        user.role.clone(),
-        &state.config.jwt_secret,
-        state.config.jwt_expiry_hours,
-    )?;
-
-    // Create session
-    let token_hash = hash_token(&token);
-    let expires_at = Utc::now() + chrono::Duration::hours(state.config.jwt_expiry_hours);
-    Session::create(pool, user.id, token_hash, expires_at).await?;
+    let audit = audit.with_actor(AuditActor::user(user.id, &user.email));
+    if user.disabled_at.is_some() {
+        audit
+            .record(
+                pool,
+                AuditRecord::new("user.login_failed")
+                    .target("user", user.id)
+                    .details(serde_json::json!({
+                        "method": provider.name(),
+                        "reason": "disabled",
+                    })),
+            )
+            .await;
+        return Err(ApiError::AccountDisabled);
+    }
 
-    // Encode user data as JSON for passing to frontend
-    let user_data = serde_json::to_string(&UserResponse {
-        id: user.id,
-        email: user.email,
-        username: user.username,
-        role: user.role,
-    })
-    .map_err(|e| ApiError::InternalError(format!("Failed to serialize user data: {}", e)))?;
+    // Sync role and team memberships from provider groups, when it sends them
+    let user = match &oauth_user_info.groups {
+        Some(groups) => {
+            state
+                .oauth
+                .group_mappings
+                .apply(pool, &user, groups)
+                .await?;
+            User::find_by_id(pool, user.id)
+                .await?
+                .ok_or_else(|| ApiError::NotFound("User not found".to_string()))?
+        }
+        None => user,
+    };
 
-    // Redirect to frontend with token and user data as URL parameters
-    // Using base64 encoding for cleaner URLs and to avoid URL encoding issues
-    let user_data_encoded = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(&user_data);
+    // The frontend exchanges the code for the session over POST (see oauth_exchange)
+    let code = generate_login_code();
+    OAuthLoginCode::create(
+        pool,
+        &OAuthLoginCode {
+            code_hash: hash_state(&code),
+            user_id: user.id,
+            provider: provider.name().to_string(),
+            expires_at: Utc::now() + chrono::Duration::seconds(OAUTH_LOGIN_CODE_TTL_SECONDS),
+        },
+    )
+    .await?;
 
     let redirect_url = format!(
-        "{}/auth/oauth/callback?token={}&user={}",
-        state.config.frontend_url, token, user_data_encoded
+        "{}/auth/oauth/callback?login_code={}",
+        state.config.frontend_url, code
     );
 
-    Ok(Redirect::to(&redirect_url))
+    let clear_cookie = state_cookie("", 0, secure_cookies(&state));
+
+    Ok((
+        [(header::SET_COOKIE, clear_cookie)],
+        Redirect::to(&redirect_url),
+    ))
+}
+
+/// Exchange the one-time code of a finished OAuth login for a session
+///
+/// Users with two-factor authentication must also send a TOTP or recovery code, as for
+/// password logins; the login code stays valid until it's given or the code expires.
+pub async fn oauth_exchange(
+    State(state): State<Arc<AppState>>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    audit: AuditContext,
+    Json(request): Json<OAuthExchangeRequest>,
+) -> ApiResult<Json<AuthResponse>> {
+    let pool = state
+        .database
+        .get_pool()
+        .ok_or_else(|| ApiError::DatabaseError("Database not available".to_string()))?;
+
+    let code_hash = hash_state(&request.code);
+    let login = OAuthLoginCode::find(pool, &code_hash)
+        .await?
+        .ok_or(ApiError::AuthenticationFailed)?;
+    let user = User::find_by_id(pool, login.user_id)
+        .await?
+        .ok_or(ApiError::AuthenticationFailed)?;
+    let audit = audit.with_actor(AuditActor::user(user.id, &user.email));
+
+    // The account may have been disabled since the callback
+    if user.disabled_at.is_some() {
+        return Err(ApiError::AccountDisabled);
+    }
+
+    // Second factor, once the user has enrolled; wrong codes count as failed logins
+    let totp = UserTotp::find_enabled(pool, user.id).await?;
+    if let Some(totp) = &totp {
+        let ip = request_ip(&state, connect_info, &headers);
+        state
+            .login_throttle
+            .check(&state.rate_limiter, ip, Some(&user.email))
+            .await?;
+        if let Some(locked_until) = LoginLockout::locked_until(pool, user.id).await? {
+            return Err(ApiError::TooManyAttempts(seconds_until(locked_until)));
+        }
+
+        let Some(code) = &request.totp_code else {
+            return Err(ApiError::TotpRequired);
+        };
+        if !verify_second_factor(&state, pool, totp, code).await? {
+            return Err(reject_login(&state, pool, &user, ip, &audit, "invalid_totp_code").await?);
+        }
+        LoginLockout::clear_failures(pool, user.id).await?;
+    }
+
+    // Only one of several concurrent exchanges of the same code gets a session
+    if OAuthLoginCode::consume(pool, &code_hash).await?.is_none() {
+        return Err(ApiError::AuthenticationFailed);
+    }
+    audit
+        .record(
+            pool,
+            AuditRecord::new("user.login")
+                .target("user", user.id)
+                .details(serde_json::json!({
+                    "method": login.provider,
+                    "two_factor": totp.is_some(),
+                })),
+        )
+        .await;
+
+    let tokens = start_session(&state, pool, &user).await?;
+
+    Ok(Json(AuthResponse {
+        tokens,
+        user: UserResponse {
+            id: user.id,
+            email: user.email,
+            username: user.username,
+            role: user.role,
+        },
+    }))
 }
 
 // ============================================================================
@@ -391,6 +871,7 @@ pub async fn oauth_callback(
 pub async fn generate_key(
     State(state): State<Arc<AppState>>,
     auth_user: AuthUser,
+    audit: AuditContext,
     Json(request): Json<CreateVirtualKeyRequest>,
 ) -> ApiResult<Json<VirtualKeyResponse>> {
     let pool = state
@@ -400,8 +881,7 @@ pub async fn generate_key(
 
     // Generate new key
     let key = generate_virtual_key();
-    let key_hash = hash_virtual_key(&key)?;
-    let key_lookup_hash = create_lookup_hash(&key);
+    let key_hash = hash_virtual_key(&key, &state.config.key_hash_pepper);
     let key_prefix = get_key_prefix(&key);
 
     // Determine user_id (master key creates system keys, JWT creates user keys)
@@ -410,33 +890,91 @@ pub async fn generate_key(
         _ => Some(auth_user.user_id),
     };
 
+    // Any team member can create keys billed to the team
+    if let (Some(team_id), Some(user_id)) = (request.team_id, user_id) {
+        if Team::member_role(pool, team_id, user_id).await?.is_none() {
+            return Err(ApiError::Forbidden);
+        }
+    }
+
+    // Periodic budgets start their first period now
+    let budget_period = request
+        .budget_period
+        .as_deref()
+        .map(BudgetPeriod::parse)
+        .transpose()?;
+    let budget_anchor_at = budget_period.map(|_| Utc::now());
+    let budget_reset_at = budget_period
+        .zip(budget_anchor_at)
+        .map(|(period, anchor)| period.next_reset(anchor, anchor));
+
+    let model_limits = request.model_limits.unwrap_or_default();
+    validate_model_limits(&model_limits)?;
+    validate_max_parallel_requests(request.max_parallel_requests)?;
+    for entries in [&request.allowed_models, &request.denied_models]
+        .into_iter()
+        .flatten()
+    {
+        validate_model_entries(entries, true)?;
+    }
+    validate_key_restrictions(
+        request.restrictions.allowed_routes.as_deref(),
+        request.restrictions.allowed_ips.as_deref(),
+        request.restrictions.allowed_origins.as_deref(),
+    )?;
+
     let virtual_key = VirtualKey::create(
         pool,
-        key_hash,
-        key_lookup_hash,
-        key_prefix.clone(),
-        user_id,
-        request.name,
-        request.max_budget,
-        request.rate_limit_rpm,
-        request.rate_limit_tpm,
-        request.allowed_models,
-        request.expires_at,
+        NewVirtualKey {
+            key_hash,
+            key_lookup_hash: None,
+            key_prefix: key_prefix.clone(),
+            user_id,
+            team_id: request.team_id,
+            name: request.name,
+            max_budget: request.max_budget,
+            rate_limit_rpm: request.rate_limit_rpm,
+            rate_limit_tpm: request.rate_limit_tpm,
+            allowed_models: request.allowed_models,
+            denied_models: request.denied_models,
+            expires_at: request.expires_at,
+            budget_period: budget_period.map(|period| period.to_string()),
+            budget_reset_at,
+            budget_anchor_at,
+            model_limits,
+            max_parallel_requests: request.max_parallel_requests,
+            restrictions: request.restrictions,
+        },
     )
     .await?;
+    audit
+        .record(
+            pool,
+            AuditRecord::new("key.create")
+                .target("virtual_key", virtual_key.id)
+                .created(&virtual_key),
+        )
+        .await;
 
     Ok(Json(VirtualKeyResponse {
         id: virtual_key.id,
         key, // Only returned on creation
         key_prefix,
+        team_id: virtual_key.team_id,
         name: virtual_key.name,
         max_budget: virtual_key.max_budget,
         current_spend: virtual_key.current_spend,
         rate_limit_rpm: virtual_key.rate_limit_rpm,
         rate_limit_tpm: virtual_key.rate_limit_tpm,
         allowed_models: virtual_key.allowed_models,
+        denied_models: virtual_key.denied_models,
         expires_at: virtual_key.expires_at,
         blocked: virtual_key.blocked,
+        budget_period: virtual_key.budget_period,
+        budget_reset_at: virtual_key.budget_reset_at,
+        model_limits: virtual_key.model_limits,
+        max_parallel_requests: virtual_key.max_parallel_requests,
+        restrictions: virtual_key.restrictions,
         created_at: virtual_key.created_at,
     }))
 }
@@ -461,11 +999,20 @@ pub struct KeyIdPath {
     pub key_id: Uuid,
 }
 
-/// Get key info (requires master key)
+#[derive(Debug, Serialize)]
+pub struct KeyInfoResponse {
+    #[serde(flatten)]
+    pub key: VirtualKey,
+    /// Requests currently being served for this key, across all replicas
+    pub in_flight_requests: i64,
+}
+
+/// Get key info (requires key owner, team member or a role that can view every key)
 pub async fn get_key_info(
     State(state): State<Arc<AppState>>,
+    auth_user: AuthUser,
     Query(query): Query<KeyIdPath>,
-) -> ApiResult<Json<VirtualKey>> {
+) -> ApiResult<Json<KeyInfoResponse>> {
     let pool = state
         .database
         .get_pool()
@@ -475,7 +1022,17 @@ pub async fn get_key_info(
         .await?
         .ok_or_else(|| ApiError::NotFound("Key not found".to_string()))?;
 
-    Ok(Json(key))
+    authorize_key(pool, &auth_user, &key, KeyAccess::View).await?;
+
+    let in_flight_requests = state
+        .concurrency_limiter
+        .in_flight(&key.id.to_string())
+        .await?;
+
+    Ok(Json(KeyInfoResponse {
+        key,
+        in_flight_requests,
+    }))
 }
 
 #[derive(Debug, Deserialize)]
@@ -486,13 +1043,26 @@ pub struct UpdateKeyRequest {
     pub rate_limit_rpm: Option<i32>,
     pub rate_limit_tpm: Option<i32>,
     pub allowed_models: Option<Vec<String>>,
+    /// Replaces the key's deny list when set; an empty list removes it
+    pub denied_models: Option<Vec<String>>,
     pub blocked: Option<bool>,
+    /// Starts a new budget period from now; "none" turns the budget into a lifetime budget
+    pub budget_period: Option<String>,
+    /// Replaces the key's per-model limits when set
+    pub model_limits: Option<HashMap<String, ModelLimit>>,
+    pub max_parallel_requests: Option<i32>,
+    #[serde(flatten)]
+    pub restrictions: UpdateKeyRestrictions,
 }
 
-/// Update virtual key (requires master key or key owner)
+/// Update virtual key (requires key owner, team admin or a role that can manage every key)
+///
+/// Only roles that manage every key and admins of the key's team may loosen its limits;
+/// owners can tighten them.
 pub async fn update_key(
     State(state): State<Arc<AppState>>,
     auth_user: AuthUser,
+    audit: AuditContext,
     Json(request): Json<UpdateKeyRequest>,
 ) -> ApiResult<Json<VirtualKey>> {
     let pool = state
@@ -500,37 +1070,82 @@ pub async fn update_key(
         .get_pool()
         .ok_or_else(|| ApiError::DatabaseError("Database not available".to_string()))?;
 
-    // Verify ownership if not master key
-    if !matches!(auth_user.auth_type, crate::auth::AuthType::MasterKey) {
-        let key = VirtualKey::find_by_id(pool, request.key_id)
-            .await?
-            .ok_or_else(|| ApiError::NotFound("Key not found".to_string()))?;
+    let key = VirtualKey::find_by_id(pool, request.key_id)
+        .await?
+        .ok_or_else(|| ApiError::NotFound("Key not found".to_string()))?;
 
-        if key.user_id != Some(auth_user.user_id) {
-            return Err(ApiError::Forbidden);
-        }
+    authorize_key(pool, &auth_user, &key, KeyAccess::Manage).await?;
+
+    // Changing the budget period starts a fresh period from now; "none" removes it
+    let budget_period = request
+        .budget_period
+        .as_deref()
+        .map(BudgetPeriod::parse_update)
+        .transpose()?;
+    let budget_anchor_at = budget_period.flatten().map(|_| Utc::now());
+    let budget_reset_at = budget_period
+        .flatten()
+        .zip(budget_anchor_at)
+        .map(|(period, anchor)| period.next_reset(anchor, anchor));
+
+    if let Some(model_limits) = &request.model_limits {
+        validate_model_limits(model_limits)?;
+    }
+    validate_max_parallel_requests(request.max_parallel_requests)?;
+    validate_key_restrictions(
+        request.restrictions.allowed_routes.as_deref(),
+        request.restrictions.allowed_ips.as_deref(),
+        request.restrictions.allowed_origins.as_deref(),
+    )?;
+    for entries in [&request.allowed_models, &request.denied_models]
+        .into_iter()
+        .flatten()
+    {
+        validate_model_entries(entries, true)?;
     }
 
-    let updated_key = VirtualKey::update(
-        pool,
-        request.key_id,
-        request.name,
-        request.max_budget,
-        request.rate_limit_rpm,
-        request.rate_limit_tpm,
-        request.allowed_models,
-        None,
-        request.blocked,
-    )
-    .await?;
+    let update = KeyUpdate {
+        name: request.name,
+        max_budget: request.max_budget,
+        rate_limit_rpm: request.rate_limit_rpm,
+        rate_limit_tpm: request.rate_limit_tpm,
+        allowed_models: request.allowed_models,
+        denied_models: request.denied_models,
+        expires_at: None,
+        blocked: request.blocked,
+        budget_period: budget_period
+            .map(|period| period.map(|period| period.to_string()).unwrap_or_default()),
+        budget_reset_at,
+        budget_anchor_at,
+        model_limits: request.model_limits,
+        max_parallel_requests: request.max_parallel_requests,
+        restrictions: request.restrictions,
+    };
+    if loosens_key_limits(&key, &update) && !can_loosen_key_limits(pool, &auth_user, &key).await? {
+        return Err(ApiError::Forbidden);
+    }
+
+    let updated_key = VirtualKey::update(pool, request.key_id, update).await?;
+
+    // Blocking or restricting a key applies to the next request, not after the cache expires
+    state.key_cache.invalidate(&updated_key).await;
+    audit
+        .record(
+            pool,
+            AuditRecord::new("key.update")
+                .target("virtual_key", key.id)
+                .updated(&key, &updated_key),
+        )
+        .await;
 
     Ok(Json(updated_key))
 }
 
-/// Delete virtual key (requires master key or key owner)
+/// Delete virtual key (requires key owner, team admin or a role that can manage every key)
 pub async fn delete_key(
     State(state): State<Arc<AppState>>,
     auth_user: AuthUser,
+    audit: AuditContext,
     Query(query): Query<KeyIdPath>,
 ) -> ApiResult<StatusCode> {
     let pool = state
@@ -538,18 +1153,167 @@ pub async fn delete_key(
         .get_pool()
         .ok_or_else(|| ApiError::DatabaseError("Database not available".to_string()))?;
 
-    // Verify ownership if not master key
-    if !matches!(auth_user.auth_type, crate::auth::AuthType::MasterKey) {
-        let key = VirtualKey::find_by_id(pool, query.key_id)
-            .await?
-            .ok_or_else(|| ApiError::NotFound("Key not found".to_string()))?;
+    let key = VirtualKey::find_by_id(pool, query.key_id)
+        .await?
+        .ok_or_else(|| ApiError::NotFound("Key not found".to_string()))?;
+
+    authorize_key(pool, &auth_user, &key, KeyAccess::Manage).await?;
+
+    VirtualKey::delete(pool, query.key_id).await?;
+    state.key_cache.invalidate(&key).await;
+    audit
+        .record(
+            pool,
+            AuditRecord::new("key.delete")
+                .target("virtual_key", key.id)
+                .deleted(&key),
+        )
+        .await;
+
+    Ok(StatusCode::OK)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MoveKeyRequest {
+    pub key_id: Uuid,
+    /// Destination team, or None to make it a personal key again
+    pub team_id: Option<Uuid>,
+}
+
+/// Move a key to another team (requires managing the key and being an owner/admin of the
+/// destination team); future usage is billed to the new team
+pub async fn move_key(
+    State(state): State<Arc<AppState>>,
+    auth_user: AuthUser,
+    audit: AuditContext,
+    Json(request): Json<MoveKeyRequest>,
+) -> ApiResult<Json<VirtualKey>> {
+    let pool = state
+        .database
+        .get_pool()
+        .ok_or_else(|| ApiError::DatabaseError("Database not available".to_string()))?;
+
+    let key = VirtualKey::find_by_id(pool, request.key_id)
+        .await?
+        .ok_or_else(|| ApiError::NotFound("Key not found".to_string()))?;
+
+    authorize_key(pool, &auth_user, &key, KeyAccess::Manage).await?;
+
+    if let Some(team_id) = request.team_id {
+        if Team::find_by_id(pool, team_id).await?.is_none() {
+            return Err(ApiError::NotFound("Team not found".to_string()));
+        }
 
-        if key.user_id != Some(auth_user.user_id) {
+        let manages_target = Team::member_role(pool, team_id, auth_user.user_id)
+            .await?
+            .is_some_and(|role| role.can_manage());
+        if !auth_user.has(Permission::ManageAllKeys) && !manages_target {
             return Err(ApiError::Forbidden);
         }
     }
 
-    VirtualKey::delete(pool, query.key_id).await?;
+    let moved_key = VirtualKey::set_team(pool, request.key_id, request.team_id).await?;
+    state.key_cache.invalidate(&moved_key).await;
+    audit
+        .record(
+            pool,
+            AuditRecord::new("key.move")
+                .target("virtual_key", key.id)
+                .updated(&key, &moved_key),
+        )
+        .await;
 
-    Ok(StatusCode::OK)
+    Ok(Json(moved_key))
+}
+
+/// Longest grace period a rotation can give the previous secret
+const MAX_KEY_ROTATION_GRACE_SECONDS: i64 = 30 * 24 * 3600;
+
+#[derive(Debug, Deserialize)]
+pub struct RotateKeyRequest {
+    pub key_id: Uuid,
+    /// How long the current secret keeps working; defaults to KEY_ROTATION_GRACE_SECONDS,
+    /// 0 revokes it immediately
+    pub grace_period_seconds: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RotateKeyResponse {
+    pub id: Uuid,
+    pub key: String, // Only returned on rotation
+    pub key_prefix: String,
+    pub previous_key_prefix: Option<String>,
+    pub previous_key_expires_at: Option<chrono::DateTime<Utc>>,
+}
+
+/// Issue a new secret for a key (requires key owner, team admin or a role that can manage
+/// every key); limits, spend and history are kept and the old secret works until the
+/// grace period ends
+pub async fn rotate_key(
+    State(state): State<Arc<AppState>>,
+    auth_user: AuthUser,
+    audit: AuditContext,
+    Json(request): Json<RotateKeyRequest>,
+) -> ApiResult<Json<RotateKeyResponse>> {
+    let pool = state
+        .database
+        .get_pool()
+        .ok_or_else(|| ApiError::DatabaseError("Database not available".to_string()))?;
+
+    let key = VirtualKey::find_by_id(pool, request.key_id)
+        .await?
+        .ok_or_else(|| ApiError::NotFound("Key not found".to_string()))?;
+
+    authorize_key(pool, &auth_user, &key, KeyAccess::Manage).await?;
+
+    let grace_period_seconds = request
+        .grace_period_seconds
+        .unwrap_or(state.config.key_rotation_grace_seconds);
+    if !(0..=MAX_KEY_ROTATION_GRACE_SECONDS).contains(&grace_period_seconds) {
+        return Err(ApiError::BadRequest(format!(
+            "grace_period_seconds must be between 0 and {}",
+            MAX_KEY_ROTATION_GRACE_SECONDS
+        )));
+    }
+
+    let new_key = generate_virtual_key();
+    let rotated = VirtualKey::rotate(
+        pool,
+        key.id,
+        hash_virtual_key(&new_key, &state.config.key_hash_pepper),
+        get_key_prefix(&new_key),
+        Utc::now() + chrono::Duration::seconds(grace_period_seconds),
+    )
+    .await?;
+
+    // Cached copies of the key, under either old secret, predate the rotation
+    state.key_cache.invalidate(&key).await;
+
+    tracing::info!(
+        "Rotated key {} ({} -> {}), previous secret valid for {}s",
+        rotated.id,
+        key.key_prefix,
+        rotated.key_prefix,
+        grace_period_seconds
+    );
+    audit
+        .record(
+            pool,
+            AuditRecord::new("key.rotate")
+                .target("virtual_key", rotated.id)
+                .details(serde_json::json!({
+                    "previous_key_prefix": key.key_prefix,
+                    "key_prefix": rotated.key_prefix,
+                    "grace_period_seconds": grace_period_seconds,
+                })),
+        )
+        .await;
+
+    Ok(Json(RotateKeyResponse {
+        id: rotated.id,
+        key: new_key,
+        key_prefix: rotated.key_prefix,
+        previous_key_prefix: rotated.previous_key_prefix,
+        previous_key_expires_at: rotated.previous_key_expires_at,
+    }))
 }
@@ -0,0 +1,123 @@
+use axum::{
+    extract::{Query, State},
+    Json,
+};
+use serde::Deserialize;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::{
+    audit::{AuditContext, AuditRecord},
+    auth::{require, AuthUser, Authorized, Permission},
+    error::{ApiError, ApiResult},
+    models::{ScopeLimits, UpdateLimitsRequest},
+    AppState,
+};
+
+// ============================================================================
+// User and Global Limits
+// ============================================================================
+
+#[derive(Debug, Deserialize)]
+pub struct UserIdQuery {
+    pub user_id: Uuid,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateUserLimitsRequest {
+    pub user_id: Uuid,
+    #[serde(flatten)]
+    pub limits: UpdateLimitsRequest,
+}
+
+/// Get the gateway-wide rate limits and budget
+pub async fn get_global_limits(
+    State(state): State<Arc<AppState>>,
+    _auth: Authorized<require::ManageLimits>,
+) -> ApiResult<Json<ScopeLimits>> {
+    let pool = state
+        .database
+        .get_pool()
+        .ok_or_else(|| ApiError::DatabaseError("Database not available".to_string()))?;
+
+    Ok(Json(ScopeLimits::find_global(pool).await?))
+}
+
+/// Update the gateway-wide rate limits and budget
+pub async fn update_global_limits(
+    State(state): State<Arc<AppState>>,
+    _auth: Authorized<require::ManageLimits>,
+    audit: AuditContext,
+    Json(request): Json<UpdateLimitsRequest>,
+) -> ApiResult<Json<ScopeLimits>> {
+    let pool = state
+        .database
+        .get_pool()
+        .ok_or_else(|| ApiError::DatabaseError("Database not available".to_string()))?;
+
+    let previous = ScopeLimits::find_global(pool).await?;
+    let limits = ScopeLimits::update_global(pool, &request).await?;
+    // Every cached principal carries the global limits
+    state.key_cache.invalidate_all().await;
+    audit
+        .record(
+            pool,
+            AuditRecord::new("limits.update")
+                .target("global", "global")
+                .updated(&previous, &limits),
+        )
+        .await;
+
+    Ok(Json(limits))
+}
+
+/// Get a user's rate limits and budget (admins, or the user themselves)
+pub async fn get_user_limits(
+    State(state): State<Arc<AppState>>,
+    auth_user: AuthUser,
+    Query(query): Query<UserIdQuery>,
+) -> ApiResult<Json<ScopeLimits>> {
+    if !auth_user.has(Permission::ManageLimits) && auth_user.user_id != query.user_id {
+        return Err(ApiError::Forbidden);
+    }
+
+    let pool = state
+        .database
+        .get_pool()
+        .ok_or_else(|| ApiError::DatabaseError("Database not available".to_string()))?;
+
+    let limits = ScopeLimits::find_for_user(pool, query.user_id)
+        .await?
+        .ok_or_else(|| ApiError::NotFound("User not found".to_string()))?;
+
+    Ok(Json(limits))
+}
+
+/// Update a user's rate limits and budget
+pub async fn update_user_limits(
+    State(state): State<Arc<AppState>>,
+    _auth: Authorized<require::ManageLimits>,
+    audit: AuditContext,
+    Json(request): Json<UpdateUserLimitsRequest>,
+) -> ApiResult<Json<ScopeLimits>> {
+    let pool = state
+        .database
+        .get_pool()
+        .ok_or_else(|| ApiError::DatabaseError("Database not available".to_string()))?;
+
+    let previous = ScopeLimits::find_for_user(pool, request.user_id)
+        .await?
+        .ok_or_else(|| ApiError::NotFound("User not found".to_string()))?;
+    let limits = ScopeLimits::update_for_user(pool, request.user_id, &request.limits).await?;
+    state.key_cache.invalidate_all().await;
+    audit
+        .record(
+            pool,
+            AuditRecord::new("limits.update")
+                .target("user", request.user_id)
+                .updated(&previous, &limits),
+        )
+        .await;
+
+    Ok(Json(limits))
+}
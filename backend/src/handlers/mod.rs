@@ -1,5 +1,17 @@
+pub mod audit;
 pub mod auth;
+pub mod limits;
+pub mod model_access;
 pub mod provider;
+pub mod team;
+pub mod totp;
+pub mod users;
 
+pub use audit::*;
 pub use auth::*;
+pub use limits::*;
+pub use model_access::*;
 pub use provider::*;
+pub use team::*;
+pub use totp::*;
+pub use users::*;
@@ -0,0 +1,93 @@
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    Json,
+};
+use serde::Deserialize;
+use std::sync::Arc;
+
+use crate::{
+    audit::{AuditContext, AuditRecord},
+    auth::{require, Authorized},
+    error::{ApiError, ApiResult},
+    models::{validate_model_entries, ModelAccessGroup, UpsertModelAccessGroupRequest},
+    AppState,
+};
+
+// ============================================================================
+// Model Access Groups
+// ============================================================================
+
+#[derive(Debug, Deserialize)]
+pub struct ModelAccessGroupQuery {
+    pub name: String,
+}
+
+/// List model access groups
+pub async fn list_model_access_groups(
+    State(state): State<Arc<AppState>>,
+    _auth: Authorized<require::ManageProviders>,
+) -> ApiResult<Json<Vec<ModelAccessGroup>>> {
+    let pool = state
+        .database
+        .get_pool()
+        .ok_or_else(|| ApiError::DatabaseError("Database not available".to_string()))?;
+
+    Ok(Json(ModelAccessGroup::list(pool).await?))
+}
+
+/// Create a model access group, or replace the models of an existing one
+pub async fn upsert_model_access_group(
+    State(state): State<Arc<AppState>>,
+    _auth: Authorized<require::ManageProviders>,
+    audit: AuditContext,
+    Json(request): Json<UpsertModelAccessGroupRequest>,
+) -> ApiResult<Json<ModelAccessGroup>> {
+    let pool = state
+        .database
+        .get_pool()
+        .ok_or_else(|| ApiError::DatabaseError("Database not available".to_string()))?;
+
+    if request.name.trim().is_empty() {
+        return Err(ApiError::BadRequest("Group name is required".to_string()));
+    }
+    validate_model_entries(&request.models, false)?;
+
+    let group = ModelAccessGroup::upsert(pool, &request).await?;
+    // Cached principals carry the models of the groups their keys and teams refer to
+    state.key_cache.invalidate_all().await;
+    audit
+        .record(
+            pool,
+            AuditRecord::new("model_group.upsert")
+                .target("model_group", &group.name)
+                .created(&group),
+        )
+        .await;
+
+    Ok(Json(group))
+}
+
+/// Delete a model access group
+pub async fn delete_model_access_group(
+    State(state): State<Arc<AppState>>,
+    _auth: Authorized<require::ManageProviders>,
+    audit: AuditContext,
+    Query(query): Query<ModelAccessGroupQuery>,
+) -> ApiResult<StatusCode> {
+    let pool = state
+        .database
+        .get_pool()
+        .ok_or_else(|| ApiError::DatabaseError("Database not available".to_string()))?;
+
+    ModelAccessGroup::delete(pool, &query.name).await?;
+    state.key_cache.invalidate_all().await;
+    audit
+        .record(
+            pool,
+            AuditRecord::new("model_group.delete").target("model_group", &query.name),
+        )
+        .await;
+
+    Ok(StatusCode::OK)
+}
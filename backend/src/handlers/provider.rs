@@ -2,7 +2,12 @@ use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 
-use crate::{error::ApiResult, provider_config, AppState};
+use crate::{
+    audit::{AuditContext, AuditRecord},
+    auth::{require, Authorized},
+    error::ApiResult,
+    provider_config, AppState,
+};
 
 #[derive(Debug, Deserialize)]
 pub struct UpdateProviderRequest {
@@ -25,6 +30,8 @@ pub struct UpdateProviderResponse {
 /// This requires admin access (master key or admin user)
 pub async fn update_provider_key(
     State(state): State<Arc<AppState>>,
+    _auth: Authorized<require::ManageProviders>,
+    audit: AuditContext,
     Json(request): Json<UpdateProviderRequest>,
 ) -> ApiResult<impl IntoResponse> {
     tracing::info!("🔧 Updating API key for provider: {}", request.provider_id);
@@ -116,6 +123,19 @@ pub async fn update_provider_key(
         configured_count,
         request.provider_id
     );
+    if let Some(pool) = state.database.get_pool() {
+        audit
+            .record(
+                pool,
+                AuditRecord::new("provider.configure")
+                    .target("provider", &request.provider_id)
+                    .details(serde_json::json!({
+                        "azure_resource_name": request.azure_resource_name,
+                        "models_configured": configured_count,
+                    })),
+            )
+            .await;
+    }
 
     Ok((
         StatusCode::OK,
@@ -133,8 +153,11 @@ pub async fn update_provider_key(
 }
 
 /// Delete API key for a provider (removes all model routes)
+/// This requires admin access (master key or admin user)
 pub async fn delete_provider_key(
     State(state): State<Arc<AppState>>,
+    _auth: Authorized<require::ManageProviders>,
+    audit: AuditContext,
     Json(request): Json<serde_json::Value>,
 ) -> ApiResult<impl IntoResponse> {
     let provider_id = request["provider_id"]
@@ -172,6 +195,16 @@ pub async fn delete_provider_key(
         keys_to_remove.len(),
         provider_id
     );
+    if let Some(pool) = state.database.get_pool() {
+        audit
+            .record(
+                pool,
+                AuditRecord::new("provider.delete")
+                    .target("provider", provider_id)
+                    .details(serde_json::json!({ "models_removed": keys_to_remove.len() })),
+            )
+            .await;
+    }
 
     Ok((
         StatusCode::OK,
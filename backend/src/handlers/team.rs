@@ -0,0 +1,438 @@
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::{
+    audit::{AuditContext, AuditRecord},
+    auth::{AuthType, AuthUser, Permission},
+    error::{ApiError, ApiResult},
+    models::{
+        validate_model_entries, CreateTeamRequest, Team, TeamMember, TeamRole, TeamUsage,
+        UpdateTeamRequest, User, VirtualKey,
+    },
+    AppState,
+};
+
+// ============================================================================
+// Teams
+// ============================================================================
+
+#[derive(Debug, Deserialize)]
+pub struct TeamIdQuery {
+    pub team_id: Uuid,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TeamUsageQuery {
+    pub team_id: Uuid,
+    pub days: Option<i32>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TeamDetailsResponse {
+    #[serde(flatten)]
+    pub team: Team,
+    pub members: Vec<TeamMember>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TeamUsageResponse {
+    pub team_id: Uuid,
+    pub max_budget: Option<f64>,
+    pub current_spend: f64,
+    pub days: i32,
+    pub usage_by_model: Vec<TeamUsage>,
+}
+
+/// Add a user to a team by ID or email
+#[derive(Debug, Deserialize)]
+pub struct AddTeamMemberRequest {
+    pub team_id: Uuid,
+    pub user_id: Option<Uuid>,
+    pub email: Option<String>,
+    pub role: Option<TeamRole>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateTeamMemberRequest {
+    pub team_id: Uuid,
+    pub user_id: Uuid,
+    pub role: TeamRole,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RemoveTeamMemberRequest {
+    pub team_id: Uuid,
+    pub user_id: Uuid,
+}
+
+fn get_pool(state: &AppState) -> ApiResult<&Pool<Postgres>> {
+    state
+        .database
+        .get_pool()
+        .ok_or_else(|| ApiError::DatabaseError("Database not available".to_string()))
+}
+
+/// The caller's role in a team; roles that manage every team act as its owner
+async fn caller_role(
+    pool: &Pool<Postgres>,
+    auth_user: &AuthUser,
+    team_id: Uuid,
+) -> ApiResult<Option<TeamRole>> {
+    if auth_user.has(Permission::ManageTeams) {
+        return Ok(Some(TeamRole::Owner));
+    }
+    Team::member_role(pool, team_id, auth_user.user_id).await
+}
+
+/// Require the caller to hold at least `required` in the team
+async fn require_team_role(
+    pool: &Pool<Postgres>,
+    auth_user: &AuthUser,
+    team_id: Uuid,
+    required: TeamRole,
+) -> ApiResult<TeamRole> {
+    match caller_role(pool, auth_user, team_id).await? {
+        Some(role) if role >= required => Ok(role),
+        Some(_) => Err(ApiError::Forbidden),
+        // Don't reveal whether a team exists to non-members
+        None => Err(ApiError::NotFound("Team not found".to_string())),
+    }
+}
+
+/// Refuse to leave a team without an owner
+async fn ensure_not_last_owner(
+    pool: &Pool<Postgres>,
+    team_id: Uuid,
+    user_id: Uuid,
+) -> ApiResult<()> {
+    if Team::member_role(pool, team_id, user_id).await? == Some(TeamRole::Owner)
+        && Team::owner_count(pool, team_id).await? <= 1
+    {
+        return Err(ApiError::BadRequest(
+            "A team must keep at least one owner".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// List teams (every team for roles that manage teams, otherwise the caller's teams)
+pub async fn list_teams(
+    State(state): State<Arc<AppState>>,
+    auth_user: AuthUser,
+) -> ApiResult<Json<Vec<Team>>> {
+    let pool = get_pool(&state)?;
+
+    let teams = if auth_user.has(Permission::ManageTeams) {
+        Team::find_all(pool).await?
+    } else {
+        Team::find_by_member(pool, auth_user.user_id).await?
+    };
+
+    Ok(Json(teams))
+}
+
+/// Create a team; the caller becomes its owner
+/// Budgets and rate limits can only be set by gateway admins.
+pub async fn create_team(
+    State(state): State<Arc<AppState>>,
+    auth_user: AuthUser,
+    audit: AuditContext,
+    Json(request): Json<CreateTeamRequest>,
+) -> ApiResult<Json<Team>> {
+    let pool = get_pool(&state)?;
+
+    if request.name.trim().is_empty() {
+        return Err(ApiError::BadRequest("Team name is required".to_string()));
+    }
+    if let Some(allowed_models) = &request.allowed_models {
+        validate_model_entries(allowed_models, true)?;
+    }
+
+    let sets_limits = request.max_budget.is_some()
+        || request.rate_limit_rpm.is_some()
+        || request.rate_limit_tpm.is_some();
+    if sets_limits {
+        auth_user.require(Permission::ManageTeams)?;
+    }
+
+    let owner_id = match auth_user.auth_type {
+        AuthType::MasterKey => None,
+        _ => Some(auth_user.user_id),
+    };
+
+    let team = Team::create(pool, &request, owner_id).await?;
+    audit
+        .record(
+            pool,
+            AuditRecord::new("team.create")
+                .target("team", team.id)
+                .created(&team),
+        )
+        .await;
+
+    Ok(Json(team))
+}
+
+/// Get a team with its members (members only)
+pub async fn get_team(
+    State(state): State<Arc<AppState>>,
+    auth_user: AuthUser,
+    Query(query): Query<TeamIdQuery>,
+) -> ApiResult<Json<TeamDetailsResponse>> {
+    let pool = get_pool(&state)?;
+    require_team_role(pool, &auth_user, query.team_id, TeamRole::Member).await?;
+
+    let team = Team::find_by_id(pool, query.team_id)
+        .await?
+        .ok_or_else(|| ApiError::NotFound("Team not found".to_string()))?;
+    let members = Team::members(pool, query.team_id).await?;
+
+    Ok(Json(TeamDetailsResponse { team, members }))
+}
+
+/// Update a team (owners; budgets and rate limits require a gateway admin)
+pub async fn update_team(
+    State(state): State<Arc<AppState>>,
+    auth_user: AuthUser,
+    audit: AuditContext,
+    Json(request): Json<UpdateTeamRequest>,
+) -> ApiResult<Json<Team>> {
+    let pool = get_pool(&state)?;
+    require_team_role(pool, &auth_user, request.team_id, TeamRole::Owner).await?;
+
+    let sets_limits = request.max_budget.is_some()
+        || request.rate_limit_rpm.is_some()
+        || request.rate_limit_tpm.is_some()
+        || request.reset_spend.is_some();
+    if sets_limits {
+        auth_user.require(Permission::ManageTeams)?;
+    }
+
+    if request
+        .name
+        .as_deref()
+        .is_some_and(|name| name.trim().is_empty())
+    {
+        return Err(ApiError::BadRequest("Team name is required".to_string()));
+    }
+    if let Some(allowed_models) = &request.allowed_models {
+        validate_model_entries(allowed_models, true)?;
+    }
+
+    let previous = Team::find_by_id(pool, request.team_id)
+        .await?
+        .ok_or_else(|| ApiError::NotFound("Team not found".to_string()))?;
+    let team = Team::update(pool, &request).await?;
+    // Cached principals of the team's keys carry its model access and limits
+    state.key_cache.invalidate_all().await;
+    audit
+        .record(
+            pool,
+            AuditRecord::new("team.update")
+                .target("team", team.id)
+                .updated(&previous, &team),
+        )
+        .await;
+
+    Ok(Json(team))
+}
+
+/// Delete a team (owners); its keys must be moved or deleted first
+pub async fn delete_team(
+    State(state): State<Arc<AppState>>,
+    auth_user: AuthUser,
+    audit: AuditContext,
+    Query(query): Query<TeamIdQuery>,
+) -> ApiResult<StatusCode> {
+    let pool = get_pool(&state)?;
+    require_team_role(pool, &auth_user, query.team_id, TeamRole::Owner).await?;
+
+    let team = Team::find_by_id(pool, query.team_id)
+        .await?
+        .ok_or_else(|| ApiError::NotFound("Team not found".to_string()))?;
+    Team::delete(pool, query.team_id).await?;
+    audit
+        .record(
+            pool,
+            AuditRecord::new("team.delete")
+                .target("team", team.id)
+                .deleted(&team),
+        )
+        .await;
+
+    Ok(StatusCode::OK)
+}
+
+/// Add a member (team admins; only owners can add owners)
+pub async fn add_team_member(
+    State(state): State<Arc<AppState>>,
+    auth_user: AuthUser,
+    audit: AuditContext,
+    Json(request): Json<AddTeamMemberRequest>,
+) -> ApiResult<Json<Vec<TeamMember>>> {
+    let pool = get_pool(&state)?;
+    let caller = require_team_role(pool, &auth_user, request.team_id, TeamRole::Admin).await?;
+
+    let role = request.role.unwrap_or(TeamRole::Member);
+    if role == TeamRole::Owner && caller != TeamRole::Owner {
+        return Err(ApiError::Forbidden);
+    }
+
+    let user = match (request.user_id, request.email.as_deref()) {
+        (Some(user_id), _) => User::find_by_id(pool, user_id).await?,
+        (None, Some(email)) => User::find_by_email(pool, email).await?,
+        (None, None) => {
+            return Err(ApiError::BadRequest(
+                "user_id or email is required".to_string(),
+            ))
+        }
+    }
+    .ok_or_else(|| ApiError::NotFound("User not found".to_string()))?;
+
+    if Team::member_role(pool, request.team_id, user.id)
+        .await?
+        .is_some()
+    {
+        return Err(ApiError::BadRequest(
+            "User is already a member of this team".to_string(),
+        ));
+    }
+
+    Team::set_member(pool, request.team_id, user.id, role).await?;
+    audit
+        .record(
+            pool,
+            AuditRecord::new("team.member_add")
+                .target("team", request.team_id)
+                .details(serde_json::json!({
+                    "user_id": user.id,
+                    "email": user.email,
+                    "role": role,
+                })),
+        )
+        .await;
+
+    Ok(Json(Team::members(pool, request.team_id).await?))
+}
+
+/// Change a member's role (team admins; only owners can grant or change the owner role)
+pub async fn update_team_member(
+    State(state): State<Arc<AppState>>,
+    auth_user: AuthUser,
+    audit: AuditContext,
+    Json(request): Json<UpdateTeamMemberRequest>,
+) -> ApiResult<Json<Vec<TeamMember>>> {
+    let pool = get_pool(&state)?;
+    let caller = require_team_role(pool, &auth_user, request.team_id, TeamRole::Admin).await?;
+
+    let current = Team::member_role(pool, request.team_id, request.user_id)
+        .await?
+        .ok_or_else(|| ApiError::NotFound("Member not found".to_string()))?;
+
+    if (current == TeamRole::Owner || request.role == TeamRole::Owner) && caller != TeamRole::Owner
+    {
+        return Err(ApiError::Forbidden);
+    }
+    if current == TeamRole::Owner && request.role != TeamRole::Owner {
+        ensure_not_last_owner(pool, request.team_id, request.user_id).await?;
+    }
+
+    Team::set_member(pool, request.team_id, request.user_id, request.role).await?;
+    audit
+        .record(
+            pool,
+            AuditRecord::new("team.member_update")
+                .target("team", request.team_id)
+                .details(serde_json::json!({
+                    "user_id": request.user_id,
+                    "previous_role": current,
+                    "role": request.role,
+                })),
+        )
+        .await;
+
+    Ok(Json(Team::members(pool, request.team_id).await?))
+}
+
+/// Remove a member (team admins, or members leaving themselves)
+pub async fn remove_team_member(
+    State(state): State<Arc<AppState>>,
+    auth_user: AuthUser,
+    audit: AuditContext,
+    Json(request): Json<RemoveTeamMemberRequest>,
+) -> ApiResult<StatusCode> {
+    let pool = get_pool(&state)?;
+
+    let leaving = request.user_id == auth_user.user_id;
+    let required = if leaving {
+        TeamRole::Member
+    } else {
+        TeamRole::Admin
+    };
+    let caller = require_team_role(pool, &auth_user, request.team_id, required).await?;
+
+    let current = Team::member_role(pool, request.team_id, request.user_id)
+        .await?
+        .ok_or_else(|| ApiError::NotFound("Member not found".to_string()))?;
+
+    if current == TeamRole::Owner && !leaving && caller != TeamRole::Owner {
+        return Err(ApiError::Forbidden);
+    }
+    ensure_not_last_owner(pool, request.team_id, request.user_id).await?;
+
+    Team::remove_member(pool, request.team_id, request.user_id).await?;
+    audit
+        .record(
+            pool,
+            AuditRecord::new("team.member_remove")
+                .target("team", request.team_id)
+                .details(serde_json::json!({ "user_id": request.user_id, "role": current })),
+        )
+        .await;
+
+    Ok(StatusCode::OK)
+}
+
+/// List the keys a team owns (members only)
+pub async fn get_team_keys(
+    State(state): State<Arc<AppState>>,
+    auth_user: AuthUser,
+    Query(query): Query<TeamIdQuery>,
+) -> ApiResult<Json<Vec<VirtualKey>>> {
+    let pool = get_pool(&state)?;
+    require_team_role(pool, &auth_user, query.team_id, TeamRole::Member).await?;
+
+    Ok(Json(VirtualKey::find_by_team(pool, query.team_id).await?))
+}
+
+/// Spend attributed to a team, per model, over the last `days` days (default 30)
+pub async fn get_team_usage(
+    State(state): State<Arc<AppState>>,
+    auth_user: AuthUser,
+    Query(query): Query<TeamUsageQuery>,
+) -> ApiResult<Json<TeamUsageResponse>> {
+    let pool = get_pool(&state)?;
+    require_team_role(pool, &auth_user, query.team_id, TeamRole::Member).await?;
+
+    let team = Team::find_by_id(pool, query.team_id)
+        .await?
+        .ok_or_else(|| ApiError::NotFound("Team not found".to_string()))?;
+
+    let days = query.days.unwrap_or(30).clamp(1, 365);
+    let usage_by_model = Team::usage(pool, team.id, days).await?;
+
+    Ok(Json(TeamUsageResponse {
+        team_id: team.id,
+        max_budget: team.max_budget,
+        current_spend: team.current_spend,
+        days,
+        usage_by_model,
+    }))
+}
@@ -0,0 +1,314 @@
+use axum::{extract::State, Json};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use std::sync::Arc;
+
+use crate::{
+    audit::{AuditContext, AuditRecord},
+    auth::{
+        generate_recovery_codes, generate_token, generate_totp_secret, hash_token,
+        normalize_recovery_code, otpauth_uri, verify_totp, AuthType, AuthUser,
+    },
+    error::{ApiError, ApiResult},
+    models::{RecoveryCode, User, UserTotp},
+    AppState,
+};
+
+// ============================================================================
+// Two-factor authentication (TOTP)
+// ============================================================================
+
+/// Whether the user's role requires TOTP; only password logins are covered, since
+/// OAuth-only users authenticate with their identity provider
+pub(crate) fn totp_required(state: &AppState, user: &User) -> bool {
+    user.password_hash.is_some() && state.config.totp_required_roles.contains(&user.role)
+}
+
+/// Whether the user still has to set up TOTP their role requires
+pub(crate) async fn totp_enrollment_pending(
+    state: &AppState,
+    pool: &Pool<Postgres>,
+    user: &User,
+) -> ApiResult<bool> {
+    Ok(totp_required(state, user) && UserTotp::find_enabled(pool, user.id).await?.is_none())
+}
+
+/// Check a TOTP code, or failing that a recovery code, and use it up
+pub(crate) async fn verify_second_factor(
+    state: &AppState,
+    pool: &Pool<Postgres>,
+    totp: &UserTotp,
+    code: &str,
+) -> ApiResult<bool> {
+    let secret = state.database.encryptor().decrypt(
+        &totp.secret_encrypted,
+        &UserTotp::secret_context(totp.user_id),
+    )?;
+    if let Some(step) = verify_totp(&secret, code, Utc::now().timestamp()) {
+        return UserTotp::accept_step(pool, totp.user_id, step).await;
+    }
+
+    // Only enrolled users have recovery codes
+    if totp.enabled_at.is_none() {
+        return Ok(false);
+    }
+    let code_hash = hash_token(&normalize_recovery_code(code));
+    let used = RecoveryCode::consume(pool, totp.user_id, &code_hash).await?;
+    if used {
+        tracing::warn!("User {} signed in with a recovery code", totp.user_id);
+    }
+    Ok(used)
+}
+
+/// The calling user, with codes they submit charged to their login rate limit
+async fn current_user(
+    state: &AppState,
+    pool: &Pool<Postgres>,
+    auth_user: &AuthUser,
+    checks_code: bool,
+) -> ApiResult<User> {
+    let user = User::find_by_id(pool, auth_user.user_id)
+        .await?
+        .ok_or_else(|| ApiError::NotFound("User not found".to_string()))?;
+
+    if checks_code {
+        state
+            .login_throttle
+            .check(&state.rate_limiter, None, Some(&user.email))
+            .await?;
+    }
+    Ok(user)
+}
+
+/// Store fresh recovery codes for a user, returning them in plaintext (shown once)
+async fn issue_recovery_codes(pool: &Pool<Postgres>, user: &User) -> ApiResult<Vec<String>> {
+    let recovery_codes = generate_recovery_codes();
+    let code_hashes: Vec<String> = recovery_codes
+        .iter()
+        .map(|code| hash_token(&normalize_recovery_code(code)))
+        .collect();
+    RecoveryCode::replace(pool, user.id, &code_hashes).await?;
+    Ok(recovery_codes)
+}
+
+#[derive(Debug, Serialize)]
+pub struct TotpStatusResponse {
+    pub enabled: bool,
+    /// Whether the user's role requires TOTP
+    pub required: bool,
+    pub recovery_codes_remaining: i64,
+}
+
+/// Two-factor status of the current user
+pub async fn get_totp_status(
+    State(state): State<Arc<AppState>>,
+    auth_user: AuthUser,
+) -> ApiResult<Json<TotpStatusResponse>> {
+    let pool = state
+        .database
+        .get_pool()
+        .ok_or_else(|| ApiError::DatabaseError("Database not available".to_string()))?;
+
+    let user = current_user(&state, pool, &auth_user, false).await?;
+    let enabled = UserTotp::find_enabled(pool, user.id).await?.is_some();
+    let recovery_codes_remaining = if enabled {
+        RecoveryCode::count_unused(pool, user.id).await?
+    } else {
+        0
+    };
+
+    Ok(Json(TotpStatusResponse {
+        enabled,
+        required: totp_required(&state, &user),
+        recovery_codes_remaining,
+    }))
+}
+
+#[derive(Debug, Serialize)]
+pub struct TotpSetupResponse {
+    /// Base32 secret, for entering into an authenticator app by hand
+    pub secret: String,
+    /// `otpauth://` URI, usually rendered as a QR code
+    pub otpauth_uri: String,
+}
+
+/// Start TOTP enrollment: generate a secret, which only takes effect once a code from it
+/// is confirmed at `/auth/totp/confirm`
+pub async fn setup_totp(
+    State(state): State<Arc<AppState>>,
+    auth_user: AuthUser,
+) -> ApiResult<Json<TotpSetupResponse>> {
+    let pool = state
+        .database
+        .get_pool()
+        .ok_or_else(|| ApiError::DatabaseError("Database not available".to_string()))?;
+
+    let user = current_user(&state, pool, &auth_user, false).await?;
+    let secret = generate_totp_secret();
+    let secret_encrypted = state
+        .database
+        .encryptor()
+        .encrypt(&secret, &UserTotp::secret_context(user.id))?;
+
+    if !UserTotp::start_enrollment(pool, user.id, &secret_encrypted).await? {
+        return Err(ApiError::BadRequest(
+            "Two-factor authentication is already enabled".to_string(),
+        ));
+    }
+
+    Ok(Json(TotpSetupResponse {
+        otpauth_uri: otpauth_uri(&state.config.totp_issuer, &user.email, &secret),
+        secret,
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TotpCodeRequest {
+    /// Current TOTP code (recovery codes are accepted too, except when confirming setup)
+    pub code: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TotpConfirmResponse {
+    /// Single-use codes for when the authenticator is lost; shown only this once
+    pub recovery_codes: Vec<String>,
+    /// New access token for the current session, no longer limited to enrollment
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+}
+
+/// Finish TOTP enrollment with a code from the new secret
+pub async fn confirm_totp(
+    State(state): State<Arc<AppState>>,
+    auth_user: AuthUser,
+    audit: AuditContext,
+    Json(request): Json<TotpCodeRequest>,
+) -> ApiResult<Json<TotpConfirmResponse>> {
+    let pool = state
+        .database
+        .get_pool()
+        .ok_or_else(|| ApiError::DatabaseError("Database not available".to_string()))?;
+
+    let user = current_user(&state, pool, &auth_user, true).await?;
+    let totp = UserTotp::find(pool, user.id)
+        .await?
+        .ok_or_else(|| ApiError::BadRequest("Start setup at /auth/totp/setup first".to_string()))?;
+    if totp.enabled_at.is_some() {
+        return Err(ApiError::BadRequest(
+            "Two-factor authentication is already enabled".to_string(),
+        ));
+    }
+    if !verify_second_factor(&state, pool, &totp, &request.code).await? {
+        return Err(ApiError::BadRequest("Invalid code".to_string()));
+    }
+
+    let recovery_codes = issue_recovery_codes(pool, &user).await?;
+    tracing::info!("{} enabled two-factor authentication", user.email);
+    audit
+        .record(
+            pool,
+            AuditRecord::new("user.totp_enable").target("user", user.id),
+        )
+        .await;
+
+    let token = match auth_user.auth_type {
+        AuthType::JWT { session_id } => Some(generate_token(
+            user.id,
+            user.email.clone(),
+            user.role.clone(),
+            session_id,
+            false,
+            &state.jwt_keys,
+            state.config.access_token_ttl_seconds,
+        )?),
+        _ => None,
+    };
+
+    Ok(Json(TotpConfirmResponse {
+        recovery_codes,
+        token,
+    }))
+}
+
+/// Turn off TOTP for the current user, unless their role requires it
+pub async fn disable_totp(
+    State(state): State<Arc<AppState>>,
+    auth_user: AuthUser,
+    audit: AuditContext,
+    Json(request): Json<TotpCodeRequest>,
+) -> ApiResult<Json<TotpStatusResponse>> {
+    let pool = state
+        .database
+        .get_pool()
+        .ok_or_else(|| ApiError::DatabaseError("Database not available".to_string()))?;
+
+    let user = current_user(&state, pool, &auth_user, true).await?;
+    if totp_required(&state, &user) {
+        return Err(ApiError::BadRequest(format!(
+            "Two-factor authentication is required for the {} role",
+            user.role
+        )));
+    }
+    let totp = UserTotp::find_enabled(pool, user.id)
+        .await?
+        .ok_or_else(|| {
+            ApiError::BadRequest("Two-factor authentication is not enabled".to_string())
+        })?;
+    if !verify_second_factor(&state, pool, &totp, &request.code).await? {
+        return Err(ApiError::BadRequest("Invalid code".to_string()));
+    }
+
+    UserTotp::delete(pool, user.id).await?;
+    tracing::info!("{} disabled two-factor authentication", user.email);
+    audit
+        .record(
+            pool,
+            AuditRecord::new("user.totp_disable").target("user", user.id),
+        )
+        .await;
+
+    Ok(Json(TotpStatusResponse {
+        enabled: false,
+        required: false,
+        recovery_codes_remaining: 0,
+    }))
+}
+
+#[derive(Debug, Serialize)]
+pub struct RecoveryCodesResponse {
+    pub recovery_codes: Vec<String>,
+}
+
+/// Replace the current user's recovery codes, invalidating the old ones
+pub async fn regenerate_recovery_codes(
+    State(state): State<Arc<AppState>>,
+    auth_user: AuthUser,
+    audit: AuditContext,
+    Json(request): Json<TotpCodeRequest>,
+) -> ApiResult<Json<RecoveryCodesResponse>> {
+    let pool = state
+        .database
+        .get_pool()
+        .ok_or_else(|| ApiError::DatabaseError("Database not available".to_string()))?;
+
+    let user = current_user(&state, pool, &auth_user, true).await?;
+    let totp = UserTotp::find_enabled(pool, user.id)
+        .await?
+        .ok_or_else(|| {
+            ApiError::BadRequest("Two-factor authentication is not enabled".to_string())
+        })?;
+    if !verify_second_factor(&state, pool, &totp, &request.code).await? {
+        return Err(ApiError::BadRequest("Invalid code".to_string()));
+    }
+
+    let recovery_codes = issue_recovery_codes(pool, &user).await?;
+    audit
+        .record(
+            pool,
+            AuditRecord::new("user.recovery_codes_regenerate").target("user", user.id),
+        )
+        .await;
+
+    Ok(Json(RecoveryCodesResponse { recovery_codes }))
+}
@@ -0,0 +1,588 @@
+use axum::{
+    extract::{Query, State},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::{
+    audit::{AuditContext, AuditRecord},
+    auth::{require, Authorized, Role},
+    error::{ApiError, ApiResult},
+    handlers::{check_email_domain, RevokeSessionsResponse},
+    models::{LoginLockout, Session, Team, TeamRole, User, UserFilter, UserTotp, VirtualKey},
+    AppState,
+};
+
+// ============================================================================
+// User Administration
+// ============================================================================
+
+const DEFAULT_USER_PAGE_SIZE: i64 = 50;
+const MAX_USER_PAGE_SIZE: i64 = 500;
+
+async fn find_user(pool: &Pool<Postgres>, user_id: Uuid) -> ApiResult<User> {
+    User::find_by_id(pool, user_id)
+        .await?
+        .ok_or_else(|| ApiError::NotFound("User not found".to_string()))
+}
+
+/// Keep at least one enabled admin so roles can still be managed without the master key
+async fn ensure_not_last_admin(pool: &Pool<Postgres>, user: &User) -> ApiResult<()> {
+    if Role::from_stored(&user.role) == Role::Admin
+        && user.disabled_at.is_none()
+        && User::count_by_role(pool, Role::Admin.as_str()).await? <= 1
+    {
+        return Err(ApiError::BadRequest(
+            "Cannot remove the last admin".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UserPageQuery {
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UsersResponse {
+    pub users: Vec<User>,
+    /// Users matching the filters across all pages
+    pub total: i64,
+}
+
+/// List users, oldest first (requires the ManageUsers permission)
+///
+/// Filters: `search` (part of the email or username), `role` and `disabled`.
+pub async fn list_users(
+    State(state): State<Arc<AppState>>,
+    _auth: Authorized<require::ManageUsers>,
+    Query(filter): Query<UserFilter>,
+    Query(page): Query<UserPageQuery>,
+) -> ApiResult<Json<UsersResponse>> {
+    let pool = state
+        .database
+        .get_pool()
+        .ok_or_else(|| ApiError::DatabaseError("Database not available".to_string()))?;
+
+    if let Some(role) = &filter.role {
+        Role::parse(role)?;
+    }
+    let limit = page
+        .limit
+        .unwrap_or(DEFAULT_USER_PAGE_SIZE)
+        .clamp(1, MAX_USER_PAGE_SIZE);
+    let offset = page.offset.unwrap_or(0).max(0);
+
+    let (users, total) = tokio::try_join!(
+        User::list(pool, &filter, limit, offset),
+        User::count(pool, &filter)
+    )?;
+
+    Ok(Json(UsersResponse { users, total }))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UserIdQuery {
+    pub user_id: Uuid,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UserDetailsResponse {
+    #[serde(flatten)]
+    pub user: User,
+    pub keys: Vec<VirtualKey>,
+    pub teams: Vec<Team>,
+    pub totp_enabled: bool,
+    /// End of the current login lockout, if any
+    pub locked_until: Option<DateTime<Utc>>,
+}
+
+/// A user with their keys, teams and sign-in status (requires the ManageUsers permission)
+pub async fn get_user(
+    State(state): State<Arc<AppState>>,
+    _auth: Authorized<require::ManageUsers>,
+    Query(query): Query<UserIdQuery>,
+) -> ApiResult<Json<UserDetailsResponse>> {
+    let pool = state
+        .database
+        .get_pool()
+        .ok_or_else(|| ApiError::DatabaseError("Database not available".to_string()))?;
+
+    let user = find_user(pool, query.user_id).await?;
+    let (keys, teams, totp, locked_until) = tokio::try_join!(
+        VirtualKey::find_by_user(pool, user.id),
+        Team::find_by_member(pool, user.id),
+        UserTotp::find_enabled(pool, user.id),
+        LoginLockout::locked_until(pool, user.id)
+    )?;
+
+    Ok(Json(UserDetailsResponse {
+        user,
+        keys,
+        teams,
+        totp_enabled: totp.is_some(),
+        locked_until,
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateUserRoleRequest {
+    pub user_id: Uuid,
+    pub role: Role,
+}
+
+/// Change a user's gateway role (requires the ManageUsers permission)
+///
+/// Roles above `user` can only go to users whose email domain is allowed.
+pub async fn update_user_role(
+    State(state): State<Arc<AppState>>,
+    auth: Authorized<require::ManageUsers>,
+    audit: AuditContext,
+    Json(request): Json<UpdateUserRoleRequest>,
+) -> ApiResult<Json<User>> {
+    let pool = state
+        .database
+        .get_pool()
+        .ok_or_else(|| ApiError::DatabaseError("Database not available".to_string()))?;
+
+    let user = find_user(pool, request.user_id).await?;
+    if request.role != Role::Admin {
+        ensure_not_last_admin(pool, &user).await?;
+    }
+    // Only users the gateway would still let register may be given more than the default role
+    if request.role != Role::User {
+        check_email_domain(&state, &user.email)?;
+    }
+
+    User::update_role(pool, user.id, request.role.as_str().to_string()).await?;
+    // Requests made with the user's keys act with their role
+    state.key_cache.invalidate_all().await;
+    tracing::info!(
+        "{} changed the role of {} from {} to {}",
+        auth.user.email,
+        user.email,
+        user.role,
+        request.role
+    );
+
+    let updated_user = User::find_by_id(pool, user.id)
+        .await?
+        .ok_or_else(|| ApiError::NotFound("User not found".to_string()))?;
+    audit
+        .record(
+            pool,
+            AuditRecord::new("user.role_change")
+                .target("user", user.id)
+                .updated(&user, &updated_user),
+        )
+        .await;
+
+    Ok(Json(updated_user))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RevokeUserSessionsRequest {
+    pub user_id: Uuid,
+}
+
+/// Sign a user out everywhere (requires the ManageUsers permission)
+pub async fn revoke_user_sessions(
+    State(state): State<Arc<AppState>>,
+    auth: Authorized<require::ManageUsers>,
+    audit: AuditContext,
+    Json(request): Json<RevokeUserSessionsRequest>,
+) -> ApiResult<Json<RevokeSessionsResponse>> {
+    let pool = state
+        .database
+        .get_pool()
+        .ok_or_else(|| ApiError::DatabaseError("Database not available".to_string()))?;
+
+    let user = User::find_by_id(pool, request.user_id)
+        .await?
+        .ok_or_else(|| ApiError::NotFound("User not found".to_string()))?;
+
+    let revoked_sessions = Session::revoke_by_user(pool, user.id).await?;
+    tracing::info!(
+        "{} revoked {} sessions of {}",
+        auth.user.email,
+        revoked_sessions,
+        user.email
+    );
+    audit
+        .record(
+            pool,
+            AuditRecord::new("user.sessions_revoke")
+                .target("user", user.id)
+                .details(serde_json::json!({ "revoked_sessions": revoked_sessions })),
+        )
+        .await;
+
+    Ok(Json(RevokeSessionsResponse { revoked_sessions }))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UnlockUserRequest {
+    pub user_id: Uuid,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UnlockUserResponse {
+    /// Whether the user was locked out
+    pub unlocked: bool,
+}
+
+/// End a user's login lockout early and clear their failed logins (requires the
+/// ManageUsers permission)
+pub async fn unlock_user(
+    State(state): State<Arc<AppState>>,
+    auth: Authorized<require::ManageUsers>,
+    audit: AuditContext,
+    Json(request): Json<UnlockUserRequest>,
+) -> ApiResult<Json<UnlockUserResponse>> {
+    let pool = state
+        .database
+        .get_pool()
+        .ok_or_else(|| ApiError::DatabaseError("Database not available".to_string()))?;
+
+    let user = User::find_by_id(pool, request.user_id)
+        .await?
+        .ok_or_else(|| ApiError::NotFound("User not found".to_string()))?;
+
+    // The master key acts as the nil user, which can't be recorded as the unlocker
+    let unlocked_by = Some(auth.user.user_id).filter(|id| !id.is_nil());
+    let unlocked = LoginLockout::unlock(pool, user.id, unlocked_by).await?;
+    state
+        .login_throttle
+        .reset_account(&state.rate_limiter, &user.email)
+        .await?;
+    tracing::info!(
+        "{} unlocked logins for {} (was locked: {})",
+        auth.user.email,
+        user.email,
+        unlocked
+    );
+    audit
+        .record(
+            pool,
+            AuditRecord::new("user.unlock")
+                .target("user", user.id)
+                .details(serde_json::json!({ "was_locked": unlocked })),
+        )
+        .await;
+
+    Ok(Json(UnlockUserResponse { unlocked }))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResetUserTotpRequest {
+    pub user_id: Uuid,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ResetUserTotpResponse {
+    /// Whether the user had TOTP set up (or pending)
+    pub reset: bool,
+}
+
+/// Remove a user's TOTP secret and recovery codes, e.g. after they lost their
+/// authenticator (requires the ManageUsers permission)
+///
+/// If their role requires TOTP, they're asked to enroll again after their next login.
+pub async fn reset_user_totp(
+    State(state): State<Arc<AppState>>,
+    auth: Authorized<require::ManageUsers>,
+    audit: AuditContext,
+    Json(request): Json<ResetUserTotpRequest>,
+) -> ApiResult<Json<ResetUserTotpResponse>> {
+    let pool = state
+        .database
+        .get_pool()
+        .ok_or_else(|| ApiError::DatabaseError("Database not available".to_string()))?;
+
+    let user = User::find_by_id(pool, request.user_id)
+        .await?
+        .ok_or_else(|| ApiError::NotFound("User not found".to_string()))?;
+
+    let reset = UserTotp::delete(pool, user.id).await?;
+    tracing::warn!(
+        "{} reset two-factor authentication for {} (was set up: {})",
+        auth.user.email,
+        user.email,
+        reset
+    );
+    audit
+        .record(
+            pool,
+            AuditRecord::new("user.totp_reset")
+                .target("user", user.id)
+                .details(serde_json::json!({ "was_set_up": reset })),
+        )
+        .await;
+
+    Ok(Json(ResetUserTotpResponse { reset }))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ListLockoutsQuery {
+    pub user_id: Uuid,
+    pub limit: Option<i64>,
+}
+
+/// A user's login lockouts, most recent first (requires the ManageUsers permission)
+pub async fn list_user_lockouts(
+    State(state): State<Arc<AppState>>,
+    _auth: Authorized<require::ManageUsers>,
+    Query(query): Query<ListLockoutsQuery>,
+) -> ApiResult<Json<Vec<LoginLockout>>> {
+    let pool = state
+        .database
+        .get_pool()
+        .ok_or_else(|| ApiError::DatabaseError("Database not available".to_string()))?;
+
+    let limit = query.limit.unwrap_or(50).clamp(1, 500);
+    Ok(Json(
+        LoginLockout::list_by_user(pool, query.user_id, limit).await?,
+    ))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UserStatusRequest {
+    pub user_id: Uuid,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DisableUserResponse {
+    pub user: User,
+    /// Keys blocked because of the disable; enabling the user unblocks them
+    pub blocked_keys: u64,
+    pub revoked_sessions: u64,
+}
+
+/// Disable a user: sign them out everywhere, refuse their logins and block their keys
+/// (requires the ManageUsers permission)
+pub async fn disable_user(
+    State(state): State<Arc<AppState>>,
+    auth: Authorized<require::ManageUsers>,
+    audit: AuditContext,
+    Json(request): Json<UserStatusRequest>,
+) -> ApiResult<Json<DisableUserResponse>> {
+    let pool = state
+        .database
+        .get_pool()
+        .ok_or_else(|| ApiError::DatabaseError("Database not available".to_string()))?;
+
+    let user = find_user(pool, request.user_id).await?;
+    if user.disabled_at.is_some() {
+        return Err(ApiError::BadRequest("User is already disabled".to_string()));
+    }
+    if user.id == auth.user.user_id {
+        return Err(ApiError::BadRequest(
+            "Cannot disable your own account".to_string(),
+        ));
+    }
+    ensure_not_last_admin(pool, &user).await?;
+
+    let blocked_keys = User::disable(pool, user.id).await?;
+    let revoked_sessions = Session::revoke_by_user(pool, user.id).await?;
+    state.key_cache.invalidate_all().await;
+    tracing::warn!(
+        "{} disabled {} ({} keys blocked, {} sessions revoked)",
+        auth.user.email,
+        user.email,
+        blocked_keys,
+        revoked_sessions
+    );
+
+    let updated_user = find_user(pool, user.id).await?;
+    audit
+        .record(
+            pool,
+            AuditRecord::new("user.disable")
+                .target("user", user.id)
+                .updated(&user, &updated_user)
+                .details(serde_json::json!({
+                    "blocked_keys": blocked_keys,
+                    "revoked_sessions": revoked_sessions,
+                })),
+        )
+        .await;
+
+    Ok(Json(DisableUserResponse {
+        user: updated_user,
+        blocked_keys,
+        revoked_sessions,
+    }))
+}
+
+#[derive(Debug, Serialize)]
+pub struct EnableUserResponse {
+    pub user: User,
+    /// Keys unblocked because disabling the user had blocked them
+    pub unblocked_keys: u64,
+}
+
+/// Enable a disabled user and unblock the keys disabling them blocked (requires the
+/// ManageUsers permission)
+pub async fn enable_user(
+    State(state): State<Arc<AppState>>,
+    auth: Authorized<require::ManageUsers>,
+    audit: AuditContext,
+    Json(request): Json<UserStatusRequest>,
+) -> ApiResult<Json<EnableUserResponse>> {
+    let pool = state
+        .database
+        .get_pool()
+        .ok_or_else(|| ApiError::DatabaseError("Database not available".to_string()))?;
+
+    let user = find_user(pool, request.user_id).await?;
+    if user.disabled_at.is_none() {
+        return Err(ApiError::BadRequest("User is not disabled".to_string()));
+    }
+    check_email_domain(&state, &user.email)?;
+
+    let unblocked_keys = User::enable(pool, user.id).await?;
+    state.key_cache.invalidate_all().await;
+    tracing::info!(
+        "{} enabled {} ({} keys unblocked)",
+        auth.user.email,
+        user.email,
+        unblocked_keys
+    );
+
+    let updated_user = find_user(pool, user.id).await?;
+    audit
+        .record(
+            pool,
+            AuditRecord::new("user.enable")
+                .target("user", user.id)
+                .updated(&user, &updated_user)
+                .details(serde_json::json!({ "unblocked_keys": unblocked_keys })),
+        )
+        .await;
+
+    Ok(Json(EnableUserResponse {
+        user: updated_user,
+        unblocked_keys,
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteUserRequest {
+    pub user_id: Uuid,
+    /// Give the user's keys to this user instead of deleting them
+    pub reassign_keys_to: Option<Uuid>,
+    /// Delete the user's keys with them; required when they have keys and no
+    /// `reassign_keys_to` is given
+    #[serde(default)]
+    pub delete_keys: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeleteUserResponse {
+    pub keys_reassigned: u64,
+    pub keys_deleted: u64,
+}
+
+/// Delete a user, reassigning or deleting their keys (requires the ManageUsers permission)
+///
+/// The last owner of a team can't be deleted until ownership is transferred.
+pub async fn delete_user(
+    State(state): State<Arc<AppState>>,
+    auth: Authorized<require::ManageUsers>,
+    audit: AuditContext,
+    Json(request): Json<DeleteUserRequest>,
+) -> ApiResult<Json<DeleteUserResponse>> {
+    let pool = state
+        .database
+        .get_pool()
+        .ok_or_else(|| ApiError::DatabaseError("Database not available".to_string()))?;
+
+    let user = find_user(pool, request.user_id).await?;
+    if user.id == auth.user.user_id {
+        return Err(ApiError::BadRequest(
+            "Cannot delete your own account".to_string(),
+        ));
+    }
+    ensure_not_last_admin(pool, &user).await?;
+
+    for team in Team::find_by_member(pool, user.id).await? {
+        if Team::member_role(pool, team.id, user.id).await? == Some(TeamRole::Owner)
+            && Team::owner_count(pool, team.id).await? <= 1
+        {
+            return Err(ApiError::BadRequest(format!(
+                "{} is the last owner of team '{}'; transfer ownership first",
+                user.email, team.name
+            )));
+        }
+    }
+
+    let key_count = VirtualKey::find_by_user(pool, user.id).await?.len();
+    match request.reassign_keys_to {
+        Some(_) if request.delete_keys => {
+            return Err(ApiError::BadRequest(
+                "Set either reassign_keys_to or delete_keys, not both".to_string(),
+            ));
+        }
+        Some(new_owner_id) => {
+            if new_owner_id == user.id {
+                return Err(ApiError::BadRequest(
+                    "Cannot reassign keys to the user being deleted".to_string(),
+                ));
+            }
+            let new_owner = User::find_by_id(pool, new_owner_id)
+                .await?
+                .ok_or_else(|| ApiError::BadRequest("Key recipient not found".to_string()))?;
+            if new_owner.disabled_at.is_some() {
+                return Err(ApiError::BadRequest(
+                    "Cannot reassign keys to a disabled user".to_string(),
+                ));
+            }
+            check_email_domain(&state, &new_owner.email)?;
+        }
+        None if key_count > 0 && !request.delete_keys => {
+            return Err(ApiError::BadRequest(format!(
+                "User has {} keys; set reassign_keys_to or delete_keys",
+                key_count
+            )));
+        }
+        None => {}
+    }
+
+    let keys = User::delete(pool, user.id, request.reassign_keys_to).await?;
+    state.key_cache.invalidate_all().await;
+    let (keys_reassigned, keys_deleted) = match request.reassign_keys_to {
+        Some(_) => (keys, 0),
+        None => (0, keys),
+    };
+    tracing::warn!(
+        "{} deleted {} ({} keys reassigned, {} keys deleted)",
+        auth.user.email,
+        user.email,
+        keys_reassigned,
+        keys_deleted
+    );
+    audit
+        .record(
+            pool,
+            AuditRecord::new("user.delete")
+                .target("user", user.id)
+                .deleted(&user)
+                .details(serde_json::json!({
+                    "reassign_keys_to": request.reassign_keys_to,
+                    "keys_reassigned": keys_reassigned,
+                    "keys_deleted": keys_deleted,
+                })),
+        )
+        .await;
+
+    Ok(Json(DeleteUserResponse {
+        keys_reassigned,
+        keys_deleted,
+    }))
+}
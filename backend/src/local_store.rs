@@ -0,0 +1,527 @@
+use dashmap::DashMap;
+use redis::aio::{ConnectionManager, ConnectionManagerConfig};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+
+/// How long to stay on local state after a Redis error before trying Redis again
+const REDIS_RETRY_INTERVAL_MS: i64 = 5_000;
+
+/// Sweep idle rate limit buckets after this many operations
+const EVICTION_INTERVAL_OPS: u64 = 1_024;
+
+fn now_ms() -> i64 {
+    chrono::Utc::now().timestamp_millis()
+}
+
+/// Tracks whether Redis is currently usable
+///
+/// After a failure Redis is skipped for a short interval so requests don't all pay for a
+/// timeout; the next call after that interval tries Redis again and switches back on success.
+#[derive(Clone)]
+pub struct RedisHealth {
+    name: &'static str,
+    down_until_ms: Arc<AtomicI64>,
+}
+
+impl RedisHealth {
+    pub fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            down_until_ms: Arc::new(AtomicI64::new(0)),
+        }
+    }
+
+    /// Whether Redis should be tried for the next operation
+    pub fn should_try(&self) -> bool {
+        now_ms() >= self.down_until_ms.load(Ordering::Relaxed)
+    }
+
+    /// Whether the last Redis operation failed
+    pub fn is_degraded(&self) -> bool {
+        self.down_until_ms.load(Ordering::Relaxed) != 0
+    }
+
+    pub fn record_success(&self) {
+        if self.down_until_ms.swap(0, Ordering::Relaxed) != 0 {
+            info!(
+                "✅ Redis is reachable again, {} switched back to Redis",
+                self.name
+            );
+        }
+    }
+
+    pub fn record_failure(&self, error: &dyn std::fmt::Display) {
+        let previous = self
+            .down_until_ms
+            .swap(now_ms() + REDIS_RETRY_INTERVAL_MS, Ordering::Relaxed);
+        if previous == 0 {
+            warn!(
+                "Redis unavailable for {} ({}), falling back to in-process state",
+                self.name, error
+            );
+        }
+    }
+}
+
+/// Redis connection shared by the Redis-backed components
+///
+/// When Redis is configured but unreachable at startup, a background task keeps connecting;
+/// until it succeeds the components treat Redis as down, exactly as during an outage that
+/// starts mid-run (in-process state, or 503s when failing closed).
+#[derive(Clone, Default)]
+pub struct RedisConnection {
+    manager: Option<Arc<OnceLock<ConnectionManager>>>,
+}
+
+impl RedisConnection {
+    /// Redis isn't configured; everything stays in-process
+    pub fn disabled() -> Self {
+        Self::default()
+    }
+
+    /// Connect to Redis, retrying in the background if it can't be reached yet
+    pub async fn connect(client: redis::Client, config: ConnectionManagerConfig) -> Self {
+        let slot = Arc::new(OnceLock::new());
+        match ConnectionManager::new_with_config(client.clone(), config.clone()).await {
+            Ok(manager) => {
+                info!("Redis connection established");
+                let _ = slot.set(manager);
+            }
+            Err(e) => {
+                warn!(
+                    "Redis unreachable at startup ({}), retrying every {}ms",
+                    e, REDIS_RETRY_INTERVAL_MS
+                );
+                let slot = slot.clone();
+                tokio::spawn(async move {
+                    loop {
+                        tokio::time::sleep(Duration::from_millis(REDIS_RETRY_INTERVAL_MS as u64))
+                            .await;
+                        match ConnectionManager::new_with_config(client.clone(), config.clone())
+                            .await
+                        {
+                            Ok(manager) => {
+                                info!("✅ Redis connection established");
+                                let _ = slot.set(manager);
+                                break;
+                            }
+                            Err(e) => debug!("Redis still unreachable: {}", e),
+                        }
+                    }
+                });
+            }
+        }
+        Self {
+            manager: Some(slot),
+        }
+    }
+
+    /// Whether a Redis URL was configured, connected or not
+    pub fn is_configured(&self) -> bool {
+        self.manager.is_some()
+    }
+
+    /// The connection, once established
+    pub fn get(&self) -> Option<ConnectionManager> {
+        self.manager.as_ref()?.get().cloned()
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.get().is_some()
+    }
+}
+
+/// What a sliding window operation does with its cost
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WindowMode {
+    /// Charge every dimension only if all of them stay within their limit
+    Check,
+    /// Charge unconditionally (the cost may be negative to refund)
+    Debit,
+    /// Read-only
+    Peek,
+}
+
+/// Fixed-window counter pair approximating a sliding window:
+/// the previous window's count is weighted by how much of it still overlaps
+#[derive(Debug, Clone, Copy)]
+struct WindowCounter {
+    window_start: i64,
+    current: i64,
+    previous: i64,
+}
+
+impl WindowCounter {
+    fn new(now: i64, window: i64) -> Self {
+        Self {
+            window_start: now - now.rem_euclid(window),
+            current: 0,
+            previous: 0,
+        }
+    }
+
+    fn roll(&mut self, now: i64, window: i64) {
+        let elapsed_windows = (now - self.window_start) / window;
+        if elapsed_windows >= 2 {
+            self.previous = 0;
+            self.current = 0;
+        } else if elapsed_windows == 1 {
+            self.previous = self.current;
+            self.current = 0;
+        }
+        self.window_start += elapsed_windows.max(0) * window;
+    }
+
+    fn weighted(&self, now: i64, window: i64) -> f64 {
+        let overlap = 1.0 - (now - self.window_start) as f64 / window as f64;
+        self.previous as f64 * overlap + self.current as f64
+    }
+
+    /// Milliseconds until `cost` more fits under `limit`
+    fn retry_after(&self, now: i64, window: i64, limit: i64, cost: i64) -> i64 {
+        let needed = limit - cost;
+        if needed < 0 {
+            // Can never fit; report a full replenish
+            return 2 * window;
+        }
+
+        let elapsed = now - self.window_start;
+        let wait = if self.current <= needed {
+            // Fits once enough of the previous window has slid out
+            if self.previous == 0 {
+                0.0
+            } else {
+                window as f64 * (1.0 - (needed - self.current) as f64 / self.previous as f64)
+                    - elapsed as f64
+            }
+        } else {
+            // Has to wait for this window to become the previous one and partly slide out
+            (window - elapsed) as f64 + window as f64 * (1.0 - needed as f64 / self.current as f64)
+        };
+
+        wait.max(0.0).ceil() as i64
+    }
+
+    /// When the counter drains to zero
+    fn reset_at(&self, now: i64, window: i64) -> i64 {
+        if self.current > 0 {
+            self.window_start + 2 * window
+        } else if self.previous > 0 {
+            self.window_start + window
+        } else {
+            now
+        }
+    }
+}
+
+/// In-process sliding window limiter
+///
+/// Buckets live in a sharded `DashMap`, so unrelated keys don't contend. Each bucket has
+/// its own lock, and the buckets touched by one call are locked in key order so a check
+/// across several of them is atomic without risking deadlock.
+pub struct SlidingWindowLimiter {
+    buckets: DashMap<String, Arc<Mutex<WindowCounter>>>,
+    window_ms: i64,
+    ops: AtomicU64,
+}
+
+impl SlidingWindowLimiter {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            buckets: DashMap::new(),
+            window_ms: window.as_millis().max(1) as i64,
+            ops: AtomicU64::new(0),
+        }
+    }
+
+    /// Apply (key, limit, cost) to each bucket; all are charged or, when checking, none are
+    /// Bucket keys must be distinct.
+    ///
+    /// Returns the same layout as the Redis GCRA script:
+    /// {allowed, now_ms, blocking_bucket, retry_after_ms, remaining_1, reset_ms_1, ...}
+    pub fn apply(&self, mode: WindowMode, buckets: &[(&str, i64, i64)]) -> Vec<i64> {
+        let now = now_ms();
+        let window = self.window_ms;
+
+        if self
+            .ops
+            .fetch_add(1, Ordering::Relaxed)
+            .is_multiple_of(EVICTION_INTERVAL_OPS)
+        {
+            self.evict_idle(now);
+        }
+
+        let counters: Vec<Arc<Mutex<WindowCounter>>> = buckets
+            .iter()
+            .map(|(key, _, _)| {
+                self.buckets
+                    .entry(key.to_string())
+                    .or_insert_with(|| Arc::new(Mutex::new(WindowCounter::new(now, window))))
+                    .clone()
+            })
+            .collect();
+
+        let mut lock_order: Vec<usize> = (0..buckets.len()).collect();
+        lock_order.sort_by_key(|&i| buckets[i].0);
+        let mut guards: Vec<Option<MutexGuard<'_, WindowCounter>>> =
+            (0..buckets.len()).map(|_| None).collect();
+        for i in lock_order {
+            let mut counter = counters[i].lock().unwrap_or_else(|e| e.into_inner());
+            counter.roll(now, window);
+            guards[i] = Some(counter);
+        }
+        let mut guards: Vec<MutexGuard<'_, WindowCounter>> = guards.into_iter().flatten().collect();
+
+        let mut blocked = 0;
+        let mut retry_after = 0;
+        if mode == WindowMode::Check {
+            for (i, counter) in guards.iter().enumerate() {
+                let (_, limit, cost) = buckets[i];
+                if counter.weighted(now, window) + cost as f64 > limit as f64 {
+                    let wait = counter.retry_after(now, window, limit, cost).max(1);
+                    if wait > retry_after {
+                        retry_after = wait;
+                        blocked = i as i64 + 1;
+                    }
+                }
+            }
+        }
+        let allowed = blocked == 0;
+
+        if mode != WindowMode::Peek && allowed {
+            for (counter, &(_, _, cost)) in guards.iter_mut().zip(buckets) {
+                counter.current = (counter.current + cost).max(0);
+            }
+        }
+
+        let mut reply = vec![allowed as i64, now, blocked, retry_after];
+        for (counter, &(_, limit, _)) in guards.iter().zip(buckets) {
+            let remaining = (limit as f64 - counter.weighted(now, window)).floor() as i64;
+            reply.extend([remaining.max(0), counter.reset_at(now, window)]);
+        }
+
+        reply
+    }
+
+    /// Forget a bucket's counters
+    pub fn reset(&self, key: &str) {
+        self.buckets.remove(key);
+    }
+
+    /// Drop buckets that no longer carry any weight
+    fn evict_idle(&self, now: i64) {
+        let window = self.window_ms;
+        self.buckets.retain(|_, counter| match counter.try_lock() {
+            Ok(counter) => now - counter.window_start < 2 * window,
+            Err(_) => true,
+        });
+    }
+}
+
+/// In-process counting semaphore per key
+#[derive(Default)]
+pub struct LocalSemaphore {
+    counts: DashMap<String, i64>,
+}
+
+impl LocalSemaphore {
+    /// Take a permit if fewer than `limit` are held; returns the count including it
+    pub fn try_acquire(&self, key: &str, limit: i64) -> Option<i64> {
+        let mut count = self.counts.entry(key.to_string()).or_insert(0);
+        if *count >= limit {
+            return None;
+        }
+        *count += 1;
+        Some(*count)
+    }
+
+    /// Return a permit; returns the remaining count
+    pub fn release(&self, key: &str) -> i64 {
+        let remaining = match self.counts.get_mut(key) {
+            Some(mut count) => {
+                *count = (*count - 1).max(0);
+                *count
+            }
+            None => 0,
+        };
+        if remaining == 0 {
+            self.counts.remove_if(key, |_, count| *count == 0);
+        }
+        remaining
+    }
+
+    pub fn count(&self, key: &str) -> i64 {
+        self.counts.get(key).map(|count| *count).unwrap_or(0)
+    }
+}
+
+struct CacheEntry {
+    value: String,
+    expires_at: Instant,
+    tick: u64,
+}
+
+#[derive(Default)]
+struct LruState {
+    entries: HashMap<String, CacheEntry>,
+    // Access order: oldest tick first
+    order: BTreeMap<u64, String>,
+    next_tick: u64,
+}
+
+impl LruState {
+    fn remove(&mut self, key: &str) -> Option<CacheEntry> {
+        let entry = self.entries.remove(key)?;
+        self.order.remove(&entry.tick);
+        Some(entry)
+    }
+
+    fn touch(&mut self, key: &str) -> Option<&CacheEntry> {
+        self.next_tick += 1;
+        let tick = self.next_tick;
+        let entry = self.entries.get_mut(key)?;
+        self.order.remove(&entry.tick);
+        self.order.insert(tick, key.to_string());
+        entry.tick = tick;
+        Some(entry)
+    }
+}
+
+/// Bounded in-process LRU cache with a per-entry TTL
+pub struct LruCache {
+    state: Mutex<LruState>,
+    capacity: usize,
+}
+
+impl LruCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            state: Mutex::new(LruState::default()),
+            capacity: capacity.max(1),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let expired = state.entries.get(key)?.expires_at <= Instant::now();
+        if expired {
+            state.remove(key);
+            return None;
+        }
+        state.touch(key).map(|entry| entry.value.clone())
+    }
+
+    pub fn set(&self, key: &str, value: String, ttl: Duration) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.remove(key);
+
+        // Evict least recently used entries to make room
+        while state.entries.len() >= self.capacity {
+            let Some((_, oldest)) = state.order.pop_first() else {
+                break;
+            };
+            state.entries.remove(&oldest);
+        }
+
+        state.next_tick += 1;
+        let tick = state.next_tick;
+        state.order.insert(tick, key.to_string());
+        state.entries.insert(
+            key.to_string(),
+            CacheEntry {
+                value,
+                expires_at: Instant::now() + ttl,
+                tick,
+            },
+        );
+    }
+
+    pub fn delete(&self, key: &str) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.remove(key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sliding_window_check_and_debit() {
+        let limiter = SlidingWindowLimiter::new(Duration::from_secs(60));
+
+        // 2 requests per window on the first bucket, 10 on the second
+        let reply = limiter.apply(WindowMode::Check, &[("a", 2, 1), ("b", 10, 1)]);
+        assert_eq!(&reply[..3], &[1, reply[1], 0]);
+        assert_eq!(reply[4], 1);
+        assert_eq!(reply[6], 9);
+
+        limiter.apply(WindowMode::Check, &[("a", 2, 1), ("b", 10, 1)]);
+        let reply = limiter.apply(WindowMode::Check, &[("a", 2, 1), ("b", 10, 1)]);
+        assert_eq!(reply[0], 0);
+        assert_eq!(reply[2], 1, "first bucket blocks");
+        assert!(reply[3] > 0);
+        // Nothing was charged to the second bucket
+        assert_eq!(reply[6], 8);
+
+        // Refunds free capacity again
+        limiter.apply(WindowMode::Debit, &[("a", 2, -1)]);
+        let reply = limiter.apply(WindowMode::Peek, &[("a", 2, 0)]);
+        assert_eq!(reply[4], 1);
+
+        limiter.reset("a");
+        let reply = limiter.apply(WindowMode::Peek, &[("a", 2, 0)]);
+        assert_eq!(reply[4], 2);
+    }
+
+    #[test]
+    fn test_sliding_window_weights_previous_window() {
+        let window = 60_000;
+        let counter = WindowCounter {
+            window_start: 0,
+            current: 0,
+            previous: 10,
+        };
+        // Halfway through the window, half of the previous count still applies
+        assert_eq!(counter.weighted(30_000, window), 5.0);
+        // Room for 5 more under a limit of 10, 6 needs another 10% of the window to slide out
+        assert_eq!(counter.retry_after(30_000, window, 10, 5), 0);
+        assert_eq!(counter.retry_after(30_000, window, 10, 6), 6_000);
+        assert_eq!(counter.reset_at(30_000, window), window);
+    }
+
+    #[test]
+    fn test_local_semaphore() {
+        let semaphore = LocalSemaphore::default();
+        assert_eq!(semaphore.try_acquire("k", 2), Some(1));
+        assert_eq!(semaphore.try_acquire("k", 2), Some(2));
+        assert_eq!(semaphore.try_acquire("k", 2), None);
+        assert_eq!(semaphore.release("k"), 1);
+        assert_eq!(semaphore.count("k"), 1);
+        assert_eq!(semaphore.release("k"), 0);
+        assert_eq!(semaphore.count("k"), 0);
+    }
+
+    #[test]
+    fn test_lru_cache_eviction_and_ttl() {
+        let cache = LruCache::new(2);
+        cache.set("a", "1".to_string(), Duration::from_secs(60));
+        cache.set("b", "2".to_string(), Duration::from_secs(60));
+
+        // Touch "a" so "b" is the least recently used
+        assert_eq!(cache.get("a").as_deref(), Some("1"));
+        cache.set("c", "3".to_string(), Duration::from_secs(60));
+        assert!(cache.get("b").is_none());
+        assert_eq!(cache.get("c").as_deref(), Some("3"));
+        assert_eq!(cache.get("a").as_deref(), Some("1"));
+
+        cache.set("d", "4".to_string(), Duration::ZERO);
+        assert!(cache.get("d").is_none());
+
+        cache.delete("a");
+        assert!(cache.get("a").is_none());
+    }
+}
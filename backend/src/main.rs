@@ -4,32 +4,40 @@ use axum::{
     middleware,
     response::{IntoResponse, Response},
     routing::{get, post},
-    Router,
+    Extension, Router,
 };
 use dashmap::DashMap;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
 use tower_http::cors::CorsLayer;
 use tracing::info;
 
+mod audit;
 mod auth;
+mod budget;
 mod cache;
+mod concurrency;
 mod config;
 mod cost;
+mod crypto;
 mod database;
 mod error;
 mod handlers;
 mod load_balancer;
+mod local_store;
 mod metrics;
 mod models;
 mod provider_config;
 mod providers;
 mod rate_limiter;
+mod token_counter;
+mod usage;
 
 use cache::CacheManager;
+use concurrency::ConcurrencyLimiter;
 use config::AppConfig;
 use cost::CostCalculator;
-use database::DatabaseManager;
+use database::{DatabaseManager, NewUsageRecord};
 use error::{ApiError, ApiResult};
 use load_balancer::{LoadBalancer, LoadBalancingStrategy};
 use metrics::MetricsCollector;
@@ -62,6 +70,14 @@ pub struct ChatCompletionRequest {
     pub n: Option<i32>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub user: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream_options: Option<StreamOptions>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct StreamOptions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub include_usage: Option<bool>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -112,7 +128,7 @@ pub struct Choice {
     pub finish_reason: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Usage {
     pub prompt_tokens: i32,
     pub completion_tokens: i32,
@@ -135,8 +151,16 @@ pub struct AppState {
     pub database: DatabaseManager,
     pub cost_calculator: CostCalculator,
     pub load_balancer: LoadBalancer,
-    pub redis: Option<redis::aio::ConnectionManager>,
+    pub redis: local_store::RedisConnection,
     pub rate_limiter: RateLimiter,
+    pub concurrency_limiter: ConcurrencyLimiter,
+    pub key_cache: auth::KeyCache,
+    pub usage_recorder: Arc<usage::UsageRecorder>,
+    pub oauth: auth::OAuthRegistry,
+    pub jwt_keys: auth::JwtKeys,
+    pub login_throttle: auth::LoginThrottle,
+    pub password_hasher: auth::PasswordHasher,
+    pub password_policy: auth::PasswordPolicy,
 }
 
 // Implement middleware traits for AppState
@@ -146,9 +170,35 @@ impl auth::HasMasterKey for AppState {
     }
 }
 
-impl auth::HasJwtSecret for AppState {
-    fn get_jwt_secret(&self) -> &str {
-        &self.config.jwt_secret
+impl auth::HasJwtKeys for AppState {
+    fn get_jwt_keys(&self) -> &auth::JwtKeys {
+        &self.jwt_keys
+    }
+}
+
+impl auth::HasKeyPepper for AppState {
+    fn get_key_pepper(&self) -> &str {
+        &self.config.key_hash_pepper
+    }
+}
+
+impl auth::HasModelProviders for AppState {
+    fn get_model_provider(&self, model: &str) -> Option<String> {
+        self.model_routes
+            .get(model)
+            .map(|route| route.provider.clone())
+    }
+}
+
+impl auth::HasTrustedProxy for AppState {
+    fn trusts_forwarded_for(&self) -> bool {
+        self.config.trust_forwarded_for
+    }
+}
+
+impl auth::HasUsageRecorder for AppState {
+    fn get_usage_recorder(&self) -> &usage::UsageRecorder {
+        &self.usage_recorder
     }
 }
 
@@ -158,9 +208,9 @@ impl auth::HasDatabase for AppState {
     }
 }
 
-impl auth::HasRedis for AppState {
-    fn get_redis_connection(&self) -> Option<&redis::aio::ConnectionManager> {
-        self.redis.as_ref()
+impl auth::HasKeyCache for AppState {
+    fn get_key_cache(&self) -> &auth::KeyCache {
+        &self.key_cache
     }
 }
 
@@ -170,6 +220,12 @@ impl auth::HasRateLimiter for AppState {
     }
 }
 
+impl auth::HasConcurrencyLimiter for AppState {
+    fn get_concurrency_limiter(&self) -> Option<&ConcurrencyLimiter> {
+        Some(&self.concurrency_limiter)
+    }
+}
+
 #[tokio::main]
 async fn main() {
     // Initialize tracing
@@ -178,42 +234,61 @@ async fn main() {
     // Load configuration
     let config = AppConfig::load().expect("Failed to load configuration");
 
-    // Initialize cache
-    let cache = CacheManager::new(
-        config.redis_url.clone(),
-        config.cache_ttl_seconds,
-        config.enable_caching,
+    // Encryption for provider keys and OAuth tokens stored in the database
+    let encryptor = crypto::Encryptor::from_settings(
+        config.encryption_key.as_deref(),
+        config.encryption_key_file.as_deref(),
+        config.encryption_previous_keys.as_deref(),
     )
-    .await;
-    info!("Cache initialized: {}", cache.is_enabled());
+    .expect("Invalid encryption key configuration");
+    match encryptor.current_key_id() {
+        Some(key_id) => info!("Secrets at rest encrypted with key {}", key_id),
+        None => tracing::warn!(
+            "⚠️ ENCRYPTION_KEY not set: provider keys and OAuth tokens are stored unencrypted"
+        ),
+    }
 
     // Initialize database
-    let database = DatabaseManager::new(config.database_url.clone()).await;
+    let database = DatabaseManager::new(config.database_url.clone(), Arc::new(encryptor)).await;
     info!("Database initialized: {}", database.is_enabled());
 
-    // Initialize Redis connection for auth caching
+    // Initialize Redis connection for caching, auth caching and rate limiting
     let redis = if let Some(redis_url) = &config.redis_url {
+        // Bounded timeouts so an outage falls back to in-process state instead of stalling requests
+        let manager_config = redis::aio::ConnectionManagerConfig::new()
+            .set_connection_timeout(std::time::Duration::from_secs(1))
+            .set_response_timeout(std::time::Duration::from_secs(1))
+            .set_number_of_retries(1);
+
         match redis::Client::open(redis_url.as_str()) {
-            Ok(client) => match client.get_connection_manager().await {
-                Ok(manager) => {
-                    info!("Redis connection for auth caching established");
-                    Some(manager)
-                }
-                Err(e) => {
-                    tracing::warn!("Failed to create Redis connection manager: {}", e);
-                    None
-                }
-            },
+            // Keeps connecting in the background if Redis is down right now
+            Ok(client) => local_store::RedisConnection::connect(client, manager_config).await,
+            Err(e) if !config.redis_fail_open => {
+                tracing::error!(
+                    "Invalid REDIS_URL ({}) and REDIS_FAILURE_MODE=closed, refusing to start",
+                    e
+                );
+                std::process::exit(1);
+            }
             Err(e) => {
                 tracing::warn!("Failed to create Redis client: {}", e);
-                None
+                local_store::RedisConnection::disabled()
             }
         }
     } else {
-        info!("Redis URL not provided, auth caching disabled");
-        None
+        info!("Redis URL not provided, using in-process caching and rate limiting");
+        local_store::RedisConnection::disabled()
     };
 
+    // Initialize cache
+    let cache = CacheManager::new(
+        redis.clone(),
+        config.cache_ttl_seconds,
+        config.enable_caching,
+        config.local_cache_max_entries,
+    );
+    info!("Cache initialized: {}", cache.is_enabled());
+
     // Initialize cost calculator
     let cost_calculator = CostCalculator::new();
     info!("Cost calculator initialized");
@@ -223,9 +298,71 @@ async fn main() {
     info!("Load balancer initialized with RoundRobin strategy");
 
     // Initialize rate limiter
-    let rate_limiter = RateLimiter::new(redis.clone());
+    let rate_limiter = RateLimiter::new(
+        redis.clone(),
+        config.rate_limit_default_max_tokens,
+        std::time::Duration::from_millis(config.rate_limit_max_queue_ms),
+        config.redis_fail_open,
+    );
     info!("Rate limiter initialized");
 
+    let concurrency_limiter = ConcurrencyLimiter::new(redis.clone(), config.redis_fail_open);
+
+    // Authenticated keys are cached per replica; changes are broadcast over Redis
+    let key_cache = auth::KeyCache::new(redis.clone());
+    if let (Some(redis_url), true) = (&config.redis_url, redis.is_configured()) {
+        match redis::Client::open(redis_url.as_str()) {
+            Ok(client) => key_cache.spawn_invalidation_listener(client),
+            Err(e) => tracing::warn!("Failed to listen for key invalidations: {}", e),
+        }
+    }
+
+    // Delete expired and revoked sessions
+    if let Some(pool) = database.get_pool() {
+        auth::spawn_session_cleanup_job(pool.clone(), config.session_cleanup_interval_seconds);
+    }
+
+    // Start periodic budget resets
+    if let Some(pool) = database.get_pool() {
+        budget::spawn_budget_reset_job(
+            pool.clone(),
+            key_cache.clone(),
+            config.budget_reset_interval_seconds,
+        );
+        info!(
+            "Budget reset job started (every {}s)",
+            config.budget_reset_interval_seconds
+        );
+    }
+
+    // Key usage and spend are written in batches off the request path
+    let usage_recorder = Arc::new(usage::UsageRecorder::new());
+    if let Some(pool) = database.get_pool() {
+        usage_recorder.spawn_flush_job(
+            pool.clone(),
+            key_cache.clone(),
+            std::time::Duration::from_millis(config.usage_flush_interval_ms),
+        );
+    }
+
+    let oauth = auth::OAuthRegistry::from_config(&config).expect("Invalid OAuth configuration");
+    info!("OAuth providers: {:?}", oauth.names());
+
+    let jwt_keys = auth::JwtKeys::from_settings(
+        &config.jwt_secret,
+        config.jwt_signing_key_file.as_deref(),
+        config.jwt_verification_key_files.as_deref(),
+    )
+    .expect("Invalid JWT key configuration");
+    match jwt_keys.signing_key_id() {
+        Some(kid) => info!(
+            "Signing access tokens with {:?} key {}",
+            jwt_keys.algorithm(),
+            kid
+        ),
+        None => info!("Signing access tokens with JWT_SECRET (HS256)"),
+    }
+
     // Initialize providers
     let mut providers: HashMap<String, Box<dyn LLMProvider>> = HashMap::new();
     providers.insert("anthropic".to_string(), Box::new(AnthropicProvider::new()));
@@ -347,19 +484,42 @@ async fn main() {
         load_balancer,
         redis,
         rate_limiter,
+        concurrency_limiter,
+        key_cache,
+        usage_recorder,
+        oauth,
+        jwt_keys,
+        login_throttle: auth::LoginThrottle::from_config(&config),
+        password_hasher: auth::PasswordHasher::from_config(&config)
+            .expect("Invalid PASSWORD_HASH_* settings"),
+        password_policy: auth::PasswordPolicy::from_config(&config),
     });
 
     // Build authentication routes (public)
     let auth_routes = Router::new()
         .route("/auth/register", post(handlers::register))
         .route("/auth/login", post(handlers::login))
-        .route("/auth/oauth/github", get(handlers::github_oauth_start))
-        .route("/auth/oauth/callback", get(handlers::oauth_callback));
+        .route("/auth/refresh", post(handlers::refresh_session))
+        .route("/auth/oauth/providers", get(handlers::list_oauth_providers))
+        .route("/auth/oauth/callback", get(handlers::oauth_callback))
+        .route("/auth/oauth/exchange", post(handlers::oauth_exchange))
+        .route("/auth/oauth/:provider", get(handlers::oauth_start))
+        .route("/.well-known/jwks.json", get(handlers::jwks));
 
     // User routes (require JWT)
     let user_routes = Router::new()
         .route("/auth/me", get(handlers::get_current_user))
         .route("/auth/logout", post(handlers::logout))
+        .route("/auth/logout-all", post(handlers::logout_all))
+        .route("/auth/sessions", get(handlers::list_sessions))
+        .route("/auth/totp", get(handlers::get_totp_status))
+        .route("/auth/totp/setup", post(handlers::setup_totp))
+        .route("/auth/totp/confirm", post(handlers::confirm_totp))
+        .route("/auth/totp/disable", post(handlers::disable_totp))
+        .route(
+            "/auth/totp/recovery-codes",
+            post(handlers::regenerate_recovery_codes),
+        )
         .route("/auth/keys", get(handlers::get_user_keys))
         .route_layer(middleware::from_fn_with_state(
             app_state.clone(),
@@ -372,18 +532,110 @@ async fn main() {
         .route("/auth/key/info", get(handlers::get_key_info))
         .route("/auth/key/update", post(handlers::update_key))
         .route("/auth/key/delete", post(handlers::delete_key))
+        .route("/auth/key/move", post(handlers::move_key))
+        .route("/auth/key/rotate", post(handlers::rotate_key))
+        .route_layer(middleware::from_fn(auth::deny_virtual_keys))
+        .route_layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            auth::require_auth,
+        ));
+
+    // User and global limit routes (require auth - ManageLimits permission for changes)
+    let limit_routes = Router::new()
+        .route(
+            "/admin/limits/global",
+            get(handlers::get_global_limits).post(handlers::update_global_limits),
+        )
+        .route(
+            "/admin/limits/user",
+            get(handlers::get_user_limits).post(handlers::update_user_limits),
+        )
+        .route_layer(middleware::from_fn(auth::deny_virtual_keys))
+        .route_layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            auth::require_auth,
+        ));
+
+    // User administration routes (require auth - ManageUsers permission)
+    let user_admin_routes = Router::new()
+        .route("/admin/users", get(handlers::list_users))
+        .route("/admin/users/info", get(handlers::get_user))
+        .route("/admin/users/role", post(handlers::update_user_role))
+        .route("/admin/users/disable", post(handlers::disable_user))
+        .route("/admin/users/enable", post(handlers::enable_user))
+        .route("/admin/users/delete", post(handlers::delete_user))
+        .route(
+            "/admin/users/sessions/revoke",
+            post(handlers::revoke_user_sessions),
+        )
+        .route("/admin/users/unlock", post(handlers::unlock_user))
+        .route("/admin/users/totp/reset", post(handlers::reset_user_totp))
+        .route("/admin/users/lockouts", get(handlers::list_user_lockouts))
+        .route_layer(middleware::from_fn(auth::deny_virtual_keys))
         .route_layer(middleware::from_fn_with_state(
             app_state.clone(),
             auth::require_auth,
         ));
 
-    // Provider configuration routes (require auth - master key OR JWT)
+    // Audit log routes (require auth - ViewAuditLog permission)
+    let audit_routes = Router::new()
+        .route("/admin/audit/events", get(handlers::list_audit_events))
+        .route(
+            "/admin/audit/events/export",
+            get(handlers::export_audit_events),
+        )
+        .route_layer(middleware::from_fn(auth::deny_virtual_keys))
+        .route_layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            auth::require_auth,
+        ));
+
+    // Team routes (require auth - team roles checked per handler)
+    let team_routes = Router::new()
+        .route("/teams", get(handlers::list_teams))
+        .route("/teams/create", post(handlers::create_team))
+        .route("/teams/info", get(handlers::get_team))
+        .route("/teams/update", post(handlers::update_team))
+        .route("/teams/delete", post(handlers::delete_team))
+        .route("/teams/members/add", post(handlers::add_team_member))
+        .route("/teams/members/update", post(handlers::update_team_member))
+        .route("/teams/members/remove", post(handlers::remove_team_member))
+        .route("/teams/keys", get(handlers::get_team_keys))
+        .route("/teams/usage", get(handlers::get_team_usage))
+        .route_layer(middleware::from_fn(auth::deny_virtual_keys))
+        .route_layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            auth::require_auth,
+        ));
+
+    // Provider configuration routes (require auth - ManageProviders permission)
     let provider_routes = Router::new()
         .route(
             "/v1/providers/configure",
             post(handlers::update_provider_key),
         )
         .route("/v1/providers/delete", post(handlers::delete_provider_key))
+        .route_layer(middleware::from_fn(auth::deny_virtual_keys))
+        .route_layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            auth::require_auth,
+        ));
+
+    // Model access group routes (require auth - ManageProviders permission)
+    let model_access_routes = Router::new()
+        .route(
+            "/admin/model-groups",
+            get(handlers::list_model_access_groups),
+        )
+        .route(
+            "/admin/model-groups/upsert",
+            post(handlers::upsert_model_access_group),
+        )
+        .route(
+            "/admin/model-groups/delete",
+            post(handlers::delete_model_access_group),
+        )
+        .route_layer(middleware::from_fn(auth::deny_virtual_keys))
         .route_layer(middleware::from_fn_with_state(
             app_state.clone(),
             auth::require_auth,
@@ -393,7 +645,8 @@ async fn main() {
     let api_routes = if config.require_auth {
         Router::new()
             .route("/v1/chat/completions", post(chat_completions))
-            .route("/v1/models", post(list_models))
+            // GET as in the OpenAI API (and for read-only keys); POST for older clients
+            .route("/v1/models", get(list_models).post(list_models))
             .route_layer(middleware::from_fn_with_state(
                 app_state.clone(),
                 auth::enforce_rate_limit,
@@ -405,14 +658,21 @@ async fn main() {
     } else {
         Router::new()
             .route("/v1/chat/completions", post(chat_completions))
-            .route("/v1/models", post(list_models))
+            .route("/v1/models", get(list_models).post(list_models))
     };
 
+    // Stats routes (require auth - scoped to the caller's keys unless their role can view all)
+    let stats_routes = Router::new()
+        .route("/stats", get(stats_handler))
+        .route_layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            auth::require_auth,
+        ));
+
     // Public routes (health and metrics)
     let public_routes = Router::new()
         .route("/health", post(health_check))
         .route("/metrics", get(metrics_handler))
-        .route("/stats", get(stats_handler))
         .route("/v1/providers", get(list_providers));
 
     // Combine all routes
@@ -420,11 +680,17 @@ async fn main() {
         .merge(auth_routes)
         .merge(user_routes)
         .merge(key_routes)
+        .merge(limit_routes)
+        .merge(team_routes)
+        .merge(user_admin_routes)
+        .merge(audit_routes)
         .merge(provider_routes)
+        .merge(model_access_routes)
+        .merge(stats_routes)
         .merge(api_routes)
         .merge(public_routes)
         .layer(CorsLayer::permissive())
-        .with_state(app_state);
+        .with_state(app_state.clone());
 
     let addr = format!("{}:{}", config.host, config.port);
     info!("LLM Gateway server listening on {}", addr);
@@ -433,7 +699,49 @@ async fn main() {
         .await
         .expect("Failed to bind to address");
 
-    axum::serve(listener, app).await.unwrap();
+    // Peer addresses are needed for virtual key IP allowlists
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(shutdown_signal())
+    .await
+    .unwrap();
+
+    // Write usage buffered since the last flush so it isn't lost on shutdown
+    if let Some(pool) = app_state.database.get_pool() {
+        app_state
+            .usage_recorder
+            .flush(pool, &app_state.key_cache)
+            .await;
+    }
+    info!("Server shut down");
+}
+
+/// Resolve on Ctrl+C or SIGTERM
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to install Ctrl+C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+    info!("Shutdown signal received, draining connections");
 }
 
 /// Helper function to add rate limit headers to a response
@@ -441,33 +749,92 @@ fn add_rate_limit_headers(
     mut response: Response,
     status: &rate_limiter::RateLimitStatus,
 ) -> Response {
-    let headers = response.headers_mut();
+    auth::apply_rate_limit_headers(response.headers_mut(), status);
+    response
+}
 
-    if let Some(requests_remaining) = status.requests_remaining {
-        headers.insert(
-            "X-RateLimit-Limit-Requests",
-            axum::http::HeaderValue::from_str(&requests_remaining.to_string())
-                .unwrap_or_else(|_| axum::http::HeaderValue::from_static("0")),
-        );
+/// Settle a key's token reservation against the tokens the call actually used
+/// Returns the updated status so response headers reflect real usage
+async fn reconcile_token_reservation(
+    rate_limiter: &RateLimiter,
+    key_info: &auth::VirtualKeyInfo,
+    actual_tokens: i32,
+) -> Option<rate_limiter::RateLimitStatus> {
+    if !key_info.has_token_limit() {
+        return None;
     }
 
-    if let Some(tokens_remaining) = status.tokens_remaining {
-        headers.insert(
-            "X-RateLimit-Limit-Tokens",
-            axum::http::HeaderValue::from_str(&tokens_remaining.to_string())
-                .unwrap_or_else(|_| axum::http::HeaderValue::from_static("0")),
-        );
+    match rate_limiter
+        .debit_tokens(
+            &key_info.rate_limit_levels,
+            actual_tokens - key_info.reserved_tokens,
+        )
+        .await
+    {
+        Ok(status) => Some(status),
+        Err(e) => {
+            tracing::warn!(
+                "Failed to reconcile token usage for key {}: {}",
+                key_info.key_id,
+                e
+            );
+            None
+        }
     }
+}
 
-    if let Some(reset_at) = status.reset_at {
-        headers.insert(
-            "X-RateLimit-Reset",
-            axum::http::HeaderValue::from_str(&reset_at.to_string())
-                .unwrap_or_else(|_| axum::http::HeaderValue::from_static("0")),
+/// Charge and record a finished (or abandoned) stream the way a regular response is
+async fn record_stream_usage(
+    state: &AppState,
+    key_info: Option<&auth::VirtualKeyInfo>,
+    model: &str,
+    provider: &str,
+    user: Option<String>,
+    usage: Usage,
+    start_time: std::time::Instant,
+) {
+    let cost =
+        state
+            .cost_calculator
+            .calculate_cost(model, usage.prompt_tokens, usage.completion_tokens);
+    MetricsCollector::record_tokens(
+        model,
+        provider,
+        usage.prompt_tokens,
+        usage.completion_tokens,
+    );
+    MetricsCollector::record_cost(model, provider, cost);
+
+    if let Some(info) = key_info {
+        state.usage_recorder.record_spend(
+            info.key_id,
+            info.user_id,
+            info.team_id,
+            info.model_pattern.clone(),
+            cost,
         );
+        reconcile_token_reservation(&state.rate_limiter, info, usage.total_tokens).await;
     }
 
-    response
+    if state.database.is_enabled() {
+        let _ = state
+            .database
+            .record_usage(NewUsageRecord {
+                model,
+                provider,
+                prompt_tokens: usage.prompt_tokens,
+                completion_tokens: usage.completion_tokens,
+                total_tokens: usage.total_tokens,
+                cost_usd: cost,
+                latency_ms: start_time.elapsed().as_millis() as i64,
+                user_id: user,
+                virtual_key_id: key_info.map(|info| info.key_id),
+                team_id: key_info.and_then(|info| info.team_id),
+                cached: false,
+                error: None,
+            })
+            .await;
+    }
 }
 
 async fn chat_completions(
@@ -478,34 +845,22 @@ async fn chat_completions(
     let start_time = std::time::Instant::now();
     tracing::info!("🚀 Request started for model: {}", request.model);
 
-    // Get current rate limit status for headers
-    let rate_limit_status = if let Some(ref info) = key_info {
-        if info.rate_limit_rpm.is_some() || info.rate_limit_tpm.is_some() {
-            state
-                .rate_limiter
-                .get_status(
-                    &info.key_id.to_string(),
-                    &rate_limiter::RateLimit {
-                        requests_per_minute: info.rate_limit_rpm,
-                        tokens_per_minute: info.rate_limit_tpm,
-                    },
-                )
-                .await
-                .ok()
-        } else {
-            None
-        }
-    } else {
-        None
-    };
+    // Rate limit status from the pre-flight check, used for response headers
+    let mut rate_limit_status = key_info
+        .as_ref()
+        .and_then(|info| info.rate_limit_status.clone());
 
     // Get model route (lock-free with DashMap)
     let route_lookup_start = std::time::Instant::now();
-    let route = state
-        .model_routes
-        .get(&request.model)
-        .ok_or_else(|| ApiError::ModelNotFound(request.model.clone()))?
-        .clone();
+    let route = match state.model_routes.get(&request.model) {
+        Some(route) => route.clone(),
+        None => {
+            if let Some(ref info) = key_info {
+                reconcile_token_reservation(&state.rate_limiter, info, 0).await;
+            }
+            return Err(ApiError::ModelNotFound(request.model.clone()));
+        }
+    };
     tracing::info!(
         "📋 Route lookup (lock-free): {:?}",
         route_lookup_start.elapsed()
@@ -532,24 +887,35 @@ async fn chat_completions(
             if state.database.is_enabled() {
                 let _ = state
                     .database
-                    .record_usage(
-                        &request.model,
-                        &route.provider,
-                        cached_response.usage.prompt_tokens,
-                        cached_response.usage.completion_tokens,
-                        cached_response.usage.total_tokens,
-                        0.0, // No cost for cached requests
-                        start_time.elapsed().as_millis() as i64,
-                        request.user.clone(),
-                        true,
-                        None,
-                    )
+                    .record_usage(NewUsageRecord {
+                        model: &request.model,
+                        provider: &route.provider,
+                        prompt_tokens: cached_response.usage.prompt_tokens,
+                        completion_tokens: cached_response.usage.completion_tokens,
+                        total_tokens: cached_response.usage.total_tokens,
+                        cost_usd: 0.0, // No cost for cached requests
+                        latency_ms: start_time.elapsed().as_millis() as i64,
+                        user_id: request.user.clone(),
+                        virtual_key_id: key_info.as_ref().map(|info| info.key_id),
+                        team_id: key_info.as_ref().and_then(|info| info.team_id),
+                        cached: true,
+                        error: None,
+                    })
                     .await;
             }
 
+            // Cached responses don't use provider tokens, so release the reservation
+            if let Some(ref info) = key_info {
+                if let Some(status) =
+                    reconcile_token_reservation(&state.rate_limiter, info, 0).await
+                {
+                    rate_limit_status = Some(status);
+                }
+            }
+
             tracing::info!("✅ Total time (cached): {:?}", start_time.elapsed());
             let mut response = Json(cached_response).into_response();
-            if let Some(ref status) = rate_limit_status {
+            if let Some(status) = &rate_limit_status {
                 response = add_rate_limit_headers(response, status);
             }
             return Ok(response);
@@ -560,10 +926,15 @@ async fn chat_completions(
     }
 
     // Get provider
-    let provider = state
-        .providers
-        .get(&route.provider)
-        .ok_or_else(|| ApiError::ProviderNotFound(route.provider.clone()))?;
+    let provider = match state.providers.get(&route.provider) {
+        Some(provider) => provider,
+        None => {
+            if let Some(ref info) = key_info {
+                reconcile_token_reservation(&state.rate_limiter, info, 0).await;
+            }
+            return Err(ApiError::ProviderNotFound(route.provider.clone()));
+        }
+    };
 
     // Record active request
     MetricsCollector::inc_active_requests(&route.provider);
@@ -586,31 +957,59 @@ async fn chat_completions(
                     )
                     .await;
 
-                let mut response_builder = Response::builder()
+                // Charge the stream's cost, settle the token reservation and record usage once
+                // the stream ends or the client goes away
+                let estimated_prompt_tokens = token_counter::estimate_prompt_tokens(&request);
+                let stream = {
+                    let state = state.clone();
+                    let key_info = key_info.clone();
+                    let model = request.model.clone();
+                    let provider_name = route.provider.clone();
+                    let user = request.user.clone();
+                    token_counter::track_stream_usage(
+                        stream,
+                        estimated_prompt_tokens,
+                        move |usage| {
+                            tokio::spawn(async move {
+                                record_stream_usage(
+                                    &state,
+                                    key_info.as_ref(),
+                                    &model,
+                                    &provider_name,
+                                    user,
+                                    usage,
+                                    start_time,
+                                )
+                                .await;
+                            });
+                        },
+                    )
+                };
+                let client_wants_usage = request
+                    .stream_options
+                    .as_ref()
+                    .and_then(|options| options.include_usage)
+                    .unwrap_or(false);
+                let stream = if client_wants_usage {
+                    stream
+                } else {
+                    token_counter::strip_usage_chunks(stream)
+                };
+
+                let mut response = Response::builder()
                     .status(StatusCode::OK)
                     .header("Content-Type", "text/event-stream")
                     .header("Cache-Control", "no-cache")
-                    .header("Connection", "keep-alive");
+                    .header("Connection", "keep-alive")
+                    .body(axum::body::Body::from_stream(stream))
+                    .unwrap();
 
-                // Add rate limit headers to streaming response
-                if let Some(ref status) = rate_limit_status {
-                    if let Some(requests_remaining) = status.requests_remaining {
-                        response_builder = response_builder
-                            .header("X-RateLimit-Limit-Requests", requests_remaining.to_string());
-                    }
-                    if let Some(tokens_remaining) = status.tokens_remaining {
-                        response_builder = response_builder
-                            .header("X-RateLimit-Limit-Tokens", tokens_remaining.to_string());
-                    }
-                    if let Some(reset_at) = status.reset_at {
-                        response_builder =
-                            response_builder.header("X-RateLimit-Reset", reset_at.to_string());
-                    }
+                // Add rate limit headers to streaming response (remaining reflects the reservation)
+                if let Some(status) = &rate_limit_status {
+                    response = add_rate_limit_headers(response, status);
                 }
 
-                Ok(response_builder
-                    .body(axum::body::Body::from_stream(stream))
-                    .unwrap())
+                Ok(response)
             }
             Err(e) => {
                 MetricsCollector::dec_active_requests(&route.provider);
@@ -619,6 +1018,9 @@ async fn chat_completions(
                     .load_balancer
                     .record_error(&route.provider, &route.target_model)
                     .await;
+                if let Some(ref info) = key_info {
+                    reconcile_token_reservation(&state.rate_limiter, info, 0).await;
+                }
                 Err(e)
             }
         }
@@ -654,22 +1056,49 @@ async fn chat_completions(
                 MetricsCollector::record_cost(&request.model, &route.provider, cost);
                 MetricsCollector::record_latency(&request.model, &route.provider, latency_secs);
 
+                // Charge the key (and its matching model limit), its team, its user and the global
+                // budget; written in the background with the next usage flush
+                if let Some(info) = &key_info {
+                    state.usage_recorder.record_spend(
+                        info.key_id,
+                        info.user_id,
+                        info.team_id,
+                        info.model_pattern.clone(),
+                        cost,
+                    );
+                }
+
+                // Settle the token reservation against the real usage
+                if let Some(ref info) = key_info {
+                    if let Some(status) = reconcile_token_reservation(
+                        &state.rate_limiter,
+                        info,
+                        response.usage.total_tokens,
+                    )
+                    .await
+                    {
+                        rate_limit_status = Some(status);
+                    }
+                }
+
                 // Record in database
                 if state.database.is_enabled() {
                     let _ = state
                         .database
-                        .record_usage(
-                            &request.model,
-                            &route.provider,
-                            response.usage.prompt_tokens,
-                            response.usage.completion_tokens,
-                            response.usage.total_tokens,
-                            cost,
+                        .record_usage(NewUsageRecord {
+                            model: &request.model,
+                            provider: &route.provider,
+                            prompt_tokens: response.usage.prompt_tokens,
+                            completion_tokens: response.usage.completion_tokens,
+                            total_tokens: response.usage.total_tokens,
+                            cost_usd: cost,
                             latency_ms,
-                            request.user.clone(),
-                            false,
-                            None,
-                        )
+                            user_id: request.user.clone(),
+                            virtual_key_id: key_info.as_ref().map(|info| info.key_id),
+                            team_id: key_info.as_ref().and_then(|info| info.team_id),
+                            cached: false,
+                            error: None,
+                        })
                         .await;
                 }
 
@@ -692,7 +1121,7 @@ async fn chat_completions(
 
                 tracing::info!("✅ Total time: {:?}", start_time.elapsed());
                 let mut final_response = Json(response).into_response();
-                if let Some(ref status) = rate_limit_status {
+                if let Some(status) = &rate_limit_status {
                     final_response = add_rate_limit_headers(final_response, status);
                 }
                 Ok(final_response)
@@ -707,22 +1136,28 @@ async fn chat_completions(
                     .record_error(&route.provider, &route.target_model)
                     .await;
 
+                if let Some(ref info) = key_info {
+                    reconcile_token_reservation(&state.rate_limiter, info, 0).await;
+                }
+
                 // Record error in database
                 if state.database.is_enabled() {
                     let _ = state
                         .database
-                        .record_usage(
-                            &request.model,
-                            &route.provider,
-                            0,
-                            0,
-                            0,
-                            0.0,
+                        .record_usage(NewUsageRecord {
+                            model: &request.model,
+                            provider: &route.provider,
+                            prompt_tokens: 0,
+                            completion_tokens: 0,
+                            total_tokens: 0,
+                            cost_usd: 0.0,
                             latency_ms,
-                            request.user.clone(),
-                            false,
-                            Some(e.to_string()),
-                        )
+                            user_id: request.user.clone(),
+                            virtual_key_id: key_info.as_ref().map(|info| info.key_id),
+                            team_id: key_info.as_ref().and_then(|info| info.team_id),
+                            cached: false,
+                            error: Some(e.to_string()),
+                        })
                         .await;
                 }
 
@@ -734,11 +1169,21 @@ async fn chat_completions(
     result
 }
 
-async fn list_models(State(state): State<Arc<AppState>>) -> ApiResult<Json<serde_json::Value>> {
-    // DashMap provides lock-free iteration
+async fn list_models(
+    State(state): State<Arc<AppState>>,
+    principal: Option<Extension<auth::Principal>>,
+) -> ApiResult<Json<serde_json::Value>> {
+    // DashMap provides lock-free iteration; keys only see the models they may use
     let models: Vec<serde_json::Value> = state
         .model_routes
         .iter()
+        .filter(|entry| {
+            principal.as_ref().is_none_or(|Extension(principal)| {
+                principal
+                    .model_denial(entry.key(), Some(&entry.value().provider))
+                    .is_none()
+            })
+        })
         .map(|entry| {
             serde_json::json!({
                 "id": entry.key(),
@@ -835,15 +1280,25 @@ async fn metrics_handler() -> impl IntoResponse {
     }
 }
 
-async fn stats_handler(State(state): State<Arc<AppState>>) -> ApiResult<Json<serde_json::Value>> {
+/// Usage stats: gateway-wide for roles with `ViewStats`, otherwise only the caller's keys
+async fn stats_handler(
+    State(state): State<Arc<AppState>>,
+    auth_user: auth::AuthUser,
+) -> ApiResult<Json<serde_json::Value>> {
     if !state.database.is_enabled() {
         return Ok(Json(serde_json::json!({
             "error": "Database not enabled, stats unavailable"
         })));
     }
 
-    let stats = state.database.get_usage_stats(7).await?;
-    let recent_usage = state.database.get_recent_usage(10).await?;
+    let owner_id = if auth_user.has(auth::Permission::ViewStats) {
+        None
+    } else {
+        Some(auth_user.user_id)
+    };
+
+    let stats = state.database.get_usage_stats(7, owner_id).await?;
+    let recent_usage = state.database.get_recent_usage(10, owner_id).await?;
     let health_stats = state.load_balancer.get_all_health_stats().await;
 
     Ok(Json(serde_json::json!({
@@ -851,6 +1306,8 @@ async fn stats_handler(State(state): State<Arc<AppState>>) -> ApiResult<Json<ser
         "recent_requests": recent_usage,
         "provider_health": health_stats,
         "cache_enabled": state.cache.is_enabled(),
+        "cache_backend": if state.cache.is_local() { "local" } else { "redis" },
+        "rate_limit_backend": if state.rate_limiter.is_local() { "local" } else { "redis" },
         "database_enabled": state.database.is_enabled(),
     })))
 }
@@ -76,6 +76,14 @@ lazy_static! {
         &["key_id", "limit_type"]
     )
     .unwrap();
+
+    // Parallel request metrics
+    pub static ref KEY_IN_FLIGHT_REQUESTS: GaugeVec = register_gauge_vec!(
+        "llm_gateway_key_in_flight_requests",
+        "Number of in-flight requests per virtual key",
+        &["key_id"]
+    )
+    .unwrap();
 }
 
 pub struct MetricsCollector;
@@ -144,6 +152,12 @@ impl MetricsCollector {
             .set(remaining as f64);
     }
 
+    pub fn set_key_in_flight_requests(key_id: &str, in_flight: i64) {
+        KEY_IN_FLIGHT_REQUESTS
+            .with_label_values(&[key_id])
+            .set(in_flight as f64);
+    }
+
     pub fn export_metrics() -> Result<String, Box<dyn std::error::Error>> {
         let encoder = TextEncoder::new();
         let metric_families = prometheus::gather();
@@ -0,0 +1,150 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use uuid::Uuid;
+
+use crate::error::{ApiError, ApiResult};
+
+/// An entry of the append-only audit log (see [`crate::audit`])
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct AuditEvent {
+    pub id: i64,
+    /// `user`, `virtual_key`, `master_key` or `anonymous`
+    pub actor_type: String,
+    pub actor_id: Option<Uuid>,
+    pub actor_email: Option<String>,
+    pub actor_key_id: Option<Uuid>,
+    /// e.g. `key.update`, `provider.configure`, `user.login`
+    pub action: String,
+    pub target_type: Option<String>,
+    pub target_id: Option<String>,
+    /// Redacted `{"before": .., "after": .., "details": ..}`
+    pub changes: Option<serde_json::Value>,
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// An audit event to append
+#[derive(Debug, Clone)]
+pub struct NewAuditEvent {
+    pub actor_type: String,
+    pub actor_id: Option<Uuid>,
+    pub actor_email: Option<String>,
+    pub actor_key_id: Option<Uuid>,
+    pub action: String,
+    pub target_type: Option<String>,
+    pub target_id: Option<String>,
+    pub changes: Option<serde_json::Value>,
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+/// Which audit events to return; unset fields match everything
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AuditEventFilter {
+    pub actor_id: Option<Uuid>,
+    /// An exact action, or a prefix ending in `.` such as `key.`
+    pub action: Option<String>,
+    pub target_type: Option<String>,
+    pub target_id: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+}
+
+impl AuditEvent {
+    pub async fn insert(pool: &Pool<Postgres>, event: &NewAuditEvent) -> ApiResult<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO audit_events
+            (actor_type, actor_id, actor_email, actor_key_id, action, target_type, target_id,
+             changes, ip_address, user_agent)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+            "#,
+        )
+        .bind(&event.actor_type)
+        .bind(event.actor_id)
+        .bind(&event.actor_email)
+        .bind(event.actor_key_id)
+        .bind(&event.action)
+        .bind(&event.target_type)
+        .bind(&event.target_id)
+        .bind(&event.changes)
+        .bind(&event.ip_address)
+        .bind(&event.user_agent)
+        .execute(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        Ok(())
+    }
+
+    /// Events matching `filter`, newest first, starting below `before_id` when given
+    pub async fn list(
+        pool: &Pool<Postgres>,
+        filter: &AuditEventFilter,
+        before_id: Option<i64>,
+        limit: i64,
+    ) -> ApiResult<Vec<Self>> {
+        Self::query(
+            pool,
+            filter,
+            "id < $7",
+            "DESC",
+            before_id.unwrap_or(i64::MAX),
+            limit,
+        )
+        .await
+    }
+
+    /// Events matching `filter`, oldest first, starting above `after_id`, for exports
+    pub async fn list_after(
+        pool: &Pool<Postgres>,
+        filter: &AuditEventFilter,
+        after_id: i64,
+        limit: i64,
+    ) -> ApiResult<Vec<Self>> {
+        Self::query(pool, filter, "id > $7", "ASC", after_id, limit).await
+    }
+
+    async fn query(
+        pool: &Pool<Postgres>,
+        filter: &AuditEventFilter,
+        cursor_condition: &str,
+        order: &str,
+        cursor: i64,
+        limit: i64,
+    ) -> ApiResult<Vec<Self>> {
+        let sql = format!(
+            r#"
+            SELECT id, actor_type, actor_id, actor_email, actor_key_id, action, target_type,
+                   target_id, changes, ip_address, user_agent, created_at
+            FROM audit_events
+            WHERE ($1::uuid IS NULL OR actor_id = $1)
+              AND ($2::text IS NULL OR action = $2
+                   OR (right($2, 1) = '.' AND starts_with(action, $2)))
+              AND ($3::text IS NULL OR target_type = $3)
+              AND ($4::text IS NULL OR target_id = $4)
+              AND ($5::timestamptz IS NULL OR created_at >= $5)
+              AND ($6::timestamptz IS NULL OR created_at < $6)
+              AND {}
+            ORDER BY id {}
+            LIMIT $8
+            "#,
+            cursor_condition, order
+        );
+
+        sqlx::query_as::<_, AuditEvent>(&sql)
+            .bind(filter.actor_id)
+            .bind(&filter.action)
+            .bind(&filter.target_type)
+            .bind(&filter.target_id)
+            .bind(filter.since)
+            .bind(filter.until)
+            .bind(cursor)
+            .bind(limit)
+            .fetch_all(pool)
+            .await
+            .map_err(|e| ApiError::DatabaseError(e.to_string()))
+    }
+}
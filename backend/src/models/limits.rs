@@ -0,0 +1,231 @@
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use uuid::Uuid;
+
+use crate::error::{ApiError, ApiResult};
+use crate::rate_limiter::{LimitScope, RateLimit, RateLimitLevel};
+
+/// Rate limits and budget attached to a team, a user or the whole gateway
+/// These apply on top of each virtual key's own limits; NULL means unlimited.
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct ScopeLimits {
+    pub rate_limit_rpm: Option<i32>,
+    pub rate_limit_tpm: Option<i32>,
+    pub max_budget: Option<f64>,
+    pub current_spend: f64,
+}
+
+/// Limits for one level above the key, tagged with the level they belong to
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScopedLimits {
+    pub scope: LimitScope,
+    pub id: String,
+    pub limits: ScopeLimits,
+}
+
+#[derive(sqlx::FromRow)]
+struct ScopedLimitsRow {
+    scope: String,
+    id: String,
+    rate_limit_rpm: Option<i32>,
+    rate_limit_tpm: Option<i32>,
+    max_budget: Option<f64>,
+    current_spend: f64,
+}
+
+impl ScopeLimits {
+    pub fn rate_limit(&self) -> RateLimit {
+        RateLimit {
+            requests_per_minute: self.rate_limit_rpm,
+            tokens_per_minute: self.rate_limit_tpm,
+        }
+    }
+
+    pub fn is_over_budget(&self) -> bool {
+        self.max_budget
+            .is_some_and(|max_budget| self.current_spend >= max_budget)
+    }
+
+    /// Limits for the team and user (if any) and the global scope, fetched in one query
+    pub async fn applicable(
+        pool: &Pool<Postgres>,
+        user_id: Option<Uuid>,
+        team_id: Option<Uuid>,
+    ) -> ApiResult<Vec<ScopedLimits>> {
+        let rows = sqlx::query_as::<_, ScopedLimitsRow>(
+            r#"
+            SELECT 'team' AS scope, id::text AS id, rate_limit_rpm, rate_limit_tpm, max_budget, current_spend
+            FROM teams
+            WHERE id = $2
+            UNION ALL
+            SELECT 'user' AS scope, id::text AS id, rate_limit_rpm, rate_limit_tpm, max_budget, current_spend
+            FROM users
+            WHERE id = $1
+            UNION ALL
+            SELECT 'global' AS scope, 'global' AS id, rate_limit_rpm, rate_limit_tpm, max_budget, current_spend
+            FROM global_limits
+            "#,
+        )
+        .bind(user_id)
+        .bind(team_id)
+        .fetch_all(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| ScopedLimits {
+                scope: match row.scope.as_str() {
+                    "team" => LimitScope::Team,
+                    "user" => LimitScope::User,
+                    _ => LimitScope::Global,
+                },
+                id: row.id,
+                limits: ScopeLimits {
+                    rate_limit_rpm: row.rate_limit_rpm,
+                    rate_limit_tpm: row.rate_limit_tpm,
+                    max_budget: row.max_budget,
+                    current_spend: row.current_spend,
+                },
+            })
+            .collect())
+    }
+
+    /// Find a user's limits
+    pub async fn find_for_user(pool: &Pool<Postgres>, user_id: Uuid) -> ApiResult<Option<Self>> {
+        sqlx::query_as::<_, ScopeLimits>(
+            r#"
+            SELECT rate_limit_rpm, rate_limit_tpm, max_budget, current_spend
+            FROM users
+            WHERE id = $1
+            "#,
+        )
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))
+    }
+
+    /// Get the gateway-wide limits
+    pub async fn find_global(pool: &Pool<Postgres>) -> ApiResult<Self> {
+        sqlx::query_as::<_, ScopeLimits>(
+            r#"
+            SELECT rate_limit_rpm, rate_limit_tpm, max_budget, current_spend
+            FROM global_limits
+            "#,
+        )
+        .fetch_one(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))
+    }
+
+    /// Update a user's limits; `reset_spend` zeroes the spend counted against the budget
+    pub async fn update_for_user(
+        pool: &Pool<Postgres>,
+        user_id: Uuid,
+        update: &UpdateLimitsRequest,
+    ) -> ApiResult<Self> {
+        sqlx::query_as::<_, ScopeLimits>(
+            r#"
+            UPDATE users
+            SET rate_limit_rpm = COALESCE($2, rate_limit_rpm),
+                rate_limit_tpm = COALESCE($3, rate_limit_tpm),
+                max_budget = COALESCE($4, max_budget),
+                current_spend = CASE WHEN $5 THEN 0 ELSE current_spend END,
+                updated_at = NOW()
+            WHERE id = $1
+            RETURNING rate_limit_rpm, rate_limit_tpm, max_budget, current_spend
+            "#,
+        )
+        .bind(user_id)
+        .bind(update.rate_limit_rpm)
+        .bind(update.rate_limit_tpm)
+        .bind(update.max_budget)
+        .bind(update.reset_spend.unwrap_or(false))
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?
+        .ok_or_else(|| ApiError::NotFound("User not found".to_string()))
+    }
+
+    /// Update the gateway-wide limits; `reset_spend` zeroes the spend counted against the budget
+    pub async fn update_global(
+        pool: &Pool<Postgres>,
+        update: &UpdateLimitsRequest,
+    ) -> ApiResult<Self> {
+        sqlx::query_as::<_, ScopeLimits>(
+            r#"
+            UPDATE global_limits
+            SET rate_limit_rpm = COALESCE($1, rate_limit_rpm),
+                rate_limit_tpm = COALESCE($2, rate_limit_tpm),
+                max_budget = COALESCE($3, max_budget),
+                current_spend = CASE WHEN $4 THEN 0 ELSE current_spend END,
+                updated_at = NOW()
+            RETURNING rate_limit_rpm, rate_limit_tpm, max_budget, current_spend
+            "#,
+        )
+        .bind(update.rate_limit_rpm)
+        .bind(update.rate_limit_tpm)
+        .bind(update.max_budget)
+        .bind(update.reset_spend.unwrap_or(false))
+        .fetch_one(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))
+    }
+
+    /// Charge a request's cost to its user and team (if any) and to the global budget
+    /// Returns whether this pushed any of them over its budget.
+    pub async fn increment_spend(
+        pool: &Pool<Postgres>,
+        user_id: Option<Uuid>,
+        team_id: Option<Uuid>,
+        amount: f64,
+    ) -> ApiResult<bool> {
+        sqlx::query_scalar::<_, bool>(
+            r#"
+            WITH user_spend AS (
+                UPDATE users
+                SET current_spend = current_spend + $2
+                WHERE id = $1
+                RETURNING max_budget, current_spend
+            ), team_spend AS (
+                UPDATE teams
+                SET current_spend = current_spend + $2
+                WHERE id = $3
+                RETURNING max_budget, current_spend
+            ), global_spend AS (
+                UPDATE global_limits
+                SET current_spend = current_spend + $2
+                RETURNING max_budget, current_spend
+            )
+            SELECT COALESCE(BOOL_OR(current_spend >= max_budget AND current_spend - $2 < max_budget), FALSE)
+            FROM (
+                SELECT * FROM user_spend
+                UNION ALL SELECT * FROM team_spend
+                UNION ALL SELECT * FROM global_spend
+            ) AS charged
+            "#,
+        )
+        .bind(user_id)
+        .bind(amount)
+        .bind(team_id)
+        .fetch_one(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))
+    }
+}
+
+impl ScopedLimits {
+    pub fn rate_limit_level(&self) -> RateLimitLevel {
+        RateLimitLevel::new(self.scope, self.id.clone(), self.limits.rate_limit())
+    }
+}
+
+/// Request to update user or global limits
+#[derive(Debug, Deserialize)]
+pub struct UpdateLimitsRequest {
+    pub rate_limit_rpm: Option<i32>,
+    pub rate_limit_tpm: Option<i32>,
+    pub max_budget: Option<f64>,
+    pub reset_spend: Option<bool>,
+}
@@ -0,0 +1,212 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use uuid::Uuid;
+
+use crate::error::{ApiError, ApiResult};
+
+/// An account locked after too many consecutive failed logins
+///
+/// Rows are never deleted (except with the user), so they double as the audit trail of
+/// lockouts and of early unlocks by admins.
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct LoginLockout {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub ip_address: Option<String>,
+    pub failed_attempts: i32,
+    pub locked_until: DateTime<Utc>,
+    pub unlocked_at: Option<DateTime<Utc>>,
+    pub unlocked_by: Option<Uuid>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Outcome of recording a failed login
+#[derive(Debug, Clone)]
+pub struct LoginFailure {
+    /// Consecutive failures, including this one
+    pub attempts: i32,
+    /// Set when this failure locked the account
+    pub lockout: Option<LoginLockout>,
+}
+
+impl LoginLockout {
+    /// When the user's current lockout ends, if they're locked out
+    pub async fn locked_until(
+        pool: &Pool<Postgres>,
+        user_id: Uuid,
+    ) -> ApiResult<Option<DateTime<Utc>>> {
+        let locked_until: Option<Option<DateTime<Utc>>> = sqlx::query_scalar(
+            r#"
+            SELECT locked_until
+            FROM users
+            WHERE id = $1 AND locked_until > NOW()
+            "#,
+        )
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        Ok(locked_until.flatten())
+    }
+
+    /// Count a failed login, locking the account once `threshold` consecutive failures
+    /// happen within `lockout_seconds` of each other
+    ///
+    /// Locking resets the count, so the next lockout needs another `threshold` failures.
+    /// A `threshold` of 0 never locks.
+    pub async fn record_failure(
+        pool: &Pool<Postgres>,
+        user_id: Uuid,
+        ip_address: Option<String>,
+        threshold: i32,
+        lockout_seconds: i64,
+    ) -> ApiResult<LoginFailure> {
+        let (attempts, locked): (i32, bool) = sqlx::query_as(
+            r#"
+            WITH counted AS (
+                SELECT id,
+                       CASE WHEN last_failed_login_at > NOW() - make_interval(secs => $3)
+                            THEN failed_login_attempts + 1
+                            ELSE 1
+                       END AS attempts
+                FROM users
+                WHERE id = $1
+            )
+            UPDATE users u
+            SET failed_login_attempts = CASE WHEN $2 > 0 AND c.attempts >= $2
+                                             THEN 0 ELSE c.attempts END,
+                last_failed_login_at = NOW(),
+                locked_until = CASE WHEN $2 > 0 AND c.attempts >= $2
+                                    THEN NOW() + make_interval(secs => $3)
+                                    ELSE u.locked_until END
+            FROM counted c
+            WHERE u.id = c.id
+            RETURNING c.attempts, ($2 > 0 AND c.attempts >= $2)
+            "#,
+        )
+        .bind(user_id)
+        .bind(threshold)
+        .bind(lockout_seconds as f64)
+        .fetch_one(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        if !locked {
+            return Ok(LoginFailure {
+                attempts,
+                lockout: None,
+            });
+        }
+
+        let lockout = sqlx::query_as::<_, LoginLockout>(
+            r#"
+            INSERT INTO login_lockouts (user_id, ip_address, failed_attempts, locked_until)
+            SELECT id, $2, $3, locked_until
+            FROM users
+            WHERE id = $1
+            RETURNING id, user_id, ip_address, failed_attempts, locked_until, unlocked_at,
+                      unlocked_by, created_at
+            "#,
+        )
+        .bind(user_id)
+        .bind(ip_address)
+        .bind(attempts)
+        .fetch_one(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        Ok(LoginFailure {
+            attempts,
+            lockout: Some(lockout),
+        })
+    }
+
+    /// Forget a user's failed logins after a successful one
+    pub async fn clear_failures(pool: &Pool<Postgres>, user_id: Uuid) -> ApiResult<()> {
+        sqlx::query(
+            r#"
+            UPDATE users
+            SET failed_login_attempts = 0, last_failed_login_at = NULL
+            WHERE id = $1 AND (failed_login_attempts > 0 OR last_failed_login_at IS NOT NULL)
+            "#,
+        )
+        .bind(user_id)
+        .execute(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        Ok(())
+    }
+
+    /// End a user's lockout early and clear their failures; returns whether they were locked
+    pub async fn unlock(
+        pool: &Pool<Postgres>,
+        user_id: Uuid,
+        unlocked_by: Option<Uuid>,
+    ) -> ApiResult<bool> {
+        let mut tx = pool
+            .begin()
+            .await
+            .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        let was_locked: Option<bool> = sqlx::query_scalar(
+            r#"
+            UPDATE users u
+            SET failed_login_attempts = 0,
+                last_failed_login_at = NULL,
+                locked_until = NULL
+            FROM (SELECT id, locked_until FROM users WHERE id = $1 FOR UPDATE) previous
+            WHERE u.id = previous.id
+            RETURNING COALESCE(previous.locked_until > NOW(), false)
+            "#,
+        )
+        .bind(user_id)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        sqlx::query(
+            r#"
+            UPDATE login_lockouts
+            SET unlocked_at = NOW(), unlocked_by = $2
+            WHERE user_id = $1 AND unlocked_at IS NULL AND locked_until > NOW()
+            "#,
+        )
+        .bind(user_id)
+        .bind(unlocked_by)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        tx.commit()
+            .await
+            .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        Ok(was_locked.unwrap_or(false))
+    }
+
+    /// A user's lockouts, most recent first
+    pub async fn list_by_user(
+        pool: &Pool<Postgres>,
+        user_id: Uuid,
+        limit: i64,
+    ) -> ApiResult<Vec<Self>> {
+        sqlx::query_as::<_, LoginLockout>(
+            r#"
+            SELECT id, user_id, ip_address, failed_attempts, locked_until, unlocked_at,
+                   unlocked_by, created_at
+            FROM login_lockouts
+            WHERE user_id = $1
+            ORDER BY created_at DESC
+            LIMIT $2
+            "#,
+        )
+        .bind(user_id)
+        .bind(limit)
+        .fetch_all(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))
+    }
+}
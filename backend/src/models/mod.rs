@@ -1,5 +1,19 @@
+pub mod audit_event;
+pub mod limits;
+pub mod login_lockout;
+pub mod model_access;
+pub mod oauth_state;
+pub mod team;
+pub mod totp;
 pub mod user;
 pub mod virtual_key;
 
+pub use audit_event::*;
+pub use limits::*;
+pub use login_lockout::*;
+pub use model_access::*;
+pub use oauth_state::*;
+pub use team::*;
+pub use totp::*;
 pub use user::*;
 pub use virtual_key::*;
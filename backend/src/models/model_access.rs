@@ -0,0 +1,228 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use std::collections::HashMap;
+
+use crate::error::{ApiError, ApiResult};
+
+use super::model_pattern_matches;
+
+/// Prefix of a model entry granting every model a provider serves, e.g. "provider:gemini"
+pub const PROVIDER_PREFIX: &str = "provider:";
+
+/// Prefix of a model entry granting a named access group, e.g. "group:frontier"
+pub const GROUP_PREFIX: &str = "group:";
+
+/// A named set of model entries that keys and teams can be granted together
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct ModelAccessGroup {
+    pub name: String,
+    /// Model names, `*` patterns or `provider:` entries; groups can't contain groups
+    pub models: Vec<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpsertModelAccessGroupRequest {
+    pub name: String,
+    pub models: Vec<String>,
+}
+
+/// Check a model against `allowed_models`-style entries: exact names, `*` patterns,
+/// `provider:<id>` and `group:<name>`
+///
+/// `provider` is the provider serving the model, if it's routed at all. Groups are
+/// expanded from `groups`; a group that doesn't exist matches nothing.
+pub fn model_entries_match(
+    entries: &[String],
+    model: &str,
+    provider: Option<&str>,
+    groups: &HashMap<String, Vec<String>>,
+) -> bool {
+    entries
+        .iter()
+        .any(|entry| match entry.strip_prefix(GROUP_PREFIX) {
+            Some(group) => groups.get(group).is_some_and(|models| {
+                models
+                    .iter()
+                    .any(|entry| model_entry_matches(entry, model, provider))
+            }),
+            None => model_entry_matches(entry, model, provider),
+        })
+}
+
+/// Match a single entry that isn't a group
+fn model_entry_matches(entry: &str, model: &str, provider: Option<&str>) -> bool {
+    match entry.strip_prefix(PROVIDER_PREFIX) {
+        Some(entry_provider) => provider == Some(entry_provider),
+        None => model_pattern_matches(entry, model),
+    }
+}
+
+/// Names of the access groups a list of entries refers to
+pub fn referenced_groups<'a>(entries: impl IntoIterator<Item = &'a String>) -> Vec<String> {
+    let mut names: Vec<String> = entries
+        .into_iter()
+        .filter_map(|entry| entry.strip_prefix(GROUP_PREFIX))
+        .map(str::to_string)
+        .collect();
+    names.sort();
+    names.dedup();
+    names
+}
+
+/// Reject empty entries, and `provider:`/`group:` entries without a name
+///
+/// Groups themselves may not refer to other groups, so `allow_groups` is false for them.
+pub fn validate_model_entries(entries: &[String], allow_groups: bool) -> ApiResult<()> {
+    for entry in entries {
+        let invalid = if let Some(group) = entry.strip_prefix(GROUP_PREFIX) {
+            if !allow_groups {
+                return Err(ApiError::BadRequest(format!(
+                    "Invalid model entry '{}': access groups can't contain other groups",
+                    entry
+                )));
+            }
+            group.trim().is_empty()
+        } else if let Some(provider) = entry.strip_prefix(PROVIDER_PREFIX) {
+            provider.trim().is_empty()
+        } else {
+            entry.trim().is_empty()
+        };
+
+        if invalid {
+            return Err(ApiError::BadRequest(format!(
+                "Invalid model entry '{}': expected a model name, a pattern like 'claude-*', \
+                 'provider:<id>' or 'group:<name>'",
+                entry
+            )));
+        }
+    }
+    Ok(())
+}
+
+impl ModelAccessGroup {
+    /// All access groups, by name
+    pub async fn list(pool: &Pool<Postgres>) -> ApiResult<Vec<Self>> {
+        sqlx::query_as::<_, ModelAccessGroup>(
+            r#"
+            SELECT name, models, created_at, updated_at
+            FROM model_access_groups
+            ORDER BY name
+            "#,
+        )
+        .fetch_all(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))
+    }
+
+    /// Models of the named groups, for expanding `group:` entries
+    pub async fn models_by_name(
+        pool: &Pool<Postgres>,
+        names: &[String],
+    ) -> ApiResult<HashMap<String, Vec<String>>> {
+        if names.is_empty() {
+            return Ok(HashMap::new());
+        }
+
+        let rows: Vec<(String, Vec<String>)> = sqlx::query_as(
+            r#"
+            SELECT name, models
+            FROM model_access_groups
+            WHERE name = ANY($1)
+            "#,
+        )
+        .bind(names)
+        .fetch_all(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        Ok(rows.into_iter().collect())
+    }
+
+    /// Create a group, or replace the models of an existing one
+    pub async fn upsert(
+        pool: &Pool<Postgres>,
+        request: &UpsertModelAccessGroupRequest,
+    ) -> ApiResult<Self> {
+        sqlx::query_as::<_, ModelAccessGroup>(
+            r#"
+            INSERT INTO model_access_groups (name, models)
+            VALUES ($1, $2)
+            ON CONFLICT (name) DO UPDATE
+            SET models = EXCLUDED.models, updated_at = NOW()
+            RETURNING name, models, created_at, updated_at
+            "#,
+        )
+        .bind(request.name.trim())
+        .bind(&request.models)
+        .fetch_one(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))
+    }
+
+    /// Delete a group; keys and teams referring to it lose the models it granted
+    pub async fn delete(pool: &Pool<Postgres>, name: &str) -> ApiResult<()> {
+        let result = sqlx::query(
+            r#"
+            DELETE FROM model_access_groups
+            WHERE name = $1
+            "#,
+        )
+        .bind(name)
+        .execute(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        if result.rows_affected() == 0 {
+            return Err(ApiError::NotFound(
+                "Model access group not found".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_model_entries_match() {
+        let groups = HashMap::from([(
+            "fast".to_string(),
+            vec!["*haiku*".to_string(), "provider:gemini".to_string()],
+        )]);
+        let allows = |entry: &str, model: &str, provider: Option<&str>| {
+            model_entries_match(&[entry.to_string()], model, provider, &groups)
+        };
+
+        assert!(allows("claude-*", "claude-3-opus", Some("anthropic")));
+        assert!(!allows("claude-*", "gpt-4o", Some("openai")));
+
+        assert!(allows("provider:gemini", "gemini-1.5-pro", Some("gemini")));
+        assert!(!allows("provider:gemini", "gemini-1.5-pro", None));
+
+        assert!(allows("group:fast", "claude-3-haiku", Some("anthropic")));
+        assert!(allows("group:fast", "gemini-1.5-flash", Some("gemini")));
+        assert!(!allows("group:fast", "gpt-4o", Some("openai")));
+        assert!(!allows("group:missing", "gpt-4o", Some("openai")));
+
+        let entries = ["group:fast", "gpt-4o", "group:fast"].map(String::from);
+        assert_eq!(referenced_groups(&entries), vec!["fast".to_string()]);
+    }
+
+    #[test]
+    fn test_validate_model_entries() {
+        let entries = |values: &[&str]| values.iter().map(|v| v.to_string()).collect::<Vec<_>>();
+
+        assert!(
+            validate_model_entries(&entries(&["gpt-4o", "claude-*", "group:fast"]), true).is_ok()
+        );
+        assert!(validate_model_entries(&entries(&["group:fast"]), false).is_err());
+        assert!(validate_model_entries(&entries(&["provider:"]), true).is_err());
+        assert!(validate_model_entries(&entries(&[" "]), true).is_err());
+    }
+}
@@ -0,0 +1,128 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, Pool, Postgres};
+use uuid::Uuid;
+
+use crate::error::{ApiError, ApiResult};
+
+/// An OAuth login that has been started but not yet completed
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct OAuthState {
+    pub state_hash: String,
+    pub provider: String,
+    pub code_verifier: String,
+    pub nonce: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl OAuthState {
+    /// Store a pending login, clearing out abandoned ones
+    pub async fn create(pool: &Pool<Postgres>, pending: &OAuthState) -> ApiResult<()> {
+        sqlx::query("DELETE FROM oauth_states WHERE expires_at <= NOW()")
+            .execute(pool)
+            .await
+            .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        sqlx::query(
+            r#"
+            INSERT INTO oauth_states (state_hash, provider, code_verifier, nonce, expires_at)
+            VALUES ($1, $2, $3, $4, $5)
+            "#,
+        )
+        .bind(&pending.state_hash)
+        .bind(&pending.provider)
+        .bind(&pending.code_verifier)
+        .bind(&pending.nonce)
+        .bind(pending.expires_at)
+        .execute(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        Ok(())
+    }
+
+    /// Take a pending login by state hash; each state can be consumed only once
+    pub async fn consume(pool: &Pool<Postgres>, state_hash: &str) -> ApiResult<Option<Self>> {
+        let pending = sqlx::query_as::<_, OAuthState>(
+            r#"
+            DELETE FROM oauth_states
+            WHERE state_hash = $1
+            RETURNING state_hash, provider, code_verifier, nonce, expires_at
+            "#,
+        )
+        .bind(state_hash)
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        // Expired rows are deleted too, but don't count as a match
+        Ok(pending.filter(|p| p.expires_at > Utc::now()))
+    }
+}
+
+/// A finished OAuth login waiting for the frontend to exchange its code for a session
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct OAuthLoginCode {
+    pub code_hash: String,
+    pub user_id: Uuid,
+    pub provider: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl OAuthLoginCode {
+    /// Store a login code, clearing out unused ones that expired
+    pub async fn create(pool: &Pool<Postgres>, login: &OAuthLoginCode) -> ApiResult<()> {
+        sqlx::query("DELETE FROM oauth_login_codes WHERE expires_at <= NOW()")
+            .execute(pool)
+            .await
+            .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        sqlx::query(
+            r#"
+            INSERT INTO oauth_login_codes (code_hash, user_id, provider, expires_at)
+            VALUES ($1, $2, $3, $4)
+            "#,
+        )
+        .bind(&login.code_hash)
+        .bind(login.user_id)
+        .bind(&login.provider)
+        .bind(login.expires_at)
+        .execute(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        Ok(())
+    }
+
+    /// Look up an unexpired login by code hash without using it up
+    pub async fn find(pool: &Pool<Postgres>, code_hash: &str) -> ApiResult<Option<Self>> {
+        sqlx::query_as::<_, OAuthLoginCode>(
+            r#"
+            SELECT code_hash, user_id, provider, expires_at
+            FROM oauth_login_codes
+            WHERE code_hash = $1 AND expires_at > NOW()
+            "#,
+        )
+        .bind(code_hash)
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))
+    }
+
+    /// Take a login by code hash; each code can be exchanged only once
+    pub async fn consume(pool: &Pool<Postgres>, code_hash: &str) -> ApiResult<Option<Self>> {
+        let login = sqlx::query_as::<_, OAuthLoginCode>(
+            r#"
+            DELETE FROM oauth_login_codes
+            WHERE code_hash = $1
+            RETURNING code_hash, user_id, provider, expires_at
+            "#,
+        )
+        .bind(code_hash)
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        Ok(login.filter(|login| login.expires_at > Utc::now()))
+    }
+}
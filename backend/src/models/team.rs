@@ -0,0 +1,430 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use std::{collections::HashMap, fmt};
+use uuid::Uuid;
+
+use crate::error::{ApiError, ApiResult};
+
+use super::model_entries_match;
+
+/// A group of users sharing virtual keys, model access and a budget
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct Team {
+    pub id: Uuid,
+    pub name: String,
+    pub allowed_models: Option<Vec<String>>, // Same entries as keys; NULL means every model
+    pub max_budget: Option<f64>,
+    pub current_spend: f64,
+    pub rate_limit_rpm: Option<i32>,
+    pub rate_limit_tpm: Option<i32>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// A member's role within a team
+/// Owners manage everything including other owners; admins manage members and team keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TeamRole {
+    Member,
+    Admin,
+    Owner,
+}
+
+impl TeamRole {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TeamRole::Member => "member",
+            TeamRole::Admin => "admin",
+            TeamRole::Owner => "owner",
+        }
+    }
+
+    pub fn parse(role: &str) -> ApiResult<Self> {
+        match role {
+            "member" => Ok(TeamRole::Member),
+            "admin" => Ok(TeamRole::Admin),
+            "owner" => Ok(TeamRole::Owner),
+            _ => Err(ApiError::BadRequest(format!(
+                "Invalid team role '{}': expected owner, admin or member",
+                role
+            ))),
+        }
+    }
+
+    /// Whether this role can manage the team's members and keys
+    pub fn can_manage(&self) -> bool {
+        *self >= TeamRole::Admin
+    }
+}
+
+impl fmt::Display for TeamRole {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct TeamMember {
+    pub team_id: Uuid,
+    pub user_id: Uuid,
+    pub email: String,
+    pub username: Option<String>,
+    pub role: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Spend and usage of one team over a reporting window
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
+pub struct TeamUsage {
+    pub model: String,
+    pub count: i64,
+    pub total_tokens: i64,
+    pub total_cost: f64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateTeamRequest {
+    pub name: String,
+    pub allowed_models: Option<Vec<String>>,
+    pub max_budget: Option<f64>,
+    pub rate_limit_rpm: Option<i32>,
+    pub rate_limit_tpm: Option<i32>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateTeamRequest {
+    pub team_id: Uuid,
+    pub name: Option<String>,
+    pub allowed_models: Option<Vec<String>>,
+    pub max_budget: Option<f64>,
+    pub rate_limit_rpm: Option<i32>,
+    pub rate_limit_tpm: Option<i32>,
+    pub reset_spend: Option<bool>,
+}
+
+const TEAM_COLUMNS: &str = "id, name, allowed_models, max_budget, current_spend, rate_limit_rpm, \
+                            rate_limit_tpm, created_at, updated_at";
+
+impl Team {
+    /// Create a team; the creator (if any) becomes its first owner
+    pub async fn create(
+        pool: &Pool<Postgres>,
+        request: &CreateTeamRequest,
+        owner_id: Option<Uuid>,
+    ) -> ApiResult<Self> {
+        let mut tx = pool
+            .begin()
+            .await
+            .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        let team: Team = sqlx::query_as(&format!(
+            r#"
+            INSERT INTO teams (name, allowed_models, max_budget, rate_limit_rpm, rate_limit_tpm)
+            VALUES ($1, $2, $3, $4, $5)
+            RETURNING {}
+            "#,
+            TEAM_COLUMNS
+        ))
+        .bind(request.name.trim())
+        .bind(&request.allowed_models)
+        .bind(request.max_budget)
+        .bind(request.rate_limit_rpm)
+        .bind(request.rate_limit_tpm)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|e| {
+            if e.to_string().contains("duplicate key") {
+                ApiError::BadRequest("A team with this name already exists".to_string())
+            } else {
+                ApiError::DatabaseError(e.to_string())
+            }
+        })?;
+
+        if let Some(owner_id) = owner_id {
+            sqlx::query(
+                r#"
+                INSERT INTO team_members (team_id, user_id, role)
+                VALUES ($1, $2, 'owner')
+                "#,
+            )
+            .bind(team.id)
+            .bind(owner_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+        }
+
+        tx.commit()
+            .await
+            .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        Ok(team)
+    }
+
+    /// Find team by ID
+    pub async fn find_by_id(pool: &Pool<Postgres>, team_id: Uuid) -> ApiResult<Option<Self>> {
+        sqlx::query_as::<_, Team>(&format!("SELECT {} FROM teams WHERE id = $1", TEAM_COLUMNS))
+            .bind(team_id)
+            .fetch_optional(pool)
+            .await
+            .map_err(|e| ApiError::DatabaseError(e.to_string()))
+    }
+
+    /// All teams, for admins
+    pub async fn find_all(pool: &Pool<Postgres>) -> ApiResult<Vec<Self>> {
+        sqlx::query_as::<_, Team>(&format!("SELECT {} FROM teams ORDER BY name", TEAM_COLUMNS))
+            .fetch_all(pool)
+            .await
+            .map_err(|e| ApiError::DatabaseError(e.to_string()))
+    }
+
+    /// Teams a user belongs to
+    pub async fn find_by_member(pool: &Pool<Postgres>, user_id: Uuid) -> ApiResult<Vec<Self>> {
+        sqlx::query_as::<_, Team>(
+            r#"
+            SELECT t.id, t.name, t.allowed_models, t.max_budget, t.current_spend,
+                   t.rate_limit_rpm, t.rate_limit_tpm, t.created_at, t.updated_at
+            FROM teams t
+            JOIN team_members m ON m.team_id = t.id
+            WHERE m.user_id = $1
+            ORDER BY t.name
+            "#,
+        )
+        .bind(user_id)
+        .fetch_all(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))
+    }
+
+    /// Update a team; `reset_spend` zeroes the spend counted against its budget
+    pub async fn update(pool: &Pool<Postgres>, request: &UpdateTeamRequest) -> ApiResult<Self> {
+        sqlx::query_as::<_, Team>(&format!(
+            r#"
+            UPDATE teams
+            SET name = COALESCE($2, name),
+                allowed_models = COALESCE($3, allowed_models),
+                max_budget = COALESCE($4, max_budget),
+                rate_limit_rpm = COALESCE($5, rate_limit_rpm),
+                rate_limit_tpm = COALESCE($6, rate_limit_tpm),
+                current_spend = CASE WHEN $7 THEN 0 ELSE current_spend END,
+                updated_at = NOW()
+            WHERE id = $1
+            RETURNING {}
+            "#,
+            TEAM_COLUMNS
+        ))
+        .bind(request.team_id)
+        .bind(request.name.as_deref().map(str::trim))
+        .bind(&request.allowed_models)
+        .bind(request.max_budget)
+        .bind(request.rate_limit_rpm)
+        .bind(request.rate_limit_tpm)
+        .bind(request.reset_spend.unwrap_or(false))
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| {
+            if e.to_string().contains("duplicate key") {
+                ApiError::BadRequest("A team with this name already exists".to_string())
+            } else {
+                ApiError::DatabaseError(e.to_string())
+            }
+        })?
+        .ok_or_else(|| ApiError::NotFound("Team not found".to_string()))
+    }
+
+    /// Delete a team; fails while it still owns keys so they aren't silently reassigned
+    pub async fn delete(pool: &Pool<Postgres>, team_id: Uuid) -> ApiResult<()> {
+        let (key_count,): (i64,) =
+            sqlx::query_as("SELECT COUNT(*) FROM virtual_keys WHERE team_id = $1")
+                .bind(team_id)
+                .fetch_one(pool)
+                .await
+                .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        if key_count > 0 {
+            return Err(ApiError::BadRequest(format!(
+                "Team still owns {} key(s); move or delete them first",
+                key_count
+            )));
+        }
+
+        let result = sqlx::query("DELETE FROM teams WHERE id = $1")
+            .bind(team_id)
+            .execute(pool)
+            .await
+            .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        if result.rows_affected() == 0 {
+            return Err(ApiError::NotFound("Team not found".to_string()));
+        }
+
+        Ok(())
+    }
+
+    /// Check if a model served by `provider` is allowed for the team's keys
+    pub fn can_access_model(
+        &self,
+        model: &str,
+        provider: Option<&str>,
+        groups: &HashMap<String, Vec<String>>,
+    ) -> bool {
+        self.allowed_models.as_ref().is_none_or(|allowed_models| {
+            model_entries_match(allowed_models, model, provider, groups)
+        })
+    }
+
+    /// Team members with their user details
+    pub async fn members(pool: &Pool<Postgres>, team_id: Uuid) -> ApiResult<Vec<TeamMember>> {
+        sqlx::query_as::<_, TeamMember>(
+            r#"
+            SELECT m.team_id, m.user_id, u.email, u.username, m.role, m.created_at
+            FROM team_members m
+            JOIN users u ON u.id = m.user_id
+            WHERE m.team_id = $1
+            ORDER BY m.created_at
+            "#,
+        )
+        .bind(team_id)
+        .fetch_all(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))
+    }
+
+    /// A user's role in a team, or None if they aren't a member
+    pub async fn member_role(
+        pool: &Pool<Postgres>,
+        team_id: Uuid,
+        user_id: Uuid,
+    ) -> ApiResult<Option<TeamRole>> {
+        let role: Option<(String,)> =
+            sqlx::query_as("SELECT role FROM team_members WHERE team_id = $1 AND user_id = $2")
+                .bind(team_id)
+                .bind(user_id)
+                .fetch_optional(pool)
+                .await
+                .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        role.map(|(role,)| TeamRole::parse(&role)).transpose()
+    }
+
+    /// Add a member, or change the role of an existing one
+    pub async fn set_member(
+        pool: &Pool<Postgres>,
+        team_id: Uuid,
+        user_id: Uuid,
+        role: TeamRole,
+    ) -> ApiResult<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO team_members (team_id, user_id, role)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (team_id, user_id) DO UPDATE SET role = EXCLUDED.role
+            "#,
+        )
+        .bind(team_id)
+        .bind(user_id)
+        .bind(role.as_str())
+        .execute(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        Ok(())
+    }
+
+    /// Remove a member
+    pub async fn remove_member(
+        pool: &Pool<Postgres>,
+        team_id: Uuid,
+        user_id: Uuid,
+    ) -> ApiResult<()> {
+        sqlx::query("DELETE FROM team_members WHERE team_id = $1 AND user_id = $2")
+            .bind(team_id)
+            .bind(user_id)
+            .execute(pool)
+            .await
+            .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        Ok(())
+    }
+
+    /// Number of owners, so the last one can't be removed or demoted
+    pub async fn owner_count(pool: &Pool<Postgres>, team_id: Uuid) -> ApiResult<i64> {
+        let (count,): (i64,) = sqlx::query_as(
+            "SELECT COUNT(*) FROM team_members WHERE team_id = $1 AND role = 'owner'",
+        )
+        .bind(team_id)
+        .fetch_one(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        Ok(count)
+    }
+
+    /// Spend per model attributed to a team over the last `days` days
+    pub async fn usage(
+        pool: &Pool<Postgres>,
+        team_id: Uuid,
+        days: i32,
+    ) -> ApiResult<Vec<TeamUsage>> {
+        sqlx::query_as::<_, TeamUsage>(
+            r#"
+            SELECT
+                model,
+                COUNT(*) as count,
+                COALESCE(SUM(total_tokens), 0) as total_tokens,
+                CAST(COALESCE(SUM(cost_usd), 0) AS DOUBLE PRECISION) as total_cost
+            FROM usage_records
+            WHERE team_id = $1 AND created_at >= NOW() - INTERVAL '1 day' * $2
+            GROUP BY model
+            ORDER BY total_cost DESC
+            "#,
+        )
+        .bind(team_id)
+        .bind(days)
+        .fetch_all(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_team_role_ordering() {
+        assert!(TeamRole::Owner.can_manage());
+        assert!(TeamRole::Admin.can_manage());
+        assert!(!TeamRole::Member.can_manage());
+        assert!(TeamRole::Owner > TeamRole::Admin);
+        assert_eq!(TeamRole::parse("admin").unwrap(), TeamRole::Admin);
+        assert!(TeamRole::parse("superuser").is_err());
+    }
+
+    #[test]
+    fn test_team_allowed_models() {
+        let mut team = Team {
+            id: Uuid::nil(),
+            name: "research".to_string(),
+            allowed_models: None,
+            max_budget: None,
+            current_spend: 0.0,
+            rate_limit_rpm: None,
+            rate_limit_tpm: None,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        };
+        let groups = HashMap::new();
+        assert!(team.can_access_model("gpt-4", Some("openai"), &groups));
+
+        team.allowed_models = Some(vec!["claude-3-haiku".to_string()]);
+        assert!(team.can_access_model("claude-3-haiku", Some("anthropic"), &groups));
+        assert!(!team.can_access_model("gpt-4", Some("openai"), &groups));
+
+        team.allowed_models = Some(vec!["provider:anthropic".to_string()]);
+        assert!(team.can_access_model("claude-3-opus", Some("anthropic"), &groups));
+        assert!(!team.can_access_model("gpt-4", Some("openai"), &groups));
+    }
+}
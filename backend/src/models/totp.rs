@@ -0,0 +1,189 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use uuid::Uuid;
+
+use crate::error::{ApiError, ApiResult};
+
+/// A user's TOTP secret; enrollment is pending until `enabled_at` is set by confirming a
+/// first code
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct UserTotp {
+    pub user_id: Uuid,
+    #[serde(skip_serializing)]
+    pub secret_encrypted: String,
+    pub enabled_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl UserTotp {
+    /// Encryption context of a user's secret
+    pub fn secret_context(user_id: Uuid) -> String {
+        format!("user_totp:{}", user_id)
+    }
+
+    pub async fn find(pool: &Pool<Postgres>, user_id: Uuid) -> ApiResult<Option<Self>> {
+        sqlx::query_as::<_, UserTotp>(
+            r#"
+            SELECT user_id, secret_encrypted, enabled_at, created_at
+            FROM user_totp
+            WHERE user_id = $1
+            "#,
+        )
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))
+    }
+
+    /// The user's secret, if they've completed enrollment
+    pub async fn find_enabled(pool: &Pool<Postgres>, user_id: Uuid) -> ApiResult<Option<Self>> {
+        Ok(Self::find(pool, user_id)
+            .await?
+            .filter(|totp| totp.enabled_at.is_some()))
+    }
+
+    /// Store a new pending secret, replacing any earlier pending one
+    /// Returns false if the user already has TOTP enabled.
+    pub async fn start_enrollment(
+        pool: &Pool<Postgres>,
+        user_id: Uuid,
+        secret_encrypted: &str,
+    ) -> ApiResult<bool> {
+        let result = sqlx::query(
+            r#"
+            INSERT INTO user_totp (user_id, secret_encrypted)
+            VALUES ($1, $2)
+            ON CONFLICT (user_id) DO UPDATE
+            SET secret_encrypted = EXCLUDED.secret_encrypted,
+                last_used_step = NULL,
+                created_at = NOW()
+            WHERE user_totp.enabled_at IS NULL
+            "#,
+        )
+        .bind(user_id)
+        .bind(secret_encrypted)
+        .execute(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Accept a code's time step, once: later codes from the same or an earlier step are
+    /// replays. Enables a pending enrollment. Returns false for a replayed step.
+    pub async fn accept_step(pool: &Pool<Postgres>, user_id: Uuid, step: i64) -> ApiResult<bool> {
+        let result = sqlx::query(
+            r#"
+            UPDATE user_totp
+            SET last_used_step = $2, enabled_at = COALESCE(enabled_at, NOW())
+            WHERE user_id = $1 AND (last_used_step IS NULL OR last_used_step < $2)
+            "#,
+        )
+        .bind(user_id)
+        .bind(step)
+        .execute(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Remove a user's secret and recovery codes; returns whether they had a secret
+    pub async fn delete(pool: &Pool<Postgres>, user_id: Uuid) -> ApiResult<bool> {
+        let mut tx = pool
+            .begin()
+            .await
+            .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        sqlx::query("DELETE FROM totp_recovery_codes WHERE user_id = $1")
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        let result = sqlx::query("DELETE FROM user_totp WHERE user_id = $1")
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        tx.commit()
+            .await
+            .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        Ok(result.rows_affected() > 0)
+    }
+}
+
+/// Single-use codes that stand in for a TOTP code when the authenticator is lost
+pub struct RecoveryCode;
+
+impl RecoveryCode {
+    /// Replace all of a user's recovery codes with new ones (given as hashes)
+    pub async fn replace(
+        pool: &Pool<Postgres>,
+        user_id: Uuid,
+        code_hashes: &[String],
+    ) -> ApiResult<()> {
+        let mut tx = pool
+            .begin()
+            .await
+            .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        sqlx::query("DELETE FROM totp_recovery_codes WHERE user_id = $1")
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        sqlx::query(
+            r#"
+            INSERT INTO totp_recovery_codes (user_id, code_hash)
+            SELECT $1, UNNEST($2::VARCHAR[])
+            "#,
+        )
+        .bind(user_id)
+        .bind(code_hashes)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        tx.commit()
+            .await
+            .map_err(|e| ApiError::DatabaseError(e.to_string()))
+    }
+
+    /// Use up a recovery code; returns false if it doesn't exist or was already used
+    pub async fn consume(pool: &Pool<Postgres>, user_id: Uuid, code_hash: &str) -> ApiResult<bool> {
+        let result = sqlx::query(
+            r#"
+            UPDATE totp_recovery_codes
+            SET used_at = NOW()
+            WHERE user_id = $1 AND code_hash = $2 AND used_at IS NULL
+            "#,
+        )
+        .bind(user_id)
+        .bind(code_hash)
+        .execute(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Recovery codes the user has left
+    pub async fn count_unused(pool: &Pool<Postgres>, user_id: Uuid) -> ApiResult<i64> {
+        sqlx::query_scalar(
+            r#"
+            SELECT COUNT(*)
+            FROM totp_recovery_codes
+            WHERE user_id = $1 AND used_at IS NULL
+            "#,
+        )
+        .bind(user_id)
+        .fetch_one(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))
+    }
+}
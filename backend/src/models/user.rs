@@ -13,10 +13,21 @@ pub struct User {
     #[serde(skip_serializing)]
     pub password_hash: Option<String>,
     pub role: String,
+    /// Set while an admin has disabled the account
+    pub disabled_at: Option<DateTime<Utc>>,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
 }
 
+/// Which users to list; unset fields match everyone
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct UserFilter {
+    /// Case-insensitive substring of the email or username
+    pub search: Option<String>,
+    pub role: Option<String>,
+    pub disabled: Option<bool>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
 pub struct OAuthAccount {
     pub id: Uuid,
@@ -32,6 +43,11 @@ pub struct OAuthAccount {
     pub created_at: DateTime<Utc>,
 }
 
+/// A login: access tokens name it in their `sid` claim, and its refresh token (stored
+/// hashed in `token_hash`) issues new ones until it expires or is revoked
+///
+/// The `previous_token_hash` column keeps the token replaced by the last refresh;
+/// presenting it again means it was stolen, and [`Session::revoke_reused`] revokes the session.
 #[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
 pub struct Session {
     pub id: Uuid,
@@ -39,6 +55,8 @@ pub struct Session {
     #[serde(skip_serializing)]
     pub token_hash: String,
     pub expires_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+    pub last_refreshed_at: Option<DateTime<Utc>>,
     pub created_at: DateTime<Utc>,
 }
 
@@ -55,7 +73,7 @@ impl User {
             r#"
             INSERT INTO users (email, username, password_hash, role)
             VALUES ($1, $2, $3, $4)
-            RETURNING id, email, username, password_hash, role, created_at, updated_at
+            RETURNING id, email, username, password_hash, role, disabled_at, created_at, updated_at
             "#,
         )
         .bind(&email)
@@ -79,7 +97,7 @@ impl User {
     pub async fn find_by_email(pool: &Pool<Postgres>, email: &str) -> ApiResult<Option<Self>> {
         let user = sqlx::query_as::<_, User>(
             r#"
-            SELECT id, email, username, password_hash, role, created_at, updated_at
+            SELECT id, email, username, password_hash, role, disabled_at, created_at, updated_at
             FROM users
             WHERE email = $1
             "#,
@@ -96,7 +114,7 @@ impl User {
     pub async fn find_by_id(pool: &Pool<Postgres>, user_id: Uuid) -> ApiResult<Option<Self>> {
         let user = sqlx::query_as::<_, User>(
             r#"
-            SELECT id, email, username, password_hash, role, created_at, updated_at
+            SELECT id, email, username, password_hash, role, disabled_at, created_at, updated_at
             FROM users
             WHERE id = $1
             "#,
@@ -109,6 +127,25 @@ impl User {
         Ok(user)
     }
 
+    /// Find the user of a session that hasn't expired or been revoked, unless they're disabled
+    pub async fn find_by_session(pool: &Pool<Postgres>, session_id: Uuid) -> ApiResult<Option<Self>> {
+        let user = sqlx::query_as::<_, User>(
+            r#"
+            SELECT u.id, u.email, u.username, u.password_hash, u.role, u.disabled_at, u.created_at, u.updated_at
+            FROM sessions s
+            JOIN users u ON u.id = s.user_id
+            WHERE s.id = $1 AND s.revoked_at IS NULL AND s.expires_at > NOW()
+              AND u.disabled_at IS NULL
+            "#,
+        )
+        .bind(session_id)
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        Ok(user)
+    }
+
     /// Update user password
     pub async fn update_password(
         pool: &Pool<Postgres>,
@@ -131,6 +168,18 @@ impl User {
         Ok(())
     }
 
+    /// Count enabled users with a role (e.g. to keep at least one admin)
+    pub async fn count_by_role(pool: &Pool<Postgres>, role: &str) -> ApiResult<i64> {
+        let (count,): (i64,) =
+            sqlx::query_as("SELECT COUNT(*) FROM users WHERE role = $1 AND disabled_at IS NULL")
+                .bind(role)
+                .fetch_one(pool)
+                .await
+                .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        Ok(count)
+    }
+
     /// Update user role
     pub async fn update_role(pool: &Pool<Postgres>, user_id: Uuid, role: String) -> ApiResult<()> {
         sqlx::query(
@@ -148,9 +197,198 @@ impl User {
 
         Ok(())
     }
+
+    /// Users matching `filter`, oldest first
+    pub async fn list(
+        pool: &Pool<Postgres>,
+        filter: &UserFilter,
+        limit: i64,
+        offset: i64,
+    ) -> ApiResult<Vec<Self>> {
+        sqlx::query_as::<_, User>(
+            r#"
+            SELECT id, email, username, password_hash, role, disabled_at, created_at, updated_at
+            FROM users
+            WHERE ($1::text IS NULL OR email ILIKE '%' || $1 || '%' OR username ILIKE '%' || $1 || '%')
+              AND ($2::text IS NULL OR role = $2)
+              AND ($3::boolean IS NULL OR (disabled_at IS NOT NULL) = $3)
+            ORDER BY created_at, id
+            LIMIT $4 OFFSET $5
+            "#,
+        )
+        .bind(filter.search.as_deref().map(escape_like))
+        .bind(&filter.role)
+        .bind(filter.disabled)
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))
+    }
+
+    /// Number of users matching `filter`
+    pub async fn count(pool: &Pool<Postgres>, filter: &UserFilter) -> ApiResult<i64> {
+        let (count,): (i64,) = sqlx::query_as(
+            r#"
+            SELECT COUNT(*)
+            FROM users
+            WHERE ($1::text IS NULL OR email ILIKE '%' || $1 || '%' OR username ILIKE '%' || $1 || '%')
+              AND ($2::text IS NULL OR role = $2)
+              AND ($3::boolean IS NULL OR (disabled_at IS NOT NULL) = $3)
+            "#,
+        )
+        .bind(filter.search.as_deref().map(escape_like))
+        .bind(&filter.role)
+        .bind(filter.disabled)
+        .fetch_one(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        Ok(count)
+    }
+
+    /// Disable a user and block their keys; returns how many keys were newly blocked
+    ///
+    /// Keys that were already blocked are left unmarked, so [`User::enable`] keeps them blocked.
+    pub async fn disable(pool: &Pool<Postgres>, user_id: Uuid) -> ApiResult<u64> {
+        let mut tx = pool
+            .begin()
+            .await
+            .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        sqlx::query(
+            r#"
+            UPDATE users
+            SET disabled_at = NOW(), updated_at = NOW()
+            WHERE id = $1 AND disabled_at IS NULL
+            "#,
+        )
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        let result = sqlx::query(
+            r#"
+            UPDATE virtual_keys
+            SET blocked = true, blocked_by_user_disable = true
+            WHERE user_id = $1 AND NOT blocked
+            "#,
+        )
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        tx.commit()
+            .await
+            .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        Ok(result.rows_affected())
+    }
+
+    /// Enable a disabled user and unblock the keys disabling them blocked; returns how
+    /// many keys were unblocked
+    pub async fn enable(pool: &Pool<Postgres>, user_id: Uuid) -> ApiResult<u64> {
+        let mut tx = pool
+            .begin()
+            .await
+            .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        sqlx::query(
+            r#"
+            UPDATE users
+            SET disabled_at = NULL, updated_at = NOW()
+            WHERE id = $1
+            "#,
+        )
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        let result = sqlx::query(
+            r#"
+            UPDATE virtual_keys
+            SET blocked = false, blocked_by_user_disable = false
+            WHERE user_id = $1 AND blocked_by_user_disable
+            "#,
+        )
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        tx.commit()
+            .await
+            .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        Ok(result.rows_affected())
+    }
+
+    /// Delete a user, either with their keys or after handing the keys to `reassign_to`;
+    /// returns how many keys were reassigned or deleted
+    ///
+    /// Reassigned keys that were blocked only because this user was disabled are unblocked.
+    pub async fn delete(
+        pool: &Pool<Postgres>,
+        user_id: Uuid,
+        reassign_to: Option<Uuid>,
+    ) -> ApiResult<u64> {
+        let mut tx = pool
+            .begin()
+            .await
+            .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        let keys = match reassign_to {
+            Some(new_owner) => sqlx::query(
+                r#"
+                UPDATE virtual_keys
+                SET user_id = $2,
+                    blocked = blocked AND NOT blocked_by_user_disable,
+                    blocked_by_user_disable = false
+                WHERE user_id = $1
+                "#,
+            )
+            .bind(user_id)
+            .bind(new_owner),
+            None => sqlx::query("DELETE FROM virtual_keys WHERE user_id = $1").bind(user_id),
+        }
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        // Sessions, OAuth accounts, team memberships and TOTP secrets cascade
+        let result = sqlx::query("DELETE FROM users WHERE id = $1")
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+        if result.rows_affected() == 0 {
+            return Err(ApiError::NotFound("User not found".to_string()));
+        }
+
+        tx.commit()
+            .await
+            .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        Ok(keys.rows_affected())
+    }
+}
+
+/// Escape `%`, `_` and `\` so a search term matches literally in `LIKE`
+fn escape_like(term: &str) -> String {
+    term.replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
 }
 
 impl OAuthAccount {
+    /// Authenticated context binding an encrypted token to its account and kind
+    pub fn token_context(provider: &str, provider_user_id: &str, kind: &str) -> String {
+        format!("oauth_accounts:{}:{}:{}", provider, provider_user_id, kind)
+    }
+
     /// Create or update OAuth account
     pub async fn upsert(
         pool: &Pool<Postgres>,
@@ -235,6 +473,9 @@ impl OAuthAccount {
     }
 }
 
+const SESSION_COLUMNS: &str = "id, user_id, token_hash, expires_at, revoked_at, last_refreshed_at, \
+                               created_at";
+
 impl Session {
     /// Create a new session
     pub async fn create(
@@ -243,13 +484,14 @@ impl Session {
         token_hash: String,
         expires_at: DateTime<Utc>,
     ) -> ApiResult<Self> {
-        let session: Session = sqlx::query_as(
+        let session: Session = sqlx::query_as(&format!(
             r#"
             INSERT INTO sessions (user_id, token_hash, expires_at)
             VALUES ($1, $2, $3)
-            RETURNING id, user_id, token_hash, expires_at, created_at
+            RETURNING {}
             "#,
-        )
+            SESSION_COLUMNS
+        ))
         .bind(user_id)
         .bind(&token_hash)
         .bind(expires_at)
@@ -260,35 +502,77 @@ impl Session {
         Ok(session)
     }
 
-    /// Find session by token hash
-    pub async fn find_by_token(
+    /// Swap a session's refresh token for a new one
+    /// Returns None if the token isn't the current one of an active session; only one of
+    /// several concurrent refreshes with the same token can succeed.
+    pub async fn rotate(
         pool: &Pool<Postgres>,
         token_hash: &str,
+        new_token_hash: &str,
     ) -> ApiResult<Option<Self>> {
-        let session = sqlx::query_as::<_, Session>(
+        sqlx::query_as::<_, Session>(&format!(
             r#"
-            SELECT id, user_id, token_hash, expires_at, created_at
-            FROM sessions
-            WHERE token_hash = $1 AND expires_at > NOW()
+            UPDATE sessions
+            SET previous_token_hash = token_hash,
+                token_hash = $2,
+                last_refreshed_at = NOW()
+            WHERE token_hash = $1 AND revoked_at IS NULL AND expires_at > NOW()
+            RETURNING {}
             "#,
-        )
+            SESSION_COLUMNS
+        ))
         .bind(token_hash)
+        .bind(new_token_hash)
         .fetch_optional(pool)
         .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))
+    }
+
+    /// Revoke the session a rotated-out refresh token belonged to
+    /// Returns whether the token was a previous one, i.e. whether it's being reused.
+    pub async fn revoke_reused(pool: &Pool<Postgres>, token_hash: &str) -> ApiResult<bool> {
+        let result = sqlx::query(
+            r#"
+            UPDATE sessions
+            SET revoked_at = COALESCE(revoked_at, NOW())
+            WHERE previous_token_hash = $1
+            "#,
+        )
+        .bind(token_hash)
+        .execute(pool)
+        .await
         .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
 
-        Ok(session)
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Active sessions of a user, newest first
+    pub async fn find_active_by_user(pool: &Pool<Postgres>, user_id: Uuid) -> ApiResult<Vec<Self>> {
+        sqlx::query_as::<_, Session>(&format!(
+            r#"
+            SELECT {}
+            FROM sessions
+            WHERE user_id = $1 AND revoked_at IS NULL AND expires_at > NOW()
+            ORDER BY created_at DESC
+            "#,
+            SESSION_COLUMNS
+        ))
+        .bind(user_id)
+        .fetch_all(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))
     }
 
-    /// Delete session (logout)
-    pub async fn delete(pool: &Pool<Postgres>, token_hash: &str) -> ApiResult<()> {
+    /// Revoke one session (logout); its access tokens stop working immediately
+    pub async fn revoke(pool: &Pool<Postgres>, session_id: Uuid) -> ApiResult<()> {
         sqlx::query(
             r#"
-            DELETE FROM sessions
-            WHERE token_hash = $1
+            UPDATE sessions
+            SET revoked_at = NOW()
+            WHERE id = $1 AND revoked_at IS NULL
             "#,
         )
-        .bind(token_hash)
+        .bind(session_id)
         .execute(pool)
         .await
         .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
@@ -296,34 +580,35 @@ impl Session {
         Ok(())
     }
 
-    /// Delete all expired sessions (cleanup)
-    pub async fn cleanup_expired(pool: &Pool<Postgres>) -> ApiResult<()> {
-        sqlx::query(
+    /// Revoke every session of a user (log out everywhere); returns how many were active
+    pub async fn revoke_by_user(pool: &Pool<Postgres>, user_id: Uuid) -> ApiResult<u64> {
+        let result = sqlx::query(
             r#"
-            DELETE FROM sessions
-            WHERE expires_at <= NOW()
+            UPDATE sessions
+            SET revoked_at = NOW()
+            WHERE user_id = $1 AND revoked_at IS NULL AND expires_at > NOW()
             "#,
         )
+        .bind(user_id)
         .execute(pool)
         .await
         .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
 
-        Ok(())
+        Ok(result.rows_affected())
     }
 
-    /// Delete all sessions for a user
-    pub async fn delete_by_user(pool: &Pool<Postgres>, user_id: Uuid) -> ApiResult<()> {
-        sqlx::query(
+    /// Delete expired and revoked sessions (cleanup); returns how many were deleted
+    pub async fn cleanup_expired(pool: &Pool<Postgres>) -> ApiResult<u64> {
+        let result = sqlx::query(
             r#"
             DELETE FROM sessions
-            WHERE user_id = $1
+            WHERE expires_at <= NOW() OR revoked_at IS NOT NULL
             "#,
         )
-        .bind(user_id)
         .execute(pool)
         .await
         .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
 
-        Ok(())
+        Ok(result.rows_affected())
     }
 }
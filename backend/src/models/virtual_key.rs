@@ -1,39 +1,276 @@
 use chrono::{DateTime, Utc};
+use ipnet::IpNet;
 use serde::{Deserialize, Serialize};
 use sqlx::{Pool, Postgres};
+use std::collections::HashMap;
+use std::net::IpAddr;
 use uuid::Uuid;
 
 use crate::error::{ApiError, ApiResult};
+use crate::rate_limiter::RateLimit;
+
+use super::model_entries_match;
 
 #[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
 pub struct VirtualKey {
     pub id: Uuid,
-    #[serde(skip_serializing)]
+    /// HMAC-SHA256 of the secret (see `hash_virtual_key`), or bcrypt for keys created
+    /// before that; those are upgraded on first use
+    #[serde(skip_serializing, default)]
     pub key_hash: String,
     #[serde(skip_serializing)]
-    pub key_lookup_hash: Option<String>, // SHA256 hash for fast lookup
+    pub key_lookup_hash: Option<String>, // SHA256 lookup for legacy bcrypt-hashed keys
     pub key_prefix: String, // Show "sk-..." to users
     pub user_id: Option<Uuid>,
+    pub team_id: Option<Uuid>, // Team that owns the key and pays for its usage
     pub name: Option<String>,
     pub max_budget: Option<f64>,
     pub current_spend: f64,
     pub rate_limit_rpm: Option<i32>,
     pub rate_limit_tpm: Option<i32>,
+    /// Model names, `*` patterns, `provider:<id>` or `group:<name>`; NULL means every model
     pub allowed_models: Option<Vec<String>>,
+    /// Same kind of entries as `allowed_models`; a denied model is refused even if allowed
+    pub denied_models: Option<Vec<String>>,
     pub expires_at: Option<DateTime<Utc>>,
     pub blocked: bool,
+    pub budget_period: Option<String>, // "daily", "weekly", "monthly" or a duration like "30d"
+    pub budget_reset_at: Option<DateTime<Utc>>,
+    /// Start of the first budget period; later resets are counted from it
+    pub budget_anchor_at: Option<DateTime<Utc>>,
+    /// Limits per model name or pattern (e.g. "claude-3-opus*"), on top of the key's own
+    #[sqlx(json)]
+    #[serde(default)]
+    pub model_limits: HashMap<String, ModelLimit>,
+    /// Spend this budget period per `model_limits` pattern
+    #[sqlx(json)]
+    #[serde(default)]
+    pub model_spend: HashMap<String, f64>,
+    /// Maximum number of requests in flight at once; NULL means unlimited
+    pub max_parallel_requests: Option<i32>,
+    /// Hashes of the secret replaced by the last rotation, valid until `previous_key_expires_at`
+    #[serde(skip_serializing)]
+    pub previous_key_hash: Option<String>,
+    #[serde(skip_serializing)]
+    pub previous_key_lookup_hash: Option<String>,
+    pub previous_key_prefix: Option<String>,
+    pub previous_key_expires_at: Option<DateTime<Utc>>,
+    /// Last request made with the previous secret, to tell when callers have migrated
+    pub previous_key_last_used_at: Option<DateTime<Utc>>,
+    #[sqlx(flatten)]
+    #[serde(flatten)]
+    pub restrictions: KeyRestrictions,
     pub created_at: DateTime<Utc>,
     pub last_used_at: Option<DateTime<Utc>>,
 }
 
+/// Which secret of a rotated key authenticated a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeySecret {
+    Current,
+    Previous,
+}
+
+impl KeySecret {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            KeySecret::Current => "current",
+            KeySecret::Previous => "previous",
+        }
+    }
+}
+
+/// Where a key may be used from and what it may call, enforced by `require_auth`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, sqlx::FromRow)]
+pub struct KeyRestrictions {
+    /// Request paths the key may call, `*` matching any run of characters
+    /// (e.g. "/v1/chat/completions" or "/v1/*"); NULL means every route
+    pub allowed_routes: Option<Vec<String>>,
+    /// Source addresses or CIDR ranges (e.g. "10.0.0.0/8"); NULL means anywhere
+    pub allowed_ips: Option<Vec<String>>,
+    /// Origins browser requests must come from, per their `Origin` or `Referer` header
+    /// (e.g. "https://*.example.com"); NULL means the headers aren't checked
+    pub allowed_origins: Option<Vec<String>>,
+    /// Only GET requests, e.g. analytics keys reading `/stats`; never inference
+    #[serde(default)]
+    pub read_only: bool,
+}
+
+impl KeyRestrictions {
+    /// Check if a request path matches one of the allowed routes
+    pub fn allows_route(&self, path: &str) -> bool {
+        self.allowed_routes.as_ref().is_none_or(|routes| {
+            routes
+                .iter()
+                .any(|route| model_pattern_matches(route, path))
+        })
+    }
+
+    /// Check if a client address is within one of the allowed ranges
+    pub fn allows_ip(&self, ip: IpAddr) -> bool {
+        // IPv4 clients of a dual-stack listener show up as IPv4-mapped IPv6 addresses
+        let ip = ip.to_canonical();
+        self.allowed_ips.as_ref().is_none_or(|ranges| {
+            ranges
+                .iter()
+                .filter_map(|range| parse_ip_range(range))
+                .any(|range| range.contains(&ip))
+        })
+    }
+
+    /// Check if a browser origin ("scheme://host[:port]") matches one of the allowed origins
+    pub fn allows_origin(&self, origin: &str) -> bool {
+        self.allowed_origins.as_ref().is_none_or(|origins| {
+            origins
+                .iter()
+                .any(|allowed| model_pattern_matches(allowed.trim_end_matches('/'), origin))
+        })
+    }
+}
+
+/// Changes to a key's restrictions; an empty list removes that restriction
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct UpdateKeyRestrictions {
+    pub allowed_routes: Option<Vec<String>>,
+    pub allowed_ips: Option<Vec<String>>,
+    pub allowed_origins: Option<Vec<String>>,
+    pub read_only: Option<bool>,
+}
+
+/// Rate limits and spend cap for the models matching one pattern within a key
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ModelLimit {
+    pub rate_limit_rpm: Option<i32>,
+    pub rate_limit_tpm: Option<i32>,
+    pub max_budget: Option<f64>,
+}
+
+impl ModelLimit {
+    pub fn rate_limit(&self) -> RateLimit {
+        RateLimit {
+            requests_per_minute: self.rate_limit_rpm,
+            tokens_per_minute: self.rate_limit_tpm,
+        }
+    }
+}
+
+/// Reject empty patterns and non-positive limits
+pub fn validate_model_limits(model_limits: &HashMap<String, ModelLimit>) -> ApiResult<()> {
+    for (pattern, limit) in model_limits {
+        if pattern.trim().is_empty() {
+            return Err(ApiError::BadRequest(
+                "Model limit patterns must not be empty".to_string(),
+            ));
+        }
+        let positive = limit.rate_limit_rpm.is_none_or(|rpm| rpm > 0)
+            && limit.rate_limit_tpm.is_none_or(|tpm| tpm > 0)
+            && limit.max_budget.is_none_or(|budget| budget >= 0.0);
+        if !positive {
+            return Err(ApiError::BadRequest(format!(
+                "Invalid limits for model '{}': rate limits must be positive and budgets non-negative",
+                pattern
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Reject routes that aren't paths, unparseable IP ranges and origins without a scheme
+pub fn validate_key_restrictions(
+    allowed_routes: Option<&[String]>,
+    allowed_ips: Option<&[String]>,
+    allowed_origins: Option<&[String]>,
+) -> ApiResult<()> {
+    if let Some(route) = allowed_routes
+        .unwrap_or_default()
+        .iter()
+        .find(|route| !route.starts_with('/'))
+    {
+        return Err(ApiError::BadRequest(format!(
+            "Invalid route '{}': routes must start with '/'",
+            route
+        )));
+    }
+    if let Some(ip) = allowed_ips
+        .unwrap_or_default()
+        .iter()
+        .find(|ip| parse_ip_range(ip).is_none())
+    {
+        return Err(ApiError::BadRequest(format!(
+            "Invalid IP address or CIDR range '{}'",
+            ip
+        )));
+    }
+    if let Some(origin) = allowed_origins
+        .unwrap_or_default()
+        .iter()
+        .find(|origin| !origin.starts_with("https://") && !origin.starts_with("http://"))
+    {
+        return Err(ApiError::BadRequest(format!(
+            "Invalid origin '{}': origins must start with https:// or http://",
+            origin
+        )));
+    }
+    Ok(())
+}
+
+/// Parse a CIDR range, or a single address as a range of one
+fn parse_ip_range(value: &str) -> Option<IpNet> {
+    let value = value.trim();
+    value
+        .parse::<IpNet>()
+        .ok()
+        .or_else(|| value.parse::<IpAddr>().ok().map(IpNet::from))
+}
+
+/// Reject parallel request limits below one
+pub fn validate_max_parallel_requests(max_parallel_requests: Option<i32>) -> ApiResult<()> {
+    if max_parallel_requests.is_some_and(|max| max < 1) {
+        return Err(ApiError::BadRequest(
+            "max_parallel_requests must be at least 1".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Match a model name against a pattern where `*` matches any run of characters
+pub fn model_pattern_matches(pattern: &str, model: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = model.strip_prefix(first) else {
+        return false;
+    };
+
+    let mut parts: Vec<&str> = parts.collect();
+    let Some(last) = parts.pop() else {
+        // No wildcard: exact match
+        return rest.is_empty();
+    };
+
+    for part in parts {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CreateVirtualKeyRequest {
     pub name: Option<String>,
+    pub team_id: Option<Uuid>,
     pub max_budget: Option<f64>,
     pub rate_limit_rpm: Option<i32>,
     pub rate_limit_tpm: Option<i32>,
     pub allowed_models: Option<Vec<String>>,
+    pub denied_models: Option<Vec<String>>,
     pub expires_at: Option<DateTime<Utc>>,
+    pub budget_period: Option<String>,
+    pub model_limits: Option<HashMap<String, ModelLimit>>,
+    pub max_parallel_requests: Option<i32>,
+    #[serde(flatten)]
+    pub restrictions: KeyRestrictions,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -41,14 +278,22 @@ pub struct VirtualKeyResponse {
     pub id: Uuid,
     pub key: String, // Full key returned only on creation
     pub key_prefix: String,
+    pub team_id: Option<Uuid>,
     pub name: Option<String>,
     pub max_budget: Option<f64>,
     pub current_spend: f64,
     pub rate_limit_rpm: Option<i32>,
     pub rate_limit_tpm: Option<i32>,
     pub allowed_models: Option<Vec<String>>,
+    pub denied_models: Option<Vec<String>>,
     pub expires_at: Option<DateTime<Utc>>,
     pub blocked: bool,
+    pub budget_period: Option<String>,
+    pub budget_reset_at: Option<DateTime<Utc>>,
+    pub model_limits: HashMap<String, ModelLimit>,
+    pub max_parallel_requests: Option<i32>,
+    #[serde(flatten)]
+    pub restrictions: KeyRestrictions,
     pub created_at: DateTime<Utc>,
 }
 
@@ -61,44 +306,95 @@ pub struct UpdateVirtualKeyRequest {
     pub allowed_models: Option<Vec<String>>,
     pub expires_at: Option<DateTime<Utc>>,
     pub blocked: Option<bool>,
+    pub budget_period: Option<String>,
+    pub model_limits: Option<HashMap<String, ModelLimit>>,
+    pub max_parallel_requests: Option<i32>,
+}
+
+/// A key to insert with [`VirtualKey::create`]
+#[derive(Debug)]
+pub struct NewVirtualKey {
+    pub key_hash: String,
+    pub key_lookup_hash: Option<String>,
+    pub key_prefix: String,
+    pub user_id: Option<Uuid>,
+    pub team_id: Option<Uuid>,
+    pub name: Option<String>,
+    pub max_budget: Option<f64>,
+    pub rate_limit_rpm: Option<i32>,
+    pub rate_limit_tpm: Option<i32>,
+    pub allowed_models: Option<Vec<String>>,
+    pub denied_models: Option<Vec<String>>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub budget_period: Option<String>,
+    pub budget_reset_at: Option<DateTime<Utc>>,
+    pub budget_anchor_at: Option<DateTime<Utc>>,
+    pub model_limits: HashMap<String, ModelLimit>,
+    pub max_parallel_requests: Option<i32>,
+    pub restrictions: KeyRestrictions,
+}
+
+/// Changes for [`VirtualKey::update`]; fields left as None keep their current value
+#[derive(Debug, Default)]
+pub struct KeyUpdate {
+    pub name: Option<String>,
+    pub max_budget: Option<f64>,
+    pub rate_limit_rpm: Option<i32>,
+    pub rate_limit_tpm: Option<i32>,
+    pub allowed_models: Option<Vec<String>>,
+    /// An empty list removes the deny list
+    pub denied_models: Option<Vec<String>>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub blocked: Option<bool>,
+    /// An empty string removes the budget period along with its reset
+    pub budget_period: Option<String>,
+    pub budget_reset_at: Option<DateTime<Utc>>,
+    pub budget_anchor_at: Option<DateTime<Utc>>,
+    pub model_limits: Option<HashMap<String, ModelLimit>>,
+    pub max_parallel_requests: Option<i32>,
+    pub restrictions: UpdateKeyRestrictions,
 }
 
 impl VirtualKey {
     /// Create a new virtual key
-    pub async fn create(
-        pool: &Pool<Postgres>,
-        key_hash: String,
-        key_lookup_hash: String,
-        key_prefix: String,
-        user_id: Option<Uuid>,
-        name: Option<String>,
-        max_budget: Option<f64>,
-        rate_limit_rpm: Option<i32>,
-        rate_limit_tpm: Option<i32>,
-        allowed_models: Option<Vec<String>>,
-        expires_at: Option<DateTime<Utc>>,
-    ) -> ApiResult<Self> {
+    pub async fn create(pool: &Pool<Postgres>, new_key: NewVirtualKey) -> ApiResult<Self> {
         let key: VirtualKey = sqlx::query_as(
             r#"
             INSERT INTO virtual_keys
-            (key_hash, key_lookup_hash, key_prefix, user_id, name, max_budget, rate_limit_rpm,
-             rate_limit_tpm, allowed_models, expires_at)
-            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
-            RETURNING id, key_hash, key_lookup_hash, key_prefix, user_id, name, max_budget, current_spend,
-                      rate_limit_rpm, rate_limit_tpm, allowed_models, expires_at, blocked,
-                      created_at, last_used_at
+            (key_hash, key_lookup_hash, key_prefix, user_id, team_id, name, max_budget, rate_limit_rpm,
+             rate_limit_tpm, allowed_models, denied_models, expires_at, budget_period, budget_reset_at,
+             budget_anchor_at, model_limits, max_parallel_requests, allowed_routes, allowed_ips,
+             allowed_origins, read_only)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18, $19,
+                    $20, $21)
+            RETURNING id, key_hash, key_lookup_hash, key_prefix, user_id, team_id, name, max_budget, current_spend,
+                      rate_limit_rpm, rate_limit_tpm, allowed_models, denied_models, expires_at, blocked,
+                      budget_period, budget_reset_at, budget_anchor_at, model_limits, model_spend, max_parallel_requests, 
+                      previous_key_hash, previous_key_lookup_hash, previous_key_prefix, previous_key_expires_at,
+                      previous_key_last_used_at, allowed_routes, allowed_ips, allowed_origins, read_only, created_at, last_used_at
             "#,
         )
-        .bind(&key_hash)
-        .bind(&key_lookup_hash)
-        .bind(&key_prefix)
-        .bind(user_id)
-        .bind(&name)
-        .bind(max_budget)
-        .bind(rate_limit_rpm)
-        .bind(rate_limit_tpm)
-        .bind(&allowed_models)
-        .bind(expires_at)
+        .bind(&new_key.key_hash)
+        .bind(&new_key.key_lookup_hash)
+        .bind(&new_key.key_prefix)
+        .bind(new_key.user_id)
+        .bind(new_key.team_id)
+        .bind(&new_key.name)
+        .bind(new_key.max_budget)
+        .bind(new_key.rate_limit_rpm)
+        .bind(new_key.rate_limit_tpm)
+        .bind(&new_key.allowed_models)
+        .bind(&new_key.denied_models)
+        .bind(new_key.expires_at)
+        .bind(&new_key.budget_period)
+        .bind(new_key.budget_reset_at)
+        .bind(new_key.budget_anchor_at)
+        .bind(sqlx::types::Json(&new_key.model_limits))
+        .bind(new_key.max_parallel_requests)
+        .bind(&new_key.restrictions.allowed_routes)
+        .bind(&new_key.restrictions.allowed_ips)
+        .bind(&new_key.restrictions.allowed_origins)
+        .bind(new_key.restrictions.read_only)
         .fetch_one(pool)
         .await
         .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
@@ -106,20 +402,28 @@ impl VirtualKey {
         Ok(key)
     }
 
-    /// Find virtual key by lookup hash (fast, for authentication)
-    pub async fn find_by_lookup_hash(
+    /// Find the key a secret belongs to, by its HMAC hash or, for legacy bcrypt-hashed
+    /// keys, its SHA256 lookup hash; a previous secret only matches during its grace period
+    pub async fn find_by_secret(
         pool: &Pool<Postgres>,
+        key_hash: &str,
         lookup_hash: &str,
     ) -> ApiResult<Option<Self>> {
         let key = sqlx::query_as::<_, VirtualKey>(
             r#"
-            SELECT id, key_hash, key_lookup_hash, key_prefix, user_id, name, max_budget, current_spend,
-                   rate_limit_rpm, rate_limit_tpm, allowed_models, expires_at, blocked,
-                   created_at, last_used_at
+            SELECT id, key_hash, key_lookup_hash, key_prefix, user_id, team_id, name, max_budget, current_spend,
+                   rate_limit_rpm, rate_limit_tpm, allowed_models, denied_models, expires_at, blocked,
+                   budget_period, budget_reset_at, budget_anchor_at, model_limits, model_spend, max_parallel_requests, 
+                   previous_key_hash, previous_key_lookup_hash, previous_key_prefix, previous_key_expires_at,
+                   previous_key_last_used_at, allowed_routes, allowed_ips, allowed_origins, read_only, created_at, last_used_at
             FROM virtual_keys
-            WHERE key_lookup_hash = $1
+            WHERE key_hash = $1
+               OR key_lookup_hash = $2
+               OR ((previous_key_hash = $1 OR previous_key_lookup_hash = $2)
+                   AND previous_key_expires_at > NOW())
             "#,
         )
+        .bind(key_hash)
         .bind(lookup_hash)
         .fetch_optional(pool)
         .await
@@ -132,9 +436,11 @@ impl VirtualKey {
     pub async fn find_by_hash(pool: &Pool<Postgres>, key_hash: &str) -> ApiResult<Option<Self>> {
         let key = sqlx::query_as::<_, VirtualKey>(
             r#"
-            SELECT id, key_hash, key_lookup_hash, key_prefix, user_id, name, max_budget, current_spend,
-                   rate_limit_rpm, rate_limit_tpm, allowed_models, expires_at, blocked,
-                   created_at, last_used_at
+            SELECT id, key_hash, key_lookup_hash, key_prefix, user_id, team_id, name, max_budget, current_spend,
+                   rate_limit_rpm, rate_limit_tpm, allowed_models, denied_models, expires_at, blocked,
+                   budget_period, budget_reset_at, budget_anchor_at, model_limits, model_spend, max_parallel_requests, 
+                   previous_key_hash, previous_key_lookup_hash, previous_key_prefix, previous_key_expires_at,
+                   previous_key_last_used_at, allowed_routes, allowed_ips, allowed_origins, read_only, created_at, last_used_at
             FROM virtual_keys
             WHERE key_hash = $1
             "#,
@@ -151,9 +457,11 @@ impl VirtualKey {
     pub async fn find_all(pool: &Pool<Postgres>) -> ApiResult<Vec<Self>> {
         let keys = sqlx::query_as::<_, VirtualKey>(
             r#"
-            SELECT id, key_hash, key_lookup_hash, key_prefix, user_id, name, max_budget, current_spend,
-                   rate_limit_rpm, rate_limit_tpm, allowed_models, expires_at, blocked,
-                   created_at, last_used_at
+            SELECT id, key_hash, key_lookup_hash, key_prefix, user_id, team_id, name, max_budget, current_spend,
+                   rate_limit_rpm, rate_limit_tpm, allowed_models, denied_models, expires_at, blocked,
+                   budget_period, budget_reset_at, budget_anchor_at, model_limits, model_spend, max_parallel_requests, 
+                   previous_key_hash, previous_key_lookup_hash, previous_key_prefix, previous_key_expires_at,
+                   previous_key_last_used_at, allowed_routes, allowed_ips, allowed_origins, read_only, created_at, last_used_at
             FROM virtual_keys
             ORDER BY created_at DESC
             "#,
@@ -169,9 +477,11 @@ impl VirtualKey {
     pub async fn find_by_id(pool: &Pool<Postgres>, key_id: Uuid) -> ApiResult<Option<Self>> {
         let key = sqlx::query_as::<_, VirtualKey>(
             r#"
-            SELECT id, key_hash, key_lookup_hash, key_prefix, user_id, name, max_budget, current_spend,
-                   rate_limit_rpm, rate_limit_tpm, allowed_models, expires_at, blocked,
-                   created_at, last_used_at
+            SELECT id, key_hash, key_lookup_hash, key_prefix, user_id, team_id, name, max_budget, current_spend,
+                   rate_limit_rpm, rate_limit_tpm, allowed_models, denied_models, expires_at, blocked,
+                   budget_period, budget_reset_at, budget_anchor_at, model_limits, model_spend, max_parallel_requests, 
+                   previous_key_hash, previous_key_lookup_hash, previous_key_prefix, previous_key_expires_at,
+                   previous_key_last_used_at, allowed_routes, allowed_ips, allowed_origins, read_only, created_at, last_used_at
             FROM virtual_keys
             WHERE id = $1
             "#,
@@ -188,9 +498,11 @@ impl VirtualKey {
     pub async fn find_by_user(pool: &Pool<Postgres>, user_id: Uuid) -> ApiResult<Vec<Self>> {
         let keys = sqlx::query_as::<_, VirtualKey>(
             r#"
-            SELECT id, key_hash, key_lookup_hash, key_prefix, user_id, name, max_budget, current_spend,
-                   rate_limit_rpm, rate_limit_tpm, allowed_models, expires_at, blocked,
-                   created_at, last_used_at
+            SELECT id, key_hash, key_lookup_hash, key_prefix, user_id, team_id, name, max_budget, current_spend,
+                   rate_limit_rpm, rate_limit_tpm, allowed_models, denied_models, expires_at, blocked,
+                   budget_period, budget_reset_at, budget_anchor_at, model_limits, model_spend, max_parallel_requests, 
+                   previous_key_hash, previous_key_lookup_hash, previous_key_prefix, previous_key_expires_at,
+                   previous_key_last_used_at, allowed_routes, allowed_ips, allowed_origins, read_only, created_at, last_used_at
             FROM virtual_keys
             WHERE user_id = $1
             ORDER BY created_at DESC
@@ -204,18 +516,58 @@ impl VirtualKey {
         Ok(keys)
     }
 
-    /// Update virtual key
-    pub async fn update(
+    /// Find all keys owned by a team
+    pub async fn find_by_team(pool: &Pool<Postgres>, team_id: Uuid) -> ApiResult<Vec<Self>> {
+        let keys = sqlx::query_as::<_, VirtualKey>(
+            r#"
+            SELECT id, key_hash, key_lookup_hash, key_prefix, user_id, team_id, name, max_budget, current_spend,
+                   rate_limit_rpm, rate_limit_tpm, allowed_models, denied_models, expires_at, blocked,
+                   budget_period, budget_reset_at, budget_anchor_at, model_limits, model_spend, max_parallel_requests, 
+                   previous_key_hash, previous_key_lookup_hash, previous_key_prefix, previous_key_expires_at,
+                   previous_key_last_used_at, allowed_routes, allowed_ips, allowed_origins, read_only, created_at, last_used_at
+            FROM virtual_keys
+            WHERE team_id = $1
+            ORDER BY created_at DESC
+            "#,
+        )
+        .bind(team_id)
+        .fetch_all(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        Ok(keys)
+    }
+
+    /// Move a key to another team, or out of any team when `team_id` is None
+    pub async fn set_team(
         pool: &Pool<Postgres>,
         key_id: Uuid,
-        name: Option<String>,
-        max_budget: Option<f64>,
-        rate_limit_rpm: Option<i32>,
-        rate_limit_tpm: Option<i32>,
-        allowed_models: Option<Vec<String>>,
-        expires_at: Option<DateTime<Utc>>,
-        blocked: Option<bool>,
+        team_id: Option<Uuid>,
     ) -> ApiResult<Self> {
+        sqlx::query_as::<_, VirtualKey>(
+            r#"
+            UPDATE virtual_keys
+            SET team_id = $2
+            WHERE id = $1
+            RETURNING id, key_hash, key_lookup_hash, key_prefix, user_id, team_id, name, max_budget, current_spend,
+                      rate_limit_rpm, rate_limit_tpm, allowed_models, denied_models, expires_at, blocked,
+                      budget_period, budget_reset_at, budget_anchor_at, model_limits, model_spend, max_parallel_requests, 
+                      previous_key_hash, previous_key_lookup_hash, previous_key_prefix, previous_key_expires_at,
+                      previous_key_last_used_at, allowed_routes, allowed_ips, allowed_origins, read_only, created_at, last_used_at
+            "#,
+        )
+        .bind(key_id)
+        .bind(team_id)
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?
+        .ok_or_else(|| ApiError::NotFound("Key not found".to_string()))
+    }
+
+    /// Update virtual key
+    pub async fn update(pool: &Pool<Postgres>, key_id: Uuid, update: KeyUpdate) -> ApiResult<Self> {
+        // Replacing model_limits drops the spend of patterns that were removed. Setting
+        // `blocked` explicitly overrides a block from the owner being disabled.
         let key: VirtualKey = sqlx::query_as(
             r#"
             UPDATE virtual_keys
@@ -226,21 +578,67 @@ impl VirtualKey {
                 rate_limit_tpm = COALESCE($5, rate_limit_tpm),
                 allowed_models = COALESCE($6, allowed_models),
                 expires_at = COALESCE($7, expires_at),
-                blocked = COALESCE($8, blocked)
+                blocked = COALESCE($8, blocked),
+                blocked_by_user_disable = blocked_by_user_disable AND $8::boolean IS NULL,
+                budget_period = CASE
+                    WHEN $9::text IS NULL THEN budget_period
+                    ELSE NULLIF($9, '')
+                END,
+                budget_reset_at = CASE WHEN $9::text IS NULL THEN budget_reset_at ELSE $10 END,
+                budget_anchor_at = CASE WHEN $9::text IS NULL THEN budget_anchor_at ELSE $18 END,
+                model_limits = COALESCE($11, model_limits),
+                model_spend = CASE
+                    WHEN $11 IS NULL THEN model_spend
+                    ELSE (
+                        SELECT COALESCE(jsonb_object_agg(key, value), '{}'::jsonb)
+                        FROM jsonb_each(model_spend)
+                        WHERE $11 ? key
+                    )
+                END,
+                max_parallel_requests = COALESCE($12, max_parallel_requests),
+                allowed_routes = CASE
+                    WHEN $13::text[] IS NULL THEN allowed_routes
+                    ELSE NULLIF($13, '{}')
+                END,
+                allowed_ips = CASE
+                    WHEN $14::text[] IS NULL THEN allowed_ips
+                    ELSE NULLIF($14, '{}')
+                END,
+                allowed_origins = CASE
+                    WHEN $15::text[] IS NULL THEN allowed_origins
+                    ELSE NULLIF($15, '{}')
+                END,
+                read_only = COALESCE($16, read_only),
+                denied_models = CASE
+                    WHEN $17::text[] IS NULL THEN denied_models
+                    ELSE NULLIF($17, '{}')
+                END
             WHERE id = $1
-            RETURNING id, key_hash, key_prefix, user_id, name, max_budget, current_spend,
-                      rate_limit_rpm, rate_limit_tpm, allowed_models, expires_at, blocked,
-                      created_at, last_used_at
+            RETURNING id, key_hash, key_lookup_hash, key_prefix, user_id, team_id, name, max_budget, current_spend,
+                      rate_limit_rpm, rate_limit_tpm, allowed_models, denied_models, expires_at, blocked,
+                      budget_period, budget_reset_at, budget_anchor_at, model_limits, model_spend, max_parallel_requests, 
+                      previous_key_hash, previous_key_lookup_hash, previous_key_prefix, previous_key_expires_at,
+                      previous_key_last_used_at, allowed_routes, allowed_ips, allowed_origins, read_only, created_at, last_used_at
             "#,
         )
         .bind(key_id)
-        .bind(name)
-        .bind(max_budget)
-        .bind(rate_limit_rpm)
-        .bind(rate_limit_tpm)
-        .bind(&allowed_models)
-        .bind(expires_at)
-        .bind(blocked)
+        .bind(update.name)
+        .bind(update.max_budget)
+        .bind(update.rate_limit_rpm)
+        .bind(update.rate_limit_tpm)
+        .bind(&update.allowed_models)
+        .bind(update.expires_at)
+        .bind(update.blocked)
+        .bind(&update.budget_period)
+        .bind(update.budget_reset_at)
+        .bind(update.model_limits.map(sqlx::types::Json))
+        .bind(update.max_parallel_requests)
+        .bind(&update.restrictions.allowed_routes)
+        .bind(&update.restrictions.allowed_ips)
+        .bind(&update.restrictions.allowed_origins)
+        .bind(update.restrictions.read_only)
+        .bind(&update.denied_models)
+        .bind(update.budget_anchor_at)
         .fetch_one(pool)
         .await
         .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
@@ -248,12 +646,85 @@ impl VirtualKey {
         Ok(key)
     }
 
-    /// Block/unblock a key
+    /// Replace the key's secret, keeping the old one valid until `previous_expires_at`
+    ///
+    /// Limits, spend and history stay on the same row; a secret rotated out earlier is
+    /// dropped, so at most two secrets are ever valid.
+    pub async fn rotate(
+        pool: &Pool<Postgres>,
+        key_id: Uuid,
+        key_hash: String,
+        key_prefix: String,
+        previous_expires_at: DateTime<Utc>,
+    ) -> ApiResult<Self> {
+        sqlx::query_as::<_, VirtualKey>(
+            r#"
+            UPDATE virtual_keys
+            SET previous_key_hash = key_hash,
+                previous_key_lookup_hash = key_lookup_hash,
+                previous_key_prefix = key_prefix,
+                previous_key_expires_at = $4,
+                previous_key_last_used_at = NULL,
+                key_hash = $2,
+                key_lookup_hash = NULL,
+                key_prefix = $3
+            WHERE id = $1
+            RETURNING id, key_hash, key_lookup_hash, key_prefix, user_id, team_id, name, max_budget, current_spend,
+                      rate_limit_rpm, rate_limit_tpm, allowed_models, denied_models, expires_at, blocked,
+                      budget_period, budget_reset_at, budget_anchor_at, model_limits, model_spend, max_parallel_requests, 
+                      previous_key_hash, previous_key_lookup_hash, previous_key_prefix, previous_key_expires_at,
+                      previous_key_last_used_at, allowed_routes, allowed_ips, allowed_origins, read_only, created_at, last_used_at
+            "#,
+        )
+        .bind(key_id)
+        .bind(key_hash)
+        .bind(key_prefix)
+        .bind(previous_expires_at)
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?
+        .ok_or_else(|| ApiError::NotFound("Key not found".to_string()))
+    }
+
+    /// Replace a legacy bcrypt hash with the HMAC hash, once the secret has been verified
+    pub async fn upgrade_key_hash(
+        pool: &Pool<Postgres>,
+        key_id: Uuid,
+        secret: KeySecret,
+        key_hash: &str,
+    ) -> ApiResult<()> {
+        let query = match secret {
+            KeySecret::Current => {
+                r#"
+                UPDATE virtual_keys
+                SET key_hash = $2, key_lookup_hash = NULL
+                WHERE id = $1 AND key_hash LIKE '$2%'
+                "#
+            }
+            KeySecret::Previous => {
+                r#"
+                UPDATE virtual_keys
+                SET previous_key_hash = $2, previous_key_lookup_hash = NULL
+                WHERE id = $1 AND previous_key_hash LIKE '$2%'
+                "#
+            }
+        };
+        sqlx::query(query)
+            .bind(key_id)
+            .bind(key_hash)
+            .execute(pool)
+            .await
+            .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        Ok(())
+    }
+
+    /// Block/unblock a key; callers must invalidate the key cache so it applies immediately
     pub async fn set_blocked(pool: &Pool<Postgres>, key_id: Uuid, blocked: bool) -> ApiResult<()> {
         sqlx::query(
             r#"
             UPDATE virtual_keys
-            SET blocked = $2
+            SET blocked = $2, blocked_by_user_disable = false
             WHERE id = $1
             "#,
         )
@@ -266,27 +737,44 @@ impl VirtualKey {
         Ok(())
     }
 
-    /// Increment spend for a key
+    /// Increment spend for a key; returns whether the key, or its limit for the model
+    /// pattern, is now over budget
     pub async fn increment_spend(
         pool: &Pool<Postgres>,
         key_id: Uuid,
         amount: f64,
-    ) -> ApiResult<()> {
-        sqlx::query(
+        model_pattern: Option<&str>,
+    ) -> ApiResult<bool> {
+        let over_budget = sqlx::query_scalar::<_, bool>(
             r#"
             UPDATE virtual_keys
             SET current_spend = current_spend + $2,
+                model_spend = CASE
+                    WHEN $3::text IS NULL THEN model_spend
+                    ELSE jsonb_set(
+                        model_spend,
+                        ARRAY[$3::text],
+                        to_jsonb(COALESCE((model_spend ->> $3::text)::double precision, 0) + $2)
+                    )
+                END,
                 last_used_at = NOW()
             WHERE id = $1
+            RETURNING (max_budget IS NOT NULL AND current_spend >= max_budget)
+                OR COALESCE(
+                    (model_spend ->> $3::text)::double precision
+                        >= (model_limits -> $3::text ->> 'max_budget')::double precision,
+                    FALSE
+                )
             "#,
         )
         .bind(key_id)
         .bind(amount)
-        .execute(pool)
+        .bind(model_pattern)
+        .fetch_optional(pool)
         .await
         .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
 
-        Ok(())
+        Ok(over_budget.unwrap_or(false))
     }
 
     /// Update last used timestamp
@@ -306,6 +794,87 @@ impl VirtualKey {
         Ok(())
     }
 
+    /// Record a request made with the previous secret
+    pub async fn update_previous_last_used(pool: &Pool<Postgres>, key_id: Uuid) -> ApiResult<()> {
+        sqlx::query(
+            r#"
+            UPDATE virtual_keys
+            SET last_used_at = NOW(), previous_key_last_used_at = NOW()
+            WHERE id = $1
+            "#,
+        )
+        .bind(key_id)
+        .execute(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        Ok(())
+    }
+
+    /// Find keys whose budget period has ended and needs resetting
+    pub async fn find_due_for_budget_reset(pool: &Pool<Postgres>) -> ApiResult<Vec<Self>> {
+        let keys = sqlx::query_as::<_, VirtualKey>(
+            r#"
+            SELECT id, key_hash, key_lookup_hash, key_prefix, user_id, team_id, name, max_budget, current_spend,
+                   rate_limit_rpm, rate_limit_tpm, allowed_models, denied_models, expires_at, blocked,
+                   budget_period, budget_reset_at, budget_anchor_at, model_limits, model_spend, max_parallel_requests, 
+                   previous_key_hash, previous_key_lookup_hash, previous_key_prefix, previous_key_expires_at,
+                   previous_key_last_used_at, allowed_routes, allowed_ips, allowed_origins, read_only, created_at, last_used_at
+            FROM virtual_keys
+            WHERE budget_period IS NOT NULL AND budget_reset_at <= NOW()
+            ORDER BY budget_reset_at
+            "#,
+        )
+        .fetch_all(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        Ok(keys)
+    }
+
+    /// Archive the spend of the period ending at `reset_at` and start a new period
+    /// Returns false if the key was already reset (e.g. by another replica)
+    pub async fn reset_budget_period(
+        pool: &Pool<Postgres>,
+        key_id: Uuid,
+        period_start: DateTime<Utc>,
+        reset_at: DateTime<Utc>,
+        next_reset_at: DateTime<Utc>,
+    ) -> ApiResult<bool> {
+        // Single statement so the archive and the reset are atomic, and the row lock
+        // keeps concurrent spend increments from landing in the wrong period
+        let result = sqlx::query(
+            r#"
+            WITH previous AS (
+                SELECT id, current_spend
+                FROM virtual_keys
+                WHERE id = $1 AND budget_reset_at = $3
+                FOR UPDATE
+            ),
+            archived AS (
+                INSERT INTO virtual_key_budget_history
+                (virtual_key_id, period_start, period_end, spend)
+                SELECT id, $2, $3, current_spend FROM previous
+            )
+            UPDATE virtual_keys
+            SET current_spend = 0,
+                model_spend = '{}'::jsonb,
+                budget_reset_at = $4
+            FROM previous
+            WHERE virtual_keys.id = previous.id
+            "#,
+        )
+        .bind(key_id)
+        .bind(period_start)
+        .bind(reset_at)
+        .bind(next_reset_at)
+        .execute(pool)
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
     /// Delete a virtual key
     pub async fn delete(pool: &Pool<Postgres>, key_id: Uuid) -> ApiResult<()> {
         sqlx::query(
@@ -322,8 +891,43 @@ impl VirtualKey {
         Ok(())
     }
 
+    /// The `model_limits` entry for a model: an exact match, else the most specific pattern
+    pub fn model_limit_for(&self, model: &str) -> Option<(&String, &ModelLimit)> {
+        if let Some(entry) = self.model_limits.get_key_value(model) {
+            return Some(entry);
+        }
+
+        self.model_limits
+            .iter()
+            .filter(|(pattern, _)| model_pattern_matches(pattern, model))
+            .max_by_key(|(pattern, _)| (pattern.replace('*', "").len(), pattern.as_str()))
+    }
+
+    /// Check if the models matching a `model_limits` pattern have used up their budget
+    pub fn is_model_over_budget(&self, pattern: &str) -> bool {
+        if self.is_budget_period_elapsed() {
+            return false;
+        }
+
+        let spend = self.model_spend.get(pattern).copied().unwrap_or(0.0);
+        self.model_limits
+            .get(pattern)
+            .and_then(|limit| limit.max_budget)
+            .is_some_and(|max_budget| spend >= max_budget)
+    }
+
+    /// Spend from an elapsed period no longer counts, even before the reset job runs
+    fn is_budget_period_elapsed(&self) -> bool {
+        self.budget_reset_at
+            .is_some_and(|reset_at| reset_at <= Utc::now())
+    }
+
     /// Check if key has exceeded budget
     pub fn is_over_budget(&self) -> bool {
+        if self.is_budget_period_elapsed() {
+            return false;
+        }
+
         if let Some(max_budget) = self.max_budget {
             self.current_spend >= max_budget
         } else {
@@ -340,17 +944,124 @@ impl VirtualKey {
         }
     }
 
+    /// Which of the key's secrets a token is, going by its prefix; `None` for a previous
+    /// secret whose grace period is over
+    pub fn secret_for(&self, token: &str) -> Option<KeySecret> {
+        if token.starts_with(&self.key_prefix) {
+            return Some(KeySecret::Current);
+        }
+        let previous_valid = self
+            .previous_key_expires_at
+            .is_some_and(|expires_at| expires_at > Utc::now());
+        match &self.previous_key_prefix {
+            Some(prefix) if previous_valid && token.starts_with(prefix) => {
+                Some(KeySecret::Previous)
+            }
+            _ => None,
+        }
+    }
+
+    /// Stored hash of one of the key's secrets
+    pub fn hash_for(&self, secret: KeySecret) -> Option<&str> {
+        match secret {
+            KeySecret::Current => Some(&self.key_hash),
+            KeySecret::Previous => self.previous_key_hash.as_deref(),
+        }
+    }
+
     /// Check if key is valid for use
     pub fn is_valid(&self) -> bool {
         !self.blocked && !self.is_over_budget() && !self.is_expired()
     }
 
-    /// Check if key can access a specific model
-    pub fn can_access_model(&self, model: &str) -> bool {
-        if let Some(allowed_models) = &self.allowed_models {
-            allowed_models.iter().any(|m| m == model)
-        } else {
-            true // No restrictions
-        }
+    /// Check if key can access a model served by `provider`, expanding access groups from
+    /// `groups`; the deny list wins over the allow list
+    pub fn can_access_model(
+        &self,
+        model: &str,
+        provider: Option<&str>,
+        groups: &HashMap<String, Vec<String>>,
+    ) -> bool {
+        let allowed = self
+            .allowed_models
+            .as_ref()
+            .is_none_or(|allowed| model_entries_match(allowed, model, provider, groups));
+        let denied = self
+            .denied_models
+            .as_ref()
+            .is_some_and(|denied| model_entries_match(denied, model, provider, groups));
+        allowed && !denied
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_model_pattern_matches() {
+        assert!(model_pattern_matches("gpt-4o", "gpt-4o"));
+        assert!(!model_pattern_matches("gpt-4o", "gpt-4o-mini"));
+        assert!(model_pattern_matches(
+            "claude-3-opus*",
+            "claude-3-opus-20240229"
+        ));
+        assert!(model_pattern_matches("*haiku*", "claude-3-5-haiku-latest"));
+        assert!(model_pattern_matches(
+            "claude-*-sonnet",
+            "claude-3-5-sonnet"
+        ));
+        assert!(!model_pattern_matches(
+            "claude-*-sonnet",
+            "claude-3-5-sonnet-latest"
+        ));
+        assert!(model_pattern_matches("*", "anything"));
+    }
+
+    #[test]
+    fn test_key_restrictions() {
+        let restrictions = KeyRestrictions {
+            allowed_routes: Some(vec!["/v1/chat/*".to_string()]),
+            allowed_ips: Some(vec!["10.0.0.0/8".to_string(), "2001:db8::1".to_string()]),
+            allowed_origins: Some(vec!["https://*.example.com".to_string()]),
+            read_only: false,
+        };
+
+        assert!(restrictions.allows_route("/v1/chat/completions"));
+        assert!(!restrictions.allows_route("/stats"));
+        assert!(restrictions.allows_ip("10.1.2.3".parse().unwrap()));
+        assert!(restrictions.allows_ip("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(restrictions.allows_ip("2001:db8::1".parse().unwrap()));
+        assert!(!restrictions.allows_ip("192.168.0.1".parse().unwrap()));
+        assert!(restrictions.allows_origin("https://app.example.com"));
+        assert!(!restrictions.allows_origin("https://example.org"));
+        assert!(KeyRestrictions::default().allows_ip("192.168.0.1".parse().unwrap()));
+
+        assert!(validate_key_restrictions(
+            restrictions.allowed_routes.as_deref(),
+            restrictions.allowed_ips.as_deref(),
+            restrictions.allowed_origins.as_deref(),
+        )
+        .is_ok());
+        let invalid = |value: &str| Some(vec![value.to_string()]);
+        assert!(validate_key_restrictions(invalid("v1/*").as_deref(), None, None).is_err());
+        assert!(validate_key_restrictions(None, invalid("10.0.0.0/33").as_deref(), None).is_err());
+        assert!(validate_key_restrictions(None, None, invalid("example.com").as_deref()).is_err());
+    }
+
+    #[test]
+    fn test_validate_model_limits() {
+        let limit = |rpm| ModelLimit {
+            rate_limit_rpm: rpm,
+            ..Default::default()
+        };
+        let limits = HashMap::from([("claude-*".to_string(), limit(Some(10)))]);
+        assert!(validate_model_limits(&limits).is_ok());
+
+        let limits = HashMap::from([("claude-*".to_string(), limit(Some(0)))]);
+        assert!(validate_model_limits(&limits).is_err());
+
+        let limits = HashMap::from([(" ".to_string(), limit(None))]);
+        assert!(validate_model_limits(&limits).is_err());
     }
 }
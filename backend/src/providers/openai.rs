@@ -13,7 +13,7 @@ use crate::{
     error::{ApiError, ApiResult},
     provider_config,
     providers::LLMProvider,
-    ChatCompletionRequest, ChatCompletionResponse,
+    ChatCompletionRequest, ChatCompletionResponse, StreamOptions,
 };
 
 #[derive(Debug, Clone)]
@@ -95,6 +95,13 @@ impl LLMProvider for OpenAIProvider {
         // Create a new request with stream enabled
         let mut streaming_request = request.clone();
         streaming_request.stream = Some(true);
+        // Ask for a final usage chunk so spend and token limits can be settled; the gateway
+        // drops it again for clients that didn't ask for it
+        if streaming_request.stream_options.is_none() {
+            streaming_request.stream_options = Some(StreamOptions {
+                include_usage: Some(true),
+            });
+        }
 
         let response = self
             .client
@@ -1,9 +1,13 @@
-use chrono::Utc;
 use redis::AsyncCommands;
 use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
 use tracing::{debug, warn};
 
 use crate::error::{ApiError, ApiResult};
+use crate::local_store::{RedisConnection, RedisHealth, SlidingWindowLimiter, WindowMode};
 
 /// Rate limit configuration for a virtual key
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -12,354 +16,616 @@ pub struct RateLimit {
     pub tokens_per_minute: Option<i32>,
 }
 
+impl RateLimit {
+    pub fn is_unlimited(&self) -> bool {
+        self.requests_per_minute.is_none() && self.tokens_per_minute.is_none()
+    }
+}
+
+/// Level a rate limit or budget is attached to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LimitScope {
+    Key,
+    Model,
+    Team,
+    User,
+    Global,
+    /// Client address, for login and registration attempts
+    Ip,
+    /// Account (by email) targeted by login attempts
+    Account,
+}
+
+impl LimitScope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LimitScope::Key => "key",
+            LimitScope::Model => "model",
+            LimitScope::Team => "team",
+            LimitScope::User => "user",
+            LimitScope::Global => "global",
+            LimitScope::Ip => "ip",
+            LimitScope::Account => "account",
+        }
+    }
+}
+
+impl fmt::Display for LimitScope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A rate limit at one level of the hierarchy (e.g. the key, its user, the whole gateway)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RateLimitLevel {
+    pub scope: LimitScope,
+    pub id: String,
+    pub limit: RateLimit,
+}
+
+impl RateLimitLevel {
+    pub fn new(scope: LimitScope, id: impl Into<String>, limit: RateLimit) -> Self {
+        Self {
+            scope,
+            id: id.into(),
+            limit,
+        }
+    }
+}
+
 /// Rate limit status information
+///
+/// With several levels, remaining counts and limits come from the most restrictive level
+/// for each dimension, and `blocked_by` names the level that rejected the request.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RateLimitStatus {
     pub limited: bool,
+    pub blocked_by: Option<LimitScope>,
+    pub requests_limit: Option<i32>,
+    pub tokens_limit: Option<i32>,
     pub requests_remaining: Option<i32>,
     pub tokens_remaining: Option<i32>,
     pub reset_at: Option<i64>,    // Unix timestamp
     pub retry_after: Option<i64>, // Seconds until reset
+    pub reset_at_ms: Option<i64>, // Unix timestamp in milliseconds (exact)
+    pub retry_after_ms: Option<i64>,
 }
 
-/// Rate limiter using sliding window algorithm with Redis
+impl RateLimitStatus {
+    fn unlimited() -> Self {
+        Self {
+            limited: false,
+            blocked_by: None,
+            requests_limit: None,
+            tokens_limit: None,
+            requests_remaining: None,
+            tokens_remaining: None,
+            reset_at: None,
+            retry_after: None,
+            reset_at_ms: None,
+            retry_after_ms: None,
+        }
+    }
+}
+
+/// GCRA (generic cell rate algorithm) over any number of buckets, applied atomically.
+///
+/// Each bucket stores a single "theoretical arrival time" (TAT) in milliseconds,
+/// so a check or debit is O(1) regardless of how many tokens it covers. A limit of
+/// N per window means every unit pushes the TAT forward by window/N ms, and a call
+/// is allowed while the TAT stays within one window of now. A request is checked
+/// against the request and token buckets of every level in one call.
+///
+/// KEYS[i]: TAT key of bucket i
+/// ARGV[1]: window in ms
+/// ARGV[2]: mode - "check" (all-or-nothing), "debit" (unconditional) or "peek" (read-only)
+/// ARGV[1 + 2i], ARGV[2 + 2i]: limit and cost of bucket i (cost may be negative to refund)
+///
+/// Returns {allowed, now_ms, blocking_bucket, retry_after_ms,
+///          remaining_1, reset_ms_1, remaining_2, reset_ms_2, ...}
+/// where blocking_bucket is the 1-based bucket that needs the longest wait (0 if allowed).
+const GCRA_SCRIPT: &str = r#"
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+local window = tonumber(ARGV[1])
+local mode = ARGV[2]
+
+local buckets = {}
+for i = 1, #KEYS do
+    local limit = tonumber(ARGV[1 + i * 2])
+    local cost = tonumber(ARGV[2 + i * 2])
+    local interval = window / limit
+    local stored = redis.call('GET', KEYS[i])
+    local tat = math.max(stored and tonumber(stored) or now, now)
+    local new_tat = math.max(tat + cost * interval, now)
+    buckets[i] = { interval = interval, tat = tat, new_tat = new_tat }
+end
+
+local allowed = 1
+local blocked = 0
+local retry_after = 0
+if mode == 'check' then
+    for i = 1, #KEYS do
+        local excess = buckets[i].new_tat - now - window
+        if excess > 0 then
+            allowed = 0
+            if excess > retry_after then
+                retry_after = excess
+                blocked = i
+            end
+        end
+    end
+end
+
+local result = { allowed, now, blocked, math.ceil(retry_after) }
+for i = 1, #KEYS do
+    local bucket = buckets[i]
+    local tat = bucket.tat
+    if mode ~= 'peek' and allowed == 1 then
+        tat = bucket.new_tat
+        redis.call('SET', KEYS[i], string.format('%.3f', tat), 'PX', math.ceil(tat - now) + 1000)
+    end
+    local remaining = math.floor((window - (tat - now)) / bucket.interval)
+    table.insert(result, math.max(remaining, 0))
+    table.insert(result, math.ceil(tat))
+end
+return result
+"#;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Dimension {
+    Requests,
+    Tokens,
+}
+
+/// One limited dimension of one level, i.e. one GCRA bucket
+#[derive(Debug, Clone)]
+struct Bucket {
+    key: String,
+    scope: LimitScope,
+    dimension: Dimension,
+    limit: i32,
+    cost: i32,
+}
+
+/// Buckets for every configured limit across `levels`; unlimited dimensions are skipped
+fn buckets_for(levels: &[RateLimitLevel], requests: i32, tokens: i32) -> Vec<Bucket> {
+    let mut buckets = Vec::new();
+    for level in levels {
+        let dimensions = [
+            (
+                Dimension::Requests,
+                "requests",
+                level.limit.requests_per_minute,
+                requests,
+            ),
+            (
+                Dimension::Tokens,
+                "tokens",
+                level.limit.tokens_per_minute,
+                tokens,
+            ),
+        ];
+        for (dimension, name, limit, cost) in dimensions {
+            if let Some(limit) = limit.filter(|limit| *limit > 0) {
+                buckets.push(Bucket {
+                    key: format!("ratelimit:gcra:{}:{}:{}", level.scope, level.id, name),
+                    scope: level.scope,
+                    dimension,
+                    limit,
+                    cost,
+                });
+            }
+        }
+    }
+    buckets
+}
+
+/// Rate limiter using an atomic GCRA token bucket in Redis
+///
+/// Without Redis, or while Redis is unreachable, limits are enforced per process with an
+/// in-memory sliding window. With `fail_open` disabled, a configured but unreachable Redis
+/// rejects rate-limited requests instead, so limits are never enforced per replica.
 #[derive(Clone)]
 pub struct RateLimiter {
-    redis_client: Option<redis::aio::ConnectionManager>,
+    redis_client: RedisConnection,
     window_size_seconds: i64,
+    script: redis::Script,
+    default_max_tokens: i32,
+    max_queue_wait: Duration,
+    local: Arc<SlidingWindowLimiter>,
+    health: RedisHealth,
+    fail_open: bool,
 }
 
 impl RateLimiter {
-    pub fn new(redis_client: Option<redis::aio::ConnectionManager>) -> Self {
+    /// `default_max_tokens` is the completion budget reserved for requests without `max_tokens`.
+    /// Requests that would exceed a limit wait up to `max_queue_wait` for capacity before
+    /// being rejected (zero rejects immediately).
+    pub fn new(
+        redis_client: RedisConnection,
+        default_max_tokens: i32,
+        max_queue_wait: Duration,
+        fail_open: bool,
+    ) -> Self {
+        let window_size_seconds = 60; // 1 minute window
         Self {
             redis_client,
-            window_size_seconds: 60, // 1 minute window
+            window_size_seconds,
+            script: redis::Script::new(GCRA_SCRIPT),
+            default_max_tokens,
+            max_queue_wait,
+            local: Arc::new(SlidingWindowLimiter::new(Duration::from_secs(
+                window_size_seconds as u64,
+            ))),
+            health: RedisHealth::new("rate limiting"),
+            fail_open,
         }
     }
 
-    /// Check if a request is allowed and increment counters
-    /// Returns RateLimitStatus with remaining capacity and reset time
-    pub async fn check_and_increment(
+    /// Whether limits are currently enforced in-process rather than in Redis
+    pub fn is_local(&self) -> bool {
+        !self.redis_client.is_connected() || self.health.is_degraded()
+    }
+
+    /// Completion tokens to reserve when a request doesn't set `max_tokens`
+    pub fn default_max_tokens(&self) -> i32 {
+        self.default_max_tokens
+    }
+
+    /// Like `check_and_increment`, but waits for capacity when it frees up within the
+    /// configured queue time instead of rejecting straight away
+    pub async fn check_and_reserve(
         &self,
-        key_id: &str,
-        rate_limit: &RateLimit,
+        levels: &[RateLimitLevel],
         tokens: i32,
     ) -> ApiResult<RateLimitStatus> {
-        // If rate limiting is disabled (no Redis or no limits), allow all requests
-        if self.redis_client.is_none() {
-            return Ok(RateLimitStatus {
-                limited: false,
-                requests_remaining: None,
-                tokens_remaining: None,
-                reset_at: None,
-                retry_after: None,
-            });
-        }
+        let deadline = Instant::now() + self.max_queue_wait;
 
-        // If no limits are configured for this key, allow the request
-        if rate_limit.requests_per_minute.is_none() && rate_limit.tokens_per_minute.is_none() {
-            return Ok(RateLimitStatus {
-                limited: false,
-                requests_remaining: None,
-                tokens_remaining: None,
-                reset_at: None,
-                retry_after: None,
-            });
-        }
+        loop {
+            let status = self.check_and_increment(levels, tokens).await?;
+            let Some(retry_after_ms) = status.retry_after_ms.filter(|_| status.limited) else {
+                return Ok(status);
+            };
 
-        let redis_conn = self.redis_client.as_ref().unwrap();
-        let now = Utc::now().timestamp();
-        let window_start = now - self.window_size_seconds;
-
-        // Check and increment request count
-        let requests_status = if let Some(rpm_limit) = rate_limit.requests_per_minute {
-            self.check_and_increment_counter(
-                redis_conn,
-                &format!("ratelimit:{}:requests", key_id),
-                rpm_limit,
-                now,
-                window_start,
-                1,
-            )
-            .await?
-        } else {
-            CounterStatus {
-                allowed: true,
-                remaining: None,
-                reset_at: now + self.window_size_seconds,
+            let wait = Duration::from_millis(retry_after_ms.max(1) as u64);
+            if Instant::now() + wait > deadline {
+                return Ok(status);
             }
-        };
 
-        // Check and increment token count
-        let tokens_status = if let Some(tpm_limit) = rate_limit.tokens_per_minute {
-            self.check_and_increment_counter(
-                redis_conn,
-                &format!("ratelimit:{}:tokens", key_id),
-                tpm_limit,
-                now,
-                window_start,
-                tokens,
-            )
-            .await?
-        } else {
-            CounterStatus {
-                allowed: true,
-                remaining: None,
-                reset_at: now + self.window_size_seconds,
-            }
-        };
+            debug!(
+                "Queueing request for {:?} for {:?}",
+                levels_label(levels),
+                wait
+            );
+            tokio::time::sleep(wait).await;
+        }
+    }
 
-        // If either limit is exceeded, deny the request
-        if !requests_status.allowed || !tokens_status.allowed {
-            let reset_at = std::cmp::max(requests_status.reset_at, tokens_status.reset_at);
-            let retry_after = reset_at - now;
+    /// Check if a request is allowed at every level and increment counters
+    /// Charges one request and `tokens` tokens at each level atomically; nothing is
+    /// charged anywhere when any level is limited
+    pub async fn check_and_increment(
+        &self,
+        levels: &[RateLimitLevel],
+        tokens: i32,
+    ) -> ApiResult<RateLimitStatus> {
+        let status = self
+            .run_script(levels, WindowMode::Check, 1, tokens)
+            .await?;
 
+        if status.limited {
             warn!(
-                "Rate limit exceeded for key {}: requests_allowed={}, tokens_allowed={}",
-                key_id, requests_status.allowed, tokens_status.allowed
+                "Rate limit exceeded for {} at {:?} level: requests_remaining={:?}, tokens_remaining={:?}, retry_after={:?}s",
+                levels_label(levels),
+                status.blocked_by,
+                status.requests_remaining,
+                status.tokens_remaining,
+                status.retry_after
+            );
+        } else {
+            debug!(
+                "Rate limit check passed for {}: requests_remaining={:?}, tokens_remaining={:?}",
+                levels_label(levels),
+                status.requests_remaining,
+                status.tokens_remaining
             );
+        }
+
+        Ok(status)
+    }
 
-            return Ok(RateLimitStatus {
-                limited: true,
-                requests_remaining: requests_status.remaining,
-                tokens_remaining: tokens_status.remaining,
-                reset_at: Some(reset_at),
-                retry_after: Some(retry_after),
-            });
+    /// Charge tokens after the fact, once real usage is known
+    /// Always succeeds; overspending pushes the levels into debt so later requests wait.
+    /// A negative amount refunds tokens that were reserved but not used.
+    pub async fn debit_tokens(
+        &self,
+        levels: &[RateLimitLevel],
+        tokens: i32,
+    ) -> ApiResult<RateLimitStatus> {
+        if tokens == 0
+            || levels
+                .iter()
+                .all(|level| level.limit.tokens_per_minute.is_none())
+        {
+            return self.get_status(levels).await;
         }
 
-        // Request allowed
+        // Request cost is zero, so the request dimension is only read for the status
+        let status = self
+            .run_script(levels, WindowMode::Debit, 0, tokens)
+            .await?;
+
         debug!(
-            "Rate limit check passed for key {}: requests_remaining={:?}, tokens_remaining={:?}",
-            key_id, requests_status.remaining, tokens_status.remaining
+            "Debited {} tokens for {}: tokens_remaining={:?}",
+            tokens,
+            levels_label(levels),
+            status.tokens_remaining
         );
 
-        Ok(RateLimitStatus {
-            limited: false,
-            requests_remaining: requests_status.remaining,
-            tokens_remaining: tokens_status.remaining,
-            reset_at: Some(requests_status.reset_at),
-            retry_after: None,
-        })
+        Ok(status)
     }
 
     /// Get current rate limit status without incrementing
-    pub async fn get_status(
+    pub async fn get_status(&self, levels: &[RateLimitLevel]) -> ApiResult<RateLimitStatus> {
+        self.run_script(levels, WindowMode::Peek, 0, 0).await
+    }
+
+    async fn run_script(
         &self,
-        key_id: &str,
-        rate_limit: &RateLimit,
+        levels: &[RateLimitLevel],
+        mode: WindowMode,
+        requests: i32,
+        tokens: i32,
     ) -> ApiResult<RateLimitStatus> {
-        if self.redis_client.is_none() {
-            return Ok(RateLimitStatus {
-                limited: false,
-                requests_remaining: None,
-                tokens_remaining: None,
-                reset_at: None,
-                retry_after: None,
-            });
+        let buckets = buckets_for(levels, requests, tokens);
+        if buckets.is_empty() {
+            return Ok(RateLimitStatus::unlimited());
         }
 
-        if rate_limit.requests_per_minute.is_none() && rate_limit.tokens_per_minute.is_none() {
-            return Ok(RateLimitStatus {
-                limited: false,
-                requests_remaining: None,
-                tokens_remaining: None,
-                reset_at: None,
-                retry_after: None,
-            });
+        if !self.redis_client.is_configured() {
+            return self.run_local(&buckets, mode);
         }
 
-        let redis_conn = self.redis_client.as_ref().unwrap();
-        let now = Utc::now().timestamp();
-        let window_start = now - self.window_size_seconds;
-
-        // Get request count
-        let requests_remaining = if let Some(rpm_limit) = rate_limit.requests_per_minute {
-            let current = self
-                .get_counter_value(
-                    redis_conn,
-                    &format!("ratelimit:{}:requests", key_id),
-                    window_start,
-                )
-                .await?;
-            Some(std::cmp::max(0, rpm_limit - current))
-        } else {
-            None
-        };
-
-        // Get token count
-        let tokens_remaining = if let Some(tpm_limit) = rate_limit.tokens_per_minute {
-            let current = self
-                .get_counter_value(
-                    redis_conn,
-                    &format!("ratelimit:{}:tokens", key_id),
-                    window_start,
-                )
-                .await?;
-            Some(std::cmp::max(0, tpm_limit - current))
-        } else {
-            None
-        };
+        if let Some(mut conn) = self.redis_client.get().filter(|_| self.health.should_try()) {
+            let mut invocation = self.script.prepare_invoke();
+            invocation
+                .arg(self.window_size_seconds * 1000)
+                .arg(mode.as_str());
+            for bucket in &buckets {
+                invocation
+                    .key(&bucket.key)
+                    .arg(bucket.limit)
+                    .arg(bucket.cost);
+            }
 
-        Ok(RateLimitStatus {
-            limited: false,
-            requests_remaining,
-            tokens_remaining,
-            reset_at: Some(now + self.window_size_seconds),
-            retry_after: None,
-        })
-    }
+            let reply: redis::RedisResult<Vec<i64>> = invocation.invoke_async(&mut conn).await;
 
-    /// Sliding window counter implementation using Redis sorted sets
-    /// Returns whether the request is allowed and remaining capacity
-    async fn check_and_increment_counter(
-        &self,
-        redis_conn: &redis::aio::ConnectionManager,
-        key: &str,
-        limit: i32,
-        now: i64,
-        window_start: i64,
-        increment: i32,
-    ) -> ApiResult<CounterStatus> {
-        let mut conn = redis_conn.clone();
-
-        // Use Redis pipeline for atomic operations
-        let pipe = redis::pipe()
-            // Remove old entries outside the window
-            .zrembyscore(key, "-inf", window_start)
-            // Count current entries in the window
-            .zcount(key, window_start, "+inf")
-            // Add new entry with current timestamp as score
-            // Use unique member by appending microseconds to avoid collisions
-            .zadd(
-                key,
-                format!("{}:{}", now, Utc::now().timestamp_subsec_micros()),
-                now,
-            )
-            // Set expiration to window size + buffer
-            .expire(key, self.window_size_seconds + 10)
-            .clone();
-
-        let results: Vec<i32> = pipe
-            .query_async(&mut conn)
-            .await
-            .map_err(|e| ApiError::RateLimitError(format!("Redis error: {}", e)))?;
-
-        // Results: [removed_count, current_count, zadd_result, expire_result]
-        let current_count = results.get(1).copied().unwrap_or(0);
-
-        // Check if adding this request would exceed the limit
-        // We check current_count (before increment) + increment <= limit
-        if current_count + increment > limit {
-            // Calculate reset time (start of next window)
-            let reset_at = now + self.window_size_seconds;
-
-            return Ok(CounterStatus {
-                allowed: false,
-                remaining: Some(std::cmp::max(0, limit - current_count)),
-                reset_at,
-            });
-        }
-
-        // Increment the counter by adding 'increment' entries
-        // For tokens, we add multiple entries to represent token usage
-        if increment > 1 {
-            let mut pipe = redis::pipe();
-            for i in 1..increment {
-                pipe.zadd(
-                    key,
-                    format!("{}:{}:{}", now, Utc::now().timestamp_subsec_micros(), i),
-                    now,
-                );
+            match reply {
+                Ok(reply) => {
+                    self.health.record_success();
+                    return status_from_reply(&reply, &buckets);
+                }
+                Err(e) => self.health.record_failure(&e),
             }
-            let _: () = pipe
-                .query_async(&mut conn)
-                .await
-                .map_err(|e| ApiError::RateLimitError(format!("Redis error: {}", e)))?;
         }
 
-        let new_count = current_count + increment;
-        let remaining = limit - new_count;
+        if !self.fail_open {
+            return Err(ApiError::ServiceUnavailable);
+        }
 
-        Ok(CounterStatus {
-            allowed: true,
-            remaining: Some(std::cmp::max(0, remaining)),
-            reset_at: now + self.window_size_seconds,
-        })
+        self.run_local(&buckets, mode)
     }
 
-    /// Get the current counter value without incrementing
-    async fn get_counter_value(
-        &self,
-        redis_conn: &redis::aio::ConnectionManager,
-        key: &str,
-        window_start: i64,
-    ) -> ApiResult<i32> {
-        let mut conn = redis_conn.clone();
-
-        let count: i32 = conn
-            .zcount(key, window_start, "+inf")
-            .await
-            .map_err(|e| ApiError::RateLimitError(format!("Redis error: {}", e)))?;
-
-        Ok(count)
+    fn run_local(&self, buckets: &[Bucket], mode: WindowMode) -> ApiResult<RateLimitStatus> {
+        let limits: Vec<(&str, i64, i64)> = buckets
+            .iter()
+            .map(|bucket| (bucket.key.as_str(), bucket.limit as i64, bucket.cost as i64))
+            .collect();
+        let reply = self.local.apply(mode, &limits);
+        status_from_reply(&reply, buckets)
     }
 
-    /// Reset rate limits for a key (for testing or admin operations)
-    pub async fn reset(&self, key_id: &str) -> ApiResult<()> {
-        if let Some(redis_conn) = &self.redis_client {
-            let mut conn = redis_conn.clone();
+    /// Reset rate limits at one level (for testing or admin operations)
+    pub async fn reset(&self, scope: LimitScope, id: &str) -> ApiResult<()> {
+        let keys: Vec<String> = ["requests", "tokens"]
+            .iter()
+            .map(|name| format!("ratelimit:gcra:{}:{}:{}", scope, id, name))
+            .collect();
+
+        for key in &keys {
+            self.local.reset(key);
+        }
+
+        if let Some(mut conn) = self.redis_client.get() {
             let _: () = conn
-                .del(&[
-                    format!("ratelimit:{}:requests", key_id),
-                    format!("ratelimit:{}:tokens", key_id),
-                ])
+                .del(&keys)
                 .await
                 .map_err(|e| ApiError::RateLimitError(format!("Redis error: {}", e)))?;
 
-            debug!("Rate limits reset for key {}", key_id);
+            debug!("Rate limits reset for {} {}", scope, id);
         }
         Ok(())
     }
 }
 
-#[derive(Debug)]
-struct CounterStatus {
-    allowed: bool,
-    remaining: Option<i32>,
-    reset_at: i64,
+impl WindowMode {
+    /// Mode argument for the GCRA script
+    fn as_str(&self) -> &'static str {
+        match self {
+            WindowMode::Check => "check",
+            WindowMode::Debit => "debit",
+            WindowMode::Peek => "peek",
+        }
+    }
+}
+
+/// Short description of the levels for logs, e.g. "key 1234, user 5678"
+fn levels_label(levels: &[RateLimitLevel]) -> String {
+    levels
+        .iter()
+        .filter(|level| !level.limit.is_unlimited())
+        .map(|level| format!("{} {}", level.scope, level.id))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Convert a GCRA script reply (or the local limiter's equivalent) into a RateLimitStatus
+fn status_from_reply(reply: &[i64], buckets: &[Bucket]) -> ApiResult<RateLimitStatus> {
+    let invalid =
+        || ApiError::RateLimitError(format!("Unexpected rate limit script reply: {:?}", reply));
+
+    let [allowed, now_ms, blocking_bucket, retry_after_ms, bucket_replies @ ..] = reply else {
+        return Err(invalid());
+    };
+    if bucket_replies.len() != buckets.len() * 2 {
+        return Err(invalid());
+    }
+
+    let mut status = RateLimitStatus::unlimited();
+    // The levels are fully replenished once every bucket is
+    let mut reset_at_ms = *now_ms;
+
+    for (bucket, bucket_reply) in buckets.iter().zip(bucket_replies.chunks(2)) {
+        let remaining = bucket_reply[0] as i32;
+        reset_at_ms = reset_at_ms.max(bucket_reply[1]);
+
+        // Report the most restrictive level for each dimension
+        let (current_remaining, current_limit) = match bucket.dimension {
+            Dimension::Requests => (&mut status.requests_remaining, &mut status.requests_limit),
+            Dimension::Tokens => (&mut status.tokens_remaining, &mut status.tokens_limit),
+        };
+        if current_remaining.is_none_or(|current| remaining < current) {
+            *current_remaining = Some(remaining);
+            *current_limit = Some(bucket.limit);
+        }
+    }
+
+    status.limited = *allowed == 0;
+    if status.limited {
+        status.blocked_by = usize::try_from(*blocking_bucket)
+            .ok()
+            .and_then(|index| index.checked_sub(1))
+            .and_then(|index| buckets.get(index))
+            .map(|bucket| bucket.scope);
+        status.retry_after_ms = Some(*retry_after_ms);
+        status.retry_after = Some((*retry_after_ms + 999) / 1000);
+    }
+    status.reset_at_ms = Some(reset_at_ms);
+    status.reset_at = Some((reset_at_ms + 999) / 1000);
+
+    Ok(status)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn limit(rpm: Option<i32>, tpm: Option<i32>) -> RateLimit {
+        RateLimit {
+            requests_per_minute: rpm,
+            tokens_per_minute: tpm,
+        }
+    }
+
     #[test]
     fn test_rate_limit_status_serialization() {
         let status = RateLimitStatus {
             limited: true,
+            blocked_by: Some(LimitScope::User),
+            requests_limit: Some(60),
+            tokens_limit: Some(10000),
             requests_remaining: Some(10),
             tokens_remaining: Some(1000),
             reset_at: Some(1234567890),
             retry_after: Some(30),
+            reset_at_ms: Some(1234567890000),
+            retry_after_ms: Some(30000),
         };
 
         let json = serde_json::to_string(&status).unwrap();
+        assert!(json.contains("\"blocked_by\":\"user\""));
         let deserialized: RateLimitStatus = serde_json::from_str(&json).unwrap();
 
         assert_eq!(status.limited, deserialized.limited);
+        assert_eq!(status.blocked_by, deserialized.blocked_by);
         assert_eq!(status.requests_remaining, deserialized.requests_remaining);
         assert_eq!(status.tokens_remaining, deserialized.tokens_remaining);
     }
 
     #[test]
     fn test_rate_limit_no_limits() {
-        let rate_limit = RateLimit {
-            requests_per_minute: None,
-            tokens_per_minute: None,
-        };
+        let rate_limit = limit(None, None);
 
-        assert!(rate_limit.requests_per_minute.is_none());
-        assert!(rate_limit.tokens_per_minute.is_none());
+        assert!(rate_limit.is_unlimited());
+        let levels = [RateLimitLevel::new(LimitScope::Key, "k", rate_limit)];
+        assert!(buckets_for(&levels, 1, 100).is_empty());
+    }
+
+    #[test]
+    fn test_buckets_for_levels() {
+        let levels = [
+            RateLimitLevel::new(LimitScope::Key, "k", limit(Some(10), None)),
+            RateLimitLevel::new(LimitScope::User, "u", limit(Some(100), Some(5000))),
+            RateLimitLevel::new(LimitScope::Global, "global", limit(None, None)),
+        ];
+        let buckets = buckets_for(&levels, 1, 42);
+        let keys: Vec<&str> = buckets.iter().map(|b| b.key.as_str()).collect();
+        assert_eq!(
+            keys,
+            [
+                "ratelimit:gcra:key:k:requests",
+                "ratelimit:gcra:user:u:requests",
+                "ratelimit:gcra:user:u:tokens",
+            ]
+        );
+        assert_eq!(buckets[2].cost, 42);
+    }
+
+    #[test]
+    fn test_status_from_reply() {
+        let levels = [
+            RateLimitLevel::new(LimitScope::Key, "k", limit(Some(60), None)),
+            RateLimitLevel::new(LimitScope::User, "u", limit(Some(100), Some(5000))),
+        ];
+        let buckets = buckets_for(&levels, 1, 0);
+
+        // Allowed; the key has fewer requests left than the user
+        let status = status_from_reply(
+            &[
+                1, 1_000_000, 0, 0, 59, 1_001_000, 80, 1_001_500, 4_000, 1_000_000,
+            ],
+            &buckets,
+        )
+        .unwrap();
+        assert!(!status.limited);
+        assert_eq!(status.blocked_by, None);
+        assert_eq!(status.requests_remaining, Some(59));
+        assert_eq!(status.requests_limit, Some(60));
+        assert_eq!(status.tokens_remaining, Some(4_000));
+        assert_eq!(status.tokens_limit, Some(5_000));
+        assert_eq!(status.reset_at_ms, Some(1_001_500));
+        assert_eq!(status.reset_at, Some(1_002));
+        assert_eq!(status.retry_after, None);
+
+        // Limited on the user's tokens (bucket 3), retry in 2.1s rounds up to 3s
+        let status = status_from_reply(
+            &[
+                0, 1_000_000, 3, 2_100, 10, 1_000_500, 50, 1_000_500, 0, 1_062_100,
+            ],
+            &buckets,
+        )
+        .unwrap();
+        assert!(status.limited);
+        assert_eq!(status.blocked_by, Some(LimitScope::User));
+        assert_eq!(status.tokens_remaining, Some(0));
+        assert_eq!(status.reset_at_ms, Some(1_062_100));
+        assert_eq!(status.retry_after, Some(3));
+        assert_eq!(status.retry_after_ms, Some(2_100));
+
+        assert!(status_from_reply(&[1, 2, 3], &buckets).is_err());
+        assert!(status_from_reply(&[1, 2, 0, 0, 5, 6], &buckets).is_err());
     }
 }
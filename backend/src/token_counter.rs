@@ -0,0 +1,352 @@
+use bytes::Bytes;
+use futures::{Stream, StreamExt};
+use serde_json::Value;
+use std::pin::Pin;
+
+use crate::{ChatCompletionRequest, ContentPart, MessageContent, Usage};
+
+/// Per-message overhead for role and separators (matches OpenAI's chat format)
+const TOKENS_PER_MESSAGE: i32 = 4;
+
+/// Flat estimate for an image part (OpenAI's cost for a 512x512 high-detail tile set)
+const TOKENS_PER_IMAGE: i32 = 765;
+
+/// Rough token count for a piece of text (~4 characters per token)
+pub fn estimate_text_tokens(text: &str) -> i32 {
+    text.chars().count().div_ceil(4) as i32
+}
+
+/// Estimate the number of prompt tokens in a chat request
+pub fn estimate_prompt_tokens(request: &ChatCompletionRequest) -> i32 {
+    request
+        .messages
+        .iter()
+        .map(|message| {
+            let content_tokens = match &message.content {
+                MessageContent::Text(text) => estimate_text_tokens(text),
+                MessageContent::Parts(parts) => parts
+                    .iter()
+                    .map(|part| match part {
+                        ContentPart::Text { text } => estimate_text_tokens(text),
+                        ContentPart::ImageUrl { .. } => TOKENS_PER_IMAGE,
+                    })
+                    .sum(),
+            };
+            TOKENS_PER_MESSAGE + content_tokens
+        })
+        .sum()
+}
+
+/// Estimate the most tokens a request can consume: its prompt plus the completion budget
+/// `default_max_tokens` is used when the request does not set `max_tokens`
+pub fn estimate_request_tokens(request: &ChatCompletionRequest, default_max_tokens: i32) -> i32 {
+    let completion_budget = request.max_tokens.unwrap_or(default_max_tokens).max(0);
+    let choices = request.n.unwrap_or(1).max(1);
+    estimate_prompt_tokens(request).saturating_add(completion_budget.saturating_mul(choices))
+}
+
+/// Collects token usage from an OpenAI-style SSE stream as it passes through
+///
+/// Uses the usage reported by the provider when present (OpenAI/Azure `usage`,
+/// Anthropic `input_tokens`/`output_tokens`, Gemini `usageMetadata`), and otherwise
+/// falls back to estimating completion tokens from the streamed content.
+#[derive(Debug, Default)]
+pub struct StreamUsageTracker {
+    buffer: String,
+    prompt_tokens: Option<i32>,
+    completion_tokens: Option<i32>,
+    completion_chars: usize,
+}
+
+impl StreamUsageTracker {
+    /// Feed a chunk of the SSE stream
+    pub fn observe(&mut self, chunk: &[u8]) {
+        self.buffer.push_str(&String::from_utf8_lossy(chunk));
+
+        // Only complete lines can be parsed; keep the remainder for the next chunk
+        while let Some(newline) = self.buffer.find('\n') {
+            let line: String = self.buffer.drain(..=newline).collect();
+            self.observe_line(line.trim());
+        }
+    }
+
+    fn observe_line(&mut self, line: &str) {
+        let Some(data) = line.strip_prefix("data:") else {
+            return;
+        };
+        let Ok(event) = serde_json::from_str::<Value>(data.trim()) else {
+            return;
+        };
+
+        if let Some(choices) = event.get("choices").and_then(Value::as_array) {
+            for choice in choices {
+                if let Some(content) = choice.pointer("/delta/content").and_then(Value::as_str) {
+                    self.completion_chars += content.chars().count();
+                }
+            }
+        }
+
+        let read =
+            |value: &Value, field: &str| value.get(field).and_then(Value::as_i64).map(|v| v as i32);
+
+        if let Some(usage) = event.get("usage").filter(|u| u.is_object()) {
+            self.record(
+                read(usage, "prompt_tokens").or_else(|| read(usage, "input_tokens")),
+                read(usage, "completion_tokens").or_else(|| read(usage, "output_tokens")),
+            );
+        }
+        if let Some(usage) = event.get("usageMetadata") {
+            self.record(
+                read(usage, "promptTokenCount"),
+                read(usage, "candidatesTokenCount"),
+            );
+        }
+    }
+
+    /// Providers report cumulative counts, so keep the largest value seen
+    fn record(&mut self, prompt_tokens: Option<i32>, completion_tokens: Option<i32>) {
+        if let Some(tokens) = prompt_tokens {
+            self.prompt_tokens = Some(self.prompt_tokens.map_or(tokens, |t| t.max(tokens)));
+        }
+        if let Some(tokens) = completion_tokens {
+            self.completion_tokens = Some(self.completion_tokens.map_or(tokens, |t| t.max(tokens)));
+        }
+    }
+
+    /// Usage for the stream so far, using `estimated_prompt_tokens` if none was reported
+    pub fn usage(&self, estimated_prompt_tokens: i32) -> Usage {
+        let prompt_tokens = self.prompt_tokens.unwrap_or(estimated_prompt_tokens);
+        let completion_tokens = self
+            .completion_tokens
+            .unwrap_or_else(|| self.completion_chars.div_ceil(4) as i32);
+
+        Usage {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+        }
+    }
+}
+
+/// Runs the callback with the tracked usage when the stream is dropped
+struct UsageGuard<F: FnOnce(Usage)> {
+    tracker: StreamUsageTracker,
+    estimated_prompt_tokens: i32,
+    on_finish: Option<F>,
+}
+
+impl<F: FnOnce(Usage)> Drop for UsageGuard<F> {
+    fn drop(&mut self) {
+        if let Some(on_finish) = self.on_finish.take() {
+            on_finish(self.tracker.usage(self.estimated_prompt_tokens));
+        }
+    }
+}
+
+/// Wrap a response stream so `on_finish` receives its token usage once it ends
+/// The callback also runs if the client disconnects and the stream is dropped early
+pub fn track_stream_usage<S, F>(
+    stream: S,
+    estimated_prompt_tokens: i32,
+    on_finish: F,
+) -> Pin<Box<dyn Stream<Item = Result<Bytes, std::io::Error>> + Send>>
+where
+    S: Stream<Item = Result<Bytes, std::io::Error>> + Send + 'static,
+    F: FnOnce(Usage) + Send + 'static,
+{
+    let mut guard = UsageGuard {
+        tracker: StreamUsageTracker::default(),
+        estimated_prompt_tokens,
+        on_finish: Some(on_finish),
+    };
+
+    Box::pin(stream.map(move |chunk| {
+        if let Ok(bytes) = &chunk {
+            guard.tracker.observe(bytes);
+        }
+        chunk
+    }))
+}
+
+/// Whether an SSE line is OpenAI's final usage chunk, which has no choices
+fn is_usage_only_line(line: &[u8]) -> bool {
+    let Some(data) = line.strip_prefix(b"data:") else {
+        return false;
+    };
+    let Ok(event) = serde_json::from_slice::<Value>(data.trim_ascii()) else {
+        return false;
+    };
+    event.get("usage").is_some_and(Value::is_object)
+        && event
+            .get("choices")
+            .and_then(Value::as_array)
+            .is_some_and(Vec::is_empty)
+}
+
+/// Take the complete lines out of `buffer`, dropping usage-only chunks
+fn drain_lines_without_usage(buffer: &mut Vec<u8>) -> Vec<u8> {
+    let mut out = Vec::new();
+    while let Some(newline) = buffer.iter().position(|&b| b == b'\n') {
+        let line: Vec<u8> = buffer.drain(..=newline).collect();
+        if !is_usage_only_line(&line) {
+            out.extend_from_slice(&line);
+        }
+    }
+    out
+}
+
+/// Remove the usage-only chunk from an OpenAI-style SSE stream
+///
+/// The gateway asks OpenAI and Azure for usage on every stream to settle budgets and
+/// token limits; clients that didn't set `stream_options.include_usage` themselves don't
+/// expect the extra chunk with empty `choices`. Apply after [`track_stream_usage`].
+pub fn strip_usage_chunks<S>(
+    stream: S,
+) -> Pin<Box<dyn Stream<Item = Result<Bytes, std::io::Error>> + Send>>
+where
+    S: Stream<Item = Result<Bytes, std::io::Error>> + Send + 'static,
+{
+    let state = (Box::pin(stream), Vec::new(), false);
+    Box::pin(futures::stream::unfold(
+        state,
+        |(mut stream, mut buffer, done)| async move {
+            if done {
+                return None;
+            }
+            loop {
+                match stream.next().await {
+                    Some(Ok(bytes)) => {
+                        // Only complete lines can be inspected; keep the rest for the next chunk
+                        buffer.extend_from_slice(&bytes);
+                        let lines = drain_lines_without_usage(&mut buffer);
+                        if !lines.is_empty() {
+                            return Some((Ok(Bytes::from(lines)), (stream, buffer, false)));
+                        }
+                    }
+                    Some(Err(e)) => return Some((Err(e), (stream, buffer, false))),
+                    None if buffer.is_empty() => return None,
+                    None => {
+                        let rest = std::mem::take(&mut buffer);
+                        return Some((Ok(Bytes::from(rest)), (stream, buffer, true)));
+                    }
+                }
+            }
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Message;
+
+    fn request(content: &str, max_tokens: Option<i32>) -> ChatCompletionRequest {
+        serde_json::from_value(serde_json::json!({
+            "model": "gpt-4o",
+            "messages": [{ "role": "user", "content": content }],
+            "max_tokens": max_tokens,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_estimate_request_tokens() {
+        let req = request("abcdefgh", Some(100));
+        assert_eq!(estimate_prompt_tokens(&req), TOKENS_PER_MESSAGE + 2);
+        assert_eq!(
+            estimate_request_tokens(&req, 1024),
+            TOKENS_PER_MESSAGE + 2 + 100
+        );
+
+        let req = request("abcdefgh", None);
+        assert_eq!(
+            estimate_request_tokens(&req, 1024),
+            TOKENS_PER_MESSAGE + 2 + 1024
+        );
+    }
+
+    #[test]
+    fn test_estimate_image_parts() {
+        let mut req = request("", None);
+        req.messages = vec![Message {
+            role: "user".to_string(),
+            content: MessageContent::Parts(vec![
+                ContentPart::Text {
+                    text: "abcd".to_string(),
+                },
+                ContentPart::ImageUrl {
+                    image_url: crate::ImageUrlContent {
+                        url: "https://example.com/cat.png".to_string(),
+                        detail: None,
+                    },
+                },
+            ]),
+            name: None,
+        }];
+        assert_eq!(
+            estimate_prompt_tokens(&req),
+            TOKENS_PER_MESSAGE + 1 + TOKENS_PER_IMAGE
+        );
+    }
+
+    #[test]
+    fn test_tracker_uses_reported_usage() {
+        let mut tracker = StreamUsageTracker::default();
+        tracker.observe(b"data: {\"choices\":[{\"delta\":{\"content\":\"Hello\"}}]}\n\n");
+        // Split across chunks mid-line
+        tracker.observe(b"data: {\"choices\":[],\"usage\":{\"prompt_tokens\":12,");
+        tracker.observe(b"\"completion_tokens\":7,\"total_tokens\":19}}\n\ndata: [DONE]\n\n");
+
+        let usage = tracker.usage(100);
+        assert_eq!(usage.prompt_tokens, 12);
+        assert_eq!(usage.completion_tokens, 7);
+        assert_eq!(usage.total_tokens, 19);
+    }
+
+    #[test]
+    fn test_tracker_estimates_without_reported_usage() {
+        let mut tracker = StreamUsageTracker::default();
+        tracker.observe(b"data: {\"choices\":[{\"delta\":{\"content\":\"Hello wor\"}}]}\n\n");
+        tracker.observe(b"data: {\"choices\":[{\"delta\":{\"content\":\"ld!\"}}]}\n\n");
+
+        let usage = tracker.usage(50);
+        assert_eq!(usage.prompt_tokens, 50);
+        assert_eq!(usage.completion_tokens, 3);
+        assert_eq!(usage.total_tokens, 53);
+    }
+
+    #[test]
+    fn test_tracker_anthropic_and_gemini_usage() {
+        let mut tracker = StreamUsageTracker::default();
+        tracker.observe(b"data: {\"type\":\"message_start\",\"usage\":{\"input_tokens\":30,\"output_tokens\":1}}\n");
+        tracker.observe(b"data: {\"type\":\"message_delta\",\"usage\":{\"output_tokens\":42}}\n");
+        let usage = tracker.usage(0);
+        assert_eq!((usage.prompt_tokens, usage.completion_tokens), (30, 42));
+
+        let mut tracker = StreamUsageTracker::default();
+        tracker.observe(
+            b"data: {\"usageMetadata\":{\"promptTokenCount\":8,\"candidatesTokenCount\":16}}\n",
+        );
+        let usage = tracker.usage(0);
+        assert_eq!((usage.prompt_tokens, usage.completion_tokens), (8, 16));
+    }
+
+    #[tokio::test]
+    async fn test_strip_usage_chunks() {
+        let chunks: Vec<Result<Bytes, std::io::Error>> = vec![
+            Ok(Bytes::from_static(
+                b"data: {\"choices\":[{\"delta\":{\"content\":\"Hi\"}}]}\n\ndata: {\"choi",
+            )),
+            Ok(Bytes::from_static(
+                b"ces\":[],\"usage\":{\"prompt_tokens\":3,\"completion_tokens\":1}}\n\ndata: [DONE]",
+            )),
+        ];
+        let stream = strip_usage_chunks(futures::stream::iter(chunks));
+        let output: Vec<u8> = stream.map(|chunk| chunk.unwrap().to_vec()).concat().await;
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "data: {\"choices\":[{\"delta\":{\"content\":\"Hi\"}}]}\n\n\ndata: [DONE]"
+        );
+    }
+}
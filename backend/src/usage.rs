@@ -0,0 +1,151 @@
+use dashmap::DashMap;
+use sqlx::{Pool, Postgres};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::{
+    auth::KeyCache,
+    models::{KeySecret, ScopeLimits, VirtualKey},
+};
+
+/// Usage of one key since the last flush
+#[derive(Debug, Default)]
+struct KeyUsage {
+    used: bool,
+    previous_secret_used: bool,
+    /// Spend per matching model limit pattern (`None` when no pattern matched)
+    spend: HashMap<Option<String>, f64>,
+}
+
+/// Collects `last_used_at` updates and spend in memory and writes them in batches, so
+/// requests don't wait on Postgres for bookkeeping
+///
+/// Budgets are enforced against the flushed spend, so they can be overshot by up to one
+/// flush interval's worth of requests.
+#[derive(Default)]
+pub struct UsageRecorder {
+    keys: DashMap<Uuid, KeyUsage>,
+    /// Spend per (user, team), charged to the user, team and global budgets
+    scopes: DashMap<(Option<Uuid>, Option<Uuid>), f64>,
+}
+
+impl UsageRecorder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record that a key authenticated a request
+    pub fn record_use(&self, key_id: Uuid, secret: KeySecret) {
+        let mut usage = self.keys.entry(key_id).or_default();
+        usage.used = true;
+        usage.previous_secret_used |= secret == KeySecret::Previous;
+    }
+
+    /// Record a request's cost against its key (and model limit pattern), user and team
+    pub fn record_spend(
+        &self,
+        key_id: Uuid,
+        user_id: Option<Uuid>,
+        team_id: Option<Uuid>,
+        model_pattern: Option<String>,
+        cost: f64,
+    ) {
+        *self
+            .keys
+            .entry(key_id)
+            .or_default()
+            .spend
+            .entry(model_pattern)
+            .or_default() += cost;
+        *self.scopes.entry((user_id, team_id)).or_default() += cost;
+    }
+
+    /// Write everything recorded so far
+    ///
+    /// Spend that fails to write is kept for the next flush. Keys and scopes that run out
+    /// of budget are dropped from the key cache so they're rejected on the next request.
+    pub async fn flush(&self, pool: &Pool<Postgres>, key_cache: &KeyCache) {
+        let key_ids: Vec<Uuid> = self.keys.iter().map(|entry| *entry.key()).collect();
+        for key_id in key_ids {
+            let Some((_, usage)) = self.keys.remove(&key_id) else {
+                continue;
+            };
+
+            let mut over_budget = false;
+            for (pattern, amount) in usage.spend {
+                match VirtualKey::increment_spend(pool, key_id, amount, pattern.as_deref()).await {
+                    Ok(over) => over_budget |= over,
+                    Err(e) => {
+                        warn!("Failed to record spend for key {}: {}", key_id, e);
+                        *self
+                            .keys
+                            .entry(key_id)
+                            .or_default()
+                            .spend
+                            .entry(pattern)
+                            .or_default() += amount;
+                    }
+                }
+            }
+
+            // Charging spend already bumped last_used_at
+            let result = if usage.previous_secret_used {
+                VirtualKey::update_previous_last_used(pool, key_id).await
+            } else if usage.used {
+                VirtualKey::update_last_used(pool, key_id).await
+            } else {
+                Ok(())
+            };
+            if let Err(e) = result {
+                warn!("Failed to update last use of key {}: {}", key_id, e);
+            }
+
+            if over_budget {
+                if let Ok(Some(key)) = VirtualKey::find_by_id(pool, key_id).await {
+                    key_cache.invalidate(&key).await;
+                }
+            }
+        }
+
+        let scopes: Vec<(Option<Uuid>, Option<Uuid>)> =
+            self.scopes.iter().map(|entry| *entry.key()).collect();
+        let mut crossed_budget = false;
+        for (user_id, team_id) in scopes {
+            let Some((_, amount)) = self.scopes.remove(&(user_id, team_id)) else {
+                continue;
+            };
+            match ScopeLimits::increment_spend(pool, user_id, team_id, amount).await {
+                Ok(crossed) => crossed_budget |= crossed,
+                Err(e) => {
+                    warn!("Failed to record spend for user/team budgets: {}", e);
+                    *self.scopes.entry((user_id, team_id)).or_default() += amount;
+                }
+            }
+        }
+
+        // Cached principals carry user, team and global spend for every key under them
+        if crossed_budget {
+            key_cache.invalidate_all().await;
+        }
+    }
+
+    /// Spawn the background job that flushes recorded usage
+    pub fn spawn_flush_job(
+        self: &Arc<Self>,
+        pool: Pool<Postgres>,
+        key_cache: KeyCache,
+        interval: Duration,
+    ) {
+        let recorder = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+                recorder.flush(&pool, &key_cache).await;
+            }
+        });
+    }
+}
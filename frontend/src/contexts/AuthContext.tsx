@@ -10,7 +10,7 @@ interface AuthContextType {
   register: (data: RegisterRequest) => Promise<void>;
   logout: () => Promise<void>;
   loginWithGitHub: () => Promise<void>;
-  setAuthData: (token: string, user: User) => void;
+  setAuthData: (token: string, user: User, refreshToken?: string | null) => void;
 }
 
 const AuthContext = createContext<AuthContextType | undefined>(undefined);
@@ -39,6 +39,7 @@ export const AuthProvider: React.FC<{ children: React.ReactNode }> = ({ children
           // Token invalid, clear auth state
           console.error("Failed to load auth state:", error);
           localStorage.removeItem("jwt_token");
+          localStorage.removeItem("refresh_token");
           localStorage.removeItem("user");
           setToken(null);
           setUser(null);
@@ -51,17 +52,20 @@ export const AuthProvider: React.FC<{ children: React.ReactNode }> = ({ children
     loadAuthState();
   }, []);
 
-  const setAuthData = (newToken: string, newUser: User) => {
+  const setAuthData = (newToken: string, newUser: User, refreshToken?: string | null) => {
     setToken(newToken);
     setUser(newUser);
     localStorage.setItem("jwt_token", newToken);
     localStorage.setItem("user", JSON.stringify(newUser));
+    if (refreshToken) {
+      localStorage.setItem("refresh_token", refreshToken);
+    }
   };
 
   const login = async (data: LoginRequest) => {
     try {
       const response = await authApi.login(data);
-      setAuthData(response.token, response.user);
+      setAuthData(response.token, response.user, response.refresh_token);
     } catch (error) {
       console.error("Login failed:", error);
       throw error;
@@ -71,7 +75,7 @@ export const AuthProvider: React.FC<{ children: React.ReactNode }> = ({ children
   const register = async (data: RegisterRequest) => {
     try {
       const response = await authApi.register(data);
-      setAuthData(response.token, response.user);
+      setAuthData(response.token, response.user, response.refresh_token);
     } catch (error) {
       console.error("Registration failed:", error);
       throw error;
@@ -88,23 +92,14 @@ export const AuthProvider: React.FC<{ children: React.ReactNode }> = ({ children
       setToken(null);
       setUser(null);
       localStorage.removeItem("jwt_token");
+      localStorage.removeItem("refresh_token");
       localStorage.removeItem("user");
     }
   };
 
   const loginWithGitHub = async () => {
-    try {
-      const { auth_url, state } = await authApi.startGitHubOAuth();
-
-      // Store state for CSRF verification
-      sessionStorage.setItem("oauth_state", state);
-
-      // Redirect to GitHub OAuth
-      window.location.href = auth_url;
-    } catch (error) {
-      console.error("GitHub OAuth initiation failed:", error);
-      throw error;
-    }
+    // The backend keeps the OAuth state and PKCE verifier, bound to this browser by cookie
+    window.location.href = authApi.oauthStartUrl("github");
   };
 
   const value: AuthContextType = {
@@ -1,4 +1,4 @@
-import axios, { type AxiosError } from "axios";
+import axios, { type AxiosError, type InternalAxiosRequestConfig } from "axios";
 
 // Create axios instance with base configuration
 const api = axios.create({
@@ -22,13 +22,50 @@ api.interceptors.request.use(
   }
 );
 
-// Response interceptor - handle 401 errors globally
+type RetriableRequest = InternalAxiosRequestConfig & { _retried?: boolean };
+
+// Refresh tokens work once, so concurrent 401s share a single refresh
+let refreshInFlight: Promise<string> | null = null;
+
+const refreshAccessToken = (refreshToken: string): Promise<string> => {
+  if (!refreshInFlight) {
+    refreshInFlight = axios
+      .post<SessionTokens>(`${api.defaults.baseURL}/auth/refresh`, {
+        refresh_token: refreshToken,
+      })
+      .then((response) => {
+        localStorage.setItem("jwt_token", response.data.token);
+        localStorage.setItem("refresh_token", response.data.refresh_token);
+        return response.data.token;
+      })
+      .finally(() => {
+        refreshInFlight = null;
+      });
+  }
+  return refreshInFlight;
+};
+
+// Response interceptor - renew expired access tokens, otherwise handle 401 errors globally
 api.interceptors.response.use(
   (response) => response,
-  (error: AxiosError) => {
+  async (error: AxiosError) => {
+    const request = error.config as RetriableRequest | undefined;
+    const refreshToken = localStorage.getItem("refresh_token");
+    if (error.response?.status === 401 && request && !request._retried && refreshToken) {
+      request._retried = true;
+      try {
+        const token = await refreshAccessToken(refreshToken);
+        request.headers.Authorization = `Bearer ${token}`;
+        return api(request);
+      } catch {
+        // Fall through to logging out
+      }
+    }
+
     if (error.response?.status === 401) {
-      // Clear token and redirect to login
+      // Clear tokens and redirect to login
       localStorage.removeItem("jwt_token");
+      localStorage.removeItem("refresh_token");
       localStorage.removeItem("user");
 
       // Only redirect if not already on login/register page
@@ -57,8 +94,13 @@ export interface User {
   role: string;
 }
 
-export interface AuthResponse {
+export interface SessionTokens {
   token: string;
+  refresh_token: string;
+  expires_in: number; // Seconds until token expires
+}
+
+export interface AuthResponse extends SessionTokens {
   user: User;
 }
 
@@ -71,11 +113,8 @@ export interface RegisterRequest {
 export interface LoginRequest {
   email: string;
   password: string;
-}
-
-export interface OAuthStartResponse {
-  auth_url: string;
-  state: string;
+  /** TOTP or recovery code, for accounts with two-factor authentication */
+  totp_code?: string;
 }
 
 export interface VirtualKey {
@@ -150,14 +189,30 @@ export const authApi = {
     await api.post("/auth/logout");
   },
 
+  // Revoke every session of the current user, on all devices
+  logoutAll: async (): Promise<{ revoked_sessions: number }> => {
+    const response = await api.post<{ revoked_sessions: number }>("/auth/logout-all");
+    return response.data;
+  },
+
   getCurrentUser: async (): Promise<User> => {
     const response = await api.get<User>("/auth/me");
     return response.data;
   },
 
   // OAuth
-  startGitHubOAuth: async (): Promise<OAuthStartResponse> => {
-    const response = await api.get<OAuthStartResponse>("/auth/oauth/github");
+  // The browser navigates to this URL; the backend sets the state cookie and
+  // redirects to the provider
+  oauthStartUrl: (provider: string): string => {
+    return `${api.defaults.baseURL}/auth/oauth/${provider}`;
+  },
+
+  // The OAuth callback redirects back with a one-time code instead of the tokens
+  exchangeOAuthCode: async (code: string, totpCode?: string): Promise<AuthResponse> => {
+    const response = await api.post<AuthResponse>("/auth/oauth/exchange", {
+      code,
+      totp_code: totpCode,
+    });
     return response.data;
   },
 
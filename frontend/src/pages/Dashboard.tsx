@@ -30,7 +30,7 @@ const Dashboard: React.FC = () => {
   const { data: models, isLoading: modelsLoading } = useQuery({
     queryKey: ["models"],
     queryFn: async () => {
-      const response = await api.post("/v1/models");
+      const response = await api.get("/v1/models");
       return response.data.data;
     },
   });
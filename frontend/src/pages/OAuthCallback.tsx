@@ -8,30 +8,36 @@ export default function OAuthCallback() {
   const navigate = useNavigate();
   const { setAuthData } = useAuth();
   const [error, setError] = useState("");
+  // Set when the account has two-factor authentication and the code must be entered
+  const [totpLoginCode, setTotpLoginCode] = useState<string | null>(null);
+  const [totpCode, setTotpCode] = useState("");
+  const [totpError, setTotpError] = useState("");
 
   useEffect(() => {
     const handleCallback = async () => {
-      // Check if we have token and user data (from backend redirect)
-      const token = searchParams.get("token");
-      const userEncoded = searchParams.get("user");
+      // The backend redirects here with a one-time code to exchange for the session
+      const loginCode = searchParams.get("login_code");
 
-      if (token && userEncoded) {
+      if (loginCode) {
         try {
-          // Decode base64-encoded user data
-          const userJson = atob(userEncoded);
-          const user = JSON.parse(userJson);
+          const response = await authApi.exchangeOAuthCode(loginCode);
 
           // Clear stored state
           sessionStorage.removeItem("oauth_state");
 
           // Set auth data
-          setAuthData(token, user);
+          setAuthData(response.token, response.user, response.refresh_token);
 
           // Redirect to dashboard
           navigate("/");
           return;
         } catch (err: unknown) {
-          console.error("Failed to parse user data:", err);
+          const apiError = err as { response?: { data?: { error?: { type?: string } } } };
+          if (apiError.response?.data?.error?.type === "TotpRequired") {
+            setTotpLoginCode(loginCode);
+            return;
+          }
+          console.error("Failed to exchange login code:", err);
           setError("Failed to complete authentication");
           setTimeout(() => navigate("/login"), 3000);
           return;
@@ -74,7 +80,7 @@ export default function OAuthCallback() {
         sessionStorage.removeItem("oauth_state");
 
         // Set auth data
-        setAuthData(response.token, response.user);
+        setAuthData(response.token, response.user, response.refresh_token);
 
         // Redirect to dashboard
         navigate("/");
@@ -94,6 +100,56 @@ export default function OAuthCallback() {
     handleCallback();
   }, [searchParams, navigate, setAuthData]);
 
+  const handleTotpSubmit = async (e: React.FormEvent) => {
+    e.preventDefault();
+    if (!totpLoginCode) return;
+    setTotpError("");
+    try {
+      const response = await authApi.exchangeOAuthCode(totpLoginCode, totpCode);
+      sessionStorage.removeItem("oauth_state");
+      setAuthData(response.token, response.user, response.refresh_token);
+      navigate("/");
+    } catch (err: unknown) {
+      const apiError = err as { response?: { data?: { error?: { message?: string } } } };
+      setTotpError(apiError.response?.data?.error?.message || "Invalid code");
+    }
+  };
+
+  if (totpLoginCode && !error) {
+    return (
+      <div className="min-h-screen flex items-center justify-center bg-gray-50">
+        <form className="max-w-md w-full space-y-6" onSubmit={handleTotpSubmit}>
+          <h2 className="text-center text-3xl font-extrabold text-gray-900">
+            Two-factor authentication
+          </h2>
+          <p className="text-center text-sm text-gray-600">
+            Enter the code from your authenticator app, or a recovery code.
+          </p>
+          {totpError && (
+            <div className="rounded-md bg-red-50 p-4 text-sm text-red-800">{totpError}</div>
+          )}
+          <input
+            id="totp-code"
+            name="totp_code"
+            type="text"
+            autoComplete="one-time-code"
+            required
+            className="appearance-none rounded-md relative block w-full px-3 py-2 border border-gray-300 placeholder-gray-500 text-gray-900 focus:outline-none focus:ring-blue-500 focus:border-blue-500 sm:text-sm"
+            placeholder="123456"
+            value={totpCode}
+            onChange={(e) => setTotpCode(e.target.value)}
+          />
+          <button
+            type="submit"
+            className="w-full flex justify-center py-2 px-4 border border-transparent text-sm font-medium rounded-md text-white bg-blue-600 hover:bg-blue-700 focus:outline-none focus:ring-2 focus:ring-offset-2 focus:ring-blue-500"
+          >
+            Verify
+          </button>
+        </form>
+      </div>
+    );
+  }
+
   return (
     <div className="min-h-screen flex items-center justify-center bg-gray-50">
       <div className="max-w-md w-full space-y-8 text-center">
@@ -35,7 +35,7 @@ const Playground: React.FC = () => {
   const { data: models } = useQuery({
     queryKey: ["models"],
     queryFn: async () => {
-      const response = await api.post("/v1/models");
+      const response = await api.get("/v1/models");
       return response.data.data;
     },
   });